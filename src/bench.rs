@@ -0,0 +1,560 @@
+use crate::memcached::namespace::parse_size;
+use crate::protocol::binary;
+use crate::protocol::binary_codec::{BinaryRequest, BinaryResponse, MemcachedBinaryClientCodec};
+use futures_util::{SinkExt, StreamExt};
+use hdrhistogram::Histogram;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// Highest latency the histograms track, in microseconds.
+const MAX_LATENCY_US: u64 = 60_000_000;
+
+fn invalid(what: &str, value: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid {} {:?}", what, value),
+    )
+}
+
+/// How keys are picked from the key space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyPattern {
+    Uniform,
+    /// Key `n` is picked with a probability proportional to `1 / (n + 1)^s`
+    /// for the exponent `s`, so a few keys take most of the traffic.
+    Zipfian(f64),
+    /// Normally distributed around the middle of the key space with a
+    /// standard deviation of a sixth of it, as memtier does.
+    Gaussian,
+}
+
+impl FromStr for KeyPattern {
+    type Err = io::Error;
+
+    /// Parses `uniform`, `gaussian` or `zipfian[:exponent]`, the exponent
+    /// being 0.99 if left out.
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        match pattern.split_once(':') {
+            None if pattern == "uniform" => Ok(KeyPattern::Uniform),
+            None if pattern == "gaussian" => Ok(KeyPattern::Gaussian),
+            None if pattern == "zipfian" => Ok(KeyPattern::Zipfian(0.99)),
+            Some(("zipfian", exponent)) => match exponent.parse() {
+                Ok(exponent) if exponent > 0.0 && exponent != 1.0 => {
+                    Ok(KeyPattern::Zipfian(exponent))
+                }
+                _ => Err(invalid("zipfian exponent", exponent)),
+            },
+            _ => Err(invalid("key pattern", pattern)),
+        }
+    }
+}
+
+/// Picks key indexes below `keys` following a `KeyPattern`.
+#[derive(Debug, Clone)]
+pub struct KeyGenerator {
+    keys: u64,
+    pattern: KeyPattern,
+    zipf: Option<Zipf>,
+}
+
+/// Precomputed constants of the zipfian generator of Gray et al., "Quickly
+/// generating billion-record synthetic databases", as used by YCSB.
+#[derive(Debug, Clone)]
+struct Zipf {
+    theta: f64,
+    alpha: f64,
+    zeta_n: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    fn new(keys: u64, theta: f64) -> Zipf {
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zeta_n = zeta(keys);
+        let zeta_2 = zeta(2.min(keys));
+        Zipf {
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zeta_n,
+            eta: (1.0 - (2.0 / keys as f64).powf(1.0 - theta)) / (1.0 - zeta_2 / zeta_n),
+        }
+    }
+
+    fn sample<R: Rng>(&self, keys: u64, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zeta_n;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(keys - 1);
+        }
+        let key = (keys as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        key.min(keys - 1)
+    }
+}
+
+impl KeyGenerator {
+    pub fn new(keys: u64, pattern: KeyPattern) -> KeyGenerator {
+        let keys = keys.max(1);
+        KeyGenerator {
+            keys,
+            pattern,
+            zipf: match pattern {
+                KeyPattern::Zipfian(theta) => Some(Zipf::new(keys, theta)),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        match self.pattern {
+            KeyPattern::Uniform => rng.gen_range(0..self.keys),
+            KeyPattern::Zipfian(_) => self.zipf.as_ref().unwrap().sample(self.keys, rng),
+            KeyPattern::Gaussian => {
+                // Box-Muller transform of two uniform samples.
+                let (u1, u2): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+                let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                let middle = self.keys as f64 / 2.0;
+                let key = middle + normal * self.keys as f64 / 6.0;
+                (key.max(0.0) as u64).min(self.keys - 1)
+            }
+        }
+    }
+}
+
+/// How large the values written are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueSizes {
+    Fixed(usize),
+    /// Uniformly between the two bounds, both included.
+    Range(usize, usize),
+    /// One of the sizes, with a probability proportional to its weight.
+    Weighted(Vec<(usize, u32)>),
+}
+
+impl FromStr for ValueSizes {
+    type Err = io::Error;
+
+    /// Parses `<size>`, `<min>-<max>` or `<size>:<weight>,...`, sizes taking
+    /// the `k`, `m` and `g` suffixes.
+    fn from_str(sizes: &str) -> Result<Self, Self::Err> {
+        let size = |size: &str| parse_size(size).ok_or_else(|| invalid("value size", size));
+        if sizes.contains(':') {
+            let mut weighted = Vec::new();
+            for entry in sizes.split(',') {
+                let (value, weight) = entry
+                    .split_once(':')
+                    .ok_or_else(|| invalid("value size", entry))?;
+                let weight = weight.parse().map_err(|_| invalid("weight", weight))?;
+                weighted.push((size(value)?, weight));
+            }
+            if weighted.iter().all(|(_, weight)| *weight == 0) {
+                return Err(invalid("value sizes", sizes));
+            }
+            Ok(ValueSizes::Weighted(weighted))
+        } else if let Some((min, max)) = sizes.split_once('-') {
+            match (size(min)?, size(max)?) {
+                (min, max) if min <= max => Ok(ValueSizes::Range(min, max)),
+                _ => Err(invalid("value sizes", sizes)),
+            }
+        } else {
+            Ok(ValueSizes::Fixed(size(sizes)?))
+        }
+    }
+}
+
+impl ValueSizes {
+    pub fn max(&self) -> usize {
+        match self {
+            ValueSizes::Fixed(size) => *size,
+            ValueSizes::Range(_, max) => *max,
+            ValueSizes::Weighted(sizes) => sizes.iter().map(|(size, _)| *size).max().unwrap_or(0),
+        }
+    }
+
+    pub fn next<R: Rng>(&self, rng: &mut R) -> usize {
+        match self {
+            ValueSizes::Fixed(size) => *size,
+            ValueSizes::Range(min, max) => rng.gen_range(*min..=*max),
+            ValueSizes::Weighted(sizes) => {
+                let total: u32 = sizes.iter().map(|(_, weight)| weight).sum();
+                let mut pick = rng.gen_range(0..total);
+                for (size, weight) in sizes {
+                    if pick < *weight {
+                        return *size;
+                    }
+                    pick -= weight;
+                }
+                unreachable!()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub server: String,
+    pub connections: usize,
+    /// Requests each connection keeps in flight.
+    pub pipeline: usize,
+    /// Sets to gets ratio, as `(sets, gets)`.
+    pub ratio: (u32, u32),
+    pub keys: u64,
+    pub key_pattern: KeyPattern,
+    pub key_prefix: String,
+    pub value_sizes: ValueSizes,
+    /// How long to run for. The run stops at whichever of `duration` and
+    /// `requests` comes first.
+    pub duration: Option<Duration>,
+    /// Requests to send over all connections together.
+    pub requests: Option<u64>,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            server: "127.0.0.1:11211".to_string(),
+            connections: 4,
+            pipeline: 1,
+            ratio: (1, 10),
+            keys: 100_000,
+            key_pattern: KeyPattern::Uniform,
+            key_prefix: "key:".to_string(),
+            value_sizes: ValueSizes::Fixed(32),
+            duration: Some(Duration::from_secs(10)),
+            requests: None,
+        }
+    }
+}
+
+/// Latencies of one kind of request, in microseconds.
+#[derive(Debug, Clone)]
+pub struct OpStats {
+    pub latency: Histogram<u64>,
+}
+
+impl OpStats {
+    pub(crate) fn new() -> OpStats {
+        OpStats {
+            latency: Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).unwrap(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, latency: Duration) {
+        self.latency
+            .saturating_record((latency.as_micros() as u64).clamp(1, MAX_LATENCY_US));
+    }
+
+    pub fn count(&self) -> u64 {
+        self.latency.len()
+    }
+
+    pub(crate) fn to_json(&self, elapsed: Duration) -> serde_json::Value {
+        let percentile = |p: f64| self.latency.value_at_quantile(p / 100.0);
+        serde_json::json!({
+            "ops": self.count(),
+            "ops_per_sec": self.count() as f64 / elapsed.as_secs_f64(),
+            "latency_us": {
+                "mean": self.latency.mean(),
+                "p50": percentile(50.0),
+                "p90": percentile(90.0),
+                "p99": percentile(99.0),
+                "p99_9": percentile(99.9),
+                "max": self.latency.max(),
+            },
+        })
+    }
+}
+
+/// The results of a run.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub elapsed: Duration,
+    pub sets: OpStats,
+    pub gets: OpStats,
+    pub hits: u64,
+    pub misses: u64,
+    /// Requests answered with a status other than success or, for gets, a
+    /// miss.
+    pub errors: u64,
+}
+
+impl Report {
+    fn new() -> Report {
+        Report {
+            elapsed: Duration::ZERO,
+            sets: OpStats::new(),
+            gets: OpStats::new(),
+            hits: 0,
+            misses: 0,
+            errors: 0,
+        }
+    }
+
+    fn merge(&mut self, other: Report) {
+        self.sets.latency.add(&other.sets.latency).unwrap();
+        self.gets.latency.add(&other.gets.latency).unwrap();
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.errors += other.errors;
+    }
+
+    pub fn total(&self) -> OpStats {
+        let mut total = self.sets.clone();
+        total.latency.add(&self.gets.latency).unwrap();
+        total
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut gets = self.gets.to_json(self.elapsed);
+        gets["hits"] = self.hits.into();
+        gets["misses"] = self.misses.into();
+        serde_json::json!({
+            "duration_secs": self.elapsed.as_secs_f64(),
+            "sets": self.sets.to_json(self.elapsed),
+            "gets": gets,
+            "total": self.total().to_json(self.elapsed),
+            "errors": self.errors,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<6} {:>10} {:>12} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "", "ops", "ops/sec", "mean(us)", "p50", "p90", "p99", "p99.9", "max"
+        )?;
+        for (name, stats) in [
+            ("sets", &self.sets),
+            ("gets", &self.gets),
+            ("total", &self.total()),
+        ] {
+            let percentile = |p: f64| stats.latency.value_at_quantile(p / 100.0);
+            writeln!(
+                f,
+                "{:<6} {:>10} {:>12.0} {:>10.1} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                name,
+                stats.count(),
+                stats.count() as f64 / self.elapsed.as_secs_f64(),
+                stats.latency.mean(),
+                percentile(50.0),
+                percentile(90.0),
+                percentile(99.0),
+                percentile(99.9),
+                stats.latency.max()
+            )?;
+        }
+        write!(
+            f,
+            "{:.2}s, {} hits, {} misses, {} errors",
+            self.elapsed.as_secs_f64(),
+            self.hits,
+            self.misses,
+            self.errors
+        )
+    }
+}
+
+/// Drives one connection, keeping `pipeline` requests in flight until the
+/// deadline passes or it has sent `quota` requests.
+async fn drive(
+    config: BenchConfig,
+    keys: KeyGenerator,
+    quota: u64,
+    deadline: Option<Instant>,
+    seed: u64,
+) -> io::Result<Report> {
+    let socket = TcpStream::connect(&config.server).await?;
+    socket.set_nodelay(true)?;
+    let mut connection = Framed::new(socket, MemcachedBinaryClientCodec::new());
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let value = vec![b'x'; config.value_sizes.max()];
+    let (sets, gets) = config.ratio;
+    let mut report = Report::new();
+    let mut in_flight = VecDeque::new();
+    let mut sent = 0;
+    let mut opaque = 0u32;
+
+    loop {
+        while in_flight.len() < config.pipeline.max(1)
+            && sent < quota
+            && deadline.is_none_or(|deadline| Instant::now() < deadline)
+        {
+            opaque = opaque.wrapping_add(1);
+            let key = format!("{}{}", config.key_prefix, keys.next(&mut rng)).into_bytes();
+            let is_set = rng.gen_range(0..sets + gets) < sets;
+            let request = if is_set {
+                let header = binary::RequestHeader {
+                    opcode: binary::Command::Set as u8,
+                    opaque,
+                    ..binary::RequestHeader::default()
+                };
+                BinaryRequest::Set(binary::SetRequest {
+                    header,
+                    flags: 0,
+                    expiration: 0,
+                    grace: 0,
+                    recompute_ms: 0,
+                    key,
+                    value: value[..config.value_sizes.next(&mut rng)].to_vec(),
+                })
+            } else {
+                let header = binary::RequestHeader {
+                    opcode: binary::Command::Get as u8,
+                    opaque,
+                    ..binary::RequestHeader::default()
+                };
+                BinaryRequest::Get(binary::GetRequest { header, key })
+            };
+            connection.feed(request).await?;
+            in_flight.push_back((is_set, Instant::now()));
+            sent += 1;
+        }
+        connection.flush().await?;
+
+        let (is_set, started) = match in_flight.pop_front() {
+            Some(request) => request,
+            None => return Ok(report),
+        };
+        let response = match connection.next().await {
+            Some(response) => response?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed the connection",
+                ))
+            }
+        };
+        let status = response.get_header().status;
+        match (is_set, response) {
+            (true, _) => report.sets.record(started.elapsed()),
+            (false, BinaryResponse::Get(_)) => {
+                report.gets.record(started.elapsed());
+                report.hits += 1;
+            }
+            (false, _) => {
+                report.gets.record(started.elapsed());
+                if status == binary::ResponseStatus::KeyNotExists as u16 {
+                    report.misses += 1;
+                }
+            }
+        }
+        if status != binary::ResponseStatus::Success as u16
+            && status != binary::ResponseStatus::KeyNotExists as u16
+        {
+            report.errors += 1;
+        }
+    }
+}
+
+/// Runs the benchmark described by `config` and gathers the results of all
+/// its connections.
+pub async fn run(config: &BenchConfig) -> io::Result<Report> {
+    let keys = KeyGenerator::new(config.keys, config.key_pattern);
+    let connections = config.connections.max(1) as u64;
+    let started = Instant::now();
+    let deadline = config.duration.map(|duration| started + duration);
+    let tasks: Vec<_> = (0..connections)
+        .map(|index| {
+            let quota = match config.requests {
+                Some(requests) => requests / connections + (index < requests % connections) as u64,
+                None => u64::MAX,
+            };
+            let seed = rand::random::<u64>() ^ index;
+            tokio::spawn(drive(config.clone(), keys.clone(), quota, deadline, seed))
+        })
+        .collect();
+
+    let mut report = Report::new();
+    for task in tasks {
+        report.merge(
+            task.await
+                .map_err(|err| io::Error::other(err.to_string()))??,
+        );
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::testing::start_server;
+
+    #[test]
+    fn parse_distributions() {
+        assert_eq!(
+            "uniform".parse::<KeyPattern>().unwrap(),
+            KeyPattern::Uniform
+        );
+        assert_eq!(
+            "zipfian:1.2".parse::<KeyPattern>().unwrap(),
+            KeyPattern::Zipfian(1.2)
+        );
+        assert!("zipfian:x".parse::<KeyPattern>().is_err());
+
+        assert_eq!("1k".parse::<ValueSizes>().unwrap(), ValueSizes::Fixed(1024));
+        assert_eq!(
+            "10-20".parse::<ValueSizes>().unwrap(),
+            ValueSizes::Range(10, 20)
+        );
+        assert_eq!(
+            "64:3,1k:1".parse::<ValueSizes>().unwrap(),
+            ValueSizes::Weighted(vec![(64, 3), (1024, 1)])
+        );
+        assert!("20-10".parse::<ValueSizes>().is_err());
+    }
+
+    #[test]
+    fn key_patterns_stay_in_range_and_skew() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        let mut count = |pattern| {
+            let keys = KeyGenerator::new(1000, pattern);
+            let mut counts = vec![0u32; 1000];
+            for _ in 0..100_000 {
+                counts[keys.next(&mut rng) as usize] += 1;
+            }
+            counts
+        };
+
+        let uniform = count(KeyPattern::Uniform);
+        assert!(uniform.iter().all(|count| *count > 50 && *count < 150));
+        let zipfian = count(KeyPattern::Zipfian(0.99));
+        assert!(zipfian[0] > 10_000, "{}", zipfian[0]);
+        assert!(zipfian[0] > zipfian[10] && zipfian[10] > zipfian[500]);
+        let gaussian = count(KeyPattern::Gaussian);
+        assert!(gaussian[500] > 10 * gaussian[50].max(1));
+    }
+
+    #[tokio::test]
+    async fn run_against_a_server() {
+        let (server, _) = start_server().await;
+        let config = BenchConfig {
+            server,
+            connections: 3,
+            pipeline: 8,
+            ratio: (1, 1),
+            keys: 50,
+            value_sizes: ValueSizes::Range(1, 100),
+            duration: None,
+            requests: Some(1000),
+            ..BenchConfig::default()
+        };
+        let report = run(&config).await.unwrap();
+        assert_eq!(report.total().count(), 1000);
+        assert_eq!(report.hits + report.misses, report.gets.count());
+        assert!(report.hits > 0);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.to_json()["total"]["ops"], 1000);
+    }
+}
@@ -0,0 +1,70 @@
+use rustcache::bench::{self, BenchConfig};
+use std::time::Duration;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: rustcache-bench [options]
+    -s, --server <addr>       server to load (default 127.0.0.1:11211)
+    -c, --connections <n>     connections to open (default 4)
+    -p, --pipeline <n>        requests in flight per connection (default 1)
+    --ratio <sets>:<gets>     mix of sets and gets (default 1:10)
+    --keys <n>                size of the key space (default 100000)
+    --key-pattern <pattern>   uniform (default), gaussian or
+                              zipfian[:exponent] (exponent 0.99)
+    --key-prefix <prefix>     prefix of every key (default key:)
+    --value-size <sizes>      <size>, <min>-<max> or <size>:<weight>,...
+                              (default 32)
+    -d, --duration <secs>     how long to run (default 10 unless
+                              --requests is given)
+    -n, --requests <n>        requests to send over all connections
+    --json                    print the report as JSON"
+    );
+    std::process::exit(2)
+}
+
+#[tokio::main]
+async fn main() {
+    let mut config = BenchConfig::default();
+    let mut duration = None;
+    let mut json = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        let number = |value: String| value.parse().unwrap_or_else(|_| usage());
+        match arg.as_str() {
+            "-s" | "--server" => config.server = value(),
+            "-c" | "--connections" => config.connections = number(value()) as usize,
+            "-p" | "--pipeline" => config.pipeline = number(value()) as usize,
+            "--ratio" => {
+                let value = value();
+                let (sets, gets) = value.split_once(':').unwrap_or_else(|| usage());
+                config.ratio = (number(sets.into()) as u32, number(gets.into()) as u32);
+                if config.ratio == (0, 0) {
+                    usage();
+                }
+            }
+            "--keys" => config.keys = number(value()),
+            "--key-pattern" => config.key_pattern = value().parse().unwrap_or_else(|_| usage()),
+            "--key-prefix" => config.key_prefix = value(),
+            "--value-size" => config.value_sizes = value().parse().unwrap_or_else(|_| usage()),
+            "-d" | "--duration" => duration = Some(Duration::from_secs(number(value()))),
+            "-n" | "--requests" => config.requests = Some(number(value())),
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+    config.duration = match (duration, config.requests) {
+        (None, Some(_)) => None,
+        (duration, _) => duration.or(config.duration),
+    };
+
+    match bench::run(&config).await {
+        Ok(report) if json => println!("{:#}", report.to_json()),
+        Ok(report) => println!("{}", report),
+        Err(err) => {
+            eprintln!("{}: {}", config.server, err);
+            std::process::exit(1)
+        }
+    }
+}
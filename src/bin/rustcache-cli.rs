@@ -0,0 +1,351 @@
+use num_traits::FromPrimitive;
+use rustcache::client::connection::Client;
+use rustcache::client::error::{ClientError, ClientResult};
+use rustcache::client::text::TextClient;
+use rustcache::memcached::scan::{KeyMetadata, Segment};
+use rustcache::memcached::slowlog::SlowRequest;
+use rustcache::protocol::binary::Command;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::PathBuf;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: rustcache-cli [options] [command [args...]]
+    -H, --host <addr>         server to connect to (default 127.0.0.1:11211)
+    -s, --socket <path>       connect to a Unix socket instead
+    --text                    speak the text protocol instead of binary
+    --json                    print stats as JSON
+
+commands:
+{}
+Without a command, commands are read from an interactive prompt.",
+        COMMANDS
+    );
+    std::process::exit(2)
+}
+
+const COMMANDS: &str = "    get <key>
+    set <key> <value> [expiration [flags]]
+    delete <key>
+    incr <key> [delta]
+    decr <key> [delta]
+    touch <key> <expiration>
+    flush [delay]
+    stats [group]
+    hotkeys                   list the keys taking the most requests and bytes
+    version
+    dump [prefix]             list the keys, where the server supports it
+    slowlog [count]           list the slowest recent requests, newest first
+";
+
+/// A connection over either protocol, exposing what both have in common.
+enum Connection {
+    Binary(Client),
+    Text(TextClient),
+}
+
+/// The output of a command; an error describes why it failed, including
+/// misses, so one-shot commands can exit with a failure status on them.
+type Outcome = Result<String, String>;
+
+fn not_found() -> Outcome {
+    Err("NOT_FOUND".to_string())
+}
+
+fn failed(err: ClientError) -> Outcome {
+    if err.is_not_found() {
+        not_found()
+    } else {
+        Err(err.to_string())
+    }
+}
+
+fn number<T: std::str::FromStr>(arg: Option<&String>, default: T) -> Result<T, String> {
+    match arg {
+        Some(arg) => arg.parse().map_err(|_| format!("not a number: {}", arg)),
+        None => Ok(default),
+    }
+}
+
+fn format_stats(stats: Vec<(String, String)>, json: bool) -> String {
+    if json {
+        let object: serde_json::Map<String, serde_json::Value> = stats
+            .into_iter()
+            .map(|(name, value)| (name, serde_json::Value::String(value)))
+            .collect();
+        return serde_json::to_string_pretty(&object).unwrap();
+    }
+    let width = stats.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    stats
+        .iter()
+        .map(|(name, value)| format!("{:width$}  {}", name, value, width = width))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// An item's metadata in the style of `lru_crawler metadump`.
+fn format_metadata(entry: &KeyMetadata) -> String {
+    let expiration = match entry.expires_at {
+        0 => -1,
+        expires_at => expires_at as i64,
+    };
+    let segment = match entry.segment {
+        Segment::Memory => "memory",
+        Segment::External => "external",
+    };
+    format!(
+        "key={} ns={} exp={} la={} cas={} flags={} size={} seg={}",
+        String::from_utf8_lossy(&entry.key),
+        entry.namespace,
+        expiration,
+        entry.last_access,
+        entry.cas,
+        entry.flags,
+        entry.size,
+        segment
+    )
+}
+
+fn format_slow_request(request: &SlowRequest) -> String {
+    let command = match Command::from_u8(request.opcode) {
+        Some(command) => command.name(),
+        None => format!("0x{:02x}", request.opcode),
+    };
+    format!(
+        "id={} time={} duration_us={} cmd={} key={} size={} peer={}",
+        request.id,
+        request.timestamp,
+        request.duration.as_micros(),
+        command,
+        String::from_utf8_lossy(&request.key),
+        request.size,
+        request.peer
+    )
+}
+
+/// Every key starting with `prefix`, fetched a page at a time.
+async fn dump(client: &mut Client, prefix: &[u8]) -> ClientResult<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let page = client.metadump(prefix, cursor, 0).await?;
+        lines.extend(page.entries.iter().map(format_metadata));
+        cursor = page.cursor;
+        if cursor == 0 {
+            return Ok(lines);
+        }
+    }
+}
+
+impl Connection {
+    async fn execute(&mut self, args: &[String], json: bool) -> Outcome {
+        let arg = |index: usize| {
+            args.get(index)
+                .ok_or_else(|| format!("missing arguments, usage:\n{}", COMMANDS))
+        };
+        let key = |index: usize| arg(index).map(|key| key.as_bytes());
+        let result: ClientResult<Outcome> = match (args[0].as_str(), self) {
+            ("get", Connection::Binary(client)) => client.get(key(1)?).await.map(|item| {
+                item.map_or_else(not_found, |item| {
+                    Ok(String::from_utf8_lossy(&item.value).into_owned())
+                })
+            }),
+            ("get", Connection::Text(client)) => client.get(key(1)?).await.map(|item| {
+                item.map_or_else(not_found, |item| {
+                    Ok(String::from_utf8_lossy(&item.value).into_owned())
+                })
+            }),
+            ("set", connection) => {
+                let (key, value) = (key(1)?, key(2)?);
+                let expiration = number(args.get(3), 0)?;
+                let flags = number(args.get(4), 0)?;
+                match connection {
+                    Connection::Binary(client) => {
+                        client.set(key, value, flags, expiration).await.map(|_| ())
+                    }
+                    Connection::Text(client) => client.set(key, value, flags, expiration).await,
+                }
+                .map(|_| Ok("STORED".to_string()))
+            }
+            ("delete", connection) => match connection {
+                Connection::Binary(client) => client.delete(key(1)?).await,
+                Connection::Text(client) => client.delete(key(1)?).await,
+            }
+            .map(|deleted| match deleted {
+                true => Ok("DELETED".to_string()),
+                false => not_found(),
+            }),
+            (command @ ("incr" | "decr"), connection) => {
+                let delta = number(args.get(2), 1)?;
+                let key = key(1)?;
+                let value = match (command, connection) {
+                    ("incr", Connection::Binary(client)) => {
+                        client.incr(key, delta, None, 0).await.map(Some)
+                    }
+                    (_, Connection::Binary(client)) => {
+                        client.decr(key, delta, None, 0).await.map(Some)
+                    }
+                    ("incr", Connection::Text(client)) => client.incr(key, delta).await,
+                    (_, Connection::Text(client)) => client.decr(key, delta).await,
+                };
+                match value {
+                    Err(err) if err.is_not_found() => Ok(None),
+                    value => value,
+                }
+                .map(|value| value.map_or_else(not_found, |value| Ok(value.to_string())))
+            }
+            ("touch", connection) => {
+                let expiration = number(Some(arg(2)?), 0)?;
+                match connection {
+                    Connection::Binary(client) => client.touch(key(1)?, expiration).await,
+                    Connection::Text(client) => client.touch(key(1)?, expiration).await,
+                }
+                .map(|touched| match touched {
+                    true => Ok("TOUCHED".to_string()),
+                    false => not_found(),
+                })
+            }
+            ("flush", connection) => {
+                let delay = number(args.get(1), 0)?;
+                match connection {
+                    Connection::Binary(client) => client.flush(delay).await,
+                    Connection::Text(client) => client.flush(delay).await,
+                }
+                .map(|_| Ok("OK".to_string()))
+            }
+            ("stats", connection) => {
+                let group = args.get(1).map_or("", |group| group.as_str());
+                match connection {
+                    Connection::Binary(client) => client.stats(group).await,
+                    Connection::Text(client) => client.stats(group).await,
+                }
+                .map(|stats| Ok(format_stats(stats, json)))
+            }
+            ("hotkeys", connection) => match connection {
+                Connection::Binary(client) => client.stats("hotkeys").await,
+                Connection::Text(client) => client.stats("hotkeys").await,
+            }
+            .map(|stats| Ok(format_stats(stats, json))),
+            ("version", Connection::Binary(client)) => client.version().await.map(Ok),
+            ("version", Connection::Text(client)) => client.version().await.map(Ok),
+            ("dump", Connection::Binary(client)) => {
+                let prefix = args.get(1).map_or(&b""[..], |prefix| prefix.as_bytes());
+                dump(client, prefix).await.map(|lines| Ok(lines.join("\n")))
+            }
+            ("slowlog", Connection::Binary(client)) => {
+                let count = number(args.get(1), 0)?;
+                client.slowlog(count).await.map(|requests| {
+                    let lines: Vec<_> = requests.iter().map(format_slow_request).collect();
+                    Ok(lines.join("\n"))
+                })
+            }
+            ("slowlog", Connection::Text(_)) => Ok(Err(
+                "the text protocol has no slow log; drop --text".to_string(),
+            )),
+            ("dump", Connection::Text(client)) => {
+                let prefix = format!("key={}", args.get(1).map_or("", String::as_str));
+                client.metadump().await.map(|lines| {
+                    Ok(lines
+                        .into_iter()
+                        .filter(|line| line.starts_with(&prefix))
+                        .collect::<Vec<_>>()
+                        .join("\n"))
+                })
+            }
+            (command, _) => Ok(Err(format!(
+                "unknown command {:?}, expected one of:\n{}",
+                command, COMMANDS
+            ))),
+        };
+        result.unwrap_or_else(failed)
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rustcache_cli_history"))
+}
+
+async fn repl(connection: &mut Connection, json: bool) -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    loop {
+        let line = match editor.readline("rustcache> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err),
+        };
+        let args: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+        match args.first().map(String::as_str) {
+            None => continue,
+            Some("quit" | "exit") => break,
+            Some("help") => {
+                println!("{}    quit", COMMANDS);
+                continue;
+            }
+            Some(_) => {}
+        }
+        editor.add_history_entry(line.as_str())?;
+        match connection.execute(&args, json).await {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err),
+        }
+    }
+    if let Some(history) = &history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let mut host = "127.0.0.1:11211".to_string();
+    let mut socket = None;
+    let mut text = false;
+    let mut json = false;
+    let mut command = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-H" | "--host" => host = value(),
+            "-s" | "--socket" => socket = Some(value()),
+            "--text" => text = true,
+            "--json" => json = true,
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with('-') && command.is_empty() => usage(),
+            _ => command.push(arg),
+        }
+    }
+
+    let connected = match (&socket, text) {
+        (Some(path), false) => Client::connect_unix(path).await.map(Connection::Binary),
+        (None, false) => Client::connect(&host).await.map(Connection::Binary),
+        (Some(path), true) => TextClient::connect_unix(path).await.map(Connection::Text),
+        (None, true) => TextClient::connect(&host).await.map(Connection::Text),
+    };
+    let mut connection = connected.unwrap_or_else(|err| {
+        eprintln!("{}: {}", socket.as_ref().unwrap_or(&host), err);
+        std::process::exit(1)
+    });
+
+    if command.is_empty() {
+        if let Err(err) = repl(&mut connection, json).await {
+            eprintln!("{}", err);
+            std::process::exit(1)
+        }
+        return;
+    }
+    match connection.execute(&command, json).await {
+        Ok(output) => println!("{}", output),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1)
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use rustcache::replay::{self, ReplayConfig};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: rustcache-replay [options] <capture>
+    -s, --server <addr>       server to replay against (default 127.0.0.1:11211)
+    --speed <factor>          replay this many times faster than captured
+                              (default 1, 0 for as fast as possible)
+    --json                    print the report as JSON"
+    );
+    std::process::exit(2)
+}
+
+#[tokio::main]
+async fn main() {
+    let mut config = ReplayConfig::default();
+    let mut capture = None;
+    let mut json = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-s" | "--server" => config.server = value(),
+            "--speed" => {
+                config.speed = match value().parse() {
+                    Ok(speed) if speed >= 0.0 => speed,
+                    _ => usage(),
+                }
+            }
+            "--json" => json = true,
+            _ if arg.starts_with('-') || capture.is_some() => usage(),
+            _ => capture = Some(arg),
+        }
+    }
+    config.capture = capture.unwrap_or_else(|| usage()).into();
+
+    match replay::run(&config).await {
+        Ok(report) if json => println!("{:#}", report.to_json()),
+        Ok(report) => println!("{}", report),
+        Err(err) => {
+            eprintln!("{}: {}", config.server, err);
+            std::process::exit(1)
+        }
+    }
+}
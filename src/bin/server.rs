@@ -1,10 +1,208 @@
+use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
+use rustcache::memcached::{
+    acl, aof, auth, capture, cluster, compression, extstore, namespace, proxy, replication, server,
+    slowlog, snapshot,
+};
 use tokio::io;
 
+fn usage() -> ! {
+    eprintln!(
+        "usage: server [options]
+    --sasl-pwdb <file>        require SASL PLAIN auth against a password file
+    --acl <file>              restrict users to key prefixes
+    --namespaces <file>       partition the store into namespaces
+    --snapshot <file>         load on start, save on shutdown
+    --aof <file>              log every mutation to an append-only log
+    --aof-fsync <policy>      always, everysec (default) or never
+    --extstore <file>         move values evicted from memory to a flash file
+    --ext-page-size <size>    extstore page size (default 1m)
+    --ext-page-count <n>      number of extstore pages (default 64)
+    --ext-item-min <size>     smallest value moved to extstore (default 512)
+    --ext-compact-ratio <f>   compact pages less live than this (default 0.5)
+    --compression <algorithm> compress values in memory with lz4, zstd or
+                              zstd:<level>
+    --compression-min <size>  smallest value compressed (default 1k)
+    --lease-ttl <secs>        how long a lease on a missing key is held
+                              (default 10)
+    --watch-buffer <events>   events a watching connection may fall behind
+                              by before it misses some (default 1024)
+    --hot-key-sample-rate <n> track one in n gets and sets for the hotkeys
+                              stats, 0 to disable (default 100)
+    --slow-log-threshold <ms> log requests taking at least this long
+                              (default 10)
+    --slow-log-size <entries> slow requests kept, 0 to disable (default 128)
+    --replication <addr>      stream mutations to replicas connecting here
+    --replica-of <addr>       follow the primary replicating on addr
+    --replica-auth <file>     user:password to authenticate to the primary
+    --read-only               reject commands that modify the store
+    --cluster <file>          join the cluster listed in a member file
+    --cluster-self <addr>     address this node has in the member file
+                              (default 127.0.0.1:11211)
+    --cluster-policy <policy> forward (default) or redirect requests for
+                              keys other members own
+    --cluster-auth <file>     user:password members authenticate to each
+                              other as
+    --capture <file>          record every request and response for
+                              rustcache-replay
+    --proxy <file>            route requests to the backend pools in file
+                              instead of serving them; takes no other
+                              option"
+    );
+    std::process::exit(2)
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 11211);
-    let mut tcp_server = rustcache::memcached::server::TcpServer::new();
-    tcp_server.run(addr).await
+    let mut tcp_server = server::TcpServer::new();
+    let mut snapshot_path = None;
+    let mut aof_path = None;
+    let mut fsync_policy = aof::FsyncPolicy::EverySecond;
+    let mut extstore_config: Option<extstore::ExtStoreConfig> = None;
+    let mut compression_config: Option<compression::CompressionConfig> = None;
+    let mut cluster_members = None;
+    let mut cluster_self = addr.to_string();
+    let mut cluster_policy = cluster::WrongNodePolicy::Forward;
+    let mut cluster_auth = None;
+    let mut slow_log_threshold = slowlog::DEFAULT_SLOW_LOG_THRESHOLD;
+    let mut slow_log_size = slowlog::DEFAULT_SLOW_LOG_SIZE;
+    let mut replica_of = None;
+    let mut replica_auth = None;
+    let mut proxy_path = None;
+    let size = |value: String| namespace::parse_size(&value).unwrap_or_else(|| usage());
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--sasl-pwdb" => {
+                tcp_server = tcp_server.with_authenticator(auth::Authenticator::load(value())?);
+            }
+            "--acl" => {
+                tcp_server = tcp_server.with_acl(acl::Acl::load(value())?);
+            }
+            "--namespaces" => {
+                tcp_server = tcp_server.with_namespaces(namespace::NamespaceConfig::load(value())?);
+            }
+            "--snapshot" => snapshot_path = Some(value()),
+            "--aof" => aof_path = Some(value()),
+            "--aof-fsync" => fsync_policy = value().parse()?,
+            "--extstore" => extstore_config = Some(extstore::ExtStoreConfig::new(value())),
+            "--ext-page-size" | "--ext-page-count" | "--ext-item-min" | "--ext-compact-ratio" => {
+                let value = value();
+                let config = extstore_config.as_mut().unwrap_or_else(|| usage());
+                match arg.as_str() {
+                    "--ext-page-size" => config.page_size = size(value),
+                    "--ext-page-count" => {
+                        config.page_count = value.parse().unwrap_or_else(|_| usage())
+                    }
+                    "--ext-item-min" => config.item_min_size = size(value),
+                    _ => config.compact_ratio = value.parse().unwrap_or_else(|_| usage()),
+                }
+            }
+            "--compression" => {
+                compression_config = Some(compression::CompressionConfig::new(value().parse()?))
+            }
+            "--compression-min" => {
+                let value = value();
+                let config = compression_config.as_mut().unwrap_or_else(|| usage());
+                config.min_size = size(value);
+            }
+            "--lease-ttl" => {
+                tcp_server = tcp_server.with_lease_ttl(value().parse().unwrap_or_else(|_| usage()))
+            }
+            "--watch-buffer" => {
+                tcp_server =
+                    tcp_server.with_watch_buffer(value().parse().unwrap_or_else(|_| usage()))
+            }
+            "--hot-key-sample-rate" => {
+                tcp_server =
+                    tcp_server.with_hot_key_sample_rate(value().parse().unwrap_or_else(|_| usage()))
+            }
+            "--slow-log-threshold" => {
+                let millis = value().parse().unwrap_or_else(|_| usage());
+                slow_log_threshold = Duration::from_millis(millis);
+            }
+            "--slow-log-size" => slow_log_size = value().parse().unwrap_or_else(|_| usage()),
+            "--replication" => tcp_server = tcp_server.with_replication(value()),
+            "--replica-of" => replica_of = Some(value()),
+            "--replica-auth" => replica_auth = Some(fs::read_to_string(value())?),
+            "--read-only" => tcp_server = tcp_server.with_read_only(true),
+            "--cluster" => cluster_members = Some(cluster::Cluster::load_members(value())?),
+            "--cluster-self" => cluster_self = value(),
+            "--cluster-policy" => cluster_policy = value().parse()?,
+            "--cluster-auth" => cluster_auth = Some(fs::read_to_string(value())?),
+            "--capture" => {
+                tcp_server = tcp_server.with_capture(capture::Capture::create(value())?);
+            }
+            "--proxy" => proxy_path = Some(value()),
+            _ => usage(),
+        }
+    }
+
+    if let Some(path) = proxy_path {
+        // The proxy serves nothing itself, so no other option applies to it.
+        if std::env::args().len() != 3 {
+            usage();
+        }
+        let proxy = proxy::ProxyServer::new(&proxy::ProxyConfig::load(path)?);
+        return proxy.run(addr).await;
+    }
+
+    tcp_server = tcp_server.with_slow_log(slow_log_threshold, slow_log_size);
+
+    match (replica_of, replica_auth) {
+        (Some(primary), auth) => {
+            let mut replica = replication::Replica::new(primary);
+            if let Some(auth) = auth {
+                let (user, password) = auth.trim().split_once(':').unwrap_or_else(|| usage());
+                replica = replica.with_credentials(user, password);
+            }
+            tcp_server = tcp_server.with_replica(replica);
+        }
+        (None, Some(_)) => usage(),
+        (None, None) => {}
+    }
+
+    match (cluster_members, cluster_auth) {
+        (Some(members), auth) => {
+            let mut cluster = cluster::Cluster::new(&cluster_self, &members, cluster_policy);
+            if let Some(auth) = auth {
+                let (user, password) = auth.trim().split_once(':').unwrap_or_else(|| usage());
+                cluster = cluster.with_peer_credentials(user, password);
+            }
+            tcp_server = tcp_server.with_cluster(cluster);
+        }
+        (None, Some(_)) => usage(),
+        (None, None) => {}
+    }
+
+    if let Some(config) = extstore_config {
+        tcp_server = tcp_server.with_extstore(extstore::ExtStore::open(config)?);
+    }
+
+    if let Some(config) = compression_config {
+        tcp_server = tcp_server.with_compression(config);
+    }
+
+    match (aof_path, snapshot_path) {
+        (Some(aof_path), snapshot_path) => {
+            let snapshot_path = snapshot_path.unwrap_or_else(|| format!("{}.snapshot", aof_path));
+            let snapshot = Arc::new(snapshot::Snapshot::new(snapshot_path));
+            let log = aof::AppendOnlyLog::open(aof_path, fsync_policy, snapshot)?;
+            tcp_server = tcp_server.with_append_only_log(log);
+        }
+        (None, Some(snapshot_path)) => tcp_server = tcp_server.with_snapshot(snapshot_path),
+        (None, None) => {}
+    }
+
+    tokio::select! {
+        result = tcp_server.run(addr) => return result,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tcp_server.shutdown()
 }
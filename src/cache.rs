@@ -0,0 +1,475 @@
+use crate::memcached::error::{StorageError, StorageResult};
+use crate::memcached::namespace::{NamespaceConfig, DEFAULT_NAMESPACE};
+use crate::memcached::scan::ScanPage;
+use crate::memcached::storage::{Namespace, Record, Storage};
+use crate::memcached::timer;
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Converts cached values to and from the bytes the store keeps.
+pub trait CacheValue: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+    /// `None` if `bytes` do not hold a value of this type.
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self>;
+}
+
+impl CacheValue for Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        Some(bytes)
+    }
+}
+
+impl CacheValue for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        String::from_utf8(bytes).ok()
+    }
+}
+
+/// Stored in decimal, as the server's increment and decrement expect.
+impl CacheValue for u64 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        std::str::from_utf8(&bytes).ok()?.trim().parse().ok()
+    }
+}
+
+/// Expirations are kept in whole seconds; a partial second rounds up so an
+/// entry never expires early, and a zero TTL never expires.
+fn ttl_secs(ttl: Duration) -> u32 {
+    let secs = ttl.as_secs() + (ttl.subsec_nanos() > 0) as u64;
+    secs.min(u32::MAX as u64) as u32
+}
+
+/// Per key locks that let one caller of `get_or_load` run the loader while
+/// the others wait for its result.
+type Loads = Arc<Mutex<HashMap<Vec<u8>, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// An in-process cache backed by the same `Storage` the server uses, with
+/// its LRU eviction, memory accounting and expiry, but without a socket in
+/// between. Clones share the same entries.
+pub struct Cache<V = Vec<u8>> {
+    timer: Arc<dyn timer::Timer + Send + Sync>,
+    storage: Arc<Storage>,
+    max_memory: usize,
+    default_ttl: u32,
+    loads: Loads,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V> Clone for Cache<V> {
+    fn clone(&self) -> Self {
+        Cache {
+            timer: self.timer.clone(),
+            storage: self.storage.clone(),
+            max_memory: self.max_memory,
+            default_ttl: self.default_ttl,
+            loads: self.loads.clone(),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<V: CacheValue> Default for Cache<V> {
+    fn default() -> Self {
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        Cache {
+            timer: timer.clone(),
+            storage: Arc::new(Storage::new(timer)),
+            max_memory: 0,
+            default_ttl: 0,
+            loads: Default::default(),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<V: CacheValue> Cache<V> {
+    /// An unbounded cache whose entries never expire unless given a TTL.
+    pub fn new() -> Cache<V> {
+        Default::default()
+    }
+
+    /// Evicts the least recently used entries once keys, values and per
+    /// entry overhead take more than `max_memory` bytes; 0 means unlimited.
+    /// Entries inserted before are dropped.
+    pub fn with_max_memory(mut self, max_memory: usize) -> Self {
+        self.max_memory = max_memory;
+        self.rebuild_storage();
+        self
+    }
+
+    /// Expires entries inserted without a TTL of their own after `ttl`.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = ttl_secs(ttl);
+        self
+    }
+
+    /// Reads the time from `timer` instead of the system clock. Entries
+    /// inserted before are dropped.
+    pub fn with_timer(mut self, timer: Arc<dyn timer::Timer + Send + Sync>) -> Self {
+        self.timer = timer;
+        self.rebuild_storage();
+        self
+    }
+
+    fn rebuild_storage(&mut self) {
+        let config = NamespaceConfig::new(DEFAULT_NAMESPACE, self.max_memory);
+        self.storage = Arc::new(Storage::with_namespaces(self.timer.clone(), vec![config]));
+    }
+
+    fn entries(&self) -> &Namespace {
+        &self.storage.namespaces()[0]
+    }
+
+    /// The store behind the cache, to snapshot it or serve it over the
+    /// network.
+    pub fn storage(&self) -> Arc<Storage> {
+        self.storage.clone()
+    }
+
+    /// The value of `key`, `None` if it is missing, expired or does not
+    /// decode as a `V`.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<V> {
+        let record = self.entries().get(&key.as_ref().to_vec()).ok()?;
+        V::from_bytes(record.value)
+    }
+
+    /// The value of `key` with the flags it was inserted with.
+    pub fn get_with_flags<K: AsRef<[u8]>>(&self, key: K) -> Option<(V, u32)> {
+        let record = self.entries().get(&key.as_ref().to_vec()).ok()?;
+        let flags = record.header.flags;
+        Some((V::from_bytes(record.value)?, flags))
+    }
+
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Stores `value` under `key` with the default TTL.
+    pub fn insert<K: AsRef<[u8]>>(&self, key: K, value: &V) -> StorageResult<()> {
+        self.store(key.as_ref(), value, self.default_ttl)
+    }
+
+    /// Stores `value` under `key`, expiring it after `ttl`.
+    pub fn insert_with_ttl<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: &V,
+        ttl: Duration,
+    ) -> StorageResult<()> {
+        self.store(key.as_ref(), value, ttl_secs(ttl))
+    }
+
+    /// Stores `value` under `key` with opaque `flags` for readers, expiring
+    /// it after `ttl` or, if `None`, the default TTL.
+    pub fn insert_with_flags<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: &V,
+        flags: u32,
+        ttl: Option<Duration>,
+    ) -> StorageResult<()> {
+        let expiration = ttl.map_or(self.default_ttl, ttl_secs);
+        self.store_with_flags(key.as_ref(), value, flags, expiration)
+    }
+
+    /// Stores `value` under `key`, expiring it after `ttl` but keeping it
+    /// `grace` longer to be served stale to lease gets, which also refresh it
+    /// early by how long it takes to `recompute`.
+    pub fn insert_with_freshness<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: &V,
+        ttl: Duration,
+        grace: Duration,
+        recompute: Duration,
+    ) -> StorageResult<()> {
+        let recompute_ms = u32::try_from(recompute.as_millis()).unwrap_or(u32::MAX);
+        let record = Record::new(value.to_bytes(), 0, 0, ttl_secs(ttl))
+            .with_grace(ttl_secs(grace))
+            .with_early_recompute(recompute_ms);
+        self.entries()
+            .set(key.as_ref().to_vec(), record)
+            .map(|_| ())
+    }
+
+    fn store(&self, key: &[u8], value: &V, expiration: u32) -> StorageResult<()> {
+        self.store_with_flags(key, value, 0, expiration)
+    }
+
+    fn store_with_flags(
+        &self,
+        key: &[u8],
+        value: &V,
+        flags: u32,
+        expiration: u32,
+    ) -> StorageResult<()> {
+        let record = Record::new(value.to_bytes(), 0, flags, expiration);
+        self.entries().set(key.to_vec(), record).map(|_| ())
+    }
+
+    /// Removes `key`, returning whether it was there.
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.entries().delete(&key.as_ref().to_vec(), 0).is_ok()
+    }
+
+    /// The value of `key`, computing and inserting it with `init` on a miss.
+    /// If another caller inserts the key meanwhile, its value wins.
+    pub fn get_or_insert_with<K, F>(&self, key: K, init: F) -> StorageResult<V>
+    where
+        K: AsRef<[u8]>,
+        F: FnOnce() -> V,
+    {
+        let key = key.as_ref();
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+        let value = init();
+        let record = Record::new(value.to_bytes(), 0, 0, self.default_ttl);
+        match self.entries().add(key.to_vec(), record) {
+            Ok(_) => Ok(value),
+            Err(StorageError::KeyExists) => Ok(self.get(key).unwrap_or(value)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The value of `key`, loading and inserting it with `load` on a miss.
+    ///
+    /// Concurrent misses on the same key are coalesced: one caller runs its
+    /// loader while the others wait and then read what it inserted. Should
+    /// the loader fail, its error is returned to that caller only and the
+    /// next waiter runs its own loader. A loaded value the cache cannot hold
+    /// is still returned.
+    pub async fn get_or_load<K, F, Fut, E>(&self, key: K, load: F) -> Result<V, E>
+    where
+        K: AsRef<[u8]>,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let key = key.as_ref();
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+        let lock = self
+            .loads
+            .lock()
+            .unwrap()
+            .entry(key.to_vec())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().await;
+            match self.get(key) {
+                Some(value) => Ok(value),
+                None => load().await.inspect(|value| {
+                    if let Err(err) = self.insert(key, value) {
+                        warn!("Failed to cache loaded value: {}", err);
+                    }
+                }),
+            }
+        };
+        let mut loads = self.loads.lock().unwrap();
+        // The map and this task hold the last references unless others wait.
+        if Arc::strong_count(&lock) <= 2 {
+            loads.remove(key);
+        }
+        result
+    }
+
+    /// Drops every entry.
+    pub fn clear(&self) {
+        self.entries().flush();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+
+    /// Bytes the entries take, as counted against `with_max_memory`.
+    pub fn used_memory(&self) -> usize {
+        self.entries().used_memory()
+    }
+
+    /// Lists the metadata of up to `count` entries starting with `prefix`,
+    /// resuming after the page that returned `cursor`; 0 starts a new scan
+    /// and is returned once it is complete.
+    pub fn scan<P: AsRef<[u8]>>(&self, cursor: u128, prefix: P, count: usize) -> ScanPage {
+        self.storage.scan(cursor, prefix.as_ref(), count)
+    }
+
+    /// The live entries at the time of the call, in no particular order.
+    /// Values that do not decode as a `V` are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, V)> {
+        let now = self.timer.secs();
+        let mut entries = Vec::new();
+        self.entries().for_each(|key, record| {
+            let expires_at = record.header.expires_at();
+            if expires_at == 0 || expires_at > now {
+                entries.push((key.to_vec(), record.value.clone()));
+            }
+        });
+        entries
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, V::from_bytes(value)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::lease::LeaseGet;
+    use crate::memcached::testing::MockTimer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn typed_values_and_ttls() {
+        let timer = Arc::new(MockTimer::new(1000));
+        let cache: Cache<u64> = Cache::new()
+            .with_timer(timer.clone())
+            .with_default_ttl(Duration::from_secs(60));
+        cache.insert("a", &1).unwrap();
+        cache
+            .insert_with_ttl(b"b", &2, Duration::from_millis(1500))
+            .unwrap();
+        cache
+            .insert_with_ttl("c", &3, Duration::from_secs(600))
+            .unwrap();
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.len(), 3);
+
+        timer.set(1002);
+        assert_eq!(cache.get("b"), None);
+        timer.set(1060);
+        let mut entries: Vec<_> = cache.iter().collect();
+        entries.sort();
+        assert_eq!(entries, vec![(b"c".to_vec(), 3)]);
+
+        assert!(cache.remove("c"));
+        assert!(!cache.remove("c"));
+        let strings: Cache<String> = Cache::new();
+        strings.insert("s", &"value".to_string()).unwrap();
+        assert!(strings.contains_key("s"));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_past_max_memory() {
+        let cache: Cache = Cache::new().with_max_memory(1000);
+        for key in 0..20 {
+            cache.insert(format!("key{}", key), &vec![0; 100]).unwrap();
+        }
+        assert!(cache.used_memory() <= 1000);
+        assert!(cache.get("key19").is_some());
+        assert!(cache.get("key0").is_none());
+        assert_eq!(
+            cache.insert("huge", &vec![0; 2000]),
+            Err(StorageError::ValueTooLarge)
+        );
+    }
+
+    #[test]
+    fn inserts_may_be_served_stale_during_a_grace_period() {
+        let timer = Arc::new(MockTimer::new(1000));
+        let cache: Cache<u64> = Cache::new().with_timer(timer.clone());
+        cache
+            .insert_with_freshness(
+                "user:1",
+                &1,
+                Duration::from_secs(10),
+                Duration::from_secs(30),
+                Duration::from_millis(200),
+            )
+            .unwrap();
+        timer.set(1020);
+
+        assert_eq!(cache.get("user:1"), None);
+        match cache.storage().lease_get(&b"user:1".to_vec()).unwrap() {
+            LeaseGet::Hit { record, stale, .. } => {
+                assert!(stale);
+                assert_eq!(record.header.recompute_ms, 200);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scan_reports_expiry_and_last_access() {
+        let timer = Arc::new(MockTimer::new(1000));
+        let cache: Cache<u64> = Cache::new().with_timer(timer.clone());
+        cache.insert("user:1", &1).unwrap();
+        cache
+            .insert_with_ttl("user:2", &2, Duration::from_secs(120))
+            .unwrap();
+        cache.insert("other", &3).unwrap();
+        timer.set(1060);
+        cache.get("user:1");
+
+        let page = cache.scan(0, "user:", 10);
+        assert_eq!(page.cursor, 0);
+        let entries: Vec<_> = page
+            .entries
+            .iter()
+            .map(|entry| (entry.key.as_slice(), entry.expires_at, entry.last_access))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![(&b"user:1"[..], 0, 1060), (&b"user:2"[..], 1120, 1000)]
+        );
+    }
+
+    #[test]
+    fn get_or_insert_with_keeps_the_first_value() {
+        let cache: Cache<String> = Cache::new();
+        let value = cache.get_or_insert_with("k", || "first".to_string());
+        assert_eq!(value.unwrap(), "first");
+        let value = cache.get_or_insert_with("k", || unreachable!());
+        assert_eq!(value.unwrap(), "first");
+    }
+
+    #[tokio::test]
+    async fn concurrent_loads_are_coalesced() {
+        let cache: Cache<String> = Cache::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let cache = cache.clone();
+                let loads = loads.clone();
+                tokio::spawn(async move {
+                    cache
+                        .get_or_load("k", || async {
+                            loads.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok::<_, ()>("loaded".to_string())
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), "loaded");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(cache.loads.lock().unwrap().is_empty());
+
+        let failed = cache.get_or_load("other", || async { Err("down") }).await;
+        assert_eq!(failed, Err("down"));
+        assert!(!cache.contains_key("other"));
+    }
+}
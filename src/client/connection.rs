@@ -0,0 +1,926 @@
+use crate::client::error::{ClientError, ClientResult};
+use crate::memcached::scan::{KeyMetadata, ScanPage, Segment};
+use crate::memcached::slowlog::SlowRequest;
+use crate::memcached::watch::{Event, WatchFilter};
+use crate::protocol::binary;
+use crate::protocol::binary_codec::{BinaryRequest, BinaryResponse, MemcachedBinaryClientCodec};
+use futures_util::{SinkExt, StreamExt};
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio_util::codec::Framed;
+
+/// A byte stream a client can talk over: TCP or a Unix socket.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// An item read from the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub value: Vec<u8>,
+    pub flags: u32,
+    pub cas: u64,
+}
+
+impl Item {
+    fn from_response(response: binary::GetResponse) -> Item {
+        Item {
+            value: response.value,
+            flags: response.flags,
+            cas: response.header.cas,
+        }
+    }
+}
+
+/// What a lease get found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Leased {
+    /// The item, `stale` if it expired but is within its grace period. With
+    /// a `token`, this client should recompute it and `lease_set` the result.
+    Hit {
+        item: Item,
+        stale: bool,
+        token: Option<u64>,
+    },
+    /// The key is missing and this client holds the lease to fill it, to be
+    /// passed to `lease_set`.
+    Granted(u64),
+    /// The key is missing and another client is filling it; retry shortly.
+    Wait,
+}
+
+/// How an item stored with `lease_set` ages, beyond its expiration.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Freshness {
+    /// Seconds the item is still served, as stale, after it expires.
+    pub grace: u32,
+    /// Milliseconds recomputing the item takes, scaled by how eagerly it
+    /// should be refreshed early; 0 only refreshes it once it expired.
+    pub recompute_ms: u32,
+}
+
+/// An event reported to a watching client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchedEvent {
+    pub event: Event,
+    /// How many events the server dropped so far because this client fell
+    /// behind.
+    pub dropped: u64,
+}
+
+/// A connection turned into a stream of key events by `Client::watch`.
+pub struct Watcher {
+    client: Client,
+}
+
+impl Watcher {
+    /// The next event, waiting for one if need be. Fails once the server
+    /// hangs up.
+    pub async fn next(&mut self) -> ClientResult<WatchedEvent> {
+        loop {
+            let response = match self.client.connection.next().await {
+                Some(response) => response?,
+                None => {
+                    return Err(ClientError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "server closed the connection",
+                    )))
+                }
+            };
+            let response = match response {
+                BinaryResponse::WatchEvent(response) => response,
+                _ => continue,
+            };
+            let kind = FromPrimitive::from_u8(response.kind)
+                .ok_or_else(|| ClientError::Protocol(format!("event kind {}", response.kind)))?;
+            return Ok(WatchedEvent {
+                event: Event {
+                    kind,
+                    namespace: String::from_utf8_lossy(&response.namespace).into_owned(),
+                    key: response.key,
+                },
+                dropped: response.dropped,
+            });
+        }
+    }
+}
+
+/// An async connection to a single server speaking the memcached binary
+/// protocol. Every request carries its own opaque, and responses are matched
+/// to requests by it, so answers left over from a call that was abandoned
+/// half way are skipped rather than returned to the next one.
+pub struct Client {
+    connection: Framed<Box<dyn Transport>, MemcachedBinaryClientCodec>,
+    opaque: u32,
+    timeout: Option<Duration>,
+    broken: bool,
+}
+
+impl Client {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> ClientResult<Client> {
+        let socket = TcpStream::connect(addr).await?;
+        socket.set_nodelay(true)?;
+        Ok(Client::new(Box::new(socket)))
+    }
+
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> ClientResult<Client> {
+        let socket = UnixStream::connect(path).await?;
+        Ok(Client::new(Box::new(socket)))
+    }
+
+    /// Talks over an already connected stream.
+    pub fn new(socket: Box<dyn Transport>) -> Client {
+        Client {
+            connection: Framed::new(socket, MemcachedBinaryClientCodec::new()),
+            opaque: 0,
+            timeout: None,
+            broken: false,
+        }
+    }
+
+    /// Fails every call that takes longer than `timeout` to answer.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Whether a call failed in a way that may have left the connection out
+    /// of step with the server. Every later call fails; reconnect instead.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    fn header(&mut self, command: binary::Command) -> binary::RequestHeader {
+        self.opaque = self.opaque.wrapping_add(1);
+        binary::RequestHeader {
+            opcode: command as u8,
+            opaque: self.opaque,
+            ..binary::RequestHeader::default()
+        }
+    }
+
+    /// Writes `requests` out together and reads responses until the one
+    /// answering the last request, which therefore must not be quiet.
+    async fn call_batch(
+        &mut self,
+        requests: Vec<BinaryRequest>,
+    ) -> ClientResult<Vec<BinaryResponse>> {
+        if self.broken {
+            return Err(ClientError::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection is broken",
+            )));
+        }
+        let result = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.exchange(requests))
+                .await
+                .unwrap_or(Err(ClientError::Timeout)),
+            None => self.exchange(requests).await,
+        };
+        if let Err(ClientError::Io(_)) | Err(ClientError::Timeout) = result {
+            self.broken = true;
+        }
+        result
+    }
+
+    async fn exchange(
+        &mut self,
+        requests: Vec<BinaryRequest>,
+    ) -> ClientResult<Vec<BinaryResponse>> {
+        let first = requests[0].get_header().opaque;
+        let last = requests[requests.len() - 1].get_header().opaque;
+        for request in requests {
+            self.connection.feed(request).await?;
+        }
+        self.connection.flush().await?;
+
+        let mut responses = Vec::new();
+        loop {
+            let response = match self.connection.next().await {
+                Some(response) => response?,
+                None => {
+                    return Err(ClientError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "server closed the connection",
+                    )))
+                }
+            };
+            let opaque = response.get_header().opaque;
+            if opaque.wrapping_sub(first) > last.wrapping_sub(first) {
+                continue;
+            }
+            responses.push(response);
+            if opaque == last {
+                return Ok(responses);
+            }
+        }
+    }
+
+    /// Sends one request and returns its response, or the error its status
+    /// stands for.
+    async fn call(&mut self, request: BinaryRequest) -> ClientResult<BinaryResponse> {
+        let response = self.call_batch(vec![request]).await?.pop().unwrap();
+        match response.get_header().status {
+            status if status == binary::ResponseStatus::Success as u16 => Ok(response),
+            status => Err(ClientError::from_status(status)),
+        }
+    }
+
+    fn unexpected(response: BinaryResponse) -> ClientError {
+        ClientError::Protocol(format!("{:?}", response))
+    }
+
+    /// Reads `key`, `None` if it does not exist.
+    pub async fn get(&mut self, key: &[u8]) -> ClientResult<Option<Item>> {
+        let request = BinaryRequest::Get(binary::GetRequest {
+            header: self.header(binary::Command::Get),
+            key: key.to_vec(),
+        });
+        match self.call(request).await {
+            Ok(BinaryResponse::Get(response)) => Ok(Some(Item::from_response(response))),
+            Ok(response) => Err(Client::unexpected(response)),
+            Err(err) if err.is_not_found() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads `key`, and on a miss asks for the lease to fill it, so that of
+    /// all clients missing at once only one goes to the backing store.
+    pub async fn lease_get(&mut self, key: &[u8]) -> ClientResult<Leased> {
+        let request = BinaryRequest::LeaseGet(binary::LeaseGetRequest {
+            header: self.header(binary::Command::LeaseGet),
+            key: key.to_vec(),
+        });
+        let response = match self.call_batch(vec![request]).await?.pop().unwrap() {
+            BinaryResponse::LeaseGet(response) => response,
+            response => return Err(ClientError::from_status(response.get_header().status)),
+        };
+        match FromPrimitive::from_u16(response.header.status) {
+            Some(binary::ResponseStatus::Success) => Ok(Leased::Hit {
+                token: Some(response.token).filter(|token| *token != 0),
+                stale: response.stale,
+                item: Item {
+                    value: response.value,
+                    flags: response.flags,
+                    cas: response.header.cas,
+                },
+            }),
+            Some(binary::ResponseStatus::KeyNotExists) if response.token != 0 => {
+                Ok(Leased::Granted(response.token))
+            }
+            Some(binary::ResponseStatus::TemporaryFailure) => Ok(Leased::Wait),
+            _ => Err(ClientError::from_status(response.header.status)),
+        }
+    }
+
+    /// Stores `value` under `key` with the lease `lease_get` granted. Fails
+    /// with a not-stored error if the key was written or deleted since.
+    pub async fn lease_set(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+        token: u64,
+        freshness: Freshness,
+    ) -> ClientResult<u64> {
+        let request = BinaryRequest::LeaseSet(binary::LeaseSetRequest {
+            header: self.header(binary::Command::LeaseSet),
+            flags,
+            expiration,
+            token,
+            grace: freshness.grace,
+            recompute_ms: freshness.recompute_ms,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        let response = self.call(request).await?;
+        Ok(response.get_header().cas)
+    }
+
+    /// Reads many keys in a single round trip: a quiet get for each key,
+    /// which the server only answers on a hit, followed by a noop marking the
+    /// end. Missing keys are left out of the result.
+    pub async fn get_multi(&mut self, keys: &[&[u8]]) -> ClientResult<HashMap<Vec<u8>, Item>> {
+        let mut requests: Vec<BinaryRequest> = keys
+            .iter()
+            .map(|key| {
+                BinaryRequest::GetKeyQuietly(binary::GetKeyQuietRequest {
+                    header: self.header(binary::Command::GetKeyQuiet),
+                    key: key.to_vec(),
+                })
+            })
+            .collect();
+        requests.push(BinaryRequest::Noop(binary::NoopRequest {
+            header: self.header(binary::Command::Noop),
+        }));
+
+        let mut items = HashMap::new();
+        for response in self.call_batch(requests).await? {
+            match response {
+                BinaryResponse::GetKeyQuietly(mut response) => {
+                    let key = std::mem::take(&mut response.key);
+                    items.insert(key, Item::from_response(response));
+                }
+                BinaryResponse::Noop(_) => {}
+                response => {
+                    let status = response.get_header().status;
+                    if status != binary::ResponseStatus::KeyNotExists as u16 {
+                        return Err(ClientError::from_status(status));
+                    }
+                }
+            }
+        }
+        Ok(items)
+    }
+
+    async fn store(
+        &mut self,
+        command: binary::Command,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+    ) -> ClientResult<u64> {
+        let mut header = self.header(command);
+        header.cas = cas;
+        let set_req = binary::SetRequest {
+            header,
+            flags,
+            expiration,
+            grace: 0,
+            recompute_ms: 0,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        let request = match command {
+            binary::Command::Add => BinaryRequest::Add(set_req),
+            binary::Command::Replace => BinaryRequest::Replace(set_req),
+            _ => BinaryRequest::Set(set_req),
+        };
+        let response = self.call(request).await?;
+        Ok(response.get_header().cas)
+    }
+
+    /// Stores `value` under `key` and returns its new CAS.
+    pub async fn set(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        self.store(binary::Command::Set, key, value, flags, expiration, 0)
+            .await
+    }
+
+    /// Stores `value` under `key` with `tags`, each of which `invalidate_tag`
+    /// can later drop it by, and returns its new CAS. Tags must be 1 to 255
+    /// bytes long and all of them fit in 247 bytes including a length byte
+    /// each.
+    pub async fn set_tagged(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+        tags: &[&[u8]],
+    ) -> ClientResult<u64> {
+        let size: usize = tags.iter().map(|tag| tag.len() + 1).sum();
+        if size > 247 || tags.iter().any(|tag| tag.is_empty() || tag.len() > 255) {
+            return Err(ClientError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tags do not fit in a request",
+            )));
+        }
+        let request = BinaryRequest::SetTagged(binary::SetTaggedRequest {
+            header: self.header(binary::Command::SetTagged),
+            flags,
+            expiration,
+            tags: tags.iter().map(|tag| tag.to_vec()).collect(),
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        let response = self.call(request).await?;
+        Ok(response.get_header().cas)
+    }
+
+    /// Drops every item tagged with `tag` on this server, returning the new
+    /// generation of the tag.
+    pub async fn invalidate_tag(&mut self, tag: &[u8]) -> ClientResult<u64> {
+        let request = BinaryRequest::InvalidateTag(binary::InvalidateTagRequest {
+            header: self.header(binary::Command::InvalidateTag),
+            key: tag.to_vec(),
+        });
+        let response = self.call(request).await?;
+        Ok(response.get_header().cas)
+    }
+
+    /// Stores `value` only if `key` does not exist yet.
+    pub async fn add(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        self.store(binary::Command::Add, key, value, flags, expiration, 0)
+            .await
+    }
+
+    /// Stores `value` only if `key` exists.
+    pub async fn replace(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        self.store(binary::Command::Replace, key, value, flags, expiration, 0)
+            .await
+    }
+
+    /// Stores `value` only if `key` still has the CAS `cas`, as read by an
+    /// earlier get.
+    pub async fn cas(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+    ) -> ClientResult<u64> {
+        self.store(binary::Command::Set, key, value, flags, expiration, cas)
+            .await
+    }
+
+    async fn apply_delta(
+        &mut self,
+        command: binary::Command,
+        key: &[u8],
+        delta: u64,
+        initial: Option<u64>,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        let delta_req = binary::IncrementRequest {
+            header: self.header(command),
+            delta,
+            initial: initial.unwrap_or(0),
+            expiration: match initial {
+                Some(_) => expiration,
+                None => u32::MAX,
+            },
+            key: key.to_vec(),
+        };
+        let request = match command {
+            binary::Command::Decrement => BinaryRequest::Decrement(delta_req),
+            _ => BinaryRequest::Increment(delta_req),
+        };
+        match self.call(request).await? {
+            BinaryResponse::Increment(response) | BinaryResponse::Decrement(response) => {
+                Ok(response.value)
+            }
+            response => Err(Client::unexpected(response)),
+        }
+    }
+
+    /// Adds `delta` to the counter at `key` and returns the result. A missing
+    /// counter is created as `initial`, or the call fails if that is `None`.
+    pub async fn incr(
+        &mut self,
+        key: &[u8],
+        delta: u64,
+        initial: Option<u64>,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        self.apply_delta(binary::Command::Increment, key, delta, initial, expiration)
+            .await
+    }
+
+    /// Like `incr`, but subtracts, stopping at zero.
+    pub async fn decr(
+        &mut self,
+        key: &[u8],
+        delta: u64,
+        initial: Option<u64>,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        self.apply_delta(binary::Command::Decrement, key, delta, initial, expiration)
+            .await
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub async fn delete(&mut self, key: &[u8]) -> ClientResult<bool> {
+        let request = BinaryRequest::Delete(binary::DeleteRequest {
+            header: self.header(binary::Command::Delete),
+            key: key.to_vec(),
+        });
+        match self.call(request).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.is_not_found() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Gives `key` a new expiration, returning whether it existed.
+    pub async fn touch(&mut self, key: &[u8], expiration: u32) -> ClientResult<bool> {
+        let request = BinaryRequest::Touch(binary::TouchRequest {
+            header: self.header(binary::Command::Touch),
+            expiration,
+            key: key.to_vec(),
+        });
+        match self.call(request).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.is_not_found() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads `key` and gives it a new expiration in one go.
+    pub async fn gat(&mut self, key: &[u8], expiration: u32) -> ClientResult<Option<Item>> {
+        let request = BinaryRequest::GetAndTouch(binary::GetAndTouchRequest {
+            header: self.header(binary::Command::GetAndTouch),
+            expiration,
+            key: key.to_vec(),
+        });
+        match self.call(request).await {
+            Ok(BinaryResponse::GetAndTouch(response)) => Ok(Some(Item::from_response(response))),
+            Ok(response) => Err(Client::unexpected(response)),
+            Err(err) if err.is_not_found() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Subscribes to the events `filter` lets through, after which the
+    /// connection carries nothing else.
+    pub async fn watch(mut self, filter: WatchFilter) -> ClientResult<Watcher> {
+        let request = BinaryRequest::Watch(binary::WatchRequest {
+            header: self.header(binary::Command::Watch),
+            kinds: filter.kinds,
+            key: filter.prefix,
+        });
+        self.call(request).await?;
+        Ok(Watcher { client: self })
+    }
+
+    /// Drops every item, after `delay` seconds if it is not zero.
+    pub async fn flush(&mut self, delay: u32) -> ClientResult<()> {
+        let request = BinaryRequest::Flush(binary::FlushRequest {
+            header: self.header(binary::Command::Flush),
+            expiration: delay,
+        });
+        self.call(request).await?;
+        Ok(())
+    }
+
+    /// The statistics of `group`, the general ones for `""`.
+    pub async fn stats(&mut self, group: &str) -> ClientResult<Vec<(String, String)>> {
+        let request = BinaryRequest::Stat(binary::StatRequest {
+            header: self.header(binary::Command::Stat),
+            key: group.as_bytes().to_vec(),
+        });
+        match self.call(request).await? {
+            BinaryResponse::Stat(response) => Ok(response.stats),
+            response => Err(Client::unexpected(response)),
+        }
+    }
+
+    /// Up to `count` keys starting with `prefix`, resuming after `cursor`,
+    /// 0 to start over. The server picks the count if it is 0.
+    pub async fn metadump(
+        &mut self,
+        prefix: &[u8],
+        cursor: u128,
+        count: u32,
+    ) -> ClientResult<ScanPage> {
+        let request = BinaryRequest::MetaDump(binary::MetaDumpRequest {
+            header: self.header(binary::Command::MetaDump),
+            cursor,
+            count,
+            key: prefix.to_vec(),
+        });
+        let response = match self.call(request).await? {
+            BinaryResponse::MetaDump(response) => response,
+            response => return Err(Client::unexpected(response)),
+        };
+        let mut entries = Vec::with_capacity(response.entries.len());
+        for entry in response.entries {
+            let segment: Segment = FromPrimitive::from_u8(entry.segment)
+                .ok_or_else(|| ClientError::Protocol(format!("segment {}", entry.segment)))?;
+            entries.push(KeyMetadata {
+                namespace: String::from_utf8_lossy(&entry.namespace).into_owned(),
+                key: entry.key,
+                size: entry.size as usize,
+                flags: entry.flags,
+                cas: entry.cas,
+                expires_at: entry.expires_at,
+                last_access: entry.last_access,
+                segment,
+            });
+        }
+        Ok(ScanPage {
+            entries,
+            cursor: response.cursor,
+        })
+    }
+
+    /// Up to `count` of the slowest recent requests, newest first, all of
+    /// them if 0.
+    pub async fn slowlog(&mut self, count: u32) -> ClientResult<Vec<SlowRequest>> {
+        let request = BinaryRequest::SlowLog(binary::SlowLogRequest {
+            header: self.header(binary::Command::SlowLog),
+            count,
+        });
+        match self.call(request).await? {
+            BinaryResponse::SlowLog(response) => Ok(response
+                .entries
+                .into_iter()
+                .map(|entry| SlowRequest {
+                    id: entry.id,
+                    timestamp: entry.timestamp,
+                    duration: Duration::from_micros(entry.duration_us),
+                    opcode: entry.opcode,
+                    key: entry.key,
+                    size: entry.size as usize,
+                    peer: String::from_utf8_lossy(&entry.peer).into_owned(),
+                })
+                .collect()),
+            response => Err(Client::unexpected(response)),
+        }
+    }
+
+    /// Round trip without side effects, to check the connection is alive.
+    pub async fn noop(&mut self) -> ClientResult<()> {
+        let request = BinaryRequest::Noop(binary::NoopRequest {
+            header: self.header(binary::Command::Noop),
+        });
+        self.call(request).await?;
+        Ok(())
+    }
+
+    pub async fn version(&mut self) -> ClientResult<String> {
+        let request = BinaryRequest::Version(binary::VersionRequest {
+            header: self.header(binary::Command::Version),
+        });
+        match self.call(request).await? {
+            BinaryResponse::Version(response) => {
+                Ok(String::from_utf8_lossy(&response.value).into_owned())
+            }
+            response => Err(Client::unexpected(response)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::server::TcpServer;
+    use crate::memcached::testing::{serve, start_server};
+    use crate::memcached::watch::EventKind;
+
+    async fn connect() -> Client {
+        connect_to(&start_server().await.0).await
+    }
+
+    async fn connect_to(address: &str) -> Client {
+        Client::connect(address).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn store_and_read_back() {
+        let mut client = connect().await;
+        assert_eq!(client.get(b"key").await.unwrap(), None);
+        let cas = client.set(b"key", b"value", 7, 0).await.unwrap();
+        let item = client.get(b"key").await.unwrap().unwrap();
+        assert_eq!(
+            item,
+            Item {
+                value: b"value".to_vec(),
+                flags: 7,
+                cas
+            }
+        );
+
+        assert!(client
+            .add(b"key", b"other", 0, 0)
+            .await
+            .unwrap_err()
+            .is_exists());
+        assert!(client
+            .replace(b"missing", b"other", 0, 0)
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert!(client
+            .cas(b"key", b"stale", 0, 0, cas + 1)
+            .await
+            .unwrap_err()
+            .is_exists());
+        client.cas(b"key", b"fresh", 0, 0, cas).await.unwrap();
+        assert_eq!(
+            client.gat(b"key", 60).await.unwrap().unwrap().value,
+            b"fresh"
+        );
+        assert!(client.touch(b"key", 0).await.unwrap());
+        assert!(client.delete(b"key").await.unwrap());
+        assert!(!client.delete(b"key").await.unwrap());
+        assert!(!client.touch(b"key", 0).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn counters() {
+        let mut client = connect().await;
+        assert!(client
+            .incr(b"hits", 1, None, 0)
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert_eq!(client.incr(b"hits", 1, Some(10), 0).await.unwrap(), 10);
+        assert_eq!(client.incr(b"hits", 5, Some(10), 0).await.unwrap(), 15);
+        assert_eq!(client.decr(b"hits", 20, None, 0).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn multi_get_skips_misses() {
+        let mut client = connect().await;
+        client.set(b"a", b"1", 0, 0).await.unwrap();
+        client.set(b"c", b"3", 0, 0).await.unwrap();
+        let items = client.get_multi(&[b"a", b"b", b"c"]).await.unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[&b"a".to_vec()].value, b"1");
+        assert_eq!(items[&b"c".to_vec()].value, b"3");
+
+        client.flush(0).await.unwrap();
+        assert!(client.get_multi(&[b"a", b"c"]).await.unwrap().is_empty());
+        assert_eq!(client.version().await.unwrap(), env!("CARGO_PKG_VERSION"));
+        let stats: HashMap<_, _> = client.stats("").await.unwrap().into_iter().collect();
+        assert_eq!(stats["get_hits"], "2");
+        assert_eq!(stats["cmd_flush"], "1");
+    }
+
+    #[tokio::test]
+    async fn leases_reject_stale_sets() {
+        let mut client = connect().await;
+        let token = match client.lease_get(b"hot").await.unwrap() {
+            Leased::Granted(token) => token,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(client.lease_get(b"hot").await.unwrap(), Leased::Wait);
+
+        // The key was invalidated while the value was being computed.
+        client.delete(b"hot").await.unwrap();
+        assert!(client
+            .lease_set(b"hot", b"stale", 0, 0, token, Freshness::default())
+            .await
+            .unwrap_err()
+            .is_not_stored());
+
+        let token = match client.lease_get(b"hot").await.unwrap() {
+            Leased::Granted(token) => token,
+            other => panic!("unexpected {:?}", other),
+        };
+        client
+            .lease_set(b"hot", b"fresh", 5, 0, token, Freshness::default())
+            .await
+            .unwrap();
+        match client.lease_get(b"hot").await.unwrap() {
+            Leased::Hit { item, stale, token } => {
+                assert_eq!((item.value, item.flags), (b"fresh".to_vec(), 5));
+                assert!(!stale && token.is_none());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(client
+            .lease_set(b"hot", b"again", 0, 0, token, Freshness::default())
+            .await
+            .unwrap_err()
+            .is_not_stored());
+    }
+    #[tokio::test]
+    async fn invalidating_a_tag_drops_its_items() {
+        let mut client = connect().await;
+        client
+            .set_tagged(b"profile", b"p", 0, 0, &[b"user:1"])
+            .await
+            .unwrap();
+        client
+            .set_tagged(b"feed", b"f", 0, 0, &[b"user:1", b"feed"])
+            .await
+            .unwrap();
+        client
+            .set_tagged(b"other", b"o", 0, 0, &[b"user:2"])
+            .await
+            .unwrap();
+
+        assert_eq!(client.invalidate_tag(b"user:1").await.unwrap(), 1);
+        assert_eq!(client.get(b"profile").await.unwrap(), None);
+        assert_eq!(client.get(b"feed").await.unwrap(), None);
+        assert_eq!(client.get(b"other").await.unwrap().unwrap().value, b"o");
+
+        let stats: HashMap<_, _> = client.stats("tags").await.unwrap().into_iter().collect();
+        assert_eq!(stats["tag_invalidations"], "1");
+        assert_eq!(stats["tag_invalidated_items"], "2");
+        let long = [b'x'; 250];
+        assert!(client
+            .set_tagged(b"key", b"v", 0, 0, &[&long])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn metadump_pages_through_matching_keys() {
+        let mut client = connect().await;
+        for key in [&b"user:1"[..], b"user:2", b"user:3", b"session:1"] {
+            client.set(key, b"value", 7, 0).await.unwrap();
+        }
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let page = client.metadump(b"user:", cursor, 2).await.unwrap();
+            assert!(page.entries.len() <= 2);
+            for entry in page.entries {
+                assert_eq!((entry.flags, entry.segment), (7, Segment::Memory));
+                keys.push(entry.key);
+            }
+            cursor = page.cursor;
+            if cursor == 0 {
+                break;
+            }
+        }
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![b"user:1".to_vec(), b"user:2".to_vec(), b"user:3".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_requests_are_logged_and_timed() {
+        let server = TcpServer::new().with_slow_log(Duration::ZERO, 2);
+        let mut client = connect_to(&serve(server).await.0).await;
+        client.set(b"first", b"value", 0, 0).await.unwrap();
+        client.set(b"key", b"value", 0, 0).await.unwrap();
+        client.get(b"key").await.unwrap();
+
+        let requests = client.slowlog(0).await.unwrap();
+        let logged: Vec<_> = requests
+            .iter()
+            .map(|request| (request.id, request.opcode, request.key.as_slice()))
+            .collect();
+        assert_eq!(
+            logged,
+            vec![
+                (3, binary::Command::Get as u8, &b"key"[..]),
+                (2, binary::Command::Set as u8, &b"key"[..]),
+            ]
+        );
+        assert_eq!(requests[1].size, 24 + 8 + 3 + 5);
+        assert_eq!(client.slowlog(1).await.unwrap().len(), 1);
+
+        let stats: HashMap<_, _> = client.stats("latency").await.unwrap().into_iter().collect();
+        assert_eq!(stats["set_count"], "2");
+        assert_eq!(stats["get_count"], "1");
+    }
+
+    #[tokio::test]
+    async fn watchers_are_sent_matching_events() {
+        let (address, _) = start_server().await;
+        let mut client = connect_to(&address).await;
+        let filter = WatchFilter::new(b"user:").with_kinds(&[EventKind::Set, EventKind::Delete]);
+        let mut watcher = connect_to(&address).await.watch(filter).await.unwrap();
+
+        client.set(b"user:1", b"a", 0, 0).await.unwrap();
+        client.set(b"session:1", b"b", 0, 0).await.unwrap();
+        client.touch(b"user:1", 100).await.unwrap();
+        client.delete(b"user:1").await.unwrap();
+
+        let mut events = Vec::new();
+        for _ in 0..3 {
+            let watched = watcher.next().await.unwrap();
+            assert_eq!(
+                (watched.event.namespace.as_str(), watched.dropped),
+                ("default", 0)
+            );
+            events.push((watched.event.kind, watched.event.key));
+        }
+        let key = b"user:1".to_vec();
+        assert_eq!(
+            events,
+            vec![
+                (EventKind::Set, key.clone()),
+                (EventKind::Set, key.clone()),
+                (EventKind::Delete, key),
+            ]
+        );
+        let stats: HashMap<_, _> = client.stats("watch").await.unwrap().into_iter().collect();
+        assert_eq!(stats["watchers"], "1");
+        assert_eq!(stats["watch_events"], "3");
+    }
+}
@@ -0,0 +1,389 @@
+use crate::client::connection::Item;
+use crate::client::error::ClientResult;
+use crate::client::pool::{Pool, PoolConfig, PooledClient};
+use crate::memcached::cluster::HashRing;
+use crate::memcached::stats;
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct DistributedConfig {
+    /// Settings of the connection pool kept for each server.
+    pub pool: PoolConfig,
+    /// Consecutive failed calls after which a server is ejected and its keys
+    /// go to the next server round the ring.
+    pub failure_limit: u32,
+    /// How long an ejected server is left out before it is tried again. If
+    /// that first call fails too it is ejected straight away.
+    pub retry_timeout: Duration,
+}
+
+impl Default for DistributedConfig {
+    fn default() -> Self {
+        DistributedConfig {
+            pool: PoolConfig::default(),
+            failure_limit: 3,
+            retry_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Server {
+    pool: Pool,
+    failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    ejections: AtomicU64,
+}
+
+impl Server {
+    fn is_live(&self) -> bool {
+        match *self.ejected_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+}
+
+/// A client for a set of servers sharing the keys between them. Keys are
+/// placed with the same ketama ring as libmemcached, so other clients
+/// configured with the same servers and weights agree on where each key is.
+pub struct DistributedClient {
+    ring: HashRing,
+    servers: Vec<Server>,
+    config: DistributedConfig,
+}
+
+impl DistributedClient {
+    /// Builds the client from `(address, weight)` pairs, at least one of
+    /// which must have a weight above zero to own keys. Must be called within
+    /// a Tokio runtime, which runs the upkeep of the pools.
+    pub fn new(servers: &[(String, u32)], config: DistributedConfig) -> DistributedClient {
+        assert!(
+            servers.iter().any(|(_, weight)| *weight > 0),
+            "a client needs at least one server with a weight"
+        );
+        DistributedClient {
+            ring: HashRing::new(servers),
+            servers: servers
+                .iter()
+                .map(|(address, _)| Server {
+                    pool: Pool::new(address.as_str(), config.pool.clone()),
+                    failures: AtomicU32::new(0),
+                    ejected_until: Mutex::new(None),
+                    ejections: AtomicU64::new(0),
+                })
+                .collect(),
+            config,
+        }
+    }
+
+    /// Index of the server `key` is sent to: its owner on the ring, or while
+    /// that is ejected the first live server following it. When every
+    /// server is ejected the owner is tried anyway.
+    fn server_index(&self, key: &[u8]) -> usize {
+        let successors = self.ring.successors(key);
+        successors
+            .iter()
+            .copied()
+            .find(|index| self.servers[*index].is_live())
+            .unwrap_or(successors[0])
+    }
+
+    /// Address of the server `key` is currently sent to.
+    pub fn server(&self, key: &[u8]) -> &str {
+        self.servers[self.server_index(key)].pool.address()
+    }
+
+    async fn client(&self, index: usize) -> ClientResult<PooledClient> {
+        let result = self.servers[index].pool.get().await;
+        self.record(index, result)
+    }
+
+    /// Counts a call to the server at `index` towards ejecting it if the
+    /// server could not be reached, or clears its failures if it answered.
+    fn record<T>(&self, index: usize, result: ClientResult<T>) -> ClientResult<T> {
+        let server = &self.servers[index];
+        match &result {
+            Err(err) if err.is_unreachable() => {
+                let failures = server.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.config.failure_limit {
+                    let mut ejected_until = server.ejected_until.lock().unwrap();
+                    if ejected_until.is_none_or(|until| Instant::now() >= until) {
+                        *ejected_until = Some(Instant::now() + self.config.retry_timeout);
+                        stats::incr(&server.ejections);
+                        warn!(
+                            "Ejecting {} after {} failures: {}",
+                            server.pool.address(),
+                            failures,
+                            err
+                        );
+                    }
+                }
+            }
+            _ => {
+                server.failures.store(0, Ordering::Relaxed);
+                *server.ejected_until.lock().unwrap() = None;
+            }
+        }
+        result
+    }
+
+    pub async fn get(&self, key: &[u8]) -> ClientResult<Option<Item>> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.get(key).await;
+        self.record(index, result)
+    }
+
+    /// Reads many keys, asking every server involved for its share of them
+    /// at once. Keys on servers that cannot be reached are left out like
+    /// misses; the failures still count towards ejecting those servers.
+    pub async fn get_multi(&self, keys: &[&[u8]]) -> ClientResult<HashMap<Vec<u8>, Item>> {
+        let mut by_server: HashMap<usize, Vec<&[u8]>> = HashMap::new();
+        for key in keys {
+            by_server
+                .entry(self.server_index(key))
+                .or_default()
+                .push(key);
+        }
+        let results = join_all(by_server.into_iter().map(|(index, keys)| async move {
+            let mut client = self.client(index).await?;
+            let result = client.get_multi(&keys).await;
+            self.record(index, result)
+        }))
+        .await;
+
+        let mut items = HashMap::new();
+        for result in results {
+            match result {
+                Ok(found) => items.extend(found),
+                Err(err) if err.is_unreachable() => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(items)
+    }
+
+    pub async fn set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.set(key, value, flags, expiration).await;
+        self.record(index, result)
+    }
+
+    pub async fn add(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.add(key, value, flags, expiration).await;
+        self.record(index, result)
+    }
+
+    pub async fn replace(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.replace(key, value, flags, expiration).await;
+        self.record(index, result)
+    }
+
+    pub async fn cas(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+        cas: u64,
+    ) -> ClientResult<u64> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.cas(key, value, flags, expiration, cas).await;
+        self.record(index, result)
+    }
+
+    pub async fn incr(
+        &self,
+        key: &[u8],
+        delta: u64,
+        initial: Option<u64>,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.incr(key, delta, initial, expiration).await;
+        self.record(index, result)
+    }
+
+    pub async fn decr(
+        &self,
+        key: &[u8],
+        delta: u64,
+        initial: Option<u64>,
+        expiration: u32,
+    ) -> ClientResult<u64> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.decr(key, delta, initial, expiration).await;
+        self.record(index, result)
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> ClientResult<bool> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.delete(key).await;
+        self.record(index, result)
+    }
+
+    pub async fn touch(&self, key: &[u8], expiration: u32) -> ClientResult<bool> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.touch(key, expiration).await;
+        self.record(index, result)
+    }
+
+    pub async fn gat(&self, key: &[u8], expiration: u32) -> ClientResult<Option<Item>> {
+        let index = self.server_index(key);
+        let mut client = self.client(index).await?;
+        let result = client.gat(key, expiration).await;
+        self.record(index, result)
+    }
+
+    /// Flushes every server that is not ejected.
+    pub async fn flush(&self, delay: u32) -> ClientResult<()> {
+        let live = (0..self.servers.len()).filter(|index| self.servers[*index].is_live());
+        let results = join_all(live.map(|index| async move {
+            let mut client = self.client(index).await?;
+            let result = client.flush(delay).await;
+            self.record(index, result)
+        }))
+        .await;
+        results.into_iter().collect()
+    }
+
+    /// Client side statistics of every server, including those of its pool,
+    /// prefixed with `server:<address>:`.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        for server in &self.servers {
+            let prefix = format!("server:{}:", server.pool.address());
+            result.push((
+                format!("{}live", prefix),
+                (server.is_live() as u8).to_string(),
+            ));
+            result.push((
+                format!("{}failures", prefix),
+                server.failures.load(Ordering::Relaxed).to_string(),
+            ));
+            result.push((
+                format!("{}ejections", prefix),
+                server.ejections.load(Ordering::Relaxed).to_string(),
+            ));
+            result.extend(
+                server
+                    .pool
+                    .stats()
+                    .into_iter()
+                    .map(|(name, value)| (format!("{}{}", prefix, name), value)),
+            );
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::error::ClientError;
+    use crate::memcached::testing::{free_address, start_server};
+
+    fn config() -> DistributedConfig {
+        DistributedConfig {
+            pool: PoolConfig {
+                min_size: 0,
+                min_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                ..PoolConfig::default()
+            },
+            failure_limit: 2,
+            retry_timeout: Duration::from_millis(50),
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_follow_the_ring() {
+        let (first, first_storage) = start_server().await;
+        let (second, second_storage) = start_server().await;
+        let servers = vec![(first.clone(), 1), (second, 1)];
+        let ring = HashRing::new(&servers);
+        let client = DistributedClient::new(&servers, config());
+
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("key:{}", i).into_bytes()).collect();
+        for key in &keys {
+            client.set(key, key, 0, 0).await.unwrap();
+        }
+        for key in &keys {
+            let on_first = first_storage.get(key).is_ok();
+            assert_eq!(on_first, ring.node(key) == Some(first.as_str()));
+            assert_eq!(second_storage.get(key).is_ok(), !on_first);
+        }
+
+        let mut wanted: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+        wanted.push(b"missing");
+        let items = client.get_multi(&wanted).await.unwrap();
+        assert_eq!(items.len(), keys.len());
+        assert_eq!(items[&keys[3]].value, keys[3]);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "at least one server with a weight")]
+    async fn servers_without_weight_are_rejected() {
+        DistributedClient::new(&[(free_address(), 0)], config());
+    }
+
+    #[tokio::test]
+    async fn dead_servers_are_ejected_and_retried() {
+        let (live, _) = start_server().await;
+        let dead = free_address();
+        let servers = vec![(live.clone(), 1), (dead.clone(), 1)];
+        let client = DistributedClient::new(&servers, config());
+        let key = (0..)
+            .map(|i| format!("key:{}", i).into_bytes())
+            .find(|key| client.server(key) == dead)
+            .unwrap();
+
+        for _ in 0..2 {
+            let err = client.set(&key, b"v", 0, 0).await.unwrap_err();
+            assert!(matches!(err, ClientError::Io(_)), "{:?}", err);
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        assert_eq!(client.server(&key), live);
+        client.set(&key, b"v", 0, 0).await.unwrap();
+        assert_eq!(client.get_multi(&[&key]).await.unwrap().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(client.server(&key), dead);
+        assert!(client.get(&key).await.is_err());
+        assert_eq!(client.server(&key), live);
+        let stats: HashMap<String, String> = client.stats().into_iter().collect();
+        assert_eq!(stats[&format!("server:{}:ejections", dead)], "2");
+    }
+}
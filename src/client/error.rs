@@ -0,0 +1,63 @@
+extern crate failure;
+
+use crate::protocol::binary::ResponseStatus;
+use num_traits::FromPrimitive;
+use std::io;
+
+#[derive(Debug, Fail)]
+pub enum ClientError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "Server answered {:?}", _0)]
+    Status(ResponseStatus),
+    #[fail(display = "Unexpected response: {}", _0)]
+    Protocol(String),
+    #[fail(display = "Timed out")]
+    Timeout,
+    /// Connecting to the server failed recently and is not retried yet.
+    #[fail(display = "Server {} is unavailable", _0)]
+    Unavailable(String),
+}
+
+impl ClientError {
+    /// The error for a response status other than success.
+    pub fn from_status(status: u16) -> ClientError {
+        ClientError::Status(
+            FromPrimitive::from_u16(status).unwrap_or(ResponseStatus::InternalError),
+        )
+    }
+
+    /// Whether the server refused the command because the key is missing.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, ClientError::Status(ResponseStatus::KeyNotExists))
+    }
+
+    /// Whether the server refused the command because the key exists or its
+    /// CAS did not match.
+    pub fn is_exists(&self) -> bool {
+        matches!(self, ClientError::Status(ResponseStatus::KeyExists))
+    }
+
+    /// Whether the server refused to store the value, as for a lease set
+    /// whose lease is no longer valid.
+    pub fn is_not_stored(&self) -> bool {
+        matches!(self, ClientError::Status(ResponseStatus::NotStored))
+    }
+
+    /// Whether the server could not be reached or did not answer, as opposed
+    /// to answering with an error.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Io(_) | ClientError::Timeout | ClientError::Unavailable(_)
+        )
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
@@ -0,0 +1,5 @@
+pub mod connection;
+pub mod distributed;
+pub mod error;
+pub mod pool;
+pub mod text;
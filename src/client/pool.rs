@@ -0,0 +1,399 @@
+use crate::client::connection::Client;
+use crate::client::error::{ClientError, ClientResult};
+use crate::memcached::stats;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Connections kept open even when idle.
+    pub min_size: usize,
+    /// Connections that may be checked out at once; further callers wait.
+    pub max_size: usize,
+    /// How long a caller waits for a connection before giving up.
+    pub wait_timeout: Duration,
+    /// How long each request may take; 0 means no limit.
+    pub request_timeout: Duration,
+    /// Idle connections above `min_size` are closed after this long.
+    pub idle_timeout: Duration,
+    /// How often idle connections are evicted, checked with a noop and
+    /// topped up to `min_size`.
+    pub health_check_interval: Duration,
+    /// Delay before reconnecting after the first failed connect. It doubles
+    /// with every further failure, up to `max_backoff`.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_size: 1,
+            max_size: 16,
+            wait_timeout: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(60),
+            health_check_interval: Duration::from_secs(10),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolStats {
+    in_use: AtomicU64,
+    checkouts: AtomicU64,
+    wait_time_us: AtomicU64,
+    wait_timeouts: AtomicU64,
+    connects: AtomicU64,
+    connect_failures: AtomicU64,
+    health_check_failures: AtomicU64,
+    idle_evictions: AtomicU64,
+}
+
+struct IdleClient {
+    client: Client,
+    since: Instant,
+}
+
+/// Consecutive connect failures and when connecting may be tried again.
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+struct Shared {
+    address: String,
+    config: PoolConfig,
+    idle: Mutex<VecDeque<IdleClient>>,
+    permits: Arc<Semaphore>,
+    backoff: Mutex<Backoff>,
+    stats: PoolStats,
+}
+
+impl Shared {
+    async fn connect(&self) -> ClientResult<Client> {
+        {
+            let backoff = self.backoff.lock().unwrap();
+            if backoff
+                .retry_at
+                .is_some_and(|retry_at| Instant::now() < retry_at)
+            {
+                return Err(ClientError::Unavailable(self.address.clone()));
+            }
+        }
+        let connect =
+            tokio::time::timeout(self.config.wait_timeout, Client::connect(&self.address));
+        let result = match connect.await {
+            Ok(result) => result,
+            Err(_) => Err(ClientError::Timeout),
+        };
+        let mut backoff = self.backoff.lock().unwrap();
+        match result {
+            Ok(client) => {
+                stats::incr(&self.stats.connects);
+                *backoff = Backoff::default();
+                Ok(match self.config.request_timeout {
+                    timeout if timeout.is_zero() => client,
+                    timeout => client.with_timeout(timeout),
+                })
+            }
+            Err(err) => {
+                stats::incr(&self.stats.connect_failures);
+                let delay = self
+                    .config
+                    .min_backoff
+                    .saturating_mul(1 << backoff.failures.min(16))
+                    .min(self.config.max_backoff);
+                backoff.failures += 1;
+                backoff.retry_at = Some(Instant::now() + delay);
+                warn!(
+                    "Connecting to {} failed, retrying in {:?}: {}",
+                    self.address, delay, err
+                );
+                Err(err)
+            }
+        }
+    }
+
+    fn release(&self, client: Client) {
+        let mut idle = self.idle.lock().unwrap();
+        if !client.is_broken() && idle.len() < self.config.max_size {
+            idle.push_back(IdleClient {
+                client,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// One round of upkeep: closes connections idle for too long, drops the
+    /// ones failing a noop and opens new ones up to `min_size`.
+    async fn maintain(&self) {
+        let now = Instant::now();
+        let checked: Vec<IdleClient> = {
+            let mut idle = self.idle.lock().unwrap();
+            let in_use = self.stats.in_use.load(Ordering::Relaxed) as usize;
+            let mut kept = VecDeque::new();
+            while let Some(entry) = idle.pop_front() {
+                if in_use + kept.len() >= self.config.min_size
+                    && now.duration_since(entry.since) >= self.config.idle_timeout
+                {
+                    stats::incr(&self.stats.idle_evictions);
+                } else {
+                    kept.push_back(entry);
+                }
+            }
+            kept.into_iter().collect()
+        };
+        for mut entry in checked {
+            if entry.client.noop().await.is_ok() {
+                self.idle.lock().unwrap().push_back(entry);
+            } else {
+                stats::incr(&self.stats.health_check_failures);
+            }
+        }
+        loop {
+            let open = self.idle.lock().unwrap().len()
+                + self.stats.in_use.load(Ordering::Relaxed) as usize;
+            if open >= self.config.min_size {
+                break;
+            }
+            match self.connect().await {
+                Ok(client) => self.release(client),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// A pool of connections to one server. Connections are opened on demand up
+/// to `max_size`, and a background task keeps at least `min_size` of them
+/// open and healthy for as long as the pool exists.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    /// Must be called within a Tokio runtime, which runs the upkeep task.
+    pub fn new<A: Into<String>>(address: A, config: PoolConfig) -> Pool {
+        let shared = Arc::new(Shared {
+            address: address.into(),
+            permits: Arc::new(Semaphore::new(config.max_size)),
+            config,
+            idle: Mutex::new(VecDeque::new()),
+            backoff: Mutex::new(Backoff::default()),
+            stats: PoolStats::default(),
+        });
+        tokio::spawn(Pool::maintain(Arc::downgrade(&shared)));
+        Pool { shared }
+    }
+
+    async fn maintain(shared: Weak<Shared>) {
+        loop {
+            match shared.upgrade() {
+                Some(shared) => shared.maintain().await,
+                None => return,
+            }
+            let interval = match shared.upgrade() {
+                Some(shared) => shared.config.health_check_interval,
+                None => return,
+            };
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.shared.address
+    }
+
+    /// Checks a connection out, waiting up to `wait_timeout` for one to be
+    /// returned when `max_size` are in use. It goes back to the pool when
+    /// dropped, unless a failed request broke it.
+    pub async fn get(&self) -> ClientResult<PooledClient> {
+        let started = Instant::now();
+        let permit = tokio::time::timeout(
+            self.shared.config.wait_timeout,
+            self.shared.permits.clone().acquire_owned(),
+        )
+        .await;
+        let waited = started.elapsed().as_micros() as u64;
+        stats::incr(&self.shared.stats.checkouts);
+        self.shared
+            .stats
+            .wait_time_us
+            .fetch_add(waited, Ordering::Relaxed);
+        let permit = match permit {
+            Ok(permit) => permit.expect("pool semaphore is never closed"),
+            Err(_) => {
+                stats::incr(&self.shared.stats.wait_timeouts);
+                return Err(ClientError::Timeout);
+            }
+        };
+
+        let idle = self.shared.idle.lock().unwrap().pop_back();
+        let client = match idle {
+            Some(entry) => entry.client,
+            None => self.shared.connect().await?,
+        };
+        stats::incr(&self.shared.stats.in_use);
+        Ok(PooledClient {
+            client: Some(client),
+            shared: self.shared.clone(),
+            _permit: permit,
+        })
+    }
+
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let stats = &self.shared.stats;
+        let idle = self.shared.idle.lock().unwrap().len();
+        let checkouts = stats.checkouts.load(Ordering::Relaxed);
+        let wait_time_us = stats.wait_time_us.load(Ordering::Relaxed);
+        let mut result = vec![
+            ("pool_idle".to_string(), idle.to_string()),
+            (
+                "pool_avg_wait_us".to_string(),
+                (wait_time_us / checkouts.max(1)).to_string(),
+            ),
+        ];
+        let counters = [
+            ("pool_in_use", &stats.in_use),
+            ("pool_checkouts", &stats.checkouts),
+            ("pool_wait_time_us", &stats.wait_time_us),
+            ("pool_wait_timeouts", &stats.wait_timeouts),
+            ("pool_connects", &stats.connects),
+            ("pool_connect_failures", &stats.connect_failures),
+            ("pool_health_check_failures", &stats.health_check_failures),
+            ("pool_idle_evictions", &stats.idle_evictions),
+        ];
+        result.extend(counters.iter().map(|(name, counter)| {
+            (
+                name.to_string(),
+                counter.load(Ordering::Relaxed).to_string(),
+            )
+        }));
+        result
+    }
+}
+
+/// A connection checked out of a `Pool`.
+pub struct PooledClient {
+    client: Option<Client>,
+    shared: Arc<Shared>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        self.shared.stats.in_use.fetch_sub(1, Ordering::Relaxed);
+        if let Some(client) = self.client.take() {
+            self.shared.release(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::testing::{free_address, start_server};
+    use std::collections::HashMap;
+
+    fn stats(pool: &Pool) -> HashMap<String, String> {
+        pool.stats().into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused_and_bounded() {
+        let config = PoolConfig {
+            min_size: 0,
+            max_size: 2,
+            wait_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        };
+        let pool = Pool::new(start_server().await.0, config);
+        {
+            let mut client = pool.get().await.unwrap();
+            client.set(b"key", b"value", 0, 0).await.unwrap();
+        }
+        let mut first = pool.get().await.unwrap();
+        assert_eq!(first.get(b"key").await.unwrap().unwrap().value, b"value");
+        let _second = pool.get().await.unwrap();
+        assert_eq!(stats(&pool)["pool_in_use"], "2");
+        assert!(matches!(pool.get().await, Err(ClientError::Timeout)));
+        drop(first);
+        assert!(pool.get().await.is_ok());
+
+        let stats = stats(&pool);
+        assert_eq!(stats["pool_connects"], "2");
+        assert_eq!(stats["pool_wait_timeouts"], "1");
+        assert_eq!(stats["pool_in_use"], "1");
+    }
+
+    #[tokio::test]
+    async fn failed_connects_back_off() {
+        let config = PoolConfig {
+            min_size: 0,
+            min_backoff: Duration::from_millis(50),
+            ..PoolConfig::default()
+        };
+        let pool = Pool::new(free_address(), config);
+        assert!(matches!(pool.get().await, Err(ClientError::Io(_))));
+        assert!(matches!(pool.get().await, Err(ClientError::Unavailable(_))));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(pool.get().await, Err(ClientError::Io(_))));
+        // The second failure doubles the delay.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(pool.get().await, Err(ClientError::Unavailable(_))));
+        assert_eq!(stats(&pool)["pool_connect_failures"], "2");
+    }
+
+    #[tokio::test]
+    async fn upkeep_keeps_min_size_and_evicts_idle() {
+        let config = PoolConfig {
+            min_size: 1,
+            idle_timeout: Duration::from_millis(20),
+            health_check_interval: Duration::from_secs(3600),
+            ..PoolConfig::default()
+        };
+        let pool = Pool::new(start_server().await.0, config);
+        // The first round of upkeep runs straight away and opens `min_size`.
+        for _ in 0..100 {
+            if stats(&pool)["pool_idle"] == "1" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(stats(&pool)["pool_idle"], "1");
+
+        let clients = vec![pool.get().await.unwrap(), pool.get().await.unwrap()];
+        drop(clients);
+        assert_eq!(stats(&pool)["pool_idle"], "2");
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        pool.shared.maintain().await;
+        let stats = stats(&pool);
+        assert_eq!(stats["pool_idle"], "1");
+        assert_eq!(stats["pool_idle_evictions"], "1");
+        assert_eq!(stats["pool_health_check_failures"], "0");
+    }
+}
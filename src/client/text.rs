@@ -0,0 +1,239 @@
+use crate::client::connection::{Item, Transport};
+use crate::client::error::{ClientError, ClientResult};
+use std::io;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+
+/// A connection to a server speaking the memcached text protocol, for the
+/// tools that need to reach listeners other than binary ones. It covers the
+/// admin commands rather than the whole protocol.
+pub struct TextClient {
+    stream: BufStream<Box<dyn Transport>>,
+}
+
+impl TextClient {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> ClientResult<TextClient> {
+        let socket = TcpStream::connect(addr).await?;
+        socket.set_nodelay(true)?;
+        Ok(TextClient::new(Box::new(socket)))
+    }
+
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> ClientResult<TextClient> {
+        let socket = UnixStream::connect(path).await?;
+        Ok(TextClient::new(Box::new(socket)))
+    }
+
+    pub fn new(socket: Box<dyn Transport>) -> TextClient {
+        TextClient {
+            stream: BufStream::new(socket),
+        }
+    }
+
+    async fn send(&mut self, command: &[u8]) -> ClientResult<()> {
+        self.stream.write_all(command).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Reads one response line without its line ending. Error lines are
+    /// turned into errors.
+    async fn read_line(&mut self) -> ClientResult<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(ClientError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "server closed the connection",
+            )));
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+        if line == "ERROR" || line.starts_with("CLIENT_ERROR") || line.starts_with("SERVER_ERROR") {
+            return Err(ClientError::Protocol(line));
+        }
+        Ok(line)
+    }
+
+    async fn expect(&mut self, command: &[u8], answers: &[&str]) -> ClientResult<String> {
+        self.send(command).await?;
+        let line = self.read_line().await?;
+        if answers.contains(&line.as_str()) {
+            Ok(line)
+        } else {
+            Err(ClientError::Protocol(line))
+        }
+    }
+
+    pub async fn get(&mut self, key: &[u8]) -> ClientResult<Option<Item>> {
+        self.send(&[b"gets ", key].concat()).await?;
+        let mut item = None;
+        loop {
+            let line = self.read_line().await?;
+            if line == "END" {
+                return Ok(item);
+            }
+            // VALUE <key> <flags> <bytes> <cas>
+            let fields: Vec<&str> = line.split(' ').collect();
+            let number = |index: usize| fields.get(index).and_then(|field| field.parse().ok());
+            let (flags, length, cas) = match (fields[0], number(2), number(3), number(4)) {
+                ("VALUE", Some(flags), Some(length), Some(cas)) => (flags as u32, length, cas),
+                _ => return Err(ClientError::Protocol(line)),
+            };
+            let mut value = vec![0; length as usize + 2];
+            self.stream.read_exact(&mut value).await?;
+            value.truncate(length as usize);
+            item = Some(Item { value, flags, cas });
+        }
+    }
+
+    pub async fn set(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiration: u32,
+    ) -> ClientResult<()> {
+        let header = format!(" {} {} {}\r\n", flags, expiration, value.len());
+        let command = [b"set ", key, header.as_bytes(), value].concat();
+        self.expect(&command, &["STORED"]).await?;
+        Ok(())
+    }
+
+    /// Deletes `key`, returning whether it existed.
+    pub async fn delete(&mut self, key: &[u8]) -> ClientResult<bool> {
+        let command = [b"delete ", key].concat();
+        let line = self.expect(&command, &["DELETED", "NOT_FOUND"]).await?;
+        Ok(line == "DELETED")
+    }
+
+    async fn apply_delta(
+        &mut self,
+        command: &str,
+        key: &[u8],
+        delta: u64,
+    ) -> ClientResult<Option<u64>> {
+        let delta = format!(" {}", delta);
+        self.send(&[command.as_bytes(), b" ", key, delta.as_bytes()].concat())
+            .await?;
+        match self.read_line().await? {
+            line if line == "NOT_FOUND" => Ok(None),
+            line => line
+                .parse()
+                .map(Some)
+                .map_err(|_| ClientError::Protocol(line)),
+        }
+    }
+
+    /// Adds `delta` to the counter at `key`, `None` if it does not exist.
+    pub async fn incr(&mut self, key: &[u8], delta: u64) -> ClientResult<Option<u64>> {
+        self.apply_delta("incr", key, delta).await
+    }
+
+    pub async fn decr(&mut self, key: &[u8], delta: u64) -> ClientResult<Option<u64>> {
+        self.apply_delta("decr", key, delta).await
+    }
+
+    /// Gives `key` a new expiration, returning whether it existed.
+    pub async fn touch(&mut self, key: &[u8], expiration: u32) -> ClientResult<bool> {
+        let expiration = format!(" {}", expiration);
+        let command = [b"touch ", key, expiration.as_bytes()].concat();
+        let line = self.expect(&command, &["TOUCHED", "NOT_FOUND"]).await?;
+        Ok(line == "TOUCHED")
+    }
+
+    pub async fn flush(&mut self, delay: u32) -> ClientResult<()> {
+        let command = format!("flush_all {}", delay);
+        self.expect(command.as_bytes(), &["OK"]).await?;
+        Ok(())
+    }
+
+    /// Sends `command` and collects the lines it answers up to `END`.
+    async fn lines_until_end(&mut self, command: &[u8]) -> ClientResult<Vec<String>> {
+        self.send(command).await?;
+        let mut lines = Vec::new();
+        loop {
+            match self.read_line().await? {
+                line if line == "END" => return Ok(lines),
+                line => lines.push(line),
+            }
+        }
+    }
+
+    /// The statistics of `group`, the general ones for `""`.
+    pub async fn stats(&mut self, group: &str) -> ClientResult<Vec<(String, String)>> {
+        let command = format!("stats {}", group);
+        let mut stats = Vec::new();
+        for line in self.lines_until_end(command.trim_end().as_bytes()).await? {
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("STAT"), Some(name), value) => {
+                    stats.push((name.to_string(), value.unwrap_or("").to_string()))
+                }
+                _ => return Err(ClientError::Protocol(line)),
+            }
+        }
+        Ok(stats)
+    }
+
+    pub async fn version(&mut self) -> ClientResult<String> {
+        self.send(b"version").await?;
+        match self.read_line().await? {
+            line if line.starts_with("VERSION ") => Ok(line["VERSION ".len()..].to_string()),
+            line => Err(ClientError::Protocol(line)),
+        }
+    }
+
+    /// One line of metadata per item, as `lru_crawler metadump all` gives.
+    pub async fn metadump(&mut self) -> ClientResult<Vec<String>> {
+        self.lines_until_end(b"lru_crawler metadump all").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client talking to a fake server that checks it is sent `command`
+    /// and answers `response`.
+    fn client(command: &'static str, response: &'static str) -> TextClient {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut server = BufStream::new(server);
+            let mut line = String::new();
+            server.read_line(&mut line).await.unwrap();
+            assert_eq!(line, format!("{}\r\n", command));
+            server.write_all(response.as_bytes()).await.unwrap();
+            server.flush().await.unwrap();
+        });
+        TextClient::new(Box::new(client))
+    }
+
+    #[tokio::test]
+    async fn get_parses_values() {
+        let mut hit = client("gets key", "VALUE key 5 3 9\r\na\r\n\r\nEND\r\n");
+        let item = hit.get(b"key").await.unwrap().unwrap();
+        assert_eq!(item.value, b"a\r\n");
+        assert_eq!((item.flags, item.cas), (5, 9));
+
+        let mut miss = client("gets key", "END\r\n");
+        assert_eq!(miss.get(b"key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn stats_and_errors() {
+        let mut stats = client("stats", "STAT pid 1\r\nSTAT version 1.6 x\r\nEND\r\n");
+        assert_eq!(
+            stats.stats("").await.unwrap(),
+            vec![
+                ("pid".to_string(), "1".to_string()),
+                ("version".to_string(), "1.6 x".to_string())
+            ]
+        );
+
+        let mut counter = client("incr key 2", "CLIENT_ERROR not a number\r\n");
+        assert!(matches!(
+            counter.incr(b"key", 2).await,
+            Err(ClientError::Protocol(_))
+        ));
+    }
+}
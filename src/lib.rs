@@ -1,7 +1,15 @@
+// failure_derive expands into impls nested in anonymous consts.
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate failure_derive;
 
+pub mod bench;
+pub mod cache;
+pub mod client;
 pub mod memcached;
 pub mod protocol;
+pub mod replay;
+pub mod typed;
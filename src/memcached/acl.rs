@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Kind of access a command needs on the key it touches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Permission granted on a key prefix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl Permission {
+    fn allows(self, access: Access) -> bool {
+        match self {
+            Permission::ReadOnly => access == Access::Read,
+            Permission::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    prefix: Vec<u8>,
+    permission: Permission,
+}
+
+/// Per-user key prefix rules. Each line of an ACL file reads
+/// `user prefix permission`, where permission is `r` or `rw` and a prefix
+/// of `*` matches every key. The longest matching prefix wins; a user with
+/// no matching rule is denied.
+#[derive(Debug)]
+pub struct Acl {
+    users: HashMap<String, Vec<Rule>>,
+}
+
+impl Acl {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Acl> {
+        let contents = fs::read_to_string(path)?;
+        Acl::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Acl> {
+        let mut users: HashMap<String, Vec<Rule>> = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid acl entry on line {}", number + 1),
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(invalid());
+            }
+            let prefix = match fields[1] {
+                "*" => Vec::new(),
+                prefix => prefix.as_bytes().to_vec(),
+            };
+            let permission = match fields[2] {
+                "r" => Permission::ReadOnly,
+                "rw" => Permission::ReadWrite,
+                _ => return Err(invalid()),
+            };
+            users
+                .entry(fields[0].to_string())
+                .or_default()
+                .push(Rule { prefix, permission });
+        }
+        for rules in users.values_mut() {
+            rules.sort_by_key(|rule| std::cmp::Reverse(rule.prefix.len()));
+        }
+        Ok(Acl { users })
+    }
+
+    pub fn permission(&self, user: &str, key: &[u8]) -> Option<Permission> {
+        self.users
+            .get(user)?
+            .iter()
+            .find(|rule| key.starts_with(&rule.prefix))
+            .map(|rule| rule.permission)
+    }
+
+    pub fn is_allowed(&self, user: Option<&str>, key: &[u8], access: Access) -> bool {
+        match user.and_then(|user| self.permission(user, key)) {
+            Some(permission) => permission.allows(access),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_acl() -> Acl {
+        Acl::parse(
+            "# user prefix permission\n\
+             alice team_a: rw\n\
+             alice shared: r\n\
+             alice shared:alice: rw\n\
+             root * rw\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let acl = create_acl();
+        assert!(acl.is_allowed(Some("alice"), b"team_a:1", Access::Write));
+        assert!(acl.is_allowed(Some("alice"), b"shared:1", Access::Read));
+        assert!(!acl.is_allowed(Some("alice"), b"shared:1", Access::Write));
+        assert!(acl.is_allowed(Some("alice"), b"shared:alice:1", Access::Write));
+        assert!(acl.is_allowed(Some("root"), b"anything", Access::Write));
+    }
+
+    #[test]
+    fn unknown_users_and_keys_are_denied() {
+        let acl = create_acl();
+        assert!(!acl.is_allowed(Some("alice"), b"team_b:1", Access::Read));
+        assert!(!acl.is_allowed(Some("bob"), b"team_a:1", Access::Read));
+        assert!(!acl.is_allowed(None, b"team_a:1", Access::Read));
+    }
+
+    #[test]
+    fn reject_malformed_acl_file() {
+        assert!(Acl::parse("alice team_a:\n").is_err());
+        assert!(Acl::parse("alice team_a: w\n").is_err());
+    }
+}
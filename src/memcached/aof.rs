@@ -0,0 +1,582 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::memcached::snapshot::{self, Snapshot};
+use crate::memcached::storage::{Mutation, MutationKind, MutationObserver, Storage};
+
+const OP_STORE: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_FLUSH: u8 = 3;
+const OP_INVALIDATE_TAG: u8 = 4;
+
+/// Log size past which the background thread rewrites the log.
+const DEFAULT_COMPACT_SIZE: u64 = 64 << 20;
+
+/// When appended entries are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySecond,
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = io::Error;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySecond),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown fsync policy {:?}", policy),
+            )),
+        }
+    }
+}
+
+/// Outcome of replaying the log on startup.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplaySummary {
+    pub applied: u64,
+    pub expired: u64,
+    /// Bytes dropped from a torn or corrupt tail.
+    pub truncated: u64,
+}
+
+struct LogWriter {
+    file: BufWriter<File>,
+    /// The same file, to sync without holding the writer.
+    sync_file: Arc<File>,
+    size: u64,
+    /// Entries appended since the log was opened, across compactions.
+    appended: u64,
+}
+
+/// Append-only log of every mutation applied to the store.
+///
+/// Each entry is framed as `[u32 length][u32 crc32][payload]`, so a write torn
+/// by a crash is detected and cut off on replay. Entries carry the resulting
+/// state of the key rather than the command, which makes replaying an entry
+/// twice harmless. Compaction exploits that: the live log is renamed aside, a
+/// fresh log takes new entries, the store is saved to `snapshot` and only then
+/// is the old log deleted. Recovery loads the snapshot and replays the old log,
+/// if a crash left one behind, followed by the live one.
+///
+/// Entries are appended while their key is locked but, with `Always`, synced
+/// only once it is unlocked again, by whichever writer gets there first on
+/// behalf of all entries appended so far.
+pub struct AppendOnlyLog {
+    path: PathBuf,
+    policy: FsyncPolicy,
+    snapshot: Arc<Snapshot>,
+    compact_size: u64,
+    writer: Mutex<LogWriter>,
+    /// Entries known to be on disk; held while syncing.
+    synced: Mutex<u64>,
+    compacting: Mutex<()>,
+}
+
+impl AppendOnlyLog {
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+        policy: FsyncPolicy,
+        snapshot: Arc<Snapshot>,
+    ) -> io::Result<AppendOnlyLog> {
+        let path = path.into();
+        let writer = AppendOnlyLog::open_writer(&path)?;
+        Ok(AppendOnlyLog {
+            path,
+            policy,
+            snapshot,
+            compact_size: DEFAULT_COMPACT_SIZE,
+            writer: Mutex::new(writer),
+            synced: Mutex::new(0),
+            compacting: Mutex::new(()),
+        })
+    }
+
+    /// Sets the log size that triggers a background rewrite.
+    pub fn with_compact_size(mut self, compact_size: u64) -> Self {
+        self.compact_size = compact_size;
+        self
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.clone()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.writer.lock().unwrap().size
+    }
+
+    fn open_writer(path: &Path) -> io::Result<LogWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogWriter {
+            sync_file: Arc::new(file.try_clone()?),
+            file: BufWriter::new(file),
+            size,
+            appended: 0,
+        })
+    }
+
+    fn old_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".old");
+        path.into()
+    }
+
+    /// Replays the log into `storage`, which should already hold the snapshot.
+    /// Must run before `start`, so replayed entries are not logged again.
+    pub fn replay(&self, storage: &Storage) -> io::Result<ReplaySummary> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut summary = ReplaySummary::default();
+        let old_path = self.old_path();
+        let has_old_log = old_path.exists();
+        if has_old_log {
+            replay_file(storage, &old_path, &mut summary)?;
+        }
+        let valid = replay_file(storage, &self.path, &mut summary)?;
+        if valid < writer.size {
+            warn!(
+                "Truncating {} bytes of torn log at {:?}",
+                writer.size - valid,
+                self.path
+            );
+            writer.file.get_ref().set_len(valid)?;
+            summary.truncated = writer.size - valid;
+            writer.size = valid;
+        }
+        if has_old_log {
+            // An interrupted compaction: everything is in memory now, so
+            // finish it before the next one could overwrite the old log.
+            self.snapshot.save(storage)?;
+            fs::remove_file(&old_path)?;
+        }
+        info!("Replayed {:?} from {:?}", summary, self.path);
+        Ok(summary)
+    }
+
+    /// Starts logging mutations of `storage` and spawns the thread that
+    /// flushes the log every second and compacts it once it grows too big.
+    pub fn start(self: &Arc<Self>, storage: &Arc<Storage>) {
+        storage.add_observer(self.clone());
+        let log = self.clone();
+        let storage = Arc::downgrade(storage);
+        thread::spawn(move || log.run_background(storage));
+    }
+
+    fn run_background(&self, storage: Weak<Storage>) {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let storage = match storage.upgrade() {
+                Some(storage) => storage,
+                None => return,
+            };
+            if let Err(err) = self.flush(self.policy == FsyncPolicy::EverySecond) {
+                error!("Failed to flush log {:?}: {}", self.path, err);
+            }
+            if self.size() >= self.compact_size {
+                if let Err(err) = self.compact(&storage) {
+                    error!("Failed to compact log {:?}: {}", self.path, err);
+                }
+            }
+        }
+    }
+
+    pub fn flush(&self, sync: bool) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.file.flush()?;
+        if sync {
+            writer.file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log as a snapshot of `storage` plus an empty log.
+    pub fn compact(&self, storage: &Storage) -> io::Result<()> {
+        let _guard = self.compacting.lock().unwrap();
+        let old_path = self.old_path();
+        {
+            let mut writer = self.writer.lock().unwrap();
+            writer.file.flush()?;
+            writer.file.get_ref().sync_all()?;
+            fs::rename(&self.path, &old_path)?;
+            let appended = writer.appended;
+            *writer = AppendOnlyLog::open_writer(&self.path)?;
+            writer.appended = appended;
+        }
+        self.snapshot.save(storage)?;
+        fs::remove_file(&old_path)?;
+        info!("Compacted log {:?}", self.path);
+        Ok(())
+    }
+
+    fn append(&self, payload: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.file.write_u32::<BigEndian>(payload.len() as u32)?;
+        writer
+            .file
+            .write_u32::<BigEndian>(crc32fast::hash(payload))?;
+        writer.file.write_all(payload)?;
+        writer.size += 8 + payload.len() as u64;
+        writer.appended += 1;
+        Ok(())
+    }
+
+    /// Makes sure every entry appended so far is on disk. Writers queue up
+    /// behind a single sync, and those whose entries it covered return
+    /// without one of their own.
+    fn sync_appended(&self) -> io::Result<()> {
+        let target = self.writer.lock().unwrap().appended;
+        let mut synced = self.synced.lock().unwrap();
+        if *synced >= target {
+            return Ok(());
+        }
+        let (file, appended) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.file.flush()?;
+            (writer.sync_file.clone(), writer.appended)
+        };
+        file.sync_data()?;
+        *synced = appended;
+        Ok(())
+    }
+}
+
+impl MutationObserver for AppendOnlyLog {
+    fn on_mutation(&self, namespace: &str, mutation: &Mutation) {
+        let mut payload = Vec::new();
+        encode(&mut payload, namespace, mutation).expect("writing to a Vec cannot fail");
+        if let Err(err) = self.append(&payload) {
+            error!("Failed to append to log {:?}: {}", self.path, err);
+        }
+    }
+
+    fn commit(&self) {
+        if self.policy != FsyncPolicy::Always {
+            return;
+        }
+        if let Err(err) = self.sync_appended() {
+            error!("Failed to sync log {:?}: {}", self.path, err);
+        }
+    }
+}
+
+fn kind_code(kind: MutationKind) -> u8 {
+    match kind {
+        MutationKind::Set => 1,
+        MutationKind::Add => 2,
+        MutationKind::Replace => 3,
+        MutationKind::Append => 4,
+        MutationKind::Prepend => 5,
+        MutationKind::Cas => 6,
+        MutationKind::Increment => 7,
+        MutationKind::Decrement => 8,
+        MutationKind::Touch => 9,
+    }
+}
+
+pub(crate) fn encode<W: Write>(
+    writer: &mut W,
+    namespace: &str,
+    mutation: &Mutation,
+) -> io::Result<()> {
+    match mutation {
+        Mutation::Store { kind, key, record } => {
+            writer.write_u8(OP_STORE)?;
+            // The command is kept for inspection only; replay just stores the record.
+            writer.write_u8(kind_code(*kind))?;
+            snapshot::write_record(writer, namespace, key, record)
+        }
+        Mutation::Delete { key } => {
+            writer.write_u8(OP_DELETE)?;
+            write_namespace(writer, namespace)?;
+            writer.write_u32::<BigEndian>(key.len() as u32)?;
+            writer.write_all(key)
+        }
+        Mutation::Flush => {
+            writer.write_u8(OP_FLUSH)?;
+            write_namespace(writer, namespace)
+        }
+        Mutation::InvalidateTag { tag } => {
+            writer.write_u8(OP_INVALIDATE_TAG)?;
+            writer.write_u16::<BigEndian>(tag.len() as u16)?;
+            writer.write_all(tag)
+        }
+    }
+}
+
+fn write_namespace<W: Write>(writer: &mut W, namespace: &str) -> io::Result<()> {
+    writer.write_u16::<BigEndian>(namespace.len() as u16)?;
+    writer.write_all(namespace.as_bytes())
+}
+
+pub(crate) fn apply(
+    storage: &Storage,
+    payload: &[u8],
+    now: u64,
+    summary: &mut ReplaySummary,
+) -> io::Result<()> {
+    let mut reader = payload;
+    match reader.read_u8()? {
+        OP_STORE => {
+            let _kind = reader.read_u8()?;
+            let (namespace, key, mut record, expires_at) = snapshot::read_record(&mut reader)?;
+            if expires_at != 0 && expires_at <= now {
+                // A later entry may still resurrect the key; an earlier one
+                // must not survive it.
+                if let Some(namespace) = storage.namespace_by_name(&namespace) {
+                    namespace.remove(&key);
+                }
+                summary.expired += 1;
+                return Ok(());
+            }
+            record.header.set_expires_at(now, expires_at);
+            // A record over a shrunken quota is dropped like an eviction would.
+            let _ = snapshot::restore_record(storage, &namespace, key, record);
+        }
+        OP_DELETE => {
+            let namespace = snapshot::read_namespace(&mut reader)?;
+            let key = snapshot::read_key(&mut reader)?;
+            let namespace = storage
+                .namespace_by_name(&namespace)
+                .unwrap_or_else(|| storage.namespace(None, &key));
+            namespace.remove(&key);
+        }
+        OP_FLUSH => {
+            let namespace = snapshot::read_namespace(&mut reader)?;
+            if let Some(namespace) = storage.namespace_by_name(&namespace) {
+                namespace.clear();
+            }
+        }
+        OP_INVALIDATE_TAG => {
+            let len = reader.read_u16::<BigEndian>()? as usize;
+            let tag = snapshot::read_bytes(&mut reader, len)?;
+            storage.tags().invalidate(&tag);
+        }
+        _ => return Err(snapshot::invalid_data("invalid log entry")),
+    }
+    summary.applied += 1;
+    Ok(())
+}
+
+/// Applies every intact entry of the log at `path` and returns the length of
+/// the intact prefix. Fails on an intact entry it cannot apply, such as one
+/// a newer version wrote.
+fn replay_file(storage: &Storage, path: &Path, summary: &mut ReplaySummary) -> io::Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let now = storage.timer().secs();
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut valid = 0u64;
+    loop {
+        let len = match reader.read_u32::<BigEndian>() {
+            Ok(len) => len as usize,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+        let entry = reader
+            .read_u32::<BigEndian>()
+            .and_then(|checksum| Ok((checksum, snapshot::read_bytes(&mut reader, len)?)));
+        let payload = match entry {
+            Ok((checksum, payload)) if crc32fast::hash(&payload) == checksum => payload,
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+        // The entry is intact, so it is not a torn tail to be truncated.
+        apply(storage, &payload, now, summary).map_err(|err| {
+            snapshot::invalid_data(&format!(
+                "unreadable log entry at byte {} of {:?}: {}",
+                valid, path, err
+            ))
+        })?;
+        valid += 8 + len as u64;
+    }
+    Ok(valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::storage::{IncrementParam, Record};
+    use crate::memcached::testing::MockTimer;
+
+    struct TempFiles(PathBuf);
+
+    impl TempFiles {
+        fn new(name: &str) -> TempFiles {
+            let dir =
+                std::env::temp_dir().join(format!("rustcache-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempFiles(dir)
+        }
+
+        fn open_log(&self) -> AppendOnlyLog {
+            let snapshot = Arc::new(Snapshot::new(self.0.join("snapshot")));
+            AppendOnlyLog::open(self.0.join("log"), FsyncPolicy::Always, snapshot).unwrap()
+        }
+    }
+
+    impl Drop for TempFiles {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn create_storage() -> Arc<Storage> {
+        Arc::new(Storage::new(Arc::new(MockTimer::new(1000))))
+    }
+
+    fn record(value: &str) -> Record {
+        Record::new(value.as_bytes().to_vec(), 0, 0, 0)
+    }
+
+    fn recover(files: &TempFiles) -> (Arc<Storage>, ReplaySummary) {
+        let storage = create_storage();
+        let log = files.open_log();
+        log.snapshot().load(&storage).unwrap();
+        let summary = log.replay(&storage).unwrap();
+        (storage, summary)
+    }
+
+    fn populate(storage: &Storage) {
+        storage.set(b"a".to_vec(), record("1")).unwrap();
+        storage.set(b"b".to_vec(), record("2")).unwrap();
+        storage.append(b"b".to_vec(), record("2")).unwrap();
+        let param = IncrementParam {
+            delta: 5,
+            value: 0,
+            expiration: 0,
+        };
+        storage.increment(b"a".to_vec(), param).unwrap();
+        storage.set(b"c".to_vec(), record("3")).unwrap();
+        storage.delete(&b"c".to_vec(), 0).unwrap();
+    }
+
+    #[test]
+    fn replay_restores_every_mutation() {
+        let files = TempFiles::new("aof-replay");
+        let storage = create_storage();
+        let log = Arc::new(files.open_log());
+        log.start(&storage);
+        populate(&storage);
+
+        let (restored, summary) = recover(&files);
+        assert_eq!(summary.applied, 6);
+        assert_eq!(restored.get(&b"a".to_vec()).unwrap().value, b"6");
+        assert_eq!(restored.get(&b"b".to_vec()).unwrap().value, b"22");
+        assert!(restored.get(&b"c".to_vec()).is_err());
+    }
+
+    #[test]
+    fn tag_invalidations_are_replayed() {
+        let files = TempFiles::new("aof-tags");
+        let storage = create_storage();
+        let log = Arc::new(files.open_log());
+        log.start(&storage);
+        let tagged = |value: &str| record(value).with_tags(vec![b"user:1".to_vec()]);
+        storage.set(b"before".to_vec(), tagged("1")).unwrap();
+        storage.invalidate_tag(b"user:1");
+        storage.set(b"after".to_vec(), tagged("2")).unwrap();
+
+        let (restored, summary) = recover(&files);
+        assert_eq!(summary.applied, 3);
+        assert!(restored.get(&b"before".to_vec()).is_err());
+        assert_eq!(restored.get(&b"after".to_vec()).unwrap().value, b"2");
+        restored.invalidate_tag(b"user:1");
+        assert!(restored.get(&b"after".to_vec()).is_err());
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let files = TempFiles::new("aof-torn");
+        let storage = create_storage();
+        let log = Arc::new(files.open_log());
+        log.start(&storage);
+        populate(&storage);
+        let intact = log.size();
+        drop(log);
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(files.0.join("log"))
+            .unwrap();
+        file.write_all(&[0, 0, 0, 42, 1, 2]).unwrap();
+        drop(file);
+
+        let (restored, summary) = recover(&files);
+        assert_eq!(summary.truncated, 6);
+        assert_eq!(restored.get(&b"a".to_vec()).unwrap().value, b"6");
+        assert_eq!(fs::metadata(files.0.join("log")).unwrap().len(), intact);
+    }
+
+    #[test]
+    fn unreadable_entries_are_kept() {
+        let files = TempFiles::new("aof-unreadable");
+        let storage = create_storage();
+        let log = Arc::new(files.open_log());
+        log.start(&storage);
+        populate(&storage);
+        drop(log);
+
+        let payload = [0xEE];
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(files.0.join("log"))
+            .unwrap();
+        file.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+        file.write_u32::<BigEndian>(crc32fast::hash(&payload))
+            .unwrap();
+        file.write_all(&payload).unwrap();
+        drop(file);
+        let size = fs::metadata(files.0.join("log")).unwrap().len();
+
+        let log = files.open_log();
+        let err = log.replay(&create_storage()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(files.0.join("log")).unwrap().len(), size);
+    }
+
+    #[test]
+    fn compaction_moves_state_into_the_snapshot() {
+        let files = TempFiles::new("aof-compact");
+        let storage = create_storage();
+        let log = Arc::new(files.open_log());
+        log.start(&storage);
+        populate(&storage);
+        log.compact(&storage).unwrap();
+        assert_eq!(log.size(), 0);
+        storage.set(b"d".to_vec(), record("4")).unwrap();
+
+        let (restored, summary) = recover(&files);
+        assert_eq!(summary.applied, 1);
+        assert_eq!(restored.get(&b"b".to_vec()).unwrap().value, b"22");
+        assert_eq!(restored.get(&b"d".to_vec()).unwrap().value, b"4");
+    }
+
+    #[test]
+    fn parse_fsync_policy() {
+        assert_eq!(
+            "always".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!(
+            "everysec".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::EverySecond
+        );
+        assert_eq!("never".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Never);
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
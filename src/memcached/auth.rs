@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Mechanisms advertised in reply to `SaslListMechs`.
+pub const MECHANISMS: &str = "PLAIN";
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum AuthError {
+    #[fail(display = "Unsupported mechanism")]
+    UnsupportedMechanism,
+    #[fail(display = "Malformed auth data")]
+    Malformed,
+    #[fail(display = "Invalid credentials")]
+    InvalidCredentials,
+}
+
+/// Users and passwords loaded from a memcached style password file, one
+/// `username:password` entry per line. Empty lines and `#` comments are skipped.
+pub struct Authenticator {
+    users: HashMap<String, String>,
+}
+
+impl Authenticator {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Authenticator> {
+        let contents = fs::read_to_string(path)?;
+        Authenticator::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Authenticator> {
+        let mut users = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) if !user.is_empty() => {
+                    users.insert(user.to_string(), password.to_string());
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid password entry on line {}", number + 1),
+                    ))
+                }
+            }
+        }
+        Ok(Authenticator { users })
+    }
+
+    /// Runs a single SASL exchange and returns the authenticated user name.
+    pub fn authenticate(&self, mechanism: &[u8], data: &[u8]) -> Result<String, AuthError> {
+        if mechanism != MECHANISMS.as_bytes() {
+            return Err(AuthError::UnsupportedMechanism);
+        }
+        let (user, password) = parse_plain(data).ok_or(AuthError::Malformed)?;
+        match self.users.get(&user) {
+            Some(expected) if constant_time_eq(expected.as_bytes(), password.as_bytes()) => {
+                Ok(user)
+            }
+            _ => Err(AuthError::InvalidCredentials),
+        }
+    }
+}
+
+/// Splits a PLAIN message (`[authzid] NUL authcid NUL passwd`, RFC 4616).
+pub fn parse_plain(data: &[u8]) -> Option<(String, String)> {
+    let mut parts = data.split(|b| *b == 0);
+    let _authzid = parts.next()?;
+    let user = String::from_utf8(parts.next()?.to_vec()).ok()?;
+    let password = String::from_utf8(parts.next()?.to_vec()).ok()?;
+    if parts.next().is_some() || user.is_empty() {
+        return None;
+    }
+    Some((user, password))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_plain_message() {
+        assert_eq!(
+            parse_plain(b"\0alice\0secret"),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+        assert_eq!(
+            parse_plain(b"admin\0alice\0secret"),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+        assert_eq!(parse_plain(b"alice\0secret"), None);
+        assert_eq!(parse_plain(b"\0\0secret"), None);
+    }
+
+    #[test]
+    fn authenticate_against_password_file() {
+        let auth = Authenticator::parse("# users\nalice:secret\n\nbob:hunter2\n").unwrap();
+        assert_eq!(
+            auth.authenticate(b"PLAIN", b"\0alice\0secret"),
+            Ok("alice".to_string())
+        );
+        assert_eq!(
+            auth.authenticate(b"PLAIN", b"\0alice\0hunter2"),
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            auth.authenticate(b"PLAIN", b"\0carol\0secret"),
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            auth.authenticate(b"CRAM-MD5", b"alice"),
+            Err(AuthError::UnsupportedMechanism)
+        );
+    }
+
+    #[test]
+    fn reject_malformed_password_file() {
+        assert!(Authenticator::parse("alice\n").is_err());
+    }
+}
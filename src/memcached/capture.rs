@@ -0,0 +1,314 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BytesMut;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::codec::Decoder;
+
+use crate::memcached::snapshot::{self, invalid_data};
+use crate::protocol::binary;
+use crate::protocol::binary_codec::{
+    BinaryRequest, BinaryResponse, MemcachedBinaryClientCodec, MemcachedBinaryCodec,
+};
+
+const MAGIC: &[u8; 4] = b"RCCP";
+const VERSION: u16 = 1;
+
+const KIND_REQUEST: u8 = 1;
+const KIND_RESPONSE: u8 = 2;
+
+/// Records the decoded requests a server receives, and the responses it sends
+/// back, to a capture file for `replay`.
+///
+/// The file starts with a magic number, format version and the wall-clock
+/// time capturing started at, in microseconds since the epoch. Each packet
+/// follows as `[u8 kind][u64 micros since start][u64 connection][u32 length]
+/// [u32 crc32][packet]`, the packet re-encoded in binary protocol. A response
+/// always follows the request it answers on the same connection; quiet
+/// requests may have none. A torn tail is ignored when reading.
+///
+/// The file is only readable by its owner, and SASL requests are recorded
+/// without their credentials.
+pub struct Capture {
+    path: PathBuf,
+    started: Instant,
+    connections: AtomicU64,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Capture {
+    /// Creates the capture file at `path`, replacing any previous capture.
+    pub fn create<P: Into<PathBuf>>(path: P) -> io::Result<Capture> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        // The mode only applies to a new file, not to one being replaced.
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        let mut writer = BufWriter::new(file);
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        writer.write_u64::<BigEndian>(since_epoch.as_micros() as u64)?;
+        Ok(Capture {
+            path,
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Allocates the id the packets of a newly accepted connection are
+    /// recorded under.
+    pub fn connection(&self) -> u64 {
+        self.connections.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn record_request(&self, connection: u64, request: &BinaryRequest) {
+        let mut packet = BytesMut::new();
+        let codec = MemcachedBinaryClientCodec::new();
+        match request {
+            // The mechanism is kept but not the credentials, so a replay
+            // fails to authenticate.
+            BinaryRequest::SaslAuth(sasl) | BinaryRequest::SaslStep(sasl) => {
+                let redacted = binary::SaslRequest {
+                    header: sasl.header,
+                    key: sasl.key.clone(),
+                    value: Vec::new(),
+                };
+                let redacted = match request {
+                    BinaryRequest::SaslAuth(_) => BinaryRequest::SaslAuth(redacted),
+                    _ => BinaryRequest::SaslStep(redacted),
+                };
+                codec.write_msg(&redacted, &mut packet)
+            }
+            request => codec.write_msg(request, &mut packet),
+        }
+        self.record(KIND_REQUEST, connection, &packet);
+    }
+
+    pub fn record_response(&self, connection: u64, response: &BinaryResponse) {
+        let mut packet = BytesMut::new();
+        MemcachedBinaryCodec::new().write_msg(response, &mut packet);
+        self.record(KIND_RESPONSE, connection, &packet);
+    }
+
+    fn record(&self, kind: u8, connection: u64, packet: &[u8]) {
+        let at = self.started.elapsed().as_micros() as u64;
+        let mut writer = self.writer.lock().unwrap();
+        let result = (|| {
+            writer.write_u8(kind)?;
+            writer.write_u64::<BigEndian>(at)?;
+            writer.write_u64::<BigEndian>(connection)?;
+            writer.write_u32::<BigEndian>(packet.len() as u32)?;
+            writer.write_u32::<BigEndian>(crc32fast::hash(packet))?;
+            writer.write_all(packet)
+        })();
+        if let Err(err) = result {
+            error!("Failed to write to capture {:?}: {}", self.path, err);
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+/// A request or response read back from a capture.
+#[derive(Debug)]
+pub enum CapturedPacket {
+    Request(BinaryRequest),
+    Response(BinaryResponse),
+}
+
+#[derive(Debug)]
+pub struct CaptureEntry {
+    /// When the packet was seen, relative to the start of the capture.
+    pub at: Duration,
+    pub connection: u64,
+    pub packet: CapturedPacket,
+}
+
+/// Reads the entries of a capture file in the order they were recorded.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    /// Wall-clock time the capture started at.
+    pub started: SystemTime,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CaptureReader<BufReader<File>>> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> io::Result<CaptureReader<R>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a capture file"));
+        }
+        if reader.read_u16::<BigEndian>()? != VERSION {
+            return Err(invalid_data("unsupported capture version"));
+        }
+        let started = UNIX_EPOCH + Duration::from_micros(reader.read_u64::<BigEndian>()?);
+        Ok(CaptureReader { reader, started })
+    }
+
+    fn read_entry(&mut self) -> io::Result<CaptureEntry> {
+        let kind = self.reader.read_u8()?;
+        let at = Duration::from_micros(self.reader.read_u64::<BigEndian>()?);
+        let connection = self.reader.read_u64::<BigEndian>()?;
+        let len = self.reader.read_u32::<BigEndian>()? as usize;
+        let checksum = self.reader.read_u32::<BigEndian>()?;
+        let packet = snapshot::read_bytes(&mut self.reader, len)?;
+        if crc32fast::hash(&packet) != checksum {
+            return Err(invalid_data("capture checksum mismatch"));
+        }
+        let mut packet = BytesMut::from(&packet[..]);
+        let packet = match kind {
+            KIND_REQUEST => MemcachedBinaryCodec::new()
+                .decode(&mut packet)?
+                .map(CapturedPacket::Request),
+            KIND_RESPONSE => MemcachedBinaryClientCodec::new()
+                .decode(&mut packet)?
+                .map(CapturedPacket::Response),
+            _ => return Err(invalid_data("invalid capture entry")),
+        };
+        let packet = packet.ok_or_else(|| invalid_data("truncated capture packet"))?;
+        Ok(CaptureEntry {
+            at,
+            connection,
+            packet,
+        })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureEntry>;
+
+    /// Ends at the end of the file or at a torn last entry.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_entry() {
+            Ok(entry) => Some(Ok(entry)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(key: &[u8], opaque: u32) -> BinaryRequest {
+        let header = binary::RequestHeader {
+            opcode: binary::Command::Get as u8,
+            opaque,
+            ..binary::RequestHeader::default()
+        };
+        BinaryRequest::Get(binary::GetRequest {
+            header,
+            key: key.to_vec(),
+        })
+    }
+
+    #[test]
+    fn read_back_what_was_recorded() {
+        let path = std::env::temp_dir().join(format!("rustcache-capture-{}", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let first = capture.connection();
+        let second = capture.connection();
+        capture.record_request(first, &get(b"a", 1));
+        capture.record_request(second, &get(b"b", 2));
+        let mut header = binary::ResponseHeader::new(binary::Command::Get as u8, 2);
+        header.status = binary::ResponseStatus::KeyNotExists as u16;
+        capture.record_response(
+            second,
+            &BinaryResponse::Error(binary::ErrorResponse { header }),
+        );
+        capture.flush().unwrap();
+
+        // A torn entry at the end is dropped.
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[KIND_REQUEST, 0, 0]).unwrap();
+        drop(file);
+
+        let entries: Vec<_> = CaptureReader::open(&path)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].connection, second);
+        assert!(entries[0].at <= entries[2].at);
+        match &entries[0].packet {
+            CapturedPacket::Request(request) => {
+                assert_eq!(request.key(), Some(&b"a"[..]));
+                assert_eq!(request.get_header().opaque, 1);
+            }
+            packet => panic!("unexpected {:?}", packet),
+        }
+        match &entries[2].packet {
+            CapturedPacket::Response(response) => {
+                assert_eq!(response.get_header().opaque, 2);
+                assert_eq!(
+                    response.get_header().status,
+                    binary::ResponseStatus::KeyNotExists as u16
+                );
+            }
+            packet => panic!("unexpected {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn credentials_are_not_recorded() {
+        let path =
+            std::env::temp_dir().join(format!("rustcache-capture-sasl-{}", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let header = binary::RequestHeader {
+            opcode: binary::Command::SaslAuth as u8,
+            ..binary::RequestHeader::default()
+        };
+        let auth = BinaryRequest::SaslAuth(binary::SaslAuthRequest {
+            header,
+            key: b"PLAIN".to_vec(),
+            value: b"\0alice\0secret".to_vec(),
+        });
+        capture.record_request(capture.connection(), &auth);
+        capture.flush().unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let contents = std::fs::read(&path).unwrap();
+        let entries: Vec<_> = CaptureReader::open(&path)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!contents.windows(6).any(|window| window == b"secret"));
+        match &entries[0].packet {
+            CapturedPacket::Request(BinaryRequest::SaslAuth(request)) => {
+                assert_eq!((&request.key[..], request.value.len()), (&b"PLAIN"[..], 0))
+            }
+            packet => panic!("unexpected {:?}", packet),
+        }
+    }
+}
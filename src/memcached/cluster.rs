@@ -0,0 +1,512 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Points each server gets on the ring at the average weight, as in
+/// libketama: 40 MD5 digests of four points each.
+const POINTS_PER_SERVER: f32 = 160.0;
+
+/// Port libmemcached leaves out of the names it hashes servers under.
+const DEFAULT_PORT: &str = "11211";
+
+/// Vbucket id marking a request one node forwarded to another. The owner
+/// serves it whatever its own ring says, so misconfigured rings cannot bounce
+/// a request around forever. It is only trusted on connections authenticated
+/// as the cluster's peer user.
+pub const FORWARDED_VBUCKET: u16 = 0xffff;
+
+/// A ketama compatible consistent hash ring. Each server is placed on the
+/// ring at the MD5 digests of `"<host>:<port>-<n>"`, or `"<host>-<n>"` on the
+/// default port, so adding or removing one only moves the keys between it
+/// and its neighbours.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    nodes: Vec<String>,
+    points: Vec<(u32, usize)>,
+}
+
+impl HashRing {
+    /// Builds the ring from `(address, weight)` pairs.
+    pub fn new(nodes: &[(String, u32)]) -> HashRing {
+        let total_weight: u64 = nodes.iter().map(|(_, weight)| *weight as u64).sum();
+        let mut points = Vec::new();
+        for (index, (address, weight)) in nodes.iter().enumerate() {
+            // Single precision, like libmemcached, so the point counts agree
+            // where the share does not come out exact.
+            let share = *weight as f32 / total_weight.max(1) as f32;
+            let digests = (share * POINTS_PER_SERVER / 4.0 * nodes.len() as f32).floor() as usize;
+            let name = match address.rsplit_once(':') {
+                Some((host, DEFAULT_PORT)) => host,
+                _ => address.as_str(),
+            };
+            for n in 0..digests {
+                let digest = md5::compute(format!("{}-{}", name, n));
+                for chunk in digest.0.chunks(4) {
+                    points.push((
+                        u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+                        index,
+                    ));
+                }
+            }
+        }
+        points.sort_unstable();
+        HashRing {
+            nodes: nodes.iter().map(|(address, _)| address.clone()).collect(),
+            points,
+        }
+    }
+
+    /// The ketama hash of a key: the first four bytes of its MD5 digest.
+    pub fn hash(key: &[u8]) -> u32 {
+        let digest = md5::compute(key);
+        u32::from_le_bytes([digest.0[0], digest.0[1], digest.0[2], digest.0[3]])
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// Index into `nodes` of the server owning `key`, `None` on an empty ring.
+    pub fn node_index(&self, key: &[u8]) -> Option<usize> {
+        let hash = HashRing::hash(key);
+        let position = self.points.partition_point(|(point, _)| *point < hash);
+        self.points
+            .get(position)
+            .or_else(|| self.points.first())
+            .map(|(_, index)| *index)
+    }
+
+    pub fn node(&self, key: &[u8]) -> Option<&str> {
+        self.node_index(key).map(|index| self.nodes[index].as_str())
+    }
+
+    /// Indexes of the nodes in the order they follow `key` round the ring,
+    /// owner first: the order to fail over in when the owner is down.
+    pub fn successors(&self, key: &[u8]) -> Vec<usize> {
+        let hash = HashRing::hash(key);
+        let position = self.points.partition_point(|(point, _)| *point < hash);
+        let mut successors = Vec::with_capacity(self.nodes.len());
+        for (_, index) in self.points[position..]
+            .iter()
+            .chain(&self.points[..position])
+        {
+            if !successors.contains(index) {
+                successors.push(*index);
+                if successors.len() == self.nodes.len() {
+                    break;
+                }
+            }
+        }
+        successors
+    }
+}
+
+/// What a node does with a request for a key another node owns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrongNodePolicy {
+    /// Relay the request to the owner and pass its response back.
+    Forward,
+    /// Answer `NotMyVbucket` with the owner's address as the value.
+    Redirect,
+}
+
+impl FromStr for WrongNodePolicy {
+    type Err = io::Error;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "forward" => Ok(WrongNodePolicy::Forward),
+            "redirect" => Ok(WrongNodePolicy::Redirect),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown wrong node policy {:?}", policy),
+            )),
+        }
+    }
+}
+
+/// Static cluster membership: every node is given the same member list and
+/// maps keys to their owner with the same ring. Nodes forwarding to peers
+/// that require SASL authenticate with the peer credentials, and the user
+/// these name needs read and write access to every key.
+#[derive(Debug)]
+pub struct Cluster {
+    local: String,
+    ring: HashRing,
+    policy: WrongNodePolicy,
+    peer_credentials: Option<(String, String)>,
+}
+
+impl Cluster {
+    /// `local` is the address this node is listed under in `members`.
+    pub fn new(local: &str, members: &[(String, u32)], policy: WrongNodePolicy) -> Cluster {
+        Cluster {
+            local: local.to_string(),
+            ring: HashRing::new(members),
+            policy,
+            peer_credentials: None,
+        }
+    }
+
+    /// Authenticates to other members as `user`, and trusts forwarded
+    /// requests only from connections authenticated as it.
+    pub fn with_peer_credentials<U: Into<String>, P: Into<String>>(
+        mut self,
+        user: U,
+        password: P,
+    ) -> Self {
+        self.peer_credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    /// Loads a member file: one `host:port [weight]` per line, weight 1 if
+    /// left out.
+    pub fn load_members<P: AsRef<Path>>(path: P) -> io::Result<Vec<(String, u32)>> {
+        Cluster::parse_members(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse_members(contents: &str) -> io::Result<Vec<(String, u32)>> {
+        let mut members = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid cluster member on line {}", number + 1),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let address = fields.next().ok_or_else(invalid)?;
+            let weight = match fields.next() {
+                Some(weight) => weight.parse().map_err(|_| invalid())?,
+                None => 1,
+            };
+            if fields.next().is_some() {
+                return Err(invalid());
+            }
+            members.push((address.to_string(), weight));
+        }
+        Ok(members)
+    }
+
+    pub fn local(&self) -> &str {
+        &self.local
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    pub fn policy(&self) -> WrongNodePolicy {
+        self.policy
+    }
+
+    pub fn peer_credentials(&self) -> Option<(&str, &str)> {
+        self.peer_credentials
+            .as_ref()
+            .map(|(user, password)| (user.as_str(), password.as_str()))
+    }
+
+    /// Whether a connection authenticated as `user` belongs to another member.
+    pub fn is_peer(&self, user: Option<&str>) -> bool {
+        match (user, &self.peer_credentials) {
+            (Some(user), Some((peer, _))) => user == peer,
+            _ => false,
+        }
+    }
+
+    /// Address of the node owning `key`, or `None` if this node does.
+    pub fn remote_owner(&self, key: &[u8]) -> Option<&str> {
+        self.ring.node(key).filter(|owner| *owner != self.local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::auth::Authenticator;
+    use crate::memcached::handler::BinaryHandler;
+    use crate::memcached::server::TcpServer;
+    use crate::memcached::storage::{Record, Storage};
+    use crate::memcached::testing::{free_address, serve_at, silent_listener};
+    use crate::memcached::timer;
+    use crate::protocol::binary;
+    use crate::protocol::binary_codec::{
+        BinaryRequest, BinaryResponse, MemcachedBinaryClientCodec,
+    };
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    fn members(count: usize) -> Vec<(String, u32)> {
+        (0..count)
+            .map(|i| (format!("10.0.0.{}:11211", i + 1), 1))
+            .collect()
+    }
+
+    fn owners(ring: &HashRing, keys: usize) -> Vec<String> {
+        (0..keys)
+            .map(|i| {
+                ring.node(format!("key:{}", i).as_bytes())
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ring_follows_libketama() {
+        let ring = HashRing::new(&members(4));
+        assert_eq!(ring.points.len(), 4 * 160);
+        assert_eq!(HashRing::hash(b""), 0xd98c1dd4);
+        assert!(HashRing::new(&[]).node(b"key").is_none());
+    }
+
+    #[test]
+    fn ring_places_keys_like_libmemcached() {
+        let servers = Cluster::parse_members(
+            "10.0.1.1:11211 600\n10.0.1.2:11211 300\n10.0.1.3:11211 200\n10.0.1.4:11212 350\n",
+        )
+        .unwrap();
+        let ring = HashRing::new(&servers);
+        for (key, server) in [
+            ("apple", "10.0.1.1:11211"),
+            ("banana", "10.0.1.1:11211"),
+            ("cherry", "10.0.1.1:11211"),
+            ("user:1", "10.0.1.1:11211"),
+            ("user:2", "10.0.1.4:11212"),
+            ("session:42", "10.0.1.2:11211"),
+            ("", "10.0.1.2:11211"),
+            ("a", "10.0.1.3:11211"),
+            ("rustcache", "10.0.1.1:11211"),
+            ("memcached", "10.0.1.3:11211"),
+        ] {
+            assert_eq!(ring.node(key.as_bytes()), Some(server), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn adding_a_node_moves_only_its_share_of_keys() {
+        let before = owners(&HashRing::new(&members(4)), 10_000);
+        let after = owners(&HashRing::new(&members(5)), 10_000);
+        let moved: Vec<_> = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| before != after)
+            .collect();
+
+        assert!(moved.iter().all(|(_, after)| *after == "10.0.0.5:11211"));
+        assert!(
+            moved.len() > 1_000 && moved.len() < 3_000,
+            "{}",
+            moved.len()
+        );
+    }
+
+    #[test]
+    fn weights_skew_the_distribution() {
+        let mut weighted = members(2);
+        weighted[0].1 = 3;
+        let owners = owners(&HashRing::new(&weighted), 10_000);
+        let heavy = owners
+            .iter()
+            .filter(|owner| *owner == "10.0.0.1:11211")
+            .count();
+        assert!(heavy > 6_500 && heavy < 8_500, "{}", heavy);
+    }
+
+    #[test]
+    fn parse_member_file() {
+        let members = Cluster::parse_members("# members\nA:1\nB:2 3\n").unwrap();
+        assert_eq!(
+            members,
+            vec![("A:1".to_string(), 1), ("B:2".to_string(), 3)]
+        );
+        assert!(Cluster::parse_members("A:1 heavy\n").is_err());
+
+        let cluster = Cluster::new("A:1", &members, WrongNodePolicy::Redirect);
+        let remote = (0..100)
+            .map(|i| format!("key:{}", i))
+            .find(|key| cluster.remote_owner(key.as_bytes()).is_some())
+            .unwrap();
+        assert_eq!(cluster.remote_owner(remote.as_bytes()), Some("B:2"));
+    }
+
+    fn set_request(key: &str) -> BinaryRequest {
+        BinaryRequest::Set(binary::SetRequest {
+            header: binary::RequestHeader {
+                opcode: binary::Command::Set as u8,
+                opaque: 42,
+                ..binary::RequestHeader::default()
+            },
+            flags: 0,
+            expiration: 0,
+            grace: 0,
+            recompute_ms: 0,
+            key: key.as_bytes().to_vec(),
+            value: b"v".to_vec(),
+        })
+    }
+
+    /// A key `members[1]` owns.
+    fn remote_key(members: &[(String, u32)]) -> String {
+        let ring = HashRing::new(members);
+        (0..)
+            .map(|i| format!("key:{}", i))
+            .find(|key| ring.node(key.as_bytes()) == Some(members[1].0.as_str()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn redirect_names_the_owner() {
+        let members = Cluster::parse_members("A:1\nB:2\n").unwrap();
+        let cluster = Cluster::new("A:1", &members, WrongNodePolicy::Redirect);
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let storage = Arc::new(Storage::new(timer));
+        let mut handler = BinaryHandler::new(storage.clone()).with_cluster(Arc::new(cluster));
+
+        match handler
+            .handle_request(set_request(&remote_key(&members)))
+            .await
+        {
+            Some(BinaryResponse::NotMyVbucket(response)) => {
+                assert_eq!(response.header.opaque, 42);
+                assert_eq!(response.value, b"B:2");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(storage.is_empty());
+    }
+
+    /// Starts a forwarding cluster of `count` nodes, each a server made by
+    /// `server` for its cluster view, returning their member list, their
+    /// stores and a connection to the first one.
+    async fn start_cluster_with<F: Fn(Cluster) -> TcpServer>(
+        count: usize,
+        server: F,
+    ) -> (
+        Vec<(String, u32)>,
+        Vec<Arc<Storage>>,
+        Framed<TcpStream, MemcachedBinaryClientCodec>,
+    ) {
+        let members: Vec<(String, u32)> = (0..count).map(|_| (free_address(), 1)).collect();
+        let mut storages = Vec::new();
+        for (addr, _) in &members {
+            let server = server(Cluster::new(addr, &members, WrongNodePolicy::Forward));
+            storages.push(serve_at(addr, server).await);
+        }
+        let socket = TcpStream::connect(&members[0].0).await.unwrap();
+        let connection = Framed::new(socket, MemcachedBinaryClientCodec::new());
+        (members, storages, connection)
+    }
+
+    async fn start_cluster(
+        count: usize,
+    ) -> (
+        Vec<(String, u32)>,
+        Vec<Arc<Storage>>,
+        Framed<TcpStream, MemcachedBinaryClientCodec>,
+    ) {
+        start_cluster_with(count, |cluster| TcpServer::new().with_cluster(cluster)).await
+    }
+
+    #[tokio::test]
+    async fn requests_are_forwarded_to_the_owner() {
+        let (members, storages, mut connection) = start_cluster(2).await;
+        let key = remote_key(&members);
+        connection.send(set_request(&key)).await.unwrap();
+        match connection.next().await {
+            Some(Ok(BinaryResponse::Set(response))) => assert_eq!(response.header.opaque, 42),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(storages[0].is_empty());
+        assert!(storages[1].get(&key.into_bytes()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn clients_cannot_mark_requests_forwarded() {
+        let (members, storages, mut connection) = start_cluster_with(2, |cluster| {
+            let authenticator = Authenticator::parse("peer:secret\nalice:pw\n").unwrap();
+            TcpServer::new()
+                .with_authenticator(authenticator)
+                .with_cluster(cluster.with_peer_credentials("peer", "secret"))
+        })
+        .await;
+        let login = BinaryRequest::SaslAuth(binary::SaslAuthRequest {
+            header: binary::RequestHeader {
+                opcode: binary::Command::SaslAuth as u8,
+                ..binary::RequestHeader::default()
+            },
+            key: b"PLAIN".to_vec(),
+            value: b"\0alice\0pw".to_vec(),
+        });
+        connection.send(login).await.unwrap();
+        connection.next().await.unwrap().unwrap();
+
+        let key = remote_key(&members);
+        let mut request = set_request(&key);
+        request.get_header_mut().reserved = FORWARDED_VBUCKET;
+        connection.send(request).await.unwrap();
+        match connection.next().await {
+            Some(Ok(BinaryResponse::Set(response))) => assert_eq!(response.header.status, 0),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(storages[0].is_empty());
+        assert!(storages[1].get(&key.into_bytes()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn unresponsive_owners_time_out() {
+        let silent = silent_listener();
+        let members = vec![
+            (free_address(), 1),
+            (silent.local_addr().unwrap().to_string(), 1),
+        ];
+        let cluster = Cluster::new(&members[0].0, &members, WrongNodePolicy::Forward);
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let storage = Arc::new(Storage::new(timer));
+        let mut handler = BinaryHandler::new(storage).with_cluster(Arc::new(cluster));
+
+        let response = handler.handle_request(set_request(&remote_key(&members)));
+        match tokio::time::timeout(Duration::from_secs(5), response).await {
+            Ok(Some(BinaryResponse::Error(response))) => assert_eq!(
+                response.header.status,
+                binary::ResponseStatus::InternalError as u16
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn tag_invalidations_reach_every_member() {
+        let (_, storages, mut connection) = start_cluster(3).await;
+        for storage in &storages {
+            let record = Record::new(b"v".to_vec(), 0, 0, 0).with_tags(vec![b"user:1".to_vec()]);
+            storage.set(b"profile".to_vec(), record).unwrap();
+        }
+
+        let request = BinaryRequest::InvalidateTag(binary::InvalidateTagRequest {
+            header: binary::RequestHeader {
+                opcode: binary::Command::InvalidateTag as u8,
+                ..binary::RequestHeader::default()
+            },
+            key: b"user:1".to_vec(),
+        });
+        connection.send(request).await.unwrap();
+        match connection.next().await {
+            Some(Ok(BinaryResponse::InvalidateTag(response))) => {
+                assert_eq!(response.header.status, 0)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        for storage in &storages {
+            assert!(storage.get(&b"profile".to_vec()).is_err());
+        }
+    }
+}
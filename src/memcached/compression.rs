@@ -0,0 +1,227 @@
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memcached::storage::Record;
+
+/// How a stored value was compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Lz4,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Lz4,
+    /// Zstandard at the given level.
+    Zstd(i32),
+}
+
+impl FromStr for Algorithm {
+    type Err = io::Error;
+
+    /// Parses `lz4`, `zstd` or `zstd:<level>`, the level being 3 if left out.
+    fn from_str(algorithm: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown compression {:?}", algorithm),
+            )
+        };
+        match algorithm.split_once(':') {
+            None if algorithm == "lz4" => Ok(Algorithm::Lz4),
+            None if algorithm == "zstd" => Ok(Algorithm::Zstd(3)),
+            Some(("zstd", level)) => match level.parse() {
+                Ok(level) if zstd::compression_level_range().contains(&level) => {
+                    Ok(Algorithm::Zstd(level))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionConfig {
+    pub algorithm: Algorithm,
+    /// Values smaller than this are stored as they are.
+    pub min_size: usize,
+}
+
+impl CompressionConfig {
+    pub fn new(algorithm: Algorithm) -> CompressionConfig {
+        CompressionConfig {
+            algorithm,
+            min_size: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CompressionStats {
+    compressed: AtomicU64,
+    incompressible: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    decompressed: AtomicU64,
+}
+
+/// Compresses values as they are stored, so that both memory and the quota
+/// charged for them shrink. Values that do not get smaller are kept as they
+/// are. Records remember how they were compressed and are decompressed on
+/// every read, so clients, logs and snapshots only ever see the original.
+pub struct Compressor {
+    config: CompressionConfig,
+    stats: CompressionStats,
+}
+
+impl Compressor {
+    pub fn new(config: CompressionConfig) -> Compressor {
+        Compressor {
+            config,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &CompressionConfig {
+        &self.config
+    }
+
+    /// Compresses the value of a record about to be stored, if it is large
+    /// enough and compresses at all.
+    pub(crate) fn compress(&self, mut record: Record) -> Record {
+        if record.value.len() < self.config.min_size || record.header.compression.is_some() {
+            return record;
+        }
+        let (method, compressed) = match self.config.algorithm {
+            Algorithm::Lz4 => (Method::Lz4, lz4_flex::compress_prepend_size(&record.value)),
+            Algorithm::Zstd(level) => match zstd::bulk::compress(&record.value, level) {
+                Ok(compressed) => (Method::Zstd, compressed),
+                Err(err) => {
+                    error!("zstd compression failed: {}", err);
+                    return record;
+                }
+            },
+        };
+        if compressed.len() >= record.value.len() {
+            self.stats.incompressible.fetch_add(1, Ordering::Relaxed);
+            return record;
+        }
+        self.stats.compressed.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_in
+            .fetch_add(record.value.len() as u64, Ordering::Relaxed);
+        self.stats
+            .bytes_out
+            .fetch_add(compressed.len() as u64, Ordering::Relaxed);
+        record.value = compressed;
+        record.header.compression = Some(method);
+        record
+    }
+
+    pub(crate) fn count_decompressed(&self) {
+        self.stats.decompressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters as `(name, value)` pairs. The ratio is that of the original
+    /// to the compressed size over every value compressed so far.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (bytes_in, bytes_out) = (load(&self.stats.bytes_in), load(&self.stats.bytes_out));
+        let ratio = match bytes_out {
+            0 => 1.0,
+            bytes_out => bytes_in as f64 / bytes_out as f64,
+        };
+        let algorithm = match self.config.algorithm {
+            Algorithm::Lz4 => "lz4".to_string(),
+            Algorithm::Zstd(level) => format!("zstd:{}", level),
+        };
+        vec![
+            ("compression_algorithm".to_string(), algorithm),
+            (
+                "compression_min_size".to_string(),
+                self.config.min_size.to_string(),
+            ),
+            (
+                "compressed_items".to_string(),
+                load(&self.stats.compressed).to_string(),
+            ),
+            (
+                "incompressible_items".to_string(),
+                load(&self.stats.incompressible).to_string(),
+            ),
+            (
+                "decompressed_items".to_string(),
+                load(&self.stats.decompressed).to_string(),
+            ),
+            ("compression_bytes_in".to_string(), bytes_in.to_string()),
+            ("compression_bytes_out".to_string(), bytes_out.to_string()),
+            ("compression_ratio".to_string(), format!("{:.2}", ratio)),
+        ]
+    }
+}
+
+/// Restores the original value of a record stored compressed.
+pub(crate) fn decompress(mut record: Record) -> io::Result<Record> {
+    let method = match record.header.compression {
+        Some(method) => method,
+        None => return Ok(record),
+    };
+    record.value = match method {
+        Method::Lz4 => lz4_flex::decompress_size_prepended(&record.value)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        Method::Zstd => zstd::decode_all(&record.value[..])?,
+    };
+    record.header.compression = None;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    fn json(len: usize) -> Vec<u8> {
+        let mut value = b"[".to_vec();
+        while value.len() < len {
+            value.extend_from_slice(br#"{"name":"alice","role":"admin"},"#);
+        }
+        value
+    }
+
+    #[test]
+    fn parse_algorithm() {
+        assert_eq!("lz4".parse::<Algorithm>().unwrap(), Algorithm::Lz4);
+        assert_eq!("zstd".parse::<Algorithm>().unwrap(), Algorithm::Zstd(3));
+        assert_eq!("zstd:19".parse::<Algorithm>().unwrap(), Algorithm::Zstd(19));
+        assert!("zstd:99".parse::<Algorithm>().is_err());
+        assert!("gzip".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn round_trip_only_what_shrinks() {
+        for algorithm in [Algorithm::Lz4, Algorithm::Zstd(3)] {
+            let compressor = Compressor::new(CompressionConfig::new(algorithm));
+            let record = compressor.compress(Record::new(json(4096), 0, 0, 0));
+            assert!(record.header.compression.is_some());
+            assert!(record.value.len() < 1024);
+            assert_eq!(decompress(record).unwrap().value, json(4096));
+
+            let small = compressor.compress(Record::new(json(100), 0, 0, 0));
+            assert!(small.header.compression.is_none());
+            let mut random = vec![0; 4096];
+            SmallRng::seed_from_u64(1).fill(&mut random[..]);
+            let incompressible = compressor.compress(Record::new(random.clone(), 0, 0, 0));
+            assert!(incompressible.header.compression.is_none());
+            assert_eq!(incompressible.value, random);
+
+            let stats = compressor.stats();
+            assert!(stats.contains(&("compressed_items".to_string(), "1".to_string())));
+            let ratio = &stats.iter().find(|(name, _)| name == "compression_ratio");
+            assert!(ratio.unwrap().1.parse::<f64>().unwrap() > 4.0);
+        }
+    }
+}
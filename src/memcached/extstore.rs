@@ -0,0 +1,658 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::memcached::storage::Storage;
+
+/// Size of the fixed part of an entry: namespace, key and value lengths.
+const ENTRY_HEADER_LEN: usize = 8;
+
+/// Values waiting for the writer thread; once this many are queued, further
+/// victims are evicted outright.
+const WRITE_QUEUE_LEN: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct ExtStoreConfig {
+    pub path: PathBuf,
+    pub page_size: usize,
+    pub page_count: usize,
+    /// Values smaller than this are evicted outright instead of moved to flash.
+    pub item_min_size: usize,
+    /// Sealed pages whose live fraction drops below this get compacted.
+    pub compact_ratio: f64,
+}
+
+impl ExtStoreConfig {
+    pub fn new<P: Into<PathBuf>>(path: P) -> ExtStoreConfig {
+        ExtStoreConfig {
+            path: path.into(),
+            page_size: 1 << 20,
+            page_count: 64,
+            item_min_size: 512,
+            compact_ratio: 0.5,
+        }
+    }
+}
+
+/// Where an external value lives. A location is only valid while its page
+/// still has the version it was written with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtLocation {
+    page: u32,
+    offset: u32,
+    len: u32,
+    version: u64,
+}
+
+/// A value evicted from memory on its way to flash.
+pub(crate) struct PendingWrite {
+    pub namespace: String,
+    pub key: Vec<u8>,
+    /// LRU sequence number the item had when it was picked.
+    pub seq: u64,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Page {
+    version: u64,
+    used: usize,
+    live: usize,
+    /// Order in which the page was sealed; `None` while open or free.
+    sealed: Option<u64>,
+}
+
+struct OpenPage {
+    index: u32,
+    buffer: Vec<u8>,
+}
+
+struct State {
+    pages: Vec<Page>,
+    free: Vec<u32>,
+    open: Option<OpenPage>,
+    seal_clock: u64,
+}
+
+#[derive(Debug, Default)]
+struct ExtStoreStats {
+    items_written: AtomicU64,
+    bytes_written: AtomicU64,
+    user_bytes_written: AtomicU64,
+    items_read: AtomicU64,
+    bytes_read: AtomicU64,
+    value_bytes_read: AtomicU64,
+    stale_reads: AtomicU64,
+    pages_compacted: AtomicU64,
+    pages_reclaimed: AtomicU64,
+    items_rewritten: AtomicU64,
+}
+
+/// External storage tier for values that age out of memory.
+///
+/// The backing file is split into fixed size pages. Values are appended to
+/// the open page, which is buffered in memory and written out in one go once
+/// full. Only an `ExtLocation` stays in the item header. Once started, values
+/// are written by a thread of their own rather than by the request that
+/// evicted them. Overwritten or deleted values leave dead bytes behind;
+/// sealed pages that are mostly dead are compacted by rewriting their live
+/// values to the open page, and when no page is free the oldest one is
+/// reclaimed, dropping whatever it still holds.
+pub struct ExtStore {
+    config: ExtStoreConfig,
+    file: File,
+    state: Mutex<State>,
+    writer: OnceLock<SyncSender<PendingWrite>>,
+    stats: ExtStoreStats,
+}
+
+impl ExtStore {
+    pub fn open(config: ExtStoreConfig) -> io::Result<ExtStore> {
+        if config.page_count == 0 || config.page_size <= ENTRY_HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "extstore needs at least one page",
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&config.path)?;
+        file.set_len((config.page_size * config.page_count) as u64)?;
+        let pages = (0..config.page_count).map(|_| Page::default()).collect();
+        let free = (0..config.page_count as u32).rev().collect();
+        Ok(ExtStore {
+            config,
+            file,
+            state: Mutex::new(State {
+                pages,
+                free,
+                open: None,
+                seal_clock: 0,
+            }),
+            writer: OnceLock::new(),
+            stats: Default::default(),
+        })
+    }
+
+    pub fn item_min_size(&self) -> usize {
+        self.config.item_min_size
+    }
+
+    /// Spawns the thread that writes evicted values out and the one that
+    /// compacts sparse pages every second.
+    pub fn start(self: &Arc<Self>, storage: &Arc<Storage>) {
+        let (sender, receiver) = mpsc::sync_channel::<PendingWrite>(WRITE_QUEUE_LEN);
+        if self.writer.set(sender).is_err() {
+            return;
+        }
+        // The writer holds on to neither the store nor us, so that it stops
+        // once both are dropped.
+        let writes: Weak<Storage> = Arc::downgrade(storage);
+        thread::spawn(move || {
+            for pending in receiver {
+                let storage = match writes.upgrade() {
+                    Some(storage) => storage,
+                    None => return,
+                };
+                let (extstore, namespace) = match (
+                    storage.extstore(),
+                    storage.namespace_by_name(&pending.namespace),
+                ) {
+                    (Some(extstore), Some(namespace)) => (extstore, namespace),
+                    _ => continue,
+                };
+                let location = extstore.write(&pending.namespace, &pending.key, &pending.value);
+                namespace.finish_externalize(
+                    &pending.key,
+                    pending.seq,
+                    pending.value.len(),
+                    location,
+                    true,
+                );
+            }
+        });
+
+        let extstore = self.clone();
+        let storage: Weak<Storage> = Arc::downgrade(storage);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            let storage = match storage.upgrade() {
+                Some(storage) => storage,
+                None => return,
+            };
+            if let Err(err) = extstore.compact(&storage) {
+                error!("extstore compaction failed: {}", err);
+            }
+        });
+    }
+
+    fn encode(namespace: &str, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0; ENTRY_HEADER_LEN];
+        BigEndian::write_u16(&mut entry[0..2], namespace.len() as u16);
+        BigEndian::write_u16(&mut entry[2..4], key.len() as u16);
+        BigEndian::write_u32(&mut entry[4..8], value.len() as u32);
+        entry.extend_from_slice(namespace.as_bytes());
+        entry.extend_from_slice(key);
+        entry.extend_from_slice(value);
+        entry
+    }
+
+    /// Splits an entry into namespace, key and value.
+    fn decode(entry: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+        if entry.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let namespace_len = BigEndian::read_u16(&entry[0..2]) as usize;
+        let key_len = BigEndian::read_u16(&entry[2..4]) as usize;
+        let value_len = BigEndian::read_u32(&entry[4..8]) as usize;
+        let body = &entry[ENTRY_HEADER_LEN..];
+        if body.len() < namespace_len + key_len + value_len {
+            return None;
+        }
+        let (namespace, body) = body.split_at(namespace_len);
+        let (key, body) = body.split_at(key_len);
+        Some((namespace, key, &body[..value_len]))
+    }
+
+    /// Whether values are written by the writer thread rather than the caller.
+    pub(crate) fn has_writer(&self) -> bool {
+        self.writer.get().is_some()
+    }
+
+    /// Hands a value to the writer thread; false if its queue is full.
+    pub(crate) fn queue_write(&self, pending: PendingWrite) -> bool {
+        match self.writer.get() {
+            Some(writer) => writer.try_send(pending).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Moves a value out of memory. Returns `None` if it does not fit a page.
+    pub(crate) fn write(
+        &self,
+        namespace: &str,
+        key: &[u8],
+        value: &[u8],
+    ) -> io::Result<Option<ExtLocation>> {
+        let location = self.write_entry(&ExtStore::encode(namespace, key, value))?;
+        if location.is_some() {
+            self.stats
+                .user_bytes_written
+                .fetch_add(value.len() as u64, Ordering::Relaxed);
+        }
+        Ok(location)
+    }
+
+    fn write_entry(&self, entry: &[u8]) -> io::Result<Option<ExtLocation>> {
+        if entry.len() > self.config.page_size {
+            return Ok(None);
+        }
+        let mut state = self.state.lock().unwrap();
+        let fits = match &state.open {
+            Some(open) => open.buffer.len() + entry.len() <= self.config.page_size,
+            None => false,
+        };
+        if !fits {
+            self.seal(&mut state)?;
+            let index = match state.free.pop() {
+                Some(index) => index,
+                None => self.reclaim_oldest(&mut state),
+            };
+            state.open = Some(OpenPage {
+                index,
+                buffer: Vec::with_capacity(self.config.page_size),
+            });
+        }
+        let open = state.open.as_mut().unwrap();
+        let index = open.index;
+        let offset = open.buffer.len();
+        open.buffer.extend_from_slice(entry);
+        let page = &mut state.pages[index as usize];
+        page.used += entry.len();
+        page.live += entry.len();
+        self.stats.items_written.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(ExtLocation {
+            page: index,
+            offset: offset as u32,
+            len: entry.len() as u32,
+            version: page.version,
+        }))
+    }
+
+    fn seal(&self, state: &mut State) -> io::Result<()> {
+        if let Some(open) = state.open.take() {
+            let position = open.index as u64 * self.config.page_size as u64;
+            self.file.write_all_at(&open.buffer, position)?;
+            self.stats
+                .bytes_written
+                .fetch_add(open.buffer.len() as u64, Ordering::Relaxed);
+            state.seal_clock += 1;
+            state.pages[open.index as usize].sealed = Some(state.seal_clock);
+        }
+        Ok(())
+    }
+
+    /// Frees the page sealed longest ago; its values are lost.
+    fn reclaim_oldest(&self, state: &mut State) -> u32 {
+        let index = state
+            .pages
+            .iter()
+            .enumerate()
+            .filter_map(|(index, page)| page.sealed.map(|sealed| (sealed, index)))
+            .min()
+            .map(|(_, index)| index)
+            .expect("every page is sealed once none is free or open");
+        ExtStore::reset(&mut state.pages[index]);
+        self.stats.pages_reclaimed.fetch_add(1, Ordering::Relaxed);
+        index as u32
+    }
+
+    fn reset(page: &mut Page) {
+        page.version += 1;
+        page.used = 0;
+        page.live = 0;
+        page.sealed = None;
+    }
+
+    fn read_entry(&self, location: ExtLocation) -> io::Result<Option<Vec<u8>>> {
+        let (offset, len) = (location.offset as usize, location.len as usize);
+        {
+            let state = self.state.lock().unwrap();
+            if state.pages[location.page as usize].version != location.version {
+                return Ok(None);
+            }
+            if let Some(open) = &state.open {
+                if open.index == location.page {
+                    return Ok(open.buffer.get(offset..offset + len).map(<[u8]>::to_vec));
+                }
+            }
+        }
+        let mut entry = vec![0; len];
+        let position = location.page as u64 * self.config.page_size as u64 + offset as u64;
+        self.file.read_exact_at(&mut entry, position)?;
+        self.stats
+            .bytes_read
+            .fetch_add(len as u64, Ordering::Relaxed);
+        // The page may have been reclaimed and rewritten while we were reading.
+        let state = self.state.lock().unwrap();
+        if state.pages[location.page as usize].version != location.version {
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+
+    /// Reads a value back; `None` if its page has been reclaimed since.
+    pub(crate) fn read(&self, location: ExtLocation, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let entry = self.read_entry(location)?;
+        let value = match entry.as_deref().and_then(ExtStore::decode) {
+            Some((_, entry_key, value)) if entry_key == key => Some(value.to_vec()),
+            _ => None,
+        };
+        match &value {
+            Some(value) => {
+                self.stats.items_read.fetch_add(1, Ordering::Relaxed);
+                self.stats
+                    .value_bytes_read
+                    .fetch_add(value.len() as u64, Ordering::Relaxed);
+            }
+            None => {
+                self.stats.stale_reads.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Reads a value back on the blocking thread pool.
+    pub async fn read_async(
+        self: Arc<Self>,
+        location: ExtLocation,
+        key: Vec<u8>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        tokio::task::spawn_blocking(move || self.read(location, &key))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Marks the value at `location` dead once its item is gone.
+    pub(crate) fn release(&self, location: ExtLocation) {
+        let mut state = self.state.lock().unwrap();
+        let page = &mut state.pages[location.page as usize];
+        if page.version == location.version {
+            page.live = page.live.saturating_sub(location.len as usize);
+        }
+    }
+
+    /// Rewrites the live values of sparse sealed pages and frees those pages.
+    /// Returns the number of pages freed.
+    pub fn compact(&self, storage: &Storage) -> io::Result<usize> {
+        let candidates: Vec<(u32, u64, usize)> = {
+            let state = self.state.lock().unwrap();
+            state
+                .pages
+                .iter()
+                .enumerate()
+                .filter(|(_, page)| {
+                    page.sealed.is_some()
+                        && (page.live as f64) < (page.used as f64) * self.config.compact_ratio
+                })
+                .map(|(index, page)| (index as u32, page.version, page.used))
+                .collect()
+        };
+        let mut freed = 0;
+        for (index, version, used) in candidates {
+            let mut page = vec![0; used];
+            let position = index as u64 * self.config.page_size as u64;
+            self.file.read_exact_at(&mut page, position)?;
+            self.stats
+                .bytes_read
+                .fetch_add(used as u64, Ordering::Relaxed);
+
+            let mut offset = 0;
+            while let Some((namespace, key, value)) = ExtStore::decode(&page[offset..]) {
+                let len = ENTRY_HEADER_LEN + namespace.len() + key.len() + value.len();
+                let old = ExtLocation {
+                    page: index,
+                    offset: offset as u32,
+                    len: len as u32,
+                    version,
+                };
+                offset += len;
+                let namespace = match std::str::from_utf8(namespace)
+                    .ok()
+                    .and_then(|name| storage.namespace_by_name(name))
+                {
+                    Some(namespace) => namespace,
+                    None => continue,
+                };
+                if !namespace.is_external_at(key, old) {
+                    continue;
+                }
+                let entry = &page[old.offset as usize..offset];
+                if let Some(new) = self.write_entry(entry)? {
+                    if namespace.relocate_external(key, old, new) {
+                        self.stats.items_rewritten.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        self.release(new);
+                    }
+                }
+            }
+
+            let mut state = self.state.lock().unwrap();
+            let page = &mut state.pages[index as usize];
+            if page.version == version {
+                ExtStore::reset(page);
+                state.free.push(index);
+                self.stats.pages_compacted.fetch_add(1, Ordering::Relaxed);
+                freed += 1;
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Counters as `(name, value)` pairs, including the amplification ratios:
+    /// flash bytes written per value byte moved out of memory, and flash
+    /// bytes read per value byte served.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let ratio = |numerator: u64, denominator: u64| match denominator {
+            0 => 0.0,
+            denominator => numerator as f64 / denominator as f64,
+        };
+        let (free_pages, live_bytes) = {
+            let state = self.state.lock().unwrap();
+            let live = state.pages.iter().map(|page| page.live).sum::<usize>();
+            (state.free.len(), live)
+        };
+        let stats = &self.stats;
+        vec![
+            ("extstore_page_size", self.config.page_size.to_string()),
+            ("extstore_page_count", self.config.page_count.to_string()),
+            ("extstore_pages_free", free_pages.to_string()),
+            ("extstore_live_bytes", live_bytes.to_string()),
+            (
+                "extstore_items_written",
+                load(&stats.items_written).to_string(),
+            ),
+            (
+                "extstore_bytes_written",
+                load(&stats.bytes_written).to_string(),
+            ),
+            (
+                "extstore_user_bytes_written",
+                load(&stats.user_bytes_written).to_string(),
+            ),
+            ("extstore_items_read", load(&stats.items_read).to_string()),
+            ("extstore_bytes_read", load(&stats.bytes_read).to_string()),
+            ("extstore_stale_reads", load(&stats.stale_reads).to_string()),
+            (
+                "extstore_pages_compacted",
+                load(&stats.pages_compacted).to_string(),
+            ),
+            (
+                "extstore_pages_reclaimed",
+                load(&stats.pages_reclaimed).to_string(),
+            ),
+            (
+                "extstore_items_rewritten",
+                load(&stats.items_rewritten).to_string(),
+            ),
+            (
+                "extstore_write_amplification",
+                format!(
+                    "{:.2}",
+                    ratio(load(&stats.bytes_written), load(&stats.user_bytes_written))
+                ),
+            ),
+            (
+                "extstore_read_amplification",
+                format!(
+                    "{:.2}",
+                    ratio(load(&stats.bytes_read), load(&stats.value_bytes_read))
+                ),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::error::StorageError;
+    use crate::memcached::namespace::NamespaceConfig;
+    use crate::memcached::storage::Record;
+    use crate::memcached::timer;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path =
+                std::env::temp_dir().join(format!("rustcache-{}-{}", name, std::process::id()));
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Pages hold four of the 100 byte values `fill` writes.
+    fn create_storage(file: &TempFile, page_count: usize, memory_limit: usize) -> Storage {
+        let mut config = ExtStoreConfig::new(&file.0);
+        config.page_size = 512;
+        config.page_count = page_count;
+        config.item_min_size = 64;
+        let extstore = Arc::new(ExtStore::open(config).unwrap());
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let configs = vec![NamespaceConfig::new("default", memory_limit)];
+        Storage::with_namespaces(timer, configs).with_extstore(extstore)
+    }
+
+    fn fill(storage: &Storage, count: u8) {
+        for i in 0..count {
+            let record = Record::new(vec![i; 100], 0, 0, 0);
+            storage.set(vec![b'k', i], record).unwrap();
+        }
+    }
+
+    fn stat(storage: &Storage, name: &str) -> String {
+        let stats = storage.extstore().unwrap().stats();
+        stats.into_iter().find(|(stat, _)| stat == name).unwrap().1
+    }
+
+    #[tokio::test]
+    async fn evicted_values_are_read_back_from_flash() {
+        let file = TempFile::new("extstore-read");
+        let storage = create_storage(&file, 4, 1000);
+        fill(&storage, 10);
+
+        let namespace = &storage.namespaces()[0];
+        assert_eq!(namespace.len(), 10);
+        assert!(namespace.used_memory() <= 1000);
+        for i in 0..10 {
+            assert_eq!(storage.get(&vec![b'k', i]).unwrap().value, vec![i; 100]);
+        }
+
+        let key = vec![b'k', 0];
+        let raw = namespace.get_raw(&key).unwrap();
+        assert!(raw.header.ext.is_some() && raw.value.is_empty());
+        let loaded = namespace.load_external(&key, raw).await.unwrap();
+        assert_eq!(loaded.value, vec![0; 100]);
+        assert_eq!(stat(&storage, "extstore_user_bytes_written"), "500");
+        assert_eq!(stat(&storage, "extstore_items_read"), "6");
+    }
+
+    #[tokio::test]
+    async fn started_store_writes_values_on_its_own_thread() {
+        let file = TempFile::new("extstore-writer");
+        let storage = Arc::new(create_storage(&file, 4, 1000));
+        storage.extstore().unwrap().start(&storage);
+        fill(&storage, 10);
+
+        let namespace = &storage.namespaces()[0];
+        for _ in 0..100 {
+            if namespace.used_memory() <= 1000 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(namespace.used_memory() <= 1000);
+        for i in 0..10 {
+            assert_eq!(storage.get(&vec![b'k', i]).unwrap().value, vec![i; 100]);
+        }
+
+        let key = vec![b'k', 0];
+        assert!(namespace.get_raw(&key).unwrap().header.ext.is_some());
+        namespace.load_back(&key).await;
+        let raw = namespace.get_raw(&key).unwrap();
+        assert!(raw.header.ext.is_none());
+        assert_eq!(raw.value, vec![0; 100]);
+    }
+
+    #[test]
+    fn sparse_pages_are_compacted() {
+        let file = TempFile::new("extstore-compact");
+        let storage = create_storage(&file, 4, 1000);
+        fill(&storage, 10);
+        for i in 0..3 {
+            storage.delete(&vec![b'k', i], 0).unwrap();
+        }
+
+        let extstore = storage.extstore().unwrap();
+        assert_eq!(extstore.compact(&storage).unwrap(), 1);
+        assert_eq!(stat(&storage, "extstore_items_rewritten"), "1");
+        assert_eq!(storage.get(&vec![b'k', 3]).unwrap().value, vec![3; 100]);
+        assert_eq!(extstore.compact(&storage).unwrap(), 0);
+    }
+
+    #[test]
+    fn oldest_page_is_reclaimed_when_flash_is_full() {
+        let file = TempFile::new("extstore-reclaim");
+        let storage = create_storage(&file, 2, 2000);
+        fill(&storage, 20);
+
+        assert_eq!(stat(&storage, "extstore_pages_reclaimed"), "1");
+        assert_eq!(
+            storage.get(&vec![b'k', 0]).unwrap_err(),
+            StorageError::NotFound
+        );
+        assert_eq!(storage.namespaces()[0].len(), 19);
+        assert_eq!(storage.get(&vec![b'k', 9]).unwrap().value, vec![9; 100]);
+        assert_eq!(storage.get(&vec![b'k', 19]).unwrap().value, vec![19; 100]);
+    }
+}
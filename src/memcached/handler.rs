@@ -1,17 +1,280 @@
-use crate::memcached::storage;
+use crate::memcached::error::StorageError;
+use crate::memcached::{
+    acl, auth, cluster, hotkeys, latency, lease, replication, scan, slowlog, snapshot, stats,
+    storage, watch,
+};
 use crate::protocol::{binary, binary_codec};
+use futures_util::{SinkExt, StreamExt};
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+type PeerConnection = Framed<TcpStream, binary_codec::MemcachedBinaryClientCodec>;
+
+/// How long connecting to a cluster peer, or sending it a request or reading
+/// its answer, may take before the peer counts as unreachable.
+const PEER_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct BinaryHandler {
     storage: Arc<storage::Storage>,
+    stats: Arc<stats::Stats>,
+    authenticator: Option<Arc<auth::Authenticator>>,
+    acl: Option<Arc<acl::Acl>>,
+    snapshot: Option<Arc<snapshot::Snapshot>>,
+    replication_log: Option<Arc<replication::ReplicationLog>>,
+    replica: Option<Arc<replication::Replica>>,
+    read_only: bool,
+    cluster: Option<Arc<cluster::Cluster>>,
+    hot_keys: Option<Arc<hotkeys::HotKeys>>,
+    latencies: Option<Arc<latency::Latencies>>,
+    slow_log: Option<Arc<slowlog::SlowLog>>,
+    /// Connections to the cluster peers requests were forwarded to.
+    peers: HashMap<String, PeerConnection>,
+    user: Option<String>,
+    /// The subscription of a watch request, with its opaque, until the
+    /// connection takes it over.
+    watch: Option<(u32, watch::Watch)>,
 }
 
 impl BinaryHandler {
     pub fn new(store: Arc<storage::Storage>) -> BinaryHandler {
-        BinaryHandler { storage: store }
+        BinaryHandler {
+            storage: store,
+            stats: Arc::new(stats::Stats::new()),
+            authenticator: None,
+            acl: None,
+            snapshot: None,
+            replication_log: None,
+            replica: None,
+            read_only: false,
+            cluster: None,
+            hot_keys: None,
+            latencies: None,
+            slow_log: None,
+            peers: HashMap::new(),
+            user: None,
+            watch: None,
+        }
+    }
+
+    /// Requires every connection to authenticate through SASL before it may
+    /// issue anything but SASL commands.
+    pub fn with_authenticator(mut self, authenticator: Arc<auth::Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<stats::Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Restricts authenticated users to the key prefixes granted by `acl`.
+    pub fn with_acl(mut self, acl: Arc<acl::Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Lets clients save a snapshot of the store on demand.
+    pub fn with_snapshot(mut self, snapshot: Arc<snapshot::Snapshot>) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Reports the state of the replicas fed by `log` in the stats.
+    pub fn with_replication_log(mut self, log: Arc<replication::ReplicationLog>) -> Self {
+        self.replication_log = Some(log);
+        self
+    }
+
+    /// Reports the state of the link to the primary in the stats.
+    pub fn with_replica(mut self, replica: Arc<replication::Replica>) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// Rejects every command that would modify the store.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sends requests for keys other cluster members own to them, or tells
+    /// the client where to go, as the cluster's policy says.
+    pub fn with_cluster(mut self, cluster: Arc<cluster::Cluster>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Feeds a sample of the gets and sets to `hot_keys`.
+    pub fn with_hot_keys(mut self, hot_keys: Arc<hotkeys::HotKeys>) -> Self {
+        self.hot_keys = Some(hot_keys);
+        self
+    }
+
+    /// Reports the command latencies the connection loop records in the
+    /// stats.
+    pub fn with_latencies(mut self, latencies: Arc<latency::Latencies>) -> Self {
+        self.latencies = Some(latencies);
+        self
+    }
+
+    /// Lets clients list the slow requests the connection loop logs.
+    pub fn with_slow_log(mut self, slow_log: Arc<slowlog::SlowLog>) -> Self {
+        self.slow_log = Some(slow_log);
+        self
+    }
+
+    /// Name of the user this connection authenticated as, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// The subscription a watch request just made, along with its opaque.
+    /// The connection should send nothing but its events from then on.
+    pub fn take_watch(&mut self) -> Option<(u32, watch::Watch)> {
+        self.watch.take()
+    }
+
+    /// The packet reporting `event` to a watching connection.
+    pub fn watch_event(
+        opaque: u32,
+        event: watch::Event,
+        dropped: u64,
+    ) -> binary_codec::BinaryResponse {
+        binary_codec::BinaryResponse::WatchEvent(binary::WatchEventResponse {
+            header: binary::ResponseHeader::new(binary::Command::WatchEvent as u8, opaque),
+            kind: event.kind as u8,
+            dropped,
+            key: event.key,
+            namespace: event.namespace.into_bytes(),
+        })
+    }
+
+    /// The tracker to record the request at hand to, if it is sampled.
+    fn sample_hot_key(&self) -> Option<Arc<hotkeys::HotKeys>> {
+        self.hot_keys
+            .as_ref()
+            .filter(|hot_keys| hot_keys.sample())
+            .cloned()
+    }
+
+    /// The tracker to record a read of `key` to once it is answered, if the
+    /// read is sampled.
+    fn sample_read(&self, key: &[u8]) -> Option<(Arc<hotkeys::HotKeys>, Vec<u8>)> {
+        self.sample_hot_key()
+            .map(|hot_keys| (hot_keys, key.to_vec()))
     }
 
-    pub fn handle_request(
+    /// Records a sampled read along with the size of the value it returned.
+    fn record_read(
+        sampled: Option<(Arc<hotkeys::HotKeys>, Vec<u8>)>,
+        response: &Option<binary_codec::BinaryResponse>,
+    ) {
+        if let Some((hot_keys, key)) = sampled {
+            let bytes = match response {
+                Some(binary_codec::BinaryResponse::Get(response))
+                | Some(binary_codec::BinaryResponse::GetQuietly(response))
+                | Some(binary_codec::BinaryResponse::GetKey(response))
+                | Some(binary_codec::BinaryResponse::GetKeyQuietly(response))
+                | Some(binary_codec::BinaryResponse::GetAndTouch(response))
+                | Some(binary_codec::BinaryResponse::GetAndTouchQuietly(response)) => {
+                    response.value.len()
+                }
+                Some(binary_codec::BinaryResponse::LeaseGet(response)) => response.value.len(),
+                _ => 0,
+            };
+            hot_keys.record(&key, bytes);
+        }
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.authenticator.is_none() || self.user.is_some()
+    }
+
+    /// Whether `header` marks a request another cluster member forwarded.
+    fn is_forwarded(&self, header: &binary::RequestHeader) -> bool {
+        header.reserved == cluster::FORWARDED_VBUCKET
+            && self
+                .cluster
+                .as_ref()
+                .is_some_and(|cluster| cluster.is_peer(self.user()))
+    }
+
+    fn required_access(req: &binary_codec::BinaryRequest) -> Option<(&[u8], acl::Access)> {
+        match req {
+            binary_codec::BinaryRequest::Get(req)
+            | binary_codec::BinaryRequest::GetQuietly(req)
+            | binary_codec::BinaryRequest::GetKey(req)
+            | binary_codec::BinaryRequest::GetKeyQuietly(req)
+            | binary_codec::BinaryRequest::LeaseGet(req) => Some((&req.key, acl::Access::Read)),
+            binary_codec::BinaryRequest::LeaseSet(req) => Some((&req.key, acl::Access::Write)),
+            binary_codec::BinaryRequest::SetTagged(req) => Some((&req.key, acl::Access::Write)),
+            binary_codec::BinaryRequest::Set(req)
+            | binary_codec::BinaryRequest::SetQuietly(req)
+            | binary_codec::BinaryRequest::Add(req)
+            | binary_codec::BinaryRequest::AddQuietly(req)
+            | binary_codec::BinaryRequest::Replace(req)
+            | binary_codec::BinaryRequest::ReplaceQuietly(req) => {
+                Some((&req.key, acl::Access::Write))
+            }
+            binary_codec::BinaryRequest::Append(req)
+            | binary_codec::BinaryRequest::AppendQuietly(req)
+            | binary_codec::BinaryRequest::Prepend(req)
+            | binary_codec::BinaryRequest::PrependQuietly(req) => {
+                Some((&req.key, acl::Access::Write))
+            }
+            binary_codec::BinaryRequest::Delete(req)
+            | binary_codec::BinaryRequest::DeleteQuietly(req) => {
+                Some((&req.key, acl::Access::Write))
+            }
+            binary_codec::BinaryRequest::Increment(req)
+            | binary_codec::BinaryRequest::IncrementQuietly(req)
+            | binary_codec::BinaryRequest::Decrement(req)
+            | binary_codec::BinaryRequest::DecrementQuietly(req) => {
+                Some((&req.key, acl::Access::Write))
+            }
+            binary_codec::BinaryRequest::Touch(req)
+            | binary_codec::BinaryRequest::GetAndTouch(req)
+            | binary_codec::BinaryRequest::GetAndTouchQuietly(req) => {
+                Some((&req.key, acl::Access::Write))
+            }
+            // Flushing and snapshots take write access to every key, i.e. to
+            // the empty prefix.
+            binary_codec::BinaryRequest::Flush(_)
+            | binary_codec::BinaryRequest::FlushQuietly(_)
+            | binary_codec::BinaryRequest::Snapshot(_)
+            | binary_codec::BinaryRequest::InvalidateTag(_) => Some((&[], acl::Access::Write)),
+            // Watchers, dumps, the slow log and the hot keys see the keys of
+            // every namespace, so they need to be allowed to read them all.
+            binary_codec::BinaryRequest::Watch(_)
+            | binary_codec::BinaryRequest::MetaDump(_)
+            | binary_codec::BinaryRequest::SlowLog(_) => Some((&[], acl::Access::Read)),
+            binary_codec::BinaryRequest::Stat(req) if req.key == b"hotkeys" => {
+                Some((&[], acl::Access::Read))
+            }
+            binary_codec::BinaryRequest::Version(_)
+            | binary_codec::BinaryRequest::SaslListMechs(_)
+            | binary_codec::BinaryRequest::SaslAuth(_)
+            | binary_codec::BinaryRequest::SaslStep(_)
+            | binary_codec::BinaryRequest::Stat(_)
+            | binary_codec::BinaryRequest::Noop(_)
+            | binary_codec::BinaryRequest::Invalid(_) => None,
+        }
+    }
+
+    fn is_permitted(&self, req: &binary_codec::BinaryRequest) -> bool {
+        match (&self.acl, BinaryHandler::required_access(req)) {
+            (Some(acl), Some((key, access))) => acl.is_allowed(self.user(), key, access),
+            _ => true,
+        }
+    }
+
+    pub async fn handle_request(
         &mut self,
         req: binary_codec::BinaryRequest,
     ) -> Option<binary_codec::BinaryResponse> {
@@ -19,62 +282,1201 @@ impl BinaryHandler {
         let mut response_header =
             binary::ResponseHeader::new(request_header.opcode, request_header.opaque);
 
+        if !req.is_sasl() && !self.is_authenticated() {
+            response_header.status = binary::ResponseStatus::AuthenticationError as u16;
+            return Some(binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                header: response_header,
+            }));
+        }
+
+        if !self.is_permitted(&req) {
+            stats::incr(&self.stats.acl_denials);
+            response_header.status = binary::ResponseStatus::AuthenticationError as u16;
+            return Some(binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                header: response_header,
+            }));
+        }
+
+        if self.read_only {
+            if let Some((_, acl::Access::Write)) = BinaryHandler::required_access(&req) {
+                response_header.status = binary::ResponseStatus::NotSupported as u16;
+                return Some(binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                }));
+            }
+        }
+
+        if let Some(cluster) = self.cluster.clone() {
+            let owner = if self.is_forwarded(req.get_header()) {
+                None
+            } else {
+                // Flush and watches have no key and only ever apply to this
+                // node; tag invalidations are broadcast once applied here.
+                BinaryHandler::required_access(&req)
+                    .filter(|(key, _)| !key.is_empty())
+                    .and_then(|(key, _)| cluster.remote_owner(key))
+            };
+            if let Some(owner) = owner {
+                let owner = owner.to_string();
+                return match cluster.policy() {
+                    cluster::WrongNodePolicy::Forward => self.forward(owner, req).await,
+                    cluster::WrongNodePolicy::Redirect => {
+                        stats::incr(&self.stats.cluster_redirects);
+                        response_header.status = binary::ResponseStatus::NotMyVbucket as u16;
+                        Some(binary_codec::BinaryResponse::NotMyVbucket(
+                            binary::NotMyVbucketResponse {
+                                header: response_header,
+                                value: owner.into_bytes(),
+                            },
+                        ))
+                    }
+                };
+            }
+        }
+
         match req {
-            binary_codec::BinaryRequest::Get(get_request) => {
-                let result = self.storage.get(&get_request.key);
-                match result {
-                    Err(_e) => None,
-                    Ok(record) => {
-                        // why +4
-                        response_header.body_length = record.value.len() as u32 + 4;
-                        response_header.cas = record.header.cas;
-                        Some(binary_codec::BinaryResponse::Get(binary::GetResponse {
-                            header: response_header,
-                            flags: record.header.flags,
-                            key: Vec::new(),
-                            value: record.value,
-                        }))
+            binary_codec::BinaryRequest::Get(get_req)
+            | binary_codec::BinaryRequest::GetQuietly(get_req)
+            | binary_codec::BinaryRequest::GetKey(get_req)
+            | binary_codec::BinaryRequest::GetKeyQuietly(get_req) => {
+                let sampled = self.sample_read(&get_req.key);
+                let response = self.get(get_req, response_header).await;
+                Self::record_read(sampled, &response);
+                response
+            }
+            binary_codec::BinaryRequest::Set(set_req)
+            | binary_codec::BinaryRequest::SetQuietly(set_req)
+            | binary_codec::BinaryRequest::Add(set_req)
+            | binary_codec::BinaryRequest::AddQuietly(set_req)
+            | binary_codec::BinaryRequest::Replace(set_req)
+            | binary_codec::BinaryRequest::ReplaceQuietly(set_req) => {
+                stats::incr(&self.stats.cmd_set);
+                if let Some(hot_keys) = self.sample_hot_key() {
+                    hot_keys.record(&set_req.key, set_req.value.len());
+                }
+                self.store(set_req, response_header)
+            }
+            binary_codec::BinaryRequest::Append(append_req)
+            | binary_codec::BinaryRequest::AppendQuietly(append_req)
+            | binary_codec::BinaryRequest::Prepend(append_req)
+            | binary_codec::BinaryRequest::PrependQuietly(append_req) => {
+                stats::incr(&self.stats.cmd_set);
+                if let Some(hot_keys) = self.sample_hot_key() {
+                    hot_keys.record(&append_req.key, append_req.value.len());
+                }
+                self.concat(append_req, response_header)
+            }
+            binary_codec::BinaryRequest::LeaseGet(get_req) => {
+                let sampled = self.sample_read(&get_req.key);
+                let response = self.lease_get(get_req, response_header).await;
+                Self::record_read(sampled, &response);
+                response
+            }
+            binary_codec::BinaryRequest::LeaseSet(set_req) => {
+                stats::incr(&self.stats.cmd_set);
+                if let Some(hot_keys) = self.sample_hot_key() {
+                    hot_keys.record(&set_req.key, set_req.value.len());
+                }
+                self.lease_set(set_req, response_header)
+            }
+            binary_codec::BinaryRequest::SetTagged(set_req) => {
+                stats::incr(&self.stats.cmd_set);
+                if let Some(hot_keys) = self.sample_hot_key() {
+                    hot_keys.record(&set_req.key, set_req.value.len());
+                }
+                self.set_tagged(set_req, response_header)
+            }
+            binary_codec::BinaryRequest::InvalidateTag(invalidate_req) => {
+                response_header.cas = self.storage.invalidate_tag(&invalidate_req.key);
+                // Unlike skipping routing, skipping the fan out only narrows
+                // what the request does, so any connection may ask for it.
+                let cluster = self
+                    .cluster
+                    .clone()
+                    .filter(|_| invalidate_req.header.reserved != cluster::FORWARDED_VBUCKET);
+                if let Some(cluster) = cluster {
+                    let req = binary_codec::BinaryRequest::InvalidateTag(invalidate_req);
+                    if !self.broadcast(&cluster, req).await {
+                        response_header.status = binary::ResponseStatus::InternalError as u16;
                     }
                 }
+                Some(binary_codec::BinaryResponse::InvalidateTag(
+                    binary::InvalidateTagResponse {
+                        header: response_header,
+                    },
+                ))
+            }
+            binary_codec::BinaryRequest::Delete(delete_req)
+            | binary_codec::BinaryRequest::DeleteQuietly(delete_req) => {
+                self.delete(delete_req, response_header)
+            }
+            binary_codec::BinaryRequest::Watch(watch_req) => {
+                let filter = watch::WatchFilter {
+                    prefix: watch_req.key,
+                    kinds: watch_req.kinds,
+                };
+                let watch = self.storage.watch(filter);
+                self.watch = Some((response_header.opaque, watch));
+                Some(binary_codec::BinaryResponse::Watch(binary::WatchResponse {
+                    header: response_header,
+                }))
+            }
+            binary_codec::BinaryRequest::MetaDump(dump_req) => {
+                Some(self.metadump(dump_req, response_header))
+            }
+            binary_codec::BinaryRequest::SlowLog(slow_log_req) => {
+                Some(self.slow_log(slow_log_req, response_header))
+            }
+            binary_codec::BinaryRequest::Increment(delta_req)
+            | binary_codec::BinaryRequest::IncrementQuietly(delta_req)
+            | binary_codec::BinaryRequest::Decrement(delta_req)
+            | binary_codec::BinaryRequest::DecrementQuietly(delta_req) => {
+                self.load_back(&delta_req.key).await;
+                self.apply_delta(delta_req, response_header)
+            }
+            binary_codec::BinaryRequest::Touch(touch_req) => {
+                self.load_back(&touch_req.key).await;
+                self.touch(touch_req, response_header)
+            }
+            binary_codec::BinaryRequest::GetAndTouch(touch_req)
+            | binary_codec::BinaryRequest::GetAndTouchQuietly(touch_req) => {
+                let sampled = self.sample_read(&touch_req.key);
+                self.load_back(&touch_req.key).await;
+                let response = self.touch(touch_req, response_header);
+                Self::record_read(sampled, &response);
+                response
+            }
+            binary_codec::BinaryRequest::Flush(flush_req)
+            | binary_codec::BinaryRequest::FlushQuietly(flush_req) => {
+                self.flush(flush_req, response_header)
+            }
+            binary_codec::BinaryRequest::Version(_) => Some(binary_codec::BinaryResponse::Version(
+                binary::VersionResponse {
+                    header: response_header,
+                    value: env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+                },
+            )),
+            binary_codec::BinaryRequest::SaslListMechs(_) => Some(
+                binary_codec::BinaryResponse::SaslListMechs(binary::SaslListMechsResponse {
+                    header: response_header,
+                    value: auth::MECHANISMS.as_bytes().to_vec(),
+                }),
+            ),
+            binary_codec::BinaryRequest::SaslAuth(sasl_req) => {
+                let response = self.sasl_auth(sasl_req, &mut response_header);
+                Some(binary_codec::BinaryResponse::SaslAuth(response))
+            }
+            binary_codec::BinaryRequest::SaslStep(_) => {
+                // PLAIN completes in a single round trip, so there is never a step to continue.
+                response_header.status = binary::ResponseStatus::AuthenticationError as u16;
+                Some(binary_codec::BinaryResponse::SaslStep(
+                    binary::SaslStepResponse {
+                        header: response_header,
+                        value: b"Auth failure".to_vec(),
+                    },
+                ))
+            }
+            binary_codec::BinaryRequest::Noop(_) => {
+                Some(binary_codec::BinaryResponse::Noop(binary::NoopResponse {
+                    header: response_header,
+                }))
             }
-            binary_codec::BinaryRequest::GetQuietly(get_quietly_req) => None,
-            binary_codec::BinaryRequest::GetKey(get_key_req) => None,
-            binary_codec::BinaryRequest::GetKeyQuietly(get_key_quietly_req) => None,
-            binary_codec::BinaryRequest::Set(set_req) => {
-                let response = self.set(set_req, &mut response_header);
-                Some(binary_codec::BinaryResponse::Set(response))
+            binary_codec::BinaryRequest::Invalid(invalid_req) => {
+                response_header.status = invalid_req.status;
+                Some(binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                }))
             }
+            binary_codec::BinaryRequest::Stat(stat_req) => {
+                let response = self.stat(stat_req, &mut response_header);
+                Some(binary_codec::BinaryResponse::Stat(response))
+            }
+            binary_codec::BinaryRequest::Snapshot(_) => {
+                response_header.status = match self.snapshot.clone() {
+                    Some(snapshot) => {
+                        // Saving writes and syncs a file, so it runs off the
+                        // connection's worker.
+                        let storage = self.storage.clone();
+                        let result =
+                            tokio::task::spawn_blocking(move || snapshot.save(&storage)).await;
+                        match result.map_err(io::Error::other) {
+                            Ok(Ok(_)) => binary::ResponseStatus::Success as u16,
+                            Ok(Err(err)) | Err(err) => {
+                                error!("Snapshot failed: {}", err);
+                                binary::ResponseStatus::InternalError as u16
+                            }
+                        }
+                    }
+                    None => binary::ResponseStatus::NotSupported as u16,
+                };
+                Some(binary_codec::BinaryResponse::Snapshot(
+                    binary::SnapshotResponse {
+                        header: response_header,
+                    },
+                ))
+            }
+        }
+    }
 
-            binary_codec::BinaryRequest::Add(add_req) => None,
-            binary_codec::BinaryRequest::Replace(replace_req) => None,
+    /// Relays `req` to the cluster node owning its key. Quiet requests are
+    /// sent in their loud form, as the peer connection expects an answer to
+    /// every request, and the answer is dropped if the client would not have
+    /// got one.
+    async fn forward(
+        &mut self,
+        owner: String,
+        mut req: binary_codec::BinaryRequest,
+    ) -> Option<binary_codec::BinaryResponse> {
+        stats::incr(&self.stats.cluster_forwards);
+        let request_header = *req.get_header();
+        let command: Option<binary::Command> = FromPrimitive::from_u8(request_header.opcode);
+        let header = req.get_header_mut();
+        header.reserved = cluster::FORWARDED_VBUCKET;
+        if let Some(command) = command {
+            header.opcode = command.loud() as u8;
         }
+        let mut response = match self.send_to_peer(&owner, req).await {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("Forwarding to {} failed: {}", owner, err);
+                stats::incr(&self.stats.cluster_forward_errors);
+                let mut response_header =
+                    binary::ResponseHeader::new(request_header.opcode, request_header.opaque);
+                response_header.status = binary::ResponseStatus::InternalError as u16;
+                return Some(binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                }));
+            }
+        };
+        response.get_header_mut().opcode = request_header.opcode;
+        BinaryHandler::unless_quiet(&request_header, response)
     }
 
-    fn set(
+    /// Sends `req` to every other cluster member, for requests that apply
+    /// to all of them. Returns false if one could not be reached or failed.
+    async fn broadcast(
+        &mut self,
+        cluster: &cluster::Cluster,
+        mut req: binary_codec::BinaryRequest,
+    ) -> bool {
+        req.get_header_mut().reserved = cluster::FORWARDED_VBUCKET;
+        let mut delivered = true;
+        for member in cluster.ring().nodes() {
+            if member == cluster.local() {
+                continue;
+            }
+            stats::incr(&self.stats.cluster_forwards);
+            match self.send_to_peer(member, req.clone()).await {
+                Ok(response)
+                    if response.get_header().status == binary::ResponseStatus::Success as u16 => {}
+                Ok(response) => {
+                    warn!(
+                        "Broadcast to {} failed with status {}",
+                        member,
+                        response.get_header().status
+                    );
+                    delivered = false;
+                }
+                Err(err) => {
+                    warn!("Broadcast to {} failed: {}", member, err);
+                    stats::incr(&self.stats.cluster_forward_errors);
+                    delivered = false;
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Drops the response to a quiet request that would not be answered.
+    fn unless_quiet(
+        request_header: &binary::RequestHeader,
+        response: binary_codec::BinaryResponse,
+    ) -> Option<binary_codec::BinaryResponse> {
+        let command: Option<binary::Command> = FromPrimitive::from_u8(request_header.opcode);
+        match command {
+            Some(command) if !command.answers(response.get_header().status) => None,
+            _ => Some(response),
+        }
+    }
+
+    /// Sends `req` to `peer` over the connection kept to it, which is
+    /// dropped if the exchange fails or times out.
+    async fn send_to_peer(
+        &mut self,
+        peer: &str,
+        req: binary_codec::BinaryRequest,
+    ) -> io::Result<binary_codec::BinaryResponse> {
+        let response = self.exchange_with_peer(peer, req).await;
+        if response.is_err() {
+            self.peers.remove(peer);
+        }
+        response
+    }
+
+    async fn exchange_with_peer(
+        &mut self,
+        peer: &str,
+        req: binary_codec::BinaryRequest,
+    ) -> io::Result<binary_codec::BinaryResponse> {
+        if !self.peers.contains_key(peer) {
+            let connection = self.connect_to_peer(peer).await?;
+            self.peers.insert(peer.to_string(), connection);
+        }
+        let connection = self.peers.get_mut(peer).unwrap();
+        within_peer_timeout(connection.send(req)).await??;
+        match within_peer_timeout(connection.next()).await? {
+            Some(response) => response,
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "peer closed the connection",
+            )),
+        }
+    }
+
+    /// Opens a connection to `peer`, authenticating with the cluster's peer
+    /// credentials if it has any.
+    async fn connect_to_peer(&self, peer: &str) -> io::Result<PeerConnection> {
+        let socket = within_peer_timeout(TcpStream::connect(peer)).await??;
+        socket.set_nodelay(true)?;
+        let mut connection = Framed::new(socket, binary_codec::MemcachedBinaryClientCodec::new());
+        let credentials = self
+            .cluster
+            .as_ref()
+            .and_then(|cluster| cluster.peer_credentials());
+        if let Some((user, password)) = credentials {
+            let req = binary_codec::BinaryRequest::SaslAuth(binary::SaslAuthRequest {
+                header: binary::RequestHeader {
+                    opcode: binary::Command::SaslAuth as u8,
+                    ..binary::RequestHeader::default()
+                },
+                key: auth::MECHANISMS.as_bytes().to_vec(),
+                value: format!("\0{}\0{}", user, password).into_bytes(),
+            });
+            within_peer_timeout(connection.send(req)).await??;
+            match within_peer_timeout(connection.next()).await? {
+                Some(Ok(response))
+                    if response.get_header().status == binary::ResponseStatus::Success as u16 => {}
+                Some(Err(err)) => return Err(err),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "peer rejected the cluster credentials",
+                    ))
+                }
+            }
+        }
+        Ok(connection)
+    }
+
+    /// Serves the four get variants: the quiet ones stay silent on a miss and
+    /// the key ones echo the key back.
+    async fn get(
+        &mut self,
+        get_req: binary::GetRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        stats::incr(&self.stats.cmd_get);
+        let command = FromPrimitive::from_u8(get_req.header.opcode);
+        let quiet = matches!(
+            command,
+            Some(binary::Command::GetQuiet) | Some(binary::Command::GetKeyQuiet)
+        );
+        let namespace = self.storage.namespace(self.user(), &get_req.key);
+        let result = match namespace.get_raw(&get_req.key) {
+            Ok(record) => namespace.load_external(&get_req.key, record).await,
+            Err(err) => Err(err),
+        };
+        let record = match result {
+            Err(err) => {
+                stats::incr(&self.stats.get_misses);
+                if quiet {
+                    return None;
+                }
+                response_header.status = err as u16;
+                return Some(binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                }));
+            }
+            Ok(record) => record,
+        };
+        stats::incr(&self.stats.get_hits);
+        response_header.cas = record.header.cas;
+        let response = binary::GetResponse {
+            header: response_header,
+            flags: record.header.flags,
+            key: Vec::new(),
+            value: record.value,
+        };
+        Some(match command {
+            Some(binary::Command::GetQuiet) => binary_codec::BinaryResponse::GetQuietly(response),
+            Some(binary::Command::GetKey) => {
+                binary_codec::BinaryResponse::GetKey(binary::GetKeyResponse {
+                    key: get_req.key,
+                    ..response
+                })
+            }
+            Some(binary::Command::GetKeyQuiet) => {
+                binary_codec::BinaryResponse::GetKeyQuietly(binary::GetKeyQuietlyResponse {
+                    key: get_req.key,
+                    ..response
+                })
+            }
+            _ => binary_codec::BinaryResponse::Get(response),
+        })
+    }
+
+    fn store(
         &mut self,
         set_req: binary::SetRequest,
-        response_header: &mut binary::ResponseHeader,
-    ) -> binary::SetResponse {
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        let request_header = set_req.header;
+        let command = FromPrimitive::from_u8(request_header.opcode).map(binary::Command::loud);
+        let record = storage::Record::new(
+            set_req.value,
+            request_header.cas,
+            set_req.flags,
+            set_req.expiration,
+        )
+        .with_grace(set_req.grace)
+        .with_early_recompute(set_req.recompute_ms);
+        let namespace = self.storage.namespace(self.user(), &set_req.key);
+        let result = match command {
+            Some(binary::Command::Add) => namespace.add(set_req.key, record),
+            Some(binary::Command::Replace) => namespace.replace(set_req.key, record),
+            _ if request_header.cas != 0 => namespace.cas(set_req.key, record),
+            _ => namespace.set(set_req.key, record),
+        };
+        match result {
+            Ok(set_status) => response_header.cas = set_status.cas,
+            Err(err) => response_header.status = err as u16,
+        }
+        let response = binary::SetResponse {
+            header: response_header,
+        };
+        let response = match command {
+            Some(binary::Command::Add) => binary_codec::BinaryResponse::Add(response),
+            Some(binary::Command::Replace) => binary_codec::BinaryResponse::Replace(response),
+            _ => binary_codec::BinaryResponse::Set(response),
+        };
+        BinaryHandler::unless_quiet(&request_header, response)
+    }
+
+    /// Serves append and prepend, which keep the item's flags and expiry.
+    fn concat(
+        &mut self,
+        append_req: binary::AppendRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        let request_header = append_req.header;
+        let command = FromPrimitive::from_u8(request_header.opcode).map(binary::Command::loud);
+        let record = storage::Record::new(append_req.value, request_header.cas, 0, 0);
+        let namespace = self.storage.namespace(self.user(), &append_req.key);
+        let result = match command {
+            Some(binary::Command::Prepend) => namespace.prepend(append_req.key, record),
+            _ => namespace.append(append_req.key, record),
+        };
+        match result {
+            Ok(set_status) => response_header.cas = set_status.cas,
+            Err(err) => response_header.status = err as u16,
+        }
+        let response = binary::AppendResponse {
+            header: response_header,
+        };
+        let response = match command {
+            Some(binary::Command::Prepend) => binary_codec::BinaryResponse::Prepend(response),
+            _ => binary_codec::BinaryResponse::Append(response),
+        };
+        BinaryHandler::unless_quiet(&request_header, response)
+    }
+
+    /// Reads an external value back into memory without blocking, ahead of
+    /// commands that update it.
+    async fn load_back(&self, key: &Vec<u8>) {
+        self.storage
+            .namespace(self.user(), key)
+            .load_back(key)
+            .await;
+    }
+
+    /// Serves a get that hands out the lease to fill the key on a miss.
+    async fn lease_get(
+        &mut self,
+        get_req: binary::LeaseGetRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        stats::incr(&self.stats.cmd_get);
+        let namespace = self.storage.namespace(self.user(), &get_req.key);
+        let result = match namespace.get_raw_or_stale(&get_req.key) {
+            Ok((record, stale)) => namespace
+                .load_external(&get_req.key, record)
+                .await
+                .map(|record| (record, stale)),
+            Err(err) => Err(err),
+        };
+        let leased = match result {
+            Ok((record, stale)) => namespace.revalidate(&get_req.key, record, stale),
+            Err(StorageError::NotFound) => namespace.lease(&get_req.key),
+            Err(err) => {
+                stats::incr(&self.stats.get_misses);
+                response_header.status = err as u16;
+                return Some(binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                }));
+            }
+        };
+        let mut response = binary::LeaseGetResponse {
+            header: response_header,
+            flags: 0,
+            token: 0,
+            stale: false,
+            value: Vec::new(),
+        };
+        match leased {
+            lease::LeaseGet::Hit {
+                record,
+                stale,
+                token,
+            } => {
+                stats::incr(&self.stats.get_hits);
+                response.header.cas = record.header.cas;
+                response.flags = record.header.flags;
+                response.token = token.unwrap_or(0);
+                response.stale = stale;
+                response.value = record.value;
+            }
+            lease::LeaseGet::Granted(token) => {
+                stats::incr(&self.stats.get_misses);
+                response.header.status = binary::ResponseStatus::KeyNotExists as u16;
+                response.token = token;
+            }
+            lease::LeaseGet::Wait => {
+                stats::incr(&self.stats.get_misses);
+                response.header.status = binary::ResponseStatus::TemporaryFailure as u16;
+            }
+        }
+        Some(binary_codec::BinaryResponse::LeaseGet(response))
+    }
+
+    fn lease_set(
+        &mut self,
+        set_req: binary::LeaseSetRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        let record = storage::Record::new(set_req.value, 0, set_req.flags, set_req.expiration)
+            .with_grace(set_req.grace)
+            .with_early_recompute(set_req.recompute_ms);
+        let namespace = self.storage.namespace(self.user(), &set_req.key);
+        match namespace.lease_set(set_req.key, record, set_req.token) {
+            Ok(set_status) => response_header.cas = set_status.cas,
+            Err(err) => response_header.status = err as u16,
+        }
+        Some(binary_codec::BinaryResponse::LeaseSet(
+            binary::LeaseSetResponse {
+                header: response_header,
+            },
+        ))
+    }
+
+    fn set_tagged(
+        &mut self,
+        set_req: binary::SetTaggedRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
         let record = storage::Record::new(
             set_req.value,
             set_req.header.cas,
             set_req.flags,
             set_req.expiration,
-        );
-        match self.storage.set(set_req.key, record) {
+        )
+        .with_tags(set_req.tags);
+        let namespace = self.storage.namespace(self.user(), &set_req.key);
+        let result = match set_req.header.cas {
+            0 => namespace.set(set_req.key, record),
+            _ => namespace.cas(set_req.key, record),
+        };
+        match result {
             Ok(set_status) => response_header.cas = set_status.cas,
             Err(err) => response_header.status = err as u16,
         }
-        binary::SetResponse {
+        Some(binary_codec::BinaryResponse::SetTagged(
+            binary::SetTaggedResponse {
+                header: response_header,
+            },
+        ))
+    }
+
+    fn delete(
+        &mut self,
+        delete_req: binary::DeleteRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        let namespace = self.storage.namespace(self.user(), &delete_req.key);
+        match namespace.delete(&delete_req.key, delete_req.header.cas) {
+            Ok(()) => stats::incr(&self.stats.delete_hits),
+            Err(err) => {
+                if err == StorageError::NotFound {
+                    stats::incr(&self.stats.delete_misses);
+                }
+                response_header.status = err as u16;
+            }
+        }
+        let response = binary_codec::BinaryResponse::Delete(binary::DeleteResponse {
+            header: response_header,
+        });
+        BinaryHandler::unless_quiet(&delete_req.header, response)
+    }
+
+    fn apply_delta(
+        &mut self,
+        delta_req: binary::IncrementRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        let decrement = matches!(
+            FromPrimitive::from_u8(delta_req.header.opcode).map(binary::Command::loud),
+            Some(binary::Command::Decrement)
+        );
+        let param = storage::IncrementParam {
+            delta: delta_req.delta,
+            value: delta_req.initial,
+            expiration: delta_req.expiration,
+        };
+        let namespace = self.storage.namespace(self.user(), &delta_req.key);
+        let (result, hits, misses) = match decrement {
+            true => (
+                namespace.decrement(delta_req.key, param),
+                &self.stats.decr_hits,
+                &self.stats.decr_misses,
+            ),
+            false => (
+                namespace.increment(delta_req.key, param),
+                &self.stats.incr_hits,
+                &self.stats.incr_misses,
+            ),
+        };
+        let response = match result {
+            Ok(delta_status) => {
+                stats::incr(hits);
+                response_header.cas = delta_status.cas;
+                let response = binary::IncrementResponse {
+                    header: response_header,
+                    value: delta_status.value,
+                };
+                match decrement {
+                    true => binary_codec::BinaryResponse::Decrement(response),
+                    false => binary_codec::BinaryResponse::Increment(response),
+                }
+            }
+            Err(err) => {
+                if err == StorageError::NotFound {
+                    stats::incr(misses);
+                }
+                response_header.status = err as u16;
+                binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                })
+            }
+        };
+        BinaryHandler::unless_quiet(&delta_req.header, response)
+    }
+
+    /// Serves touch and both get-and-touch variants.
+    fn touch(
+        &mut self,
+        touch_req: binary::TouchRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        let command = FromPrimitive::from_u8(touch_req.header.opcode);
+        let is_get = command != Some(binary::Command::Touch);
+        stats::incr(&self.stats.cmd_touch);
+        if is_get {
+            stats::incr(&self.stats.cmd_get);
+        }
+        let namespace = self.storage.namespace(self.user(), &touch_req.key);
+        let response = match namespace.touch(touch_req.key, touch_req.expiration) {
+            Ok(record) => {
+                stats::incr(&self.stats.touch_hits);
+                response_header.cas = record.header.cas;
+                if is_get {
+                    stats::incr(&self.stats.get_hits);
+                    let response = binary::GetAndTouchResponse {
+                        header: response_header,
+                        flags: record.header.flags,
+                        key: Vec::new(),
+                        value: record.value,
+                    };
+                    match command {
+                        Some(binary::Command::GetAndTouchQuiet) => {
+                            binary_codec::BinaryResponse::GetAndTouchQuietly(response)
+                        }
+                        _ => binary_codec::BinaryResponse::GetAndTouch(response),
+                    }
+                } else {
+                    binary_codec::BinaryResponse::Touch(binary::TouchResponse {
+                        header: response_header,
+                    })
+                }
+            }
+            Err(err) => {
+                stats::incr(&self.stats.touch_misses);
+                if is_get {
+                    stats::incr(&self.stats.get_misses);
+                }
+                response_header.status = err as u16;
+                binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                })
+            }
+        };
+        BinaryHandler::unless_quiet(&touch_req.header, response)
+    }
+
+    fn flush(
+        &mut self,
+        flush_req: binary::FlushRequest,
+        response_header: binary::ResponseHeader,
+    ) -> Option<binary_codec::BinaryResponse> {
+        stats::incr(&self.stats.cmd_flush);
+        if flush_req.expiration == 0 {
+            self.storage.flush();
+        } else {
+            let storage = self.storage.clone();
+            let delay = Duration::from_secs(flush_req.expiration as u64);
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                storage.flush();
+            });
+        }
+        let response = binary_codec::BinaryResponse::Flush(binary::FlushResponse {
+            header: response_header,
+        });
+        BinaryHandler::unless_quiet(&flush_req.header, response)
+    }
+
+    fn slow_log(
+        &self,
+        slow_log_req: binary::SlowLogRequest,
+        mut response_header: binary::ResponseHeader,
+    ) -> binary_codec::BinaryResponse {
+        let requests = match &self.slow_log {
+            Some(slow_log) => slow_log.requests(slow_log_req.count as usize),
+            None => {
+                response_header.status = binary::ResponseStatus::NotSupported as u16;
+                return binary_codec::BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                });
+            }
+        };
+        let entries = requests
+            .into_iter()
+            .map(|request| binary::SlowLogEntry {
+                id: request.id,
+                timestamp: request.timestamp,
+                duration_us: request.duration.as_micros() as u64,
+                size: request.size as u32,
+                opcode: request.opcode,
+                key: request.key,
+                peer: request.peer.into_bytes(),
+            })
+            .collect();
+        binary_codec::BinaryResponse::SlowLog(binary::SlowLogResponse {
+            header: response_header,
+            entries,
+        })
+    }
+
+    fn metadump(
+        &self,
+        dump_req: binary::MetaDumpRequest,
+        response_header: binary::ResponseHeader,
+    ) -> binary_codec::BinaryResponse {
+        let count = match dump_req.count {
+            0 => scan::DEFAULT_SCAN_COUNT,
+            count => count as usize,
+        };
+        let page = self.storage.scan(dump_req.cursor, &dump_req.key, count);
+        let entries = page
+            .entries
+            .into_iter()
+            .map(|entry| binary::MetaDumpEntry {
+                key: entry.key,
+                namespace: entry.namespace.into_bytes(),
+                flags: entry.flags,
+                cas: entry.cas,
+                expires_at: entry.expires_at,
+                last_access: entry.last_access,
+                size: entry.size as u32,
+                segment: entry.segment as u8,
+            })
+            .collect();
+        binary_codec::BinaryResponse::MetaDump(binary::MetaDumpResponse {
+            header: response_header,
+            entries,
+            cursor: page.cursor,
+        })
+    }
+
+    fn sasl_auth(
+        &mut self,
+        sasl_req: binary::SaslAuthRequest,
+        response_header: &mut binary::ResponseHeader,
+    ) -> binary::SaslAuthResponse {
+        stats::incr(&self.stats.auth_cmds);
+        let result = match &self.authenticator {
+            Some(authenticator) => authenticator.authenticate(&sasl_req.key, &sasl_req.value),
+            None => Err(auth::AuthError::UnsupportedMechanism),
+        };
+        let value = match result {
+            Ok(user) => {
+                self.user = Some(user);
+                b"Authenticated".to_vec()
+            }
+            Err(err) => {
+                info!("SASL auth failed: {}", err);
+                stats::incr(&self.stats.auth_errors);
+                self.user = None;
+                response_header.status = binary::ResponseStatus::AuthenticationError as u16;
+                b"Auth failure".to_vec()
+            }
+        };
+        binary::SaslAuthResponse {
+            header: *response_header,
+            value,
+        }
+    }
+
+    fn stat(
+        &mut self,
+        stat_req: binary::StatRequest,
+        response_header: &mut binary::ResponseHeader,
+    ) -> binary::StatResponse {
+        let stats = match stat_req.key.as_slice() {
+            b"" => {
+                let mut stats = vec![
+                    ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+                    ("curr_items".to_string(), self.storage.len().to_string()),
+                ];
+                stats.extend(self.stats.snapshot());
+                stats
+            }
+            b"namespaces" => self
+                .storage
+                .namespaces()
+                .iter()
+                .flat_map(|namespace| namespace.stats())
+                .collect(),
+            b"replication" => {
+                let role = match &self.replica {
+                    Some(_) => "replica",
+                    None => "primary",
+                };
+                let mut stats = vec![("repl_role".to_string(), role.to_string())];
+                if let Some(log) = &self.replication_log {
+                    stats.extend(log.stats());
+                }
+                if let Some(replica) = &self.replica {
+                    stats.extend(replica.stats());
+                }
+                stats
+            }
+            b"cluster" => match &self.cluster {
+                Some(cluster) => {
+                    let policy = match cluster.policy() {
+                        cluster::WrongNodePolicy::Forward => "forward",
+                        cluster::WrongNodePolicy::Redirect => "redirect",
+                    };
+                    vec![
+                        ("cluster_local".to_string(), cluster.local().to_string()),
+                        ("cluster_policy".to_string(), policy.to_string()),
+                        (
+                            "cluster_members".to_string(),
+                            cluster.ring().nodes().join(","),
+                        ),
+                    ]
+                }
+                None => Vec::new(),
+            },
+            b"extstore" => match self.storage.extstore() {
+                Some(extstore) => extstore.stats(),
+                None => Vec::new(),
+            },
+            b"compression" => match self.storage.compressor() {
+                Some(compressor) => compressor.stats(),
+                None => Vec::new(),
+            },
+            b"tags" => self.storage.tags().stats(),
+            b"watch" => self.storage.watchers().stats(),
+            b"hotkeys" => match &self.hot_keys {
+                Some(hot_keys) => hot_keys.stats(),
+                None => Vec::new(),
+            },
+            b"latency" => match &self.latencies {
+                Some(latencies) => latencies.stats(),
+                None => Vec::new(),
+            },
+            b"slowlog" => match &self.slow_log {
+                Some(slow_log) => slow_log.stats(),
+                None => Vec::new(),
+            },
+            _ => {
+                response_header.status = binary::ResponseStatus::KeyNotExists as u16;
+                Vec::new()
+            }
+        };
+        binary::StatResponse {
             header: *response_header,
+            stats,
         }
     }
+}
+
+/// Bounds one step of talking to a cluster peer by `PEER_TIMEOUT`.
+async fn within_peer_timeout<F: std::future::Future>(future: F) -> io::Result<F::Output> {
+    tokio::time::timeout(PEER_TIMEOUT, future)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "cluster peer timed out"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::timer;
+
+    fn create_handler() -> BinaryHandler {
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let authenticator = auth::Authenticator::parse("alice:secret\n").unwrap();
+        BinaryHandler::new(Arc::new(storage::Storage::new(timer)))
+            .with_authenticator(Arc::new(authenticator))
+    }
+
+    fn request_header(cmd: binary::Command) -> binary::RequestHeader {
+        binary::RequestHeader {
+            magic: binary::Magic::Request as u8,
+            opcode: cmd as u8,
+            ..binary::RequestHeader::default()
+        }
+    }
+
+    fn get_request(key: &[u8]) -> binary_codec::BinaryRequest {
+        binary_codec::BinaryRequest::Get(binary::GetRequest {
+            header: request_header(binary::Command::Get),
+            key: key.to_vec(),
+        })
+    }
+
+    fn sasl_auth_request(data: &[u8]) -> binary_codec::BinaryRequest {
+        binary_codec::BinaryRequest::SaslAuth(binary::SaslAuthRequest {
+            header: request_header(binary::Command::SaslAuth),
+            key: b"PLAIN".to_vec(),
+            value: data.to_vec(),
+        })
+    }
+
+    fn set_request(key: &[u8], value: &[u8]) -> binary_codec::BinaryRequest {
+        binary_codec::BinaryRequest::Set(binary::SetRequest {
+            header: request_header(binary::Command::Set),
+            flags: 0,
+            expiration: 0,
+            grace: 0,
+            recompute_ms: 0,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        })
+    }
+
+    fn status(response: Option<binary_codec::BinaryResponse>) -> u16 {
+        response.unwrap().get_header().status
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_commands_are_rejected() {
+        let mut handler = create_handler();
+        assert_eq!(
+            status(handler.handle_request(get_request(b"key")).await),
+            binary::ResponseStatus::AuthenticationError as u16
+        );
+        let list = handler
+            .handle_request(binary_codec::BinaryRequest::SaslListMechs(
+                binary::SaslListMechsRequest {
+                    header: request_header(binary::Command::SaslListMechs),
+                },
+            ))
+            .await;
+        match list {
+            Some(binary_codec::BinaryResponse::SaslListMechs(response)) => {
+                assert_eq!(response.value, b"PLAIN")
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticated_connection_may_issue_commands() {
+        let mut handler = create_handler();
+        assert_eq!(
+            status(
+                handler
+                    .handle_request(sasl_auth_request(b"\0alice\0wrong"))
+                    .await
+            ),
+            binary::ResponseStatus::AuthenticationError as u16
+        );
+        assert_eq!(handler.user(), None);
+
+        assert_eq!(
+            status(
+                handler
+                    .handle_request(sasl_auth_request(b"\0alice\0secret"))
+                    .await
+            ),
+            binary::ResponseStatus::Success as u16
+        );
+        assert_eq!(handler.user(), Some("alice"));
+        assert_eq!(
+            status(handler.handle_request(get_request(b"key")).await),
+            binary::ResponseStatus::KeyNotExists as u16
+        );
+    }
+
+    #[tokio::test]
+    async fn acl_violations_are_rejected_and_counted() {
+        let stats = Arc::new(stats::Stats::new());
+        let acl = acl::Acl::parse("alice team_a: rw\nalice shared: r\n").unwrap();
+        let mut handler = create_handler()
+            .with_stats(stats.clone())
+            .with_acl(Arc::new(acl));
+        handler
+            .handle_request(sasl_auth_request(b"\0alice\0secret"))
+            .await;
+
+        assert_eq!(
+            status(handler.handle_request(set_request(b"team_a:1", b"v")).await),
+            binary::ResponseStatus::Success as u16
+        );
+        assert_eq!(
+            status(handler.handle_request(set_request(b"shared:1", b"v")).await),
+            binary::ResponseStatus::AuthenticationError as u16
+        );
+        assert_eq!(
+            status(handler.handle_request(get_request(b"team_b:1")).await),
+            binary::ResponseStatus::AuthenticationError as u16
+        );
+        assert_eq!(
+            status(handler.handle_request(get_request(b"shared:1")).await),
+            binary::ResponseStatus::KeyNotExists as u16
+        );
+        let snapshot = binary_codec::BinaryRequest::Snapshot(binary::SnapshotRequest {
+            header: request_header(binary::Command::Snapshot),
+        });
+        assert_eq!(
+            status(handler.handle_request(snapshot).await),
+            binary::ResponseStatus::AuthenticationError as u16
+        );
+        let hot_keys = binary_codec::BinaryRequest::Stat(binary::StatRequest {
+            header: request_header(binary::Command::Stat),
+            key: b"hotkeys".to_vec(),
+        });
+        assert_eq!(
+            status(handler.handle_request(hot_keys).await),
+            binary::ResponseStatus::AuthenticationError as u16
+        );
+        assert_eq!(
+            stats.acl_denials.load(std::sync::atomic::Ordering::Relaxed),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn read_only_handler_rejects_writes() {
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let mut handler =
+            BinaryHandler::new(Arc::new(storage::Storage::new(timer))).with_read_only(true);
+        assert_eq!(
+            status(handler.handle_request(set_request(b"key", b"v")).await),
+            binary::ResponseStatus::NotSupported as u16
+        );
+        assert_eq!(
+            status(handler.handle_request(get_request(b"key")).await),
+            binary::ResponseStatus::KeyNotExists as u16
+        );
+    }
+
+    #[tokio::test]
+    async fn quiet_commands_only_answer_failures() {
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let mut handler = BinaryHandler::new(Arc::new(storage::Storage::new(timer)));
+        let quiet_add = |value: &[u8]| {
+            binary_codec::BinaryRequest::AddQuietly(binary::SetRequest {
+                header: request_header(binary::Command::AddQuiet),
+                flags: 0,
+                expiration: 0,
+                grace: 0,
+                recompute_ms: 0,
+                key: b"key".to_vec(),
+                value: value.to_vec(),
+            })
+        };
+        assert!(handler.handle_request(quiet_add(b"v")).await.is_none());
+        assert_eq!(
+            status(handler.handle_request(quiet_add(b"w")).await),
+            binary::ResponseStatus::KeyExists as u16
+        );
+
+        let quiet_get = |key: &[u8]| {
+            binary_codec::BinaryRequest::GetQuietly(binary::GetRequest {
+                header: request_header(binary::Command::GetQuiet),
+                key: key.to_vec(),
+            })
+        };
+        assert!(handler
+            .handle_request(quiet_get(b"missing"))
+            .await
+            .is_none());
+        match handler.handle_request(quiet_get(b"key")).await {
+            Some(binary_codec::BinaryResponse::GetQuietly(response)) => {
+                assert_eq!(response.value, b"v")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn gets_and_sets_feed_the_hot_keys() {
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let hot_keys = Arc::new(hotkeys::HotKeys::new(timer.clone(), 1));
+        let mut handler = BinaryHandler::new(Arc::new(storage::Storage::new(timer)))
+            .with_hot_keys(hot_keys.clone());
+        handler.handle_request(set_request(b"hot", b"value")).await;
+        handler.handle_request(set_request(b"cold", b"v")).await;
+        for _ in 0..3 {
+            handler.handle_request(get_request(b"hot")).await;
+        }
+
+        let report = hot_keys.report(1);
+        assert_eq!(report.by_requests[0].key, b"hot");
+        assert_eq!(report.by_bytes[0].key, b"hot");
+
+        let stats = match handler
+            .handle_request(binary_codec::BinaryRequest::Stat(binary::StatRequest {
+                header: request_header(binary::Command::Stat),
+                key: b"hotkeys".to_vec(),
+            }))
+            .await
+        {
+            Some(binary_codec::BinaryResponse::Stat(response)) => response.stats,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(stats
+            .iter()
+            .any(|(name, value)| name == "hotkey_requests_1" && value.ends_with(" hot")));
 
-    fn create_handler(cmd: u8) -> binary::ResponseHeader {
-        binary::ResponseHeader {
-            magic: binary::Magic::Response as u8,
-            opcode: cmd,
-            ..binary::ResponseHeader::default()
+        for _ in 0..5 {
+            let request = binary_codec::BinaryRequest::LeaseGet(binary::LeaseGetRequest {
+                header: request_header(binary::Command::LeaseGet),
+                key: b"leased".to_vec(),
+            });
+            handler.handle_request(request).await;
         }
+        assert_eq!(hot_keys.report(1).by_requests[0].key, b"leased");
     }
 }
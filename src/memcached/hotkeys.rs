@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::memcached::timer;
+
+/// One in how many gets and sets are tracked if not told otherwise.
+pub const DEFAULT_HOT_KEY_SAMPLE_RATE: u32 = 100;
+
+/// Keys counted per window; a key only shows up as hot if it stays among
+/// them.
+const TRACKED_KEYS: usize = 64;
+
+/// Keys reported in the stats for each ranking.
+const REPORTED_KEYS: usize = 10;
+
+/// Seconds a window counts for. Rates are taken over the current window and
+/// the one before it, so they reflect the last one to two windows.
+const WINDOW: u64 = 60;
+
+/// A key and its estimated rate, per second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotKey {
+    pub key: Vec<u8>,
+    pub rate: f64,
+}
+
+/// The hottest keys by requests and by bytes of values read or written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HotKeysReport {
+    pub by_requests: Vec<HotKey>,
+    pub by_bytes: Vec<HotKey>,
+}
+
+/// The space-saving algorithm: counts the first `TRACKED_KEYS` keys seen,
+/// then hands the counter of the least counted key to each new one. Keys
+/// more frequent than 1 / `TRACKED_KEYS` are never lost, and counts are
+/// overestimated by at most the count of the key they replaced.
+struct SpaceSaving {
+    counts: HashMap<Vec<u8>, u64>,
+}
+
+impl SpaceSaving {
+    fn new() -> SpaceSaving {
+        SpaceSaving {
+            counts: HashMap::with_capacity(TRACKED_KEYS),
+        }
+    }
+
+    fn add(&mut self, key: &[u8], weight: u64) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += weight;
+            return;
+        }
+        let mut count = 0;
+        if self.counts.len() >= TRACKED_KEYS {
+            let (least, least_count) = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count))
+                .unwrap();
+            self.counts.remove(&least);
+            count = least_count;
+        }
+        self.counts.insert(key.to_vec(), count + weight);
+    }
+}
+
+struct Window {
+    started: u64,
+    requests: SpaceSaving,
+    bytes: SpaceSaving,
+}
+
+impl Window {
+    fn new(started: u64) -> Window {
+        Window {
+            started,
+            requests: SpaceSaving::new(),
+            bytes: SpaceSaving::new(),
+        }
+    }
+}
+
+struct Windows {
+    previous: Option<Window>,
+    current: Window,
+}
+
+impl Windows {
+    fn rotate(&mut self, now: u64) {
+        let age = now.saturating_sub(self.current.started);
+        if age < WINDOW {
+            return;
+        }
+        let current = std::mem::replace(&mut self.current, Window::new(now));
+        self.previous = match age < 2 * WINDOW {
+            true => Some(current),
+            false => None,
+        };
+    }
+}
+
+/// Finds the keys taking the most traffic from a sample of the requests,
+/// so that tracking stays cheap: only sampled requests take the lock.
+/// The keys are reported in the `hotkeys` stats group.
+pub struct HotKeys {
+    timer: Arc<dyn timer::Timer + Send + Sync>,
+    sample_rate: u32,
+    windows: Mutex<Windows>,
+}
+
+impl HotKeys {
+    /// Tracks one in `sample_rate` requests.
+    pub fn new(timer: Arc<dyn timer::Timer + Send + Sync>, sample_rate: u32) -> HotKeys {
+        let now = timer.secs();
+        HotKeys {
+            timer,
+            sample_rate: sample_rate.max(1),
+            windows: Mutex::new(Windows {
+                previous: None,
+                current: Window::new(now),
+            }),
+        }
+    }
+
+    /// Whether the request at hand should be recorded.
+    pub fn sample(&self) -> bool {
+        self.sample_rate == 1 || rand::random::<u32>().is_multiple_of(self.sample_rate)
+    }
+
+    /// Records a sampled request on `key` that moved `bytes` of value.
+    pub fn record(&self, key: &[u8], bytes: usize) {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(self.timer.secs());
+        windows.current.requests.add(key, 1);
+        if bytes > 0 {
+            windows.current.bytes.add(key, bytes as u64);
+        }
+    }
+
+    /// The `count` hottest keys of each ranking, hottest first.
+    pub fn report(&self, count: usize) -> HotKeysReport {
+        let now = self.timer.secs();
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(now);
+        let mut elapsed = now.saturating_sub(windows.current.started);
+        let mut requests = windows.current.requests.counts.clone();
+        let mut bytes = windows.current.bytes.counts.clone();
+        if let Some(previous) = &windows.previous {
+            elapsed += windows.current.started - previous.started;
+            for (key, count) in &previous.requests.counts {
+                *requests.entry(key.clone()).or_default() += count;
+            }
+            for (key, count) in &previous.bytes.counts {
+                *bytes.entry(key.clone()).or_default() += count;
+            }
+        }
+        drop(windows);
+
+        let scale = self.sample_rate as f64 / elapsed.max(1) as f64;
+        let rank = |counts: HashMap<Vec<u8>, u64>| {
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+            counts
+                .into_iter()
+                .take(count)
+                .map(|(key, count)| HotKey {
+                    key,
+                    rate: count as f64 * scale,
+                })
+                .collect()
+        };
+        HotKeysReport {
+            by_requests: rank(requests),
+            by_bytes: rank(bytes),
+        }
+    }
+
+    /// The hottest keys as `(name, value)` pairs, each value being the rate
+    /// followed by the key.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let report = self.report(REPORTED_KEYS);
+        let mut stats = vec![(
+            "hotkey_sample_rate".to_string(),
+            self.sample_rate.to_string(),
+        )];
+        let rankings = [
+            ("hotkey_requests", report.by_requests),
+            ("hotkey_bytes", report.by_bytes),
+        ];
+        for (name, keys) in rankings {
+            for (rank, hot) in keys.iter().enumerate() {
+                stats.push((
+                    format!("{}_{}", name, rank + 1),
+                    format!("{:.1} {}", hot.rate, String::from_utf8_lossy(&hot.key)),
+                ));
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::testing::MockTimer;
+
+    #[test]
+    fn heavy_hitters_survive_a_long_tail() {
+        let timer = Arc::new(MockTimer::new(1000));
+        let hot_keys = HotKeys::new(timer.clone(), 1);
+        for i in 0..1000 {
+            hot_keys.record(b"hot", 10);
+            hot_keys.record(format!("cold:{}", i).as_bytes(), 0);
+            if i % 2 == 0 {
+                hot_keys.record(b"large", 1000);
+            }
+        }
+        timer.set(1010);
+
+        let report = hot_keys.report(2);
+        assert_eq!(
+            report.by_requests[0],
+            HotKey {
+                key: b"hot".to_vec(),
+                rate: 100.0,
+            }
+        );
+        assert_eq!(report.by_requests[1].key, b"large");
+        let bytes: Vec<_> = report.by_bytes.iter().map(|hot| hot.key.clone()).collect();
+        assert_eq!(bytes, vec![b"large".to_vec(), b"hot".to_vec()]);
+
+        timer.set(1010 + 2 * WINDOW);
+        assert_eq!(hot_keys.report(2), HotKeysReport::default());
+    }
+}
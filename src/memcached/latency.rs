@@ -0,0 +1,109 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use num_traits::FromPrimitive;
+
+use crate::protocol::binary;
+
+/// Latencies above a minute are recorded as a minute.
+const MAX_LATENCY_US: u64 = 60_000_000;
+
+/// How long each command took from being decoded to its response being
+/// written, a histogram per opcode. Each has its own lock, so connections
+/// only contend when running the same command.
+pub struct Latencies {
+    histograms: Vec<Mutex<Option<Histogram<u64>>>>,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies::new()
+    }
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies {
+            histograms: (0..=u8::MAX).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    pub fn record(&self, opcode: u8, latency: Duration) {
+        let mut histogram = self.histograms[opcode as usize].lock().unwrap();
+        histogram
+            .get_or_insert_with(|| Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).unwrap())
+            .saturating_record((latency.as_micros() as u64).clamp(1, MAX_LATENCY_US));
+    }
+
+    /// The histogram of `opcode`, in microseconds, if it ran at all.
+    pub fn histogram(&self, opcode: u8) -> Option<Histogram<u64>> {
+        self.histograms[opcode as usize].lock().unwrap().clone()
+    }
+
+    /// Count, percentiles and maximum in microseconds of each command that
+    /// ran, as `(name, value)` pairs.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let mut stats = Vec::new();
+        for opcode in 0..=u8::MAX {
+            let histogram = match self.histogram(opcode) {
+                Some(histogram) => histogram,
+                None => continue,
+            };
+            let name = match binary::Command::from_u8(opcode) {
+                Some(command) => command.name(),
+                None => format!("0x{:02x}", opcode),
+            };
+            let values = [
+                ("count", histogram.len()),
+                ("p50_us", histogram.value_at_quantile(0.5)),
+                ("p99_us", histogram.value_at_quantile(0.99)),
+                ("p999_us", histogram.value_at_quantile(0.999)),
+                ("max_us", histogram.max()),
+            ];
+            for (suffix, value) in values {
+                stats.push((format!("{}_{}", name, suffix), value.to_string()));
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_commands_that_ran() {
+        let latencies = Latencies::new();
+        for micros in 1..=100 {
+            latencies.record(
+                binary::Command::GetQuiet as u8,
+                Duration::from_micros(micros),
+            );
+        }
+        latencies.record(binary::Command::Set as u8, Duration::from_secs(120));
+
+        let stats = latencies.stats();
+        let names: Vec<_> = stats.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "set_count",
+                "set_p50_us",
+                "set_p99_us",
+                "set_p999_us",
+                "set_max_us",
+                "get_quiet_count",
+                "get_quiet_p50_us",
+                "get_quiet_p99_us",
+                "get_quiet_p999_us",
+                "get_quiet_max_us",
+            ]
+        );
+        // Clamped to a minute, give or take the histogram's precision.
+        assert_eq!(stats[4].1.parse::<u64>().unwrap() / 1_000_000, 60);
+        assert_eq!(stats[5].1, "100");
+        assert_eq!(stats[6].1, "50");
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::memcached::storage::Record;
+
+/// How long a lease is held by default, in seconds.
+pub const DEFAULT_LEASE_TTL: u64 = 10;
+
+/// What a lease get found.
+#[derive(Debug)]
+pub enum LeaseGet {
+    /// The item, `stale` if it expired but is within its grace period. The
+    /// caller holds the lease to refresh it if given a `token`.
+    Hit {
+        record: Record,
+        stale: bool,
+        token: Option<u64>,
+    },
+    /// The key is missing and the caller now holds the lease to fill it.
+    Granted(u64),
+    /// The key is missing and another client is filling it; retry shortly.
+    Wait,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Lease {
+    token: u64,
+    expires_at: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LeaseStats {
+    pub(crate) granted: AtomicU64,
+    pub(crate) waits: AtomicU64,
+    pub(crate) stale_sets: AtomicU64,
+    pub(crate) stale_hits: AtomicU64,
+    pub(crate) early_recomputes: AtomicU64,
+}
+
+/// The leases handed out on missing or stale keys of a namespace. Only one
+/// client at a time holds the lease on a key, so only it goes to the backing
+/// store while the others wait or make do with the stale value. A lease is
+/// invalidated by any write to or delete of its key, so a value read from the
+/// backing store before the key was deleted cannot be stored after it.
+pub(crate) struct Leases {
+    ttl: u64,
+    next_token: AtomicU64,
+    held: Mutex<HashMap<Vec<u8>, Lease>>,
+    /// Size of `held`, so that writes to keys nobody leased skip the lock.
+    active: AtomicUsize,
+    /// Size of `held` at which expired leases are dropped next.
+    purge_at: AtomicU64,
+    pub(crate) stats: LeaseStats,
+}
+
+impl Leases {
+    pub(crate) fn new(ttl: u64) -> Leases {
+        Leases {
+            ttl,
+            next_token: AtomicU64::new(0),
+            held: Mutex::new(HashMap::new()),
+            active: AtomicUsize::new(0),
+            purge_at: AtomicU64::new(1024),
+            stats: Default::default(),
+        }
+    }
+
+    /// Hands the lease on `key` to the caller, unless someone else holds an
+    /// unexpired one.
+    pub(crate) fn acquire(&self, key: &[u8], now: u64) -> Option<u64> {
+        let mut held = self.held.lock().unwrap();
+        if let Some(lease) = held.get(key) {
+            if lease.expires_at > now {
+                return None;
+            }
+        }
+        if held.len() as u64 >= self.purge_at.load(Ordering::Relaxed) {
+            held.retain(|_, lease| lease.expires_at > now);
+            self.purge_at
+                .store(1024.max(2 * held.len() as u64), Ordering::Relaxed);
+        }
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed) + 1;
+        let expires_at = now + self.ttl;
+        held.insert(key.to_vec(), Lease { token, expires_at });
+        self.active.store(held.len(), Ordering::Relaxed);
+        self.stats.granted.fetch_add(1, Ordering::Relaxed);
+        Some(token)
+    }
+
+    /// Takes back the lease on `key` if `token` is its current, unexpired
+    /// token; a set with it may then go ahead.
+    pub(crate) fn redeem(&self, key: &[u8], token: u64, now: u64) -> bool {
+        let mut held = self.held.lock().unwrap();
+        match held.get(key) {
+            Some(lease) if lease.token == token && lease.expires_at > now => {
+                held.remove(key);
+                self.active.store(held.len(), Ordering::Relaxed);
+                true
+            }
+            _ => {
+                self.stats.stale_sets.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Invalidates the lease on `key`, if any.
+    pub(crate) fn release(&self, key: &[u8]) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut held = self.held.lock().unwrap();
+        if held.remove(key).is_some() {
+            self.active.store(held.len(), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut held = self.held.lock().unwrap();
+        held.clear();
+        self.active.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_holder_at_a_time() {
+        let leases = Leases::new(10);
+        let token = leases.acquire(b"key", 0).unwrap();
+        assert_eq!(leases.acquire(b"key", 5), None);
+        assert!(leases.acquire(b"other", 5).is_some());
+
+        // An expired lease is handed to the next client, and the old token
+        // no longer works.
+        let renewed = leases.acquire(b"key", 10).unwrap();
+        assert!(!leases.redeem(b"key", token, 10));
+        assert!(leases.redeem(b"key", renewed, 10));
+        assert!(!leases.redeem(b"key", renewed, 10));
+
+        let token = leases.acquire(b"key", 20).unwrap();
+        leases.release(b"key");
+        assert!(!leases.redeem(b"key", token, 20));
+        assert_eq!(leases.stats.stale_sets.load(Ordering::Relaxed), 3);
+        assert_eq!(leases.active.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,5 +1,26 @@
+pub mod acl;
+pub mod aof;
+pub mod auth;
+pub mod capture;
+pub mod cluster;
+pub mod compression;
 pub mod error;
+pub mod extstore;
 pub mod handler;
+pub mod hotkeys;
+pub mod latency;
+pub mod lease;
+pub mod namespace;
+pub mod proxy;
+pub mod replication;
+pub mod scan;
 pub mod server;
+pub mod slowlog;
+pub mod snapshot;
+pub mod stats;
 pub mod storage;
+pub mod tags;
+#[cfg(test)]
+pub(crate) mod testing;
 pub mod timer;
+pub mod watch;
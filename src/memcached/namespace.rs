@@ -0,0 +1,115 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the namespace that receives every key no other namespace claims.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// A partition of the store with its own memory quota. Requests are routed to
+/// it when their key starts with one of `prefixes` or, failing that, when the
+/// connection is authenticated as one of `users`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamespaceConfig {
+    pub name: String,
+    /// Quota in bytes; 0 means unlimited.
+    pub memory_limit: usize,
+    pub users: Vec<String>,
+    pub prefixes: Vec<Vec<u8>>,
+}
+
+impl NamespaceConfig {
+    pub fn new(name: &str, memory_limit: usize) -> NamespaceConfig {
+        NamespaceConfig {
+            name: name.to_string(),
+            memory_limit,
+            users: Vec::new(),
+            prefixes: Vec::new(),
+        }
+    }
+
+    /// Loads a namespace file. Each line reads `name quota selector...` where
+    /// quota is a byte count with an optional `k`, `m` or `g` suffix and each
+    /// selector is `user:<name>` or `prefix:<key prefix>`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<NamespaceConfig>> {
+        let contents = fs::read_to_string(path)?;
+        NamespaceConfig::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Vec<NamespaceConfig>> {
+        let mut configs = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid namespace entry on line {}", number + 1),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let name = fields.next().ok_or_else(invalid)?;
+            let memory_limit = fields.next().and_then(parse_size).ok_or_else(invalid)?;
+            let mut config = NamespaceConfig::new(name, memory_limit);
+            for selector in fields {
+                match selector.split_once(':') {
+                    Some(("user", user)) if !user.is_empty() => config.users.push(user.to_string()),
+                    Some(("prefix", prefix)) if !prefix.is_empty() => {
+                        config.prefixes.push(prefix.as_bytes().to_vec())
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            configs.push(config);
+        }
+        Ok(configs)
+    }
+}
+
+/// Parses a byte count such as `4096`, `64k`, `512m` or `2g`.
+pub fn parse_size(size: &str) -> Option<usize> {
+    let lower = size.to_ascii_lowercase();
+    let (digits, multiplier) = match lower.chars().last()? {
+        'k' => (&lower[..lower.len() - 1], 1 << 10),
+        'm' => (&lower[..lower.len() - 1], 1 << 20),
+        'g' => (&lower[..lower.len() - 1], 1 << 30),
+        _ => (&lower[..], 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("lots"), None);
+    }
+
+    #[test]
+    fn parse_namespace_file() {
+        let configs = NamespaceConfig::parse(
+            "# name quota selectors\n\
+             default 1g\n\
+             team_a 64m user:alice prefix:team_a:\n",
+        )
+        .unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0], NamespaceConfig::new("default", 1 << 30));
+        assert_eq!(configs[1].memory_limit, 64 << 20);
+        assert_eq!(configs[1].users, vec!["alice".to_string()]);
+        assert_eq!(configs[1].prefixes, vec![b"team_a:".to_vec()]);
+    }
+
+    #[test]
+    fn reject_malformed_namespace_file() {
+        assert!(NamespaceConfig::parse("team_a\n").is_err());
+        assert!(NamespaceConfig::parse("team_a 1m owner:alice\n").is_err());
+    }
+}
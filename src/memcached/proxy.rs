@@ -0,0 +1,1032 @@
+use crate::memcached::cluster::HashRing;
+use crate::memcached::stats;
+use crate::protocol::binary;
+use crate::protocol::binary_codec::{
+    BinaryRequest, BinaryResponse, MemcachedBinaryClientCodec, MemcachedBinaryCodec,
+};
+use crate::protocol::text_codec::{
+    MemcachedTextCodec, StoreCommand, TextRequest, TextResponse, TextValue,
+};
+use futures_util::{SinkExt, StreamExt};
+use num_traits::FromPrimitive;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+
+/// A group of backend servers sharing one key space, spread over them with a
+/// ketama ring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub name: String,
+    /// `(address, weight)` pairs.
+    pub servers: Vec<(String, u32)>,
+    /// Further servers a request is tried on once its owner fails.
+    pub retries: usize,
+    /// How long a backend may take to answer before it counts as failed.
+    pub timeout: Duration,
+    /// How long a failed backend is skipped before it is tried again.
+    pub dead_timeout: Duration,
+}
+
+impl PoolConfig {
+    pub fn new(name: &str) -> PoolConfig {
+        PoolConfig {
+            name: name.to_string(),
+            servers: Vec::new(),
+            retries: 1,
+            timeout: Duration::from_secs(1),
+            dead_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Pools and the rules routing keys to them. A key goes to the pool of the
+/// longest route prefix it starts with, or to the default pool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    pub pools: Vec<PoolConfig>,
+    pub routes: Vec<(Vec<u8>, String)>,
+    pub default_pool: String,
+}
+
+impl ProxyConfig {
+    /// Loads a proxy file made of three kinds of line:
+    ///
+    /// ```text
+    /// pool <name> [retries=<n>] [timeout=<ms>] [dead-timeout=<ms>] <host:port>[/<weight>]...
+    /// route <key prefix> <pool>
+    /// default <pool>
+    /// ```
+    ///
+    /// Without a `default` line the first pool is the default.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ProxyConfig> {
+        ProxyConfig::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> io::Result<ProxyConfig> {
+        let mut pools: Vec<PoolConfig> = Vec::new();
+        let mut routes = Vec::new();
+        let mut default_pool = None;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid proxy entry on line {}", number + 1),
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["pool", name, settings @ ..] => {
+                    let mut pool = PoolConfig::new(name);
+                    for setting in settings {
+                        let millis = |value: &str| value.parse().map(Duration::from_millis);
+                        match setting.split_once('=') {
+                            Some(("retries", value)) => {
+                                pool.retries = value.parse().map_err(|_| invalid())?
+                            }
+                            Some(("timeout", value)) => {
+                                pool.timeout = millis(value).map_err(|_| invalid())?
+                            }
+                            Some(("dead-timeout", value)) => {
+                                pool.dead_timeout = millis(value).map_err(|_| invalid())?
+                            }
+                            Some(_) => return Err(invalid()),
+                            None => {
+                                let server = match setting.split_once('/') {
+                                    Some((address, weight)) => {
+                                        (address, weight.parse().map_err(|_| invalid())?)
+                                    }
+                                    None => (*setting, 1),
+                                };
+                                pool.servers.push((server.0.to_string(), server.1));
+                            }
+                        }
+                    }
+                    if pool.servers.is_empty() || pools.iter().any(|other| other.name == *name) {
+                        return Err(invalid());
+                    }
+                    pools.push(pool);
+                }
+                ["route", prefix, pool] => {
+                    routes.push((prefix.as_bytes().to_vec(), pool.to_string()))
+                }
+                ["default", pool] => default_pool = Some(pool.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+        let default_pool =
+            match default_pool.or_else(|| pools.first().map(|pool| pool.name.clone())) {
+                Some(pool) => pool,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "proxy configuration has no pools",
+                    ))
+                }
+            };
+        let known = |name: &String| pools.iter().any(|pool| pool.name == *name);
+        if let Some(name) = routes
+            .iter()
+            .map(|(_, pool)| pool)
+            .chain(Some(&default_pool))
+            .find(|name| !known(name))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("route to unknown pool {:?}", name),
+            ));
+        }
+        Ok(ProxyConfig {
+            pools,
+            routes,
+            default_pool,
+        })
+    }
+}
+
+/// Counters kept for each pool.
+#[derive(Debug, Default)]
+struct PoolStats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    retries: AtomicU64,
+    failovers: AtomicU64,
+    ejections: AtomicU64,
+}
+
+type Call = (BinaryRequest, oneshot::Sender<BinaryResponse>);
+
+/// One backend server. Every client connection of the proxy shares a single
+/// pipelined connection to it, with responses matched to requests in order.
+struct Backend {
+    address: String,
+    connection: tokio::sync::Mutex<Option<mpsc::UnboundedSender<Call>>>,
+    dead_until: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    fn new(address: &str) -> Backend {
+        Backend {
+            address: address.to_string(),
+            connection: tokio::sync::Mutex::new(None),
+            dead_until: Mutex::new(None),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match *self.dead_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    fn eject(&self, dead_timeout: Duration) {
+        *self.dead_until.lock().unwrap() = Some(Instant::now() + dead_timeout);
+    }
+
+    async fn call(&self, request: BinaryRequest, timeout: Duration) -> io::Result<BinaryResponse> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "backend connection closed");
+        let sender = tokio::time::timeout(timeout, self.connect())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "backend connect timed out"))??;
+        let (tx, rx) = oneshot::channel();
+        sender.send((request, tx)).map_err(|_| closed())?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(response) => response.map_err(|_| closed()),
+            Err(_) => {
+                // Later responses would be matched to the wrong requests.
+                self.connection.lock().await.take();
+                Err(io::Error::new(io::ErrorKind::TimedOut, "backend timed out"))
+            }
+        }
+    }
+
+    async fn connect(&self) -> io::Result<mpsc::UnboundedSender<Call>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(sender) = connection.as_ref().filter(|sender| !sender.is_closed()) {
+            return Ok(sender.clone());
+        }
+        let socket = TcpStream::connect(&self.address).await?;
+        socket.set_nodelay(true)?;
+        let (sender, calls) = mpsc::unbounded_channel();
+        tokio::spawn(Backend::pump(socket, calls));
+        *connection = Some(sender.clone());
+        Ok(sender)
+    }
+
+    /// Writes calls to the backend and hands each response to the oldest
+    /// waiting call, until either direction fails or the backend is
+    /// disconnected. Calls still waiting then see their channel close.
+    async fn pump(socket: TcpStream, mut calls: mpsc::UnboundedReceiver<Call>) {
+        let (mut sink, mut stream) = Framed::new(socket, MemcachedBinaryClientCodec::new()).split();
+        let waiting = Mutex::new(VecDeque::new());
+        let write = async {
+            while let Some((request, tx)) = calls.recv().await {
+                waiting.lock().unwrap().push_back(tx);
+                if sink.send(request).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let read = async {
+            while let Some(Ok(response)) = stream.next().await {
+                match waiting.lock().unwrap().pop_front() {
+                    Some(tx) => {
+                        let _ = tx.send(response);
+                    }
+                    None => break,
+                }
+            }
+        };
+        tokio::select! {
+            _ = write => {}
+            _ = read => {}
+        }
+    }
+}
+
+/// A pool of backends, ready to serve requests.
+pub struct Pool {
+    name: String,
+    ring: HashRing,
+    backends: Vec<Backend>,
+    retries: usize,
+    timeout: Duration,
+    dead_timeout: Duration,
+    stats: PoolStats,
+}
+
+impl Pool {
+    pub fn new(config: &PoolConfig) -> Pool {
+        Pool {
+            name: config.name.clone(),
+            ring: HashRing::new(&config.servers),
+            backends: config
+                .servers
+                .iter()
+                .map(|(address, _)| Backend::new(address))
+                .collect(),
+            retries: config.retries,
+            timeout: config.timeout,
+            dead_timeout: config.dead_timeout,
+            stats: PoolStats::default(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends `request` to the live backend owning `key`. When it fails, the
+    /// backend is ejected and up to `retries` of the following live backends
+    /// on the ring are tried in turn.
+    pub async fn call(&self, key: &[u8], request: BinaryRequest) -> io::Result<BinaryResponse> {
+        stats::incr(&self.stats.requests);
+        let mut attempts = 0;
+        let mut last_error = None;
+        for (rank, index) in self.ring.successors(key).into_iter().enumerate() {
+            let backend = &self.backends[index];
+            if !backend.is_alive() {
+                continue;
+            }
+            if attempts > self.retries {
+                break;
+            }
+            if attempts > 0 {
+                stats::incr(&self.stats.retries);
+            }
+            attempts += 1;
+            match backend.call(request.clone(), self.timeout).await {
+                Ok(response) => {
+                    if rank > 0 {
+                        stats::incr(&self.stats.failovers);
+                    }
+                    return Ok(response);
+                }
+                Err(err) => {
+                    warn!(
+                        "Backend {} of pool {} failed: {}",
+                        backend.address, self.name, err
+                    );
+                    stats::incr(&self.stats.errors);
+                    stats::incr(&self.stats.ejections);
+                    backend.eject(self.dead_timeout);
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("no live backend in pool {}", self.name),
+            )
+        }))
+    }
+
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let live = self
+            .backends
+            .iter()
+            .filter(|backend| backend.is_alive())
+            .count();
+        let counters = [
+            ("requests", &self.stats.requests),
+            ("errors", &self.stats.errors),
+            ("retries", &self.stats.retries),
+            ("failovers", &self.stats.failovers),
+            ("ejections", &self.stats.ejections),
+        ];
+        let mut stats = vec![
+            (
+                format!("pool:{}:servers", self.name),
+                self.backends.len().to_string(),
+            ),
+            (format!("pool:{}:live_servers", self.name), live.to_string()),
+        ];
+        stats.extend(counters.iter().map(|(name, counter)| {
+            (
+                format!("pool:{}:{}", self.name, name),
+                counter.load(Ordering::Relaxed).to_string(),
+            )
+        }));
+        stats
+    }
+}
+
+/// Routes keys to pools by prefix.
+pub struct Router {
+    pools: Vec<Arc<Pool>>,
+    routes: Vec<(Vec<u8>, usize)>,
+    default_pool: usize,
+}
+
+impl Router {
+    pub fn new(config: &ProxyConfig) -> Router {
+        let index = |name: &str| {
+            config
+                .pools
+                .iter()
+                .position(|pool| pool.name == name)
+                .unwrap_or_else(|| panic!("route to unknown pool {:?}", name))
+        };
+        let mut routes: Vec<(Vec<u8>, usize)> = config
+            .routes
+            .iter()
+            .map(|(prefix, pool)| (prefix.clone(), index(pool)))
+            .collect();
+        // Longest prefixes first, so the first match is the most specific.
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Router {
+            pools: config
+                .pools
+                .iter()
+                .map(|pool| Arc::new(Pool::new(pool)))
+                .collect(),
+            routes,
+            default_pool: index(&config.default_pool),
+        }
+    }
+
+    pub fn route(&self, key: &[u8]) -> &Arc<Pool> {
+        let index = self
+            .routes
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix))
+            .map_or(self.default_pool, |(_, index)| *index);
+        &self.pools[index]
+    }
+
+    pub fn pools(&self) -> &[Arc<Pool>] {
+        &self.pools
+    }
+}
+
+/// Handles the requests of one proxy client. Quiet gets are held back until
+/// the next other request, usually the noop ending a multi-get, and then
+/// fanned out to their backends at once.
+pub struct ProxyHandler {
+    router: Arc<Router>,
+    quiet_gets: Vec<BinaryRequest>,
+}
+
+impl ProxyHandler {
+    pub fn new(router: Arc<Router>) -> ProxyHandler {
+        ProxyHandler {
+            router,
+            quiet_gets: Vec::new(),
+        }
+    }
+
+    pub async fn handle_request(&mut self, req: BinaryRequest) -> Vec<BinaryResponse> {
+        if let BinaryRequest::GetQuietly(_) | BinaryRequest::GetKeyQuietly(_) = req {
+            self.quiet_gets.push(req);
+            return Vec::new();
+        }
+        let mut responses = self.flush().await;
+        let request_header = *req.get_header();
+        let mut response_header =
+            binary::ResponseHeader::new(request_header.opcode, request_header.opaque);
+        let response = match req {
+            BinaryRequest::Get(_) | BinaryRequest::GetKey(_) => {
+                ProxyHandler::get(&self.router, req).await
+            }
+            BinaryRequest::Noop(_) => Some(BinaryResponse::Noop(binary::NoopResponse {
+                header: response_header,
+            })),
+            BinaryRequest::Version(_) => Some(BinaryResponse::Version(binary::VersionResponse {
+                header: response_header,
+                value: env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+            })),
+            BinaryRequest::Invalid(invalid_req) => {
+                response_header.status = invalid_req.status;
+                Some(BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                }))
+            }
+            _ if req.key().is_some() => ProxyHandler::forward(&self.router, req).await,
+            BinaryRequest::Stat(stat_req) => {
+                let stats = match stat_req.key.as_slice() {
+                    b"" | b"proxy" => self
+                        .router
+                        .pools()
+                        .iter()
+                        .flat_map(|pool| pool.stats())
+                        .collect(),
+                    _ => {
+                        response_header.status = binary::ResponseStatus::KeyNotExists as u16;
+                        Vec::new()
+                    }
+                };
+                Some(BinaryResponse::Stat(binary::StatResponse {
+                    header: response_header,
+                    stats,
+                }))
+            }
+            _ => {
+                response_header.status = binary::ResponseStatus::NotSupported as u16;
+                Some(BinaryResponse::Error(binary::ErrorResponse {
+                    header: response_header,
+                }))
+            }
+        };
+        responses.extend(response);
+        responses
+    }
+
+    /// Fans the held back quiet gets out to their pools and returns the hits
+    /// in request order.
+    async fn flush(&mut self) -> Vec<BinaryResponse> {
+        let router = &self.router;
+        let gets = self
+            .quiet_gets
+            .drain(..)
+            .map(|req| ProxyHandler::get(router, req));
+        futures::future::join_all(gets)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Serves any get variant with a plain get to the backend, which every
+    /// memcached server answers the same way, and shapes the answer into the
+    /// variant the client asked for.
+    async fn get(router: &Router, req: BinaryRequest) -> Option<BinaryResponse> {
+        let request_header = *req.get_header();
+        let command = FromPrimitive::from_u8(request_header.opcode);
+        let quiet = matches!(
+            command,
+            Some(binary::Command::GetQuiet) | Some(binary::Command::GetKeyQuiet)
+        );
+        let key = match req {
+            BinaryRequest::Get(get_req)
+            | BinaryRequest::GetQuietly(get_req)
+            | BinaryRequest::GetKey(get_req)
+            | BinaryRequest::GetKeyQuietly(get_req) => get_req.key,
+            _ => unreachable!("not a get request"),
+        };
+        let backend_req = BinaryRequest::Get(binary::GetRequest {
+            header: binary::RequestHeader {
+                opcode: binary::Command::Get as u8,
+                ..request_header
+            },
+            key: key.clone(),
+        });
+        let response_header =
+            binary::ResponseHeader::new(request_header.opcode, request_header.opaque);
+        match router.route(&key).call(&key, backend_req).await {
+            Ok(BinaryResponse::Get(mut response)) => {
+                response.header.opcode = request_header.opcode;
+                Some(match command {
+                    Some(binary::Command::GetQuiet) => BinaryResponse::GetQuietly(response),
+                    Some(binary::Command::GetKey) => {
+                        BinaryResponse::GetKey(binary::GetKeyResponse { key, ..response })
+                    }
+                    Some(binary::Command::GetKeyQuiet) => {
+                        BinaryResponse::GetKeyQuietly(binary::GetKeyQuietlyResponse {
+                            key,
+                            ..response
+                        })
+                    }
+                    _ => BinaryResponse::Get(response),
+                })
+            }
+            _ if quiet => None,
+            Ok(mut response) => {
+                response.get_header_mut().opcode = request_header.opcode;
+                Some(response)
+            }
+            Err(_) => Some(ProxyHandler::error(response_header)),
+        }
+    }
+
+    /// Relays a keyed request to its backend. Quiet requests are sent in
+    /// their loud form, since backend connections match every request to a
+    /// response, and the answer is dropped if the client expects none.
+    async fn forward(router: &Router, mut req: BinaryRequest) -> Option<BinaryResponse> {
+        let request_header = *req.get_header();
+        let command: Option<binary::Command> = FromPrimitive::from_u8(request_header.opcode);
+        if let Some(command) = command {
+            req.get_header_mut().opcode = command.loud() as u8;
+        }
+        let key = req.key().unwrap_or_default().to_vec();
+        let response_header =
+            binary::ResponseHeader::new(request_header.opcode, request_header.opaque);
+        let mut response = match router.route(&key).call(&key, req).await {
+            Ok(response) => response,
+            Err(_) => return Some(ProxyHandler::error(response_header)),
+        };
+        response.get_header_mut().opcode = request_header.opcode;
+        match command {
+            Some(command) if !command.answers(response.get_header().status) => None,
+            _ => Some(response),
+        }
+    }
+
+    /// Serves a text protocol request by translating it into the binary
+    /// requests the backends speak. Requests are always sent in their loud
+    /// form; the answer to a `noreply` request is dropped here.
+    pub async fn handle_text_request(&mut self, req: TextRequest) -> Option<TextResponse> {
+        let header = |command: binary::Command, cas: u64| binary::RequestHeader {
+            magic: binary::Magic::Request as u8,
+            opcode: command as u8,
+            cas,
+            ..binary::RequestHeader::default()
+        };
+        let line = |line: &str| Some(TextResponse::Line(line.to_string()));
+        let (response, noreply, not_found, exists) = match req {
+            TextRequest::Get { keys, cas } => {
+                let router = &self.router;
+                let gets = keys.into_iter().map(|key| {
+                    let req = BinaryRequest::Get(binary::GetRequest {
+                        header: header(binary::Command::Get, 0),
+                        key: key.clone(),
+                    });
+                    async move { (key, ProxyHandler::get(router, req).await) }
+                });
+                let values = futures::future::join_all(gets)
+                    .await
+                    .into_iter()
+                    .filter_map(|(key, response)| match response {
+                        Some(BinaryResponse::Get(response))
+                            if response.header.status == binary::ResponseStatus::Success as u16 =>
+                        {
+                            Some(TextValue {
+                                key,
+                                flags: response.flags,
+                                cas: if cas { Some(response.header.cas) } else { None },
+                                value: response.value,
+                            })
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                return Some(TextResponse::Values(values));
+            }
+            TextRequest::Store(store_req) => {
+                let (command, cas) = match store_req.command {
+                    StoreCommand::Set => (binary::Command::Set, 0),
+                    StoreCommand::Add => (binary::Command::Add, 0),
+                    StoreCommand::Replace => (binary::Command::Replace, 0),
+                    StoreCommand::Cas => (binary::Command::Set, store_req.cas),
+                    StoreCommand::Append => (binary::Command::Append, 0),
+                    StoreCommand::Prepend => (binary::Command::Prepend, 0),
+                };
+                let req = match store_req.command {
+                    // The flags and expiry given to append and prepend are
+                    // ignored, as by memcached.
+                    StoreCommand::Append | StoreCommand::Prepend => {
+                        let append_req = binary::AppendRequest {
+                            header: header(command, cas),
+                            key: store_req.key,
+                            value: store_req.value,
+                        };
+                        match store_req.command {
+                            StoreCommand::Prepend => BinaryRequest::Prepend(append_req),
+                            _ => BinaryRequest::Append(append_req),
+                        }
+                    }
+                    _ => {
+                        let set_req = binary::SetRequest {
+                            header: header(command, cas),
+                            flags: store_req.flags,
+                            expiration: store_req.expiration,
+                            grace: 0,
+                            recompute_ms: 0,
+                            key: store_req.key,
+                            value: store_req.value,
+                        };
+                        match store_req.command {
+                            StoreCommand::Add => BinaryRequest::Add(set_req),
+                            StoreCommand::Replace => BinaryRequest::Replace(set_req),
+                            _ => BinaryRequest::Set(set_req),
+                        }
+                    }
+                };
+                let (not_found, exists) = match store_req.command {
+                    StoreCommand::Cas => ("NOT_FOUND", "EXISTS"),
+                    _ => ("NOT_STORED", "NOT_STORED"),
+                };
+                let response = ProxyHandler::forward(&self.router, req).await;
+                (response, store_req.noreply, not_found, exists)
+            }
+            TextRequest::Delete { key, noreply } => {
+                let req = BinaryRequest::Delete(binary::DeleteRequest {
+                    header: header(binary::Command::Delete, 0),
+                    key,
+                });
+                let response = ProxyHandler::forward(&self.router, req).await;
+                (response, noreply, "NOT_FOUND", "EXISTS")
+            }
+            TextRequest::Delta {
+                key,
+                delta,
+                increment,
+                noreply,
+            } => {
+                let command = if increment {
+                    binary::Command::Increment
+                } else {
+                    binary::Command::Decrement
+                };
+                let delta_req = binary::IncrementRequest {
+                    header: header(command, 0),
+                    delta,
+                    initial: 0,
+                    // The text protocol never creates a missing counter.
+                    expiration: u32::MAX,
+                    key,
+                };
+                let req = if increment {
+                    BinaryRequest::Increment(delta_req)
+                } else {
+                    BinaryRequest::Decrement(delta_req)
+                };
+                let response = ProxyHandler::forward(&self.router, req).await;
+                (response, noreply, "NOT_FOUND", "EXISTS")
+            }
+            TextRequest::Touch {
+                key,
+                expiration,
+                noreply,
+            } => {
+                let req = BinaryRequest::Touch(binary::TouchRequest {
+                    header: header(binary::Command::Touch, 0),
+                    expiration,
+                    key,
+                });
+                let response = ProxyHandler::forward(&self.router, req).await;
+                (response, noreply, "NOT_FOUND", "EXISTS")
+            }
+            TextRequest::Stats { group } => {
+                return match group.as_slice() {
+                    b"" | b"proxy" => Some(TextResponse::Stats(
+                        self.router
+                            .pools()
+                            .iter()
+                            .flat_map(|pool| pool.stats())
+                            .collect(),
+                    )),
+                    _ => line("ERROR"),
+                };
+            }
+            TextRequest::Version => {
+                return line(&format!("VERSION {}", env!("CARGO_PKG_VERSION")));
+            }
+            TextRequest::Quit => return None,
+            TextRequest::Invalid { error } => return Some(TextResponse::Line(error)),
+        };
+        if noreply {
+            return None;
+        }
+        let response = response?;
+        let status = FromPrimitive::from_u16(response.get_header().status);
+        match (status, response) {
+            (Some(binary::ResponseStatus::Success), BinaryResponse::Increment(response))
+            | (Some(binary::ResponseStatus::Success), BinaryResponse::Decrement(response)) => {
+                line(&response.value.to_string())
+            }
+            (Some(binary::ResponseStatus::Success), BinaryResponse::Delete(_)) => line("DELETED"),
+            (Some(binary::ResponseStatus::Success), BinaryResponse::Touch(_)) => line("TOUCHED"),
+            (Some(binary::ResponseStatus::Success), _) => line("STORED"),
+            (Some(binary::ResponseStatus::KeyNotExists), _) => line(not_found),
+            (Some(binary::ResponseStatus::KeyExists), _) => line(exists),
+            (Some(binary::ResponseStatus::NotStored), _) => line("NOT_STORED"),
+            (Some(binary::ResponseStatus::NonNumericValue), _) => {
+                line("CLIENT_ERROR cannot increment or decrement non-numeric value")
+            }
+            (Some(binary::ResponseStatus::TooBig), _) => {
+                line("SERVER_ERROR object too large for cache")
+            }
+            _ => line("SERVER_ERROR backend failure"),
+        }
+    }
+
+    fn error(mut response_header: binary::ResponseHeader) -> BinaryResponse {
+        response_header.status = binary::ResponseStatus::InternalError as u16;
+        BinaryResponse::Error(binary::ErrorResponse {
+            header: response_header,
+        })
+    }
+}
+
+/// Runs rustcache as a proxy: clients talk the binary or text protocol to it
+/// as to any server and it routes their requests to backend pools.
+pub struct ProxyServer {
+    router: Arc<Router>,
+}
+
+impl ProxyServer {
+    pub fn new(config: &ProxyConfig) -> ProxyServer {
+        ProxyServer {
+            router: Arc::new(Router::new(config)),
+        }
+    }
+
+    pub fn router(&self) -> Arc<Router> {
+        self.router.clone()
+    }
+
+    pub async fn run<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        loop {
+            match listener.accept().await {
+                Ok((socket, peer_addr)) => {
+                    let router = self.router.clone();
+                    println!("Incoming proxy connection: {}", peer_addr);
+
+                    tokio::spawn(async move {
+                        let handler = ProxyHandler::new(router);
+                        // Binary requests start with their magic byte, which no
+                        // text command does.
+                        let mut first = [0u8; 1];
+                        let result = match socket.peek(&mut first).await {
+                            Ok(0) => return,
+                            Ok(_) if first[0] == binary::Magic::Request as u8 => {
+                                ProxyServer::serve_binary(socket, handler).await
+                            }
+                            Ok(_) => ProxyServer::serve_text(socket, handler).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = result {
+                            println!("error on proxy connection {}; error = {:?}", peer_addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    println!("error on accepting connection; error = {:?}", e);
+                }
+            }
+        }
+    }
+
+    async fn serve_binary(mut socket: TcpStream, mut handler: ProxyHandler) -> io::Result<()> {
+        let (rx, tx) = socket.split();
+        let mut reader = FramedRead::new(rx, MemcachedBinaryCodec::new());
+        let mut writer = FramedWrite::new(tx, MemcachedBinaryCodec::new());
+        while let Some(request) = reader.next().await {
+            for response in handler.handle_request(request?).await {
+                writer.feed(response).await?;
+            }
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn serve_text(mut socket: TcpStream, mut handler: ProxyHandler) -> io::Result<()> {
+        let (rx, tx) = socket.split();
+        let mut reader = FramedRead::new(rx, MemcachedTextCodec::new());
+        let mut writer = FramedWrite::new(tx, MemcachedTextCodec::new());
+        while let Some(request) = reader.next().await {
+            let request = request?;
+            if request == TextRequest::Quit {
+                break;
+            }
+            if let Some(response) = handler.handle_text_request(request).await {
+                writer.send(response).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::testing::{free_address, start_server};
+
+    fn header(command: binary::Command, opaque: u32) -> binary::RequestHeader {
+        binary::RequestHeader {
+            opcode: command as u8,
+            opaque,
+            ..binary::RequestHeader::default()
+        }
+    }
+
+    fn set(key: &str) -> BinaryRequest {
+        BinaryRequest::Set(binary::SetRequest {
+            header: header(binary::Command::Set, 0),
+            flags: 0,
+            expiration: 0,
+            grace: 0,
+            recompute_ms: 0,
+            key: key.as_bytes().to_vec(),
+            value: format!("value of {}", key).into_bytes(),
+        })
+    }
+
+    fn get(command: binary::Command, key: &str, opaque: u32) -> BinaryRequest {
+        let get_req = binary::GetRequest {
+            header: header(command, opaque),
+            key: key.as_bytes().to_vec(),
+        };
+        match command {
+            binary::Command::GetKeyQuiet => BinaryRequest::GetKeyQuietly(get_req),
+            binary::Command::GetKey => BinaryRequest::GetKey(get_req),
+            _ => BinaryRequest::Get(get_req),
+        }
+    }
+
+    #[test]
+    fn parse_proxy_file() {
+        let config = ProxyConfig::parse(
+            "# pools\n\
+             pool main a:1 b:2/3\n\
+             pool sessions retries=2 timeout=50 dead-timeout=100 c:3\n\
+             route session: sessions\n",
+        )
+        .unwrap();
+        assert_eq!(config.pools.len(), 2);
+        assert_eq!(
+            config.pools[0].servers,
+            vec![("a:1".to_string(), 1), ("b:2".to_string(), 3)]
+        );
+        assert_eq!(config.pools[1].retries, 2);
+        assert_eq!(config.pools[1].timeout, Duration::from_millis(50));
+        assert_eq!(config.pools[1].dead_timeout, Duration::from_millis(100));
+        assert_eq!(config.default_pool, "main");
+
+        let router = Router::new(&config);
+        assert_eq!(router.route(b"session:1").name(), "sessions");
+        assert_eq!(router.route(b"user:1").name(), "main");
+
+        assert!(ProxyConfig::parse("").is_err());
+        assert!(ProxyConfig::parse("pool main\n").is_err());
+        assert!(ProxyConfig::parse("pool main a:1\nroute x: other\n").is_err());
+        assert!(ProxyConfig::parse("pool main colour=blue a:1\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn multi_gets_fan_out_across_the_pool() {
+        let backends = [start_server().await, start_server().await];
+        let mut pool = PoolConfig::new("main");
+        pool.servers = backends
+            .iter()
+            .map(|(address, _)| (address.clone(), 1))
+            .collect();
+        let config = ProxyConfig {
+            pools: vec![pool.clone()],
+            routes: Vec::new(),
+            default_pool: "main".to_string(),
+        };
+        let mut handler = ProxyHandler::new(Arc::new(Router::new(&config)));
+
+        let keys: Vec<String> = (0..20).map(|i| format!("key:{}", i)).collect();
+        for key in &keys {
+            let responses = handler.handle_request(set(key)).await;
+            assert!(matches!(responses.as_slice(), [BinaryResponse::Set(_)]));
+        }
+        let ring = HashRing::new(&pool.servers);
+        for key in &keys {
+            let owner = ring.node_index(key.as_bytes()).unwrap();
+            assert!(backends[owner].1.get(&key.as_bytes().to_vec()).is_ok());
+        }
+        assert!(backends.iter().all(|(_, storage)| !storage.is_empty()));
+
+        for (opaque, key) in keys.iter().chain(Some(&"missing".to_string())).enumerate() {
+            let req = get(binary::Command::GetKeyQuiet, key, opaque as u32);
+            assert!(handler.handle_request(req).await.is_empty());
+        }
+        let noop = BinaryRequest::Noop(binary::NoopRequest {
+            header: header(binary::Command::Noop, 99),
+        });
+        let responses = handler.handle_request(noop).await;
+        assert_eq!(responses.len(), keys.len() + 1);
+        for (opaque, (key, response)) in keys.iter().zip(&responses).enumerate() {
+            match response {
+                BinaryResponse::GetKeyQuietly(response) => {
+                    assert_eq!(response.header.opaque, opaque as u32);
+                    assert_eq!(response.key, key.as_bytes());
+                    assert_eq!(response.value, format!("value of {}", key).into_bytes());
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert!(matches!(responses.last(), Some(BinaryResponse::Noop(_))));
+    }
+
+    #[tokio::test]
+    async fn failed_backends_are_ejected_and_failed_over() {
+        let (live, storage) = start_server().await;
+        let mut pool = PoolConfig::new("main");
+        pool.servers = vec![(free_address(), 1), (live, 1)];
+        let config = ProxyConfig {
+            pools: vec![pool],
+            routes: Vec::new(),
+            default_pool: "main".to_string(),
+        };
+        let router = Arc::new(Router::new(&config));
+        let mut handler = ProxyHandler::new(router.clone());
+
+        for i in 0..20 {
+            let key = format!("key:{}", i);
+            let responses = handler.handle_request(set(&key)).await;
+            assert!(matches!(responses.as_slice(), [BinaryResponse::Set(_)]));
+            let responses = handler
+                .handle_request(get(binary::Command::Get, &key, i))
+                .await;
+            assert!(matches!(responses.as_slice(), [BinaryResponse::Get(_)]));
+        }
+        assert_eq!(storage.len(), 20);
+
+        let stats: std::collections::HashMap<_, _> =
+            router.pools()[0].stats().into_iter().collect();
+        assert_eq!(stats["pool:main:live_servers"], "1");
+        assert_eq!(stats["pool:main:ejections"], "1");
+        assert_eq!(stats["pool:main:requests"], "40");
+        assert_ne!(stats["pool:main:failovers"], "0");
+    }
+
+    #[tokio::test]
+    async fn text_clients_are_served() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (backend, _) = start_server().await;
+        let mut pool = PoolConfig::new("main");
+        pool.servers = vec![(backend, 1)];
+        let proxy = ProxyServer::new(&ProxyConfig {
+            pools: vec![pool],
+            routes: Vec::new(),
+            default_pool: "main".to_string(),
+        });
+        let address = free_address();
+        let run_address = address.clone();
+        tokio::spawn(async move { proxy.run(run_address).await });
+        let mut socket = None;
+        for _ in 0..100 {
+            if let Ok(connected) = TcpStream::connect(&address).await {
+                socket = Some(connected);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut socket = socket.unwrap();
+
+        socket
+            .write_all(
+                b"set a 7 0 2\r\nhi\r\nadd a 0 0 1\r\nx\r\nappend a 0 0 1\r\n!\r\n\
+                  prepend a 0 0 1\r\n>\r\nappend missing 0 0 1\r\nx\r\nset n 0 0 1 noreply\r\n5\r\n\
+                  incr n 3\r\nincr missing 1\r\nget a n missing\r\ndelete a\r\nbogus\r\nquit\r\n",
+            )
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        socket.read_to_end(&mut output).await.unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "STORED\r\nNOT_STORED\r\nSTORED\r\nSTORED\r\nNOT_STORED\r\n8\r\nNOT_FOUND\r\n\
+             VALUE a 7 4\r\n>hi!\r\nVALUE n 0 1\r\n8\r\nEND\r\nDELETED\r\nERROR\r\n"
+        );
+    }
+}
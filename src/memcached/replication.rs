@@ -0,0 +1,571 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::memcached::aof::{self, ReplaySummary};
+use crate::memcached::snapshot;
+use crate::memcached::stats;
+use crate::memcached::storage::{Mutation, MutationObserver, Storage};
+use crate::memcached::{acl, auth};
+
+const MAGIC: &[u8; 4] = b"RCRP";
+const VERSION: u8 = 3;
+const HANDSHAKE_LEN: usize = 4 + 1 + 8 + 8;
+
+const SYNC_FULL: u8 = 1;
+const SYNC_CONTINUE: u8 = 2;
+const SYNC_DENIED: u8 = 3;
+
+/// Largest entry a replica accepts, far above any single mutation.
+const MAX_ENTRY_LEN: usize = 64 << 20;
+
+/// Sequence number of a heartbeat frame, which carries no entry.
+const HEARTBEAT_SEQ: u64 = 0;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Replicas reconnect after this long without hearing from the primary.
+const LINK_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Bytes of entries kept for replicas that reconnect.
+const DEFAULT_BACKLOG_SIZE: usize = 16 << 20;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Whether a replica presenting the PLAIN message `credentials` may follow
+/// the store, which takes read access to every key.
+fn is_authorized(
+    credentials: &[u8],
+    authenticator: Option<&auth::Authenticator>,
+    acl: Option<&acl::Acl>,
+) -> bool {
+    let authenticator = match authenticator {
+        Some(authenticator) => authenticator,
+        None => return true,
+    };
+    let user = match authenticator.authenticate(auth::MECHANISMS.as_bytes(), credentials) {
+        Ok(user) => user,
+        Err(_) => return false,
+    };
+    acl.is_none_or(|acl| acl.is_allowed(Some(&user), b"", acl::Access::Read))
+}
+
+/// Reads `len` bytes, growing the buffer as they arrive rather than trusting
+/// `len` up front.
+async fn read_bytes<R: AsyncRead + Unpin>(reader: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf).await?;
+    if buf.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "replication stream ended early",
+        ));
+    }
+    Ok(buf)
+}
+
+struct Backlog {
+    entries: VecDeque<(u64, Arc<[u8]>)>,
+    bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct PrimaryStats {
+    connected_replicas: AtomicU64,
+    full_syncs: AtomicU64,
+    partial_syncs: AtomicU64,
+}
+
+/// The primary side of replication: an ordered log of every mutation of the
+/// store, served to replicas over TCP.
+///
+/// A replica opens with `MAGIC`, `VERSION`, the run id of the primary it last
+/// followed, the sequence number it last applied and a `[u16 length]` SASL
+/// PLAIN message. When the server requires authentication, replicas whose
+/// credentials fail, or whose user may not read every key, are answered
+/// `SYNC_DENIED` and disconnected. If the run id is ours
+/// and the entries after the sequence number are still in the backlog, the
+/// primary answers `SYNC_CONTINUE`; otherwise it answers `SYNC_FULL` with its
+/// run id, the sequence number and length of a snapshot, then the snapshot.
+/// Either way it goes on to stream `[u64 seq][u32 length][u32 crc32][entry]`
+/// frames, using append-only log entries, and heartbeats with sequence 0.
+///
+/// The snapshot is taken after its sequence number is read, so it may already
+/// contain some of the entries that follow. Entries carry the resulting state
+/// of a key, so applying those again is harmless.
+pub struct ReplicationLog {
+    run_id: u64,
+    backlog_size: usize,
+    backlog: Mutex<Backlog>,
+    last_seq: watch::Sender<u64>,
+    stats: PrimaryStats,
+}
+
+impl Default for ReplicationLog {
+    fn default() -> Self {
+        ReplicationLog::new()
+    }
+}
+
+impl ReplicationLog {
+    pub fn new() -> ReplicationLog {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        ReplicationLog {
+            // Never zero, which is what a replica that followed nobody sends.
+            run_id: (nanos ^ ((std::process::id() as u64) << 32)) | 1,
+            backlog_size: DEFAULT_BACKLOG_SIZE,
+            backlog: Mutex::new(Backlog {
+                entries: VecDeque::new(),
+                bytes: 0,
+            }),
+            last_seq: watch::channel(0).0,
+            stats: Default::default(),
+        }
+    }
+
+    /// Sets how many bytes of entries are kept for reconnecting replicas.
+    pub fn with_backlog_size(mut self, backlog_size: usize) -> Self {
+        self.backlog_size = backlog_size;
+        self
+    }
+
+    pub fn run_id(&self) -> u64 {
+        self.run_id
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        *self.last_seq.borrow()
+    }
+
+    /// Entries after `seq`, or `None` if some of them left the backlog.
+    fn entries_after(&self, seq: u64) -> Option<Vec<(u64, Arc<[u8]>)>> {
+        let backlog = self.backlog.lock().unwrap();
+        let last_seq = self.last_seq();
+        if seq > last_seq {
+            return None;
+        }
+        let first_seq = backlog
+            .entries
+            .front()
+            .map_or(last_seq + 1, |(seq, _)| *seq);
+        if seq + 1 < first_seq {
+            return None;
+        }
+        let skip = (seq + 1 - first_seq) as usize;
+        Some(backlog.entries.iter().skip(skip).cloned().collect())
+    }
+
+    /// Accepts replicas on `listener` until it fails, holding them to the
+    /// same credentials and ACL as clients when those are configured.
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        storage: Arc<Storage>,
+        authenticator: Option<Arc<auth::Authenticator>>,
+        acl: Option<Arc<acl::Acl>>,
+    ) -> io::Result<()> {
+        loop {
+            let (socket, peer_addr) = listener.accept().await?;
+            info!("Replica connected: {}", peer_addr);
+            let log = self.clone();
+            let storage = storage.clone();
+            let authenticator = authenticator.clone();
+            let acl = acl.clone();
+            tokio::spawn(async move {
+                log.stats.connected_replicas.fetch_add(1, Ordering::Relaxed);
+                let result = log
+                    .sync_replica(socket, storage, authenticator.as_deref(), acl.as_deref())
+                    .await;
+                if let Err(err) = result {
+                    info!("Replica {} disconnected: {}", peer_addr, err);
+                }
+                log.stats.connected_replicas.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+
+    async fn sync_replica(
+        &self,
+        socket: TcpStream,
+        storage: Arc<Storage>,
+        authenticator: Option<&auth::Authenticator>,
+        acl: Option<&acl::Acl>,
+    ) -> io::Result<()> {
+        socket.set_nodelay(true)?;
+        let (mut reader, writer) = socket.into_split();
+        let mut writer = BufWriter::new(writer);
+        let mut handshake = [0; HANDSHAKE_LEN];
+        reader.read_exact(&mut handshake).await?;
+        if &handshake[0..4] != MAGIC || handshake[4] != VERSION {
+            return Err(invalid_data("invalid replication handshake"));
+        }
+        let mut credentials = vec![0; reader.read_u16().await? as usize];
+        reader.read_exact(&mut credentials).await?;
+        if !is_authorized(&credentials, authenticator, acl) {
+            writer.write_u8(SYNC_DENIED).await?;
+            writer.flush().await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "replica failed to authenticate",
+            ));
+        }
+        let run_id = BigEndian::read_u64(&handshake[5..13]);
+        let mut seq = BigEndian::read_u64(&handshake[13..21]);
+
+        // Subscribe first, so no entry appended from here on goes unnoticed.
+        let mut updates = self.last_seq.subscribe();
+        if run_id == self.run_id && self.entries_after(seq).is_some() {
+            stats::incr(&self.stats.partial_syncs);
+            writer.write_u8(SYNC_CONTINUE).await?;
+        } else {
+            stats::incr(&self.stats.full_syncs);
+            seq = self.last_seq();
+            let dump = tokio::task::spawn_blocking(move || {
+                let mut dump = Vec::new();
+                snapshot::dump(&storage, &mut dump).map(|_| dump)
+            })
+            .await
+            .map_err(io::Error::other)??;
+            writer.write_u8(SYNC_FULL).await?;
+            writer.write_u64(self.run_id).await?;
+            writer.write_u64(seq).await?;
+            writer.write_u64(dump.len() as u64).await?;
+            writer.write_all(&dump).await?;
+        }
+        writer.flush().await?;
+
+        loop {
+            let entries = self
+                .entries_after(seq)
+                .ok_or_else(|| io::Error::other("replica fell behind the backlog"))?;
+            for (entry_seq, entry) in entries {
+                writer.write_u64(entry_seq).await?;
+                writer.write_u32(entry.len() as u32).await?;
+                writer.write_u32(crc32fast::hash(&entry)).await?;
+                writer.write_all(&entry).await?;
+                seq = entry_seq;
+            }
+            writer.flush().await?;
+            match time::timeout(HEARTBEAT_INTERVAL, updates.changed()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => return Ok(()),
+                Err(_) => {
+                    writer.write_u64(HEARTBEAT_SEQ).await?;
+                    writer.flush().await?;
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        vec![
+            ("repl_run_id".to_string(), self.run_id.to_string()),
+            ("repl_last_seq".to_string(), self.last_seq().to_string()),
+            (
+                "repl_connected_replicas".to_string(),
+                load(&self.stats.connected_replicas),
+            ),
+            ("repl_full_syncs".to_string(), load(&self.stats.full_syncs)),
+            (
+                "repl_partial_syncs".to_string(),
+                load(&self.stats.partial_syncs),
+            ),
+        ]
+    }
+}
+
+impl MutationObserver for ReplicationLog {
+    fn on_mutation(&self, namespace: &str, mutation: &Mutation) {
+        let mut entry = Vec::new();
+        aof::encode(&mut entry, namespace, mutation).expect("writing to a Vec cannot fail");
+        let mut backlog = self.backlog.lock().unwrap();
+        let seq = self.last_seq() + 1;
+        backlog.bytes += entry.len();
+        backlog.entries.push_back((seq, entry.into()));
+        while backlog.bytes > self.backlog_size && backlog.entries.len() > 1 {
+            if let Some((_, entry)) = backlog.entries.pop_front() {
+                backlog.bytes -= entry.len();
+            }
+        }
+        // Published under the lock, so the sequence number never goes back.
+        self.last_seq.send_replace(seq);
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplicaStats {
+    link_up: AtomicBool,
+    full_syncs: AtomicU64,
+    partial_syncs: AtomicU64,
+    applied: AtomicU64,
+}
+
+/// The replica side of replication: keeps the store in step with a primary,
+/// reconnecting and resynchronising whenever the link drops. Entries are
+/// applied without telling observers, so replicas do not relay them.
+pub struct Replica {
+    primary: String,
+    /// User and password presented to the primary.
+    credentials: Option<(String, String)>,
+    /// Run id of the primary followed and the last sequence number applied.
+    position: Mutex<(u64, u64)>,
+    stats: ReplicaStats,
+}
+
+impl Replica {
+    pub fn new<A: Into<String>>(primary: A) -> Replica {
+        Replica {
+            primary: primary.into(),
+            credentials: None,
+            position: Mutex::new((0, 0)),
+            stats: Default::default(),
+        }
+    }
+
+    /// Authenticates to the primary as `user`.
+    pub fn with_credentials<U: Into<String>, P: Into<String>>(
+        mut self,
+        user: U,
+        password: P,
+    ) -> Self {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.stats.link_up.load(Ordering::Relaxed)
+    }
+
+    /// Follows the primary until the process exits.
+    pub async fn run(self: Arc<Self>, storage: Arc<Storage>) {
+        loop {
+            if let Err(err) = self.sync(&storage).await {
+                warn!("Replication from {} failed: {}", self.primary, err);
+            }
+            self.stats.link_up.store(false, Ordering::Relaxed);
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn sync(&self, storage: &Arc<Storage>) -> io::Result<()> {
+        let socket = TcpStream::connect(&self.primary).await?;
+        socket.set_nodelay(true)?;
+        let (mut reader, mut writer) = socket.into_split();
+        let (run_id, seq) = *self.position.lock().unwrap();
+        let mut handshake = Vec::with_capacity(HANDSHAKE_LEN);
+        handshake.extend_from_slice(MAGIC);
+        handshake.push(VERSION);
+        handshake.extend_from_slice(&run_id.to_be_bytes());
+        handshake.extend_from_slice(&seq.to_be_bytes());
+        let credentials = match &self.credentials {
+            Some((user, password)) => format!("\0{}\0{}", user, password).into_bytes(),
+            None => Vec::new(),
+        };
+        let credentials_len = u16::try_from(credentials.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "credentials too long"))?;
+        handshake.extend_from_slice(&credentials_len.to_be_bytes());
+        handshake.extend_from_slice(&credentials);
+        writer.write_all(&handshake).await?;
+
+        match reader.read_u8().await? {
+            SYNC_FULL => {
+                let run_id = reader.read_u64().await?;
+                let seq = reader.read_u64().await?;
+                let len = reader.read_u64().await?;
+                let dump = read_bytes(&mut reader, len).await?;
+                let storage = storage.clone();
+                let summary = tokio::task::spawn_blocking(move || {
+                    snapshot::verify(&dump[..])?;
+                    for namespace in storage.namespaces() {
+                        namespace.clear();
+                    }
+                    snapshot::restore(&storage, &dump[..])
+                })
+                .await
+                .map_err(io::Error::other)??;
+                info!("Full sync from {}: {:?}", self.primary, summary);
+                *self.position.lock().unwrap() = (run_id, seq);
+                stats::incr(&self.stats.full_syncs);
+            }
+            SYNC_CONTINUE => stats::incr(&self.stats.partial_syncs),
+            SYNC_DENIED => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "primary rejected our credentials",
+                ))
+            }
+            _ => return Err(invalid_data("invalid replication reply")),
+        }
+        self.stats.link_up.store(true, Ordering::Relaxed);
+
+        let mut summary = ReplaySummary::default();
+        loop {
+            let seq = time::timeout(LINK_TIMEOUT, reader.read_u64())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "primary went silent"))??;
+            if seq == HEARTBEAT_SEQ {
+                continue;
+            }
+            let len = reader.read_u32().await? as usize;
+            if len > MAX_ENTRY_LEN {
+                return Err(invalid_data("replication entry too large"));
+            }
+            let checksum = reader.read_u32().await?;
+            let entry = read_bytes(&mut reader, len as u64).await?;
+            if crc32fast::hash(&entry) != checksum {
+                return Err(invalid_data("replication entry checksum mismatch"));
+            }
+            aof::apply(storage, &entry, storage.timer().secs(), &mut summary)?;
+            self.position.lock().unwrap().1 = seq;
+            stats::incr(&self.stats.applied);
+        }
+    }
+
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let (run_id, seq) = *self.position.lock().unwrap();
+        let link = match self.is_linked() {
+            true => "up",
+            false => "down",
+        };
+        vec![
+            ("repl_primary".to_string(), self.primary.clone()),
+            ("repl_link".to_string(), link.to_string()),
+            ("repl_primary_run_id".to_string(), run_id.to_string()),
+            ("repl_applied_seq".to_string(), seq.to_string()),
+            (
+                "repl_applied".to_string(),
+                self.stats.applied.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "repl_full_syncs".to_string(),
+                self.stats.full_syncs.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "repl_partial_syncs".to_string(),
+                self.stats.partial_syncs.load(Ordering::Relaxed).to_string(),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::server::TcpServer;
+    use crate::memcached::storage::Record;
+    use crate::memcached::testing::free_address;
+    use crate::memcached::timer;
+
+    fn record(value: &str) -> Record {
+        Record::new(value.as_bytes().to_vec(), 0, 0, 0)
+    }
+
+    async fn eventually<F: Fn() -> bool>(check: F) {
+        for _ in 0..100 {
+            if check() {
+                return;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("replica did not catch up");
+    }
+
+    #[test]
+    fn backlog_keeps_the_most_recent_entries() {
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let storage = Storage::new(timer);
+        let log = Arc::new(ReplicationLog::new().with_backlog_size(64));
+        storage.add_observer(log.clone());
+        for i in 0..10 {
+            storage
+                .set(format!("key{}", i).into_bytes(), record("v"))
+                .unwrap();
+        }
+
+        assert_eq!(log.last_seq(), 10);
+        assert_eq!(log.entries_after(10).unwrap().len(), 0);
+        let recent = log.entries_after(9).unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].0, 10);
+        assert!(log.entries_after(0).is_none());
+        assert!(log.entries_after(11).is_none());
+    }
+
+    #[tokio::test]
+    async fn replica_bootstraps_and_follows_primary() {
+        let replication_addr = free_address();
+        let mut primary = TcpServer::new().with_replication(replication_addr.clone());
+        let primary_storage = primary.storage();
+        primary_storage
+            .set(b"before".to_vec(), record("1"))
+            .unwrap();
+        let primary_addr = free_address();
+        tokio::spawn(async move { primary.run(primary_addr).await });
+
+        let mut replica = TcpServer::new()
+            .with_replica_of(replication_addr)
+            .with_read_only(true);
+        let replica_storage = replica.storage();
+        let replica_addr = free_address();
+        tokio::spawn(async move { replica.run(replica_addr).await });
+
+        eventually(|| replica_storage.get(&b"before".to_vec()).is_ok()).await;
+
+        let cas = primary_storage
+            .set(b"after".to_vec(), record("2"))
+            .unwrap()
+            .cas;
+        primary_storage.delete(&b"before".to_vec(), 0).unwrap();
+        eventually(|| {
+            replica_storage.get(&b"before".to_vec()).is_err()
+                && replica_storage.get(&b"after".to_vec()).is_ok()
+        })
+        .await;
+        let replicated = replica_storage.get(&b"after".to_vec()).unwrap();
+        assert_eq!(replicated.value, b"2");
+        assert_eq!(replicated.header.cas, cas);
+    }
+
+    #[tokio::test]
+    async fn replicas_must_authenticate() {
+        let replication_addr = free_address();
+        let mut primary = TcpServer::new()
+            .with_authenticator(auth::Authenticator::parse("repl:secret\nreader:pw\n").unwrap())
+            .with_acl(acl::Acl::parse("repl * r\nreader app: r\n").unwrap())
+            .with_replication(replication_addr.clone());
+        primary.storage().set(b"key".to_vec(), record("1")).unwrap();
+        let primary_addr = free_address();
+        tokio::spawn(async move { primary.run(primary_addr).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let storage = Arc::new(Storage::new(Arc::new(timer::SystemTimer::new())));
+        for replica in [
+            Replica::new(replication_addr.clone()),
+            Replica::new(replication_addr.clone()).with_credentials("repl", "wrong"),
+            Replica::new(replication_addr.clone()).with_credentials("reader", "pw"),
+        ] {
+            let err = replica.sync(&storage).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+        assert!(storage.get(&b"key".to_vec()).is_err());
+
+        let replica = Arc::new(Replica::new(replication_addr).with_credentials("repl", "secret"));
+        tokio::spawn(replica.clone().run(storage.clone()));
+        eventually(|| storage.get(&b"key".to_vec()).is_ok()).await;
+    }
+}
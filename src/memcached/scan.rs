@@ -0,0 +1,71 @@
+use num_derive::FromPrimitive;
+
+/// How many keys a scan returns at most if not told otherwise.
+pub const DEFAULT_SCAN_COUNT: usize = 100;
+
+/// Bits of a cursor taken by each arrival sequence number it holds: the one
+/// to resume after in the low bits, then the one the namespace's scan stops
+/// at, then the index of the namespace being scanned above them.
+const SEQ_BITS: u32 = 48;
+
+const SEQ_MASK: u128 = (1 << SEQ_BITS) - 1;
+
+/// Where an item lives.
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    /// In memory, on the namespace's LRU.
+    Memory = 0,
+    /// With its value in the external store and its header on the external
+    /// LRU.
+    External = 1,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMetadata {
+    pub namespace: String,
+    pub key: Vec<u8>,
+    /// Bytes charged for the item against the namespace quota.
+    pub size: usize,
+    pub flags: u32,
+    pub cas: u64,
+    /// Absolute expiry in timer seconds, 0 if the item never expires.
+    pub expires_at: u64,
+    /// When the item was last stored or read, in timer seconds; reads only
+    /// count once a minute.
+    pub last_access: u64,
+    pub segment: Segment,
+}
+
+/// A batch of keys and the cursor to pass to the next scan, 0 once every
+/// key was visited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanPage {
+    pub entries: Vec<KeyMetadata>,
+    pub cursor: u128,
+}
+
+/// Where a scan resumes: the namespace, the arrival sequence number to
+/// resume after, and the one it stops at, 0 until the namespace's scan
+/// starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Cursor {
+    pub namespace: usize,
+    pub after: u64,
+    pub until: u64,
+}
+
+impl Cursor {
+    pub(crate) fn split(cursor: u128) -> Cursor {
+        Cursor {
+            namespace: (cursor >> (2 * SEQ_BITS)) as usize,
+            after: (cursor & SEQ_MASK) as u64,
+            until: (cursor >> SEQ_BITS & SEQ_MASK) as u64,
+        }
+    }
+
+    pub(crate) fn join(self) -> u128 {
+        (self.namespace as u128) << (2 * SEQ_BITS)
+            | (self.until as u128 & SEQ_MASK) << SEQ_BITS
+            | self.after as u128 & SEQ_MASK
+    }
+}
@@ -1,15 +1,37 @@
-use crate::memcached::{handler, storage, timer};
-use crate::protocol::{binary, binary_codec};
-use futures_util::{SinkExt, StreamExt};
+use crate::memcached::{
+    acl, aof, auth, capture, cluster, compression, extstore, handler, hotkeys, latency, lease,
+    namespace, replication, slowlog, snapshot, stats, storage, timer, watch,
+};
+use crate::protocol::binary_codec;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use std::net::ToSocketAddrs;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io;
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs as TokioToSocketAddrs};
+use tokio::net::{TcpListener, ToSocketAddrs as TokioToSocketAddrs};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 pub struct TcpServer {
     timer: Arc<dyn timer::Timer + Send + Sync>,
     storage: Arc<storage::Storage>,
+    namespaces: Vec<namespace::NamespaceConfig>,
+    extstore: Option<Arc<extstore::ExtStore>>,
+    compressor: Option<Arc<compression::Compressor>>,
+    lease_ttl: u64,
+    watch_buffer: usize,
+    stats: Arc<stats::Stats>,
+    hot_keys: Option<Arc<hotkeys::HotKeys>>,
+    latencies: Arc<latency::Latencies>,
+    slow_log: Option<Arc<slowlog::SlowLog>>,
+    authenticator: Option<Arc<auth::Authenticator>>,
+    acl: Option<Arc<acl::Acl>>,
+    snapshot: Option<Arc<snapshot::Snapshot>>,
+    append_only_log: Option<Arc<aof::AppendOnlyLog>>,
+    replication: Option<(String, Arc<replication::ReplicationLog>)>,
+    replica: Option<Arc<replication::Replica>>,
+    read_only: bool,
+    cluster: Option<Arc<cluster::Cluster>>,
+    capture: Option<Arc<capture::Capture>>,
 }
 
 impl Default for TcpServer {
@@ -18,6 +40,31 @@ impl Default for TcpServer {
         TcpServer {
             timer: timer.clone(),
             storage: Arc::new(storage::Storage::new(timer.clone())),
+            namespaces: Vec::new(),
+            extstore: None,
+            compressor: None,
+            lease_ttl: lease::DEFAULT_LEASE_TTL,
+            watch_buffer: watch::DEFAULT_WATCH_BUFFER,
+            stats: Arc::new(stats::Stats::new()),
+            hot_keys: Some(Arc::new(hotkeys::HotKeys::new(
+                timer.clone(),
+                hotkeys::DEFAULT_HOT_KEY_SAMPLE_RATE,
+            ))),
+            latencies: Arc::new(latency::Latencies::new()),
+            slow_log: Some(Arc::new(slowlog::SlowLog::new(
+                timer.clone(),
+                slowlog::DEFAULT_SLOW_LOG_THRESHOLD,
+                slowlog::DEFAULT_SLOW_LOG_SIZE,
+            ))),
+            authenticator: None,
+            acl: None,
+            snapshot: None,
+            append_only_log: None,
+            replication: None,
+            replica: None,
+            read_only: false,
+            cluster: None,
+            capture: None,
         }
     }
 }
@@ -27,16 +74,261 @@ impl TcpServer {
         Default::default()
     }
 
+    /// Enables SASL authentication for every accepted connection.
+    pub fn with_authenticator(mut self, authenticator: auth::Authenticator) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Partitions the store into namespaces with their own memory quotas.
+    pub fn with_namespaces(mut self, configs: Vec<namespace::NamespaceConfig>) -> Self {
+        self.namespaces = configs;
+        self.rebuild_storage();
+        self
+    }
+
+    /// Moves values that age out of memory to `extstore` instead of evicting
+    /// them outright.
+    pub fn with_extstore(mut self, extstore: extstore::ExtStore) -> Self {
+        self.extstore = Some(Arc::new(extstore));
+        self.rebuild_storage();
+        self
+    }
+
+    /// Compresses values of at least `config.min_size` bytes while they are
+    /// held in memory.
+    pub fn with_compression(mut self, config: compression::CompressionConfig) -> Self {
+        self.compressor = Some(Arc::new(compression::Compressor::new(config)));
+        self.rebuild_storage();
+        self
+    }
+
+    /// Lets a client hold the lease on a missing key for `ttl` seconds.
+    pub fn with_lease_ttl(mut self, ttl: u64) -> Self {
+        self.lease_ttl = ttl;
+        self.rebuild_storage();
+        self
+    }
+
+    /// Lets each watching connection fall behind by up to `buffer` events
+    /// before it misses any.
+    pub fn with_watch_buffer(mut self, buffer: usize) -> Self {
+        self.watch_buffer = buffer;
+        self.rebuild_storage();
+        self
+    }
+
+    fn rebuild_storage(&mut self) {
+        let mut storage =
+            storage::Storage::with_namespaces(self.timer.clone(), self.namespaces.clone())
+                .with_lease_ttl(self.lease_ttl)
+                .with_watch_buffer(self.watch_buffer);
+        if let Some(extstore) = &self.extstore {
+            storage = storage.with_extstore(extstore.clone());
+        }
+        if let Some(compressor) = &self.compressor {
+            storage = storage.with_compressor(compressor.clone());
+        }
+        self.storage = Arc::new(storage);
+    }
+
+    /// Tracks one in `sample_rate` gets and sets to report the hottest keys,
+    /// or none if it is 0.
+    pub fn with_hot_key_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.hot_keys = match sample_rate {
+            0 => None,
+            rate => Some(Arc::new(hotkeys::HotKeys::new(self.timer.clone(), rate))),
+        };
+        self
+    }
+
+    /// Logs the last `size` requests that took at least `threshold`, or
+    /// none if `size` is 0.
+    pub fn with_slow_log(mut self, threshold: Duration, size: usize) -> Self {
+        self.slow_log = match size {
+            0 => None,
+            size => Some(Arc::new(slowlog::SlowLog::new(
+                self.timer.clone(),
+                threshold,
+                size,
+            ))),
+        };
+        self
+    }
+
+    /// Restricts authenticated users to the key prefixes granted by `acl`.
+    pub fn with_acl(mut self, acl: acl::Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Restores the store from `path` when the server starts and saves it
+    /// there on `shutdown` or when a client sends a snapshot command.
+    pub fn with_snapshot<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.snapshot = Some(Arc::new(snapshot::Snapshot::new(path)));
+        self
+    }
+
+    /// Logs every mutation to `log`, which is replayed when the server starts.
+    /// The log's snapshot replaces any set with `with_snapshot`.
+    pub fn with_append_only_log(mut self, log: aof::AppendOnlyLog) -> Self {
+        self.snapshot = Some(log.snapshot());
+        self.append_only_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Streams every mutation to replicas connecting to `addr`.
+    pub fn with_replication<A: Into<String>>(mut self, addr: A) -> Self {
+        let log = Arc::new(replication::ReplicationLog::new());
+        self.replication = Some((addr.into(), log));
+        self
+    }
+
+    /// Keeps the store in step with the primary serving replication at
+    /// `primary`.
+    pub fn with_replica_of<A: Into<String>>(self, primary: A) -> Self {
+        self.with_replica(replication::Replica::new(primary))
+    }
+
+    /// Keeps the store in step with the primary `replica` follows.
+    pub fn with_replica(mut self, replica: replication::Replica) -> Self {
+        self.replica = Some(Arc::new(replica));
+        self
+    }
+
+    /// Rejects every command that would modify the store, as replicas that
+    /// must not diverge from their primary should.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Makes this server a member of `cluster`, serving only the keys the
+    /// cluster's ring assigns to it.
+    pub fn with_cluster(mut self, cluster: cluster::Cluster) -> Self {
+        self.cluster = Some(Arc::new(cluster));
+        self
+    }
+
+    /// Records every request and response to `capture`.
+    pub fn with_capture(mut self, capture: capture::Capture) -> Self {
+        self.capture = Some(Arc::new(capture));
+        self
+    }
+
+    /// Saves the snapshot, if one is configured, compacting the log into it,
+    /// and flushes the capture.
+    pub fn shutdown(&self) -> io::Result<()> {
+        if let Some(capture) = &self.capture {
+            capture.flush()?;
+        }
+        if let Some(log) = &self.append_only_log {
+            return log.compact(&self.storage);
+        }
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.save(&self.storage)?;
+        }
+        Ok(())
+    }
+
+    pub fn storage(&self) -> Arc<storage::Storage> {
+        self.storage.clone()
+    }
+
+    pub fn stats(&self) -> Arc<stats::Stats> {
+        self.stats.clone()
+    }
+
+    pub fn hot_keys(&self) -> Option<Arc<hotkeys::HotKeys>> {
+        self.hot_keys.clone()
+    }
+
+    pub fn latencies(&self) -> Arc<latency::Latencies> {
+        self.latencies.clone()
+    }
+
+    pub fn slow_log(&self) -> Option<Arc<slowlog::SlowLog>> {
+        self.slow_log.clone()
+    }
+
+    pub fn timer(&self) -> Arc<dyn timer::Timer + Send + Sync> {
+        self.timer.clone()
+    }
+
     pub async fn run<A: ToSocketAddrs + TokioToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
-        let mut listener = TcpListener::bind(addr).await?;
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.load(&self.storage)?;
+        }
+        if let Some(log) = &self.append_only_log {
+            log.replay(&self.storage)?;
+            log.start(&self.storage);
+        }
+        if let Some(extstore) = &self.extstore {
+            extstore.start(&self.storage);
+        }
+        if let Some((addr, log)) = &self.replication {
+            let listener = TcpListener::bind(addr.as_str()).await?;
+            self.storage.add_observer(log.clone());
+            tokio::spawn(log.clone().serve(
+                listener,
+                self.storage.clone(),
+                self.authenticator.clone(),
+                self.acl.clone(),
+            ));
+        }
+        if let Some(replica) = &self.replica {
+            tokio::spawn(replica.clone().run(self.storage.clone()));
+        }
+        let listener = TcpListener::bind(addr).await?;
         loop {
             match listener.accept().await {
                 Ok((mut socket, peer_addr)) => {
                     let db = self.storage.clone();
+                    let stats = self.stats.clone();
+                    let hot_keys = self.hot_keys.clone();
+                    let latencies = self.latencies.clone();
+                    let slow_log = self.slow_log.clone();
+                    let authenticator = self.authenticator.clone();
+                    let acl = self.acl.clone();
+                    let snapshot = self.snapshot.clone();
+                    let replication_log = self.replication.as_ref().map(|(_, log)| log.clone());
+                    let replica = self.replica.clone();
+                    let read_only = self.read_only;
+                    let cluster = self.cluster.clone();
+                    let capture = self
+                        .capture
+                        .clone()
+                        .map(|capture| (capture.connection(), capture));
                     println!("Incoming connection: {}", peer_addr);
 
                     tokio::spawn(async move {
-                        let mut handler = handler::BinaryHandler::new(db);
+                        let mut handler = handler::BinaryHandler::new(db).with_stats(stats);
+                        if let Some(authenticator) = authenticator {
+                            handler = handler.with_authenticator(authenticator);
+                        }
+                        if let Some(acl) = acl {
+                            handler = handler.with_acl(acl);
+                        }
+                        if let Some(snapshot) = snapshot {
+                            handler = handler.with_snapshot(snapshot);
+                        }
+                        if let Some(log) = replication_log {
+                            handler = handler.with_replication_log(log);
+                        }
+                        if let Some(replica) = replica {
+                            handler = handler.with_replica(replica);
+                        }
+                        if let Some(cluster) = cluster {
+                            handler = handler.with_cluster(cluster);
+                        }
+                        if let Some(hot_keys) = hot_keys {
+                            handler = handler.with_hot_keys(hot_keys);
+                        }
+                        if let Some(slow_log) = &slow_log {
+                            handler = handler.with_slow_log(slow_log.clone());
+                        }
+                        handler = handler.with_latencies(latencies.clone());
+                        handler = handler.with_read_only(read_only);
 
                         let (rx, tx) = socket.split();
                         let mut reader =
@@ -47,21 +339,92 @@ impl TcpServer {
                         while let Some(result) = reader.next().await {
                             match result {
                                 Ok(request) => {
-                                    let response = handler.handle_request(request);
+                                    let started = Instant::now();
+                                    let header = *request.get_header();
+                                    let keyed = request.key().is_some();
+                                    if let Some((connection, capture)) = &capture {
+                                        capture.record_request(*connection, &request);
+                                    }
+                                    let response = handler.handle_request(request).await;
                                     if let Some(response) = response {
+                                        if let Some((connection, capture)) = &capture {
+                                            capture.record_response(*connection, &response);
+                                        }
                                         if let Err(e) = writer.send(response).await {
                                             println!("error on sending response; error = {:?}", e);
                                         }
                                     }
+                                    let elapsed = started.elapsed();
+                                    latencies.record(header.opcode, elapsed);
+                                    if let Some(slow_log) = &slow_log {
+                                        if slow_log.is_slow(elapsed) {
+                                            let key: &[u8] = if keyed {
+                                                reader.decoder().last_key()
+                                            } else {
+                                                &[]
+                                            };
+                                            let size =
+                                                binary_codec::MemcachedBinaryCodec::HEADER_LEN
+                                                    + header.body_length as usize;
+                                            slow_log.record(
+                                                header.opcode,
+                                                key,
+                                                size,
+                                                &peer_addr.to_string(),
+                                                elapsed,
+                                            );
+                                        }
+                                    }
+                                    if let Some((opaque, watch)) = handler.take_watch() {
+                                        stream_events(opaque, watch, &mut reader, &mut writer)
+                                            .await;
+                                        break;
+                                    }
                                 }
                                 Err(e) => {
                                     println!("error on decoding from socket; error = {:?}", e);
+                                    break;
                                 }
                             }
                         }
+                        if let Some((_, capture)) = &capture {
+                            if let Err(e) = capture.flush() {
+                                println!("error on flushing capture; error = {:?}", e);
+                            }
+                        }
                     });
                 }
-                Err(e) => {}
+                Err(e) => {
+                    println!("error on accepting connection; error = {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Sends the events of `watch` to a connection that subscribed to them,
+/// ignoring anything it sends, until it hangs up.
+async fn stream_events<R, W>(opaque: u32, mut watch: watch::Watch, reader: &mut R, writer: &mut W)
+where
+    R: Stream + Unpin,
+    W: Sink<binary_codec::BinaryResponse> + Unpin,
+{
+    loop {
+        tokio::select! {
+            event = watch.next() => {
+                let event = match event {
+                    Some(event) => event,
+                    None => return,
+                };
+                let response = handler::BinaryHandler::watch_event(opaque, event, watch.dropped());
+                if writer.send(response).await.is_err() {
+                    return;
+                }
+            }
+            request = reader.next() => {
+                if request.is_none() {
+                    return;
+                }
             }
         }
     }
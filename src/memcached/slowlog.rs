@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::memcached::timer;
+
+/// Requests taking at least this long are logged if not told otherwise.
+pub const DEFAULT_SLOW_LOG_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// How many slow requests are kept if not told otherwise.
+pub const DEFAULT_SLOW_LOG_SIZE: usize = 128;
+
+/// Bytes of a key kept in the log.
+pub const SLOW_LOG_KEY_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowRequest {
+    /// Increases by one with each logged request.
+    pub id: u64,
+    /// When the request completed, in timer seconds.
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub opcode: u8,
+    /// The first `SLOW_LOG_KEY_LEN` bytes of the key, empty for commands
+    /// without one.
+    pub key: Vec<u8>,
+    /// Bytes of the request, header included.
+    pub size: usize,
+    pub peer: String,
+}
+
+/// The most recent requests that took longer than a threshold, in a ring
+/// buffer of fixed size.
+pub struct SlowLog {
+    timer: Arc<dyn timer::Timer + Send + Sync>,
+    threshold: Duration,
+    size: usize,
+    requests: Mutex<VecDeque<SlowRequest>>,
+    logged: AtomicU64,
+}
+
+impl SlowLog {
+    pub fn new(
+        timer: Arc<dyn timer::Timer + Send + Sync>,
+        threshold: Duration,
+        size: usize,
+    ) -> SlowLog {
+        SlowLog {
+            timer,
+            threshold,
+            size: size.max(1),
+            requests: Mutex::new(VecDeque::with_capacity(size.max(1))),
+            logged: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        duration >= self.threshold
+    }
+
+    /// Logs a request that was slow, dropping the oldest one if the log is
+    /// full.
+    pub fn record(&self, opcode: u8, key: &[u8], size: usize, peer: &str, duration: Duration) {
+        let request = SlowRequest {
+            id: self.logged.fetch_add(1, Ordering::Relaxed) + 1,
+            timestamp: self.timer.secs(),
+            duration,
+            opcode,
+            key: key[..key.len().min(SLOW_LOG_KEY_LEN)].to_vec(),
+            size,
+            peer: peer.to_string(),
+        };
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() == self.size {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    /// Up to `count` logged requests, newest first, all of them if 0.
+    pub fn requests(&self, count: usize) -> Vec<SlowRequest> {
+        let count = match count {
+            0 => self.size,
+            count => count,
+        };
+        let requests = self.requests.lock().unwrap();
+        requests.iter().rev().take(count).cloned().collect()
+    }
+
+    /// Counters as `(name, value)` pairs.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "slowlog_threshold_us".to_string(),
+                self.threshold.as_micros().to_string(),
+            ),
+            (
+                "slowlog_len".to_string(),
+                self.requests.lock().unwrap().len().to_string(),
+            ),
+            (
+                "slowlog_logged".to_string(),
+                self.logged.load(Ordering::Relaxed).to_string(),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_requests() {
+        let timer: Arc<dyn timer::Timer + Send + Sync> = Arc::new(timer::SystemTimer::new());
+        let log = SlowLog::new(timer, Duration::from_millis(5), 2);
+        assert!(!log.is_slow(Duration::from_millis(4)));
+        assert!(log.is_slow(Duration::from_millis(5)));
+
+        let long = [b'k'; 100];
+        log.record(0x00, b"first", 30, "peer", Duration::from_millis(5));
+        log.record(0x01, &long, 160, "peer", Duration::from_millis(6));
+        log.record(0x04, b"third", 30, "peer", Duration::from_millis(7));
+
+        let requests = log.requests(0);
+        let ids: Vec<_> = requests.iter().map(|request| request.id).collect();
+        assert_eq!(ids, vec![3, 2]);
+        assert_eq!(requests[1].key.len(), SLOW_LOG_KEY_LEN);
+        assert_eq!(log.requests(1)[0].key, b"third");
+        assert!(log
+            .stats()
+            .contains(&("slowlog_logged".to_string(), "3".to_string())));
+    }
+}
@@ -0,0 +1,413 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::memcached::error::StorageResult;
+use crate::memcached::storage::{Record, Storage};
+
+const MAGIC: &[u8; 4] = b"RCSS";
+const VERSION: u16 = 1;
+
+const TAG_END: u8 = 0;
+const TAG_ITEM: u8 = 1;
+
+/// Outcome of loading a snapshot.
+#[derive(Debug, Default, PartialEq)]
+pub struct LoadSummary {
+    pub loaded: u64,
+    pub expired: u64,
+}
+
+/// A snapshot file holding every live item with its namespace, flags, CAS,
+/// absolute expiry, grace period, recompute time and tags.
+///
+/// The file starts with a magic number and format version, followed by one
+/// tagged entry per item, an end tag with the item count and finally a CRC32
+/// of everything before it. The checksum is verified before any item is
+/// restored, so a truncated or corrupt file leaves the store untouched.
+pub struct Snapshot {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Snapshot {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Snapshot {
+        Snapshot {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the snapshot next to its final path and renames it into place,
+    /// so a crash mid-save never clobbers the previous snapshot.
+    pub fn save(&self, storage: &Storage) -> io::Result<u64> {
+        let _guard = self.lock.lock().unwrap();
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        let items = dump(storage, &mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        info!("Saved {} items to {:?}", items, self.path);
+        Ok(items)
+    }
+
+    /// Restores the snapshot if one exists; a missing file is not an error.
+    pub fn load(&self, storage: &Storage) -> io::Result<LoadSummary> {
+        let _guard = self.lock.lock().unwrap();
+        if !self.path.exists() {
+            return Ok(LoadSummary::default());
+        }
+        verify(BufReader::new(File::open(&self.path)?))?;
+        let summary = restore(storage, BufReader::new(File::open(&self.path)?))?;
+        info!("Loaded {:?} from {:?}", summary, self.path);
+        Ok(summary)
+    }
+}
+
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ChecksumReader<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes every unexpired item of `storage` in snapshot format.
+pub fn dump<W: Write>(storage: &Storage, writer: W) -> io::Result<u64> {
+    let now = storage.timer().secs();
+    let mut writer = ChecksumWriter {
+        inner: writer,
+        hasher: crc32fast::Hasher::new(),
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_u16::<BigEndian>(VERSION)?;
+
+    let mut items = 0u64;
+    for namespace in storage.namespaces() {
+        let mut result = Ok(());
+        namespace.for_each(|key, record| {
+            let expires_at = record.header.expires_at();
+            if result.is_err() || (expires_at != 0 && expires_at <= now) {
+                return;
+            }
+            result = write_item(&mut writer, namespace.name(), key, record);
+            items += 1;
+        });
+        result?;
+    }
+
+    writer.write_u8(TAG_END)?;
+    writer.write_u64::<BigEndian>(items)?;
+    let checksum = writer.hasher.clone().finalize();
+    writer.inner.write_u32::<BigEndian>(checksum)?;
+    writer.flush()?;
+    Ok(items)
+}
+
+fn write_item<W: Write>(
+    writer: &mut W,
+    namespace: &str,
+    key: &[u8],
+    record: &Record,
+) -> io::Result<()> {
+    writer.write_u8(TAG_ITEM)?;
+    write_record(writer, namespace, key, record)
+}
+
+/// Writes an item with its namespace, absolute expiry, freshness settings
+/// and tag names. Tags are stamped anew when the item is read back, as only
+/// items none of whose tags were invalidated are written.
+pub(crate) fn write_record<W: Write>(
+    writer: &mut W,
+    namespace: &str,
+    key: &[u8],
+    record: &Record,
+) -> io::Result<()> {
+    writer.write_u16::<BigEndian>(namespace.len() as u16)?;
+    writer.write_all(namespace.as_bytes())?;
+    writer.write_u32::<BigEndian>(key.len() as u32)?;
+    writer.write_all(key)?;
+    writer.write_u32::<BigEndian>(record.header.flags)?;
+    writer.write_u64::<BigEndian>(record.header.cas)?;
+    writer.write_u64::<BigEndian>(record.header.expires_at())?;
+    writer.write_u32::<BigEndian>(record.value.len() as u32)?;
+    writer.write_all(&record.value)?;
+    writer.write_u32::<BigEndian>(record.header.grace)?;
+    writer.write_u32::<BigEndian>(record.header.recompute_ms)?;
+    writer.write_u16::<BigEndian>(record.header.tags.len() as u16)?;
+    for tag in &record.header.tags {
+        writer.write_u16::<BigEndian>(tag.name.len() as u16)?;
+        writer.write_all(&tag.name)?;
+    }
+    Ok(())
+}
+
+/// Reads an item written by `write_record`, returning its namespace, key,
+/// record and absolute expiry.
+pub(crate) fn read_record<R: Read>(reader: &mut R) -> io::Result<(String, Vec<u8>, Record, u64)> {
+    let namespace = read_namespace(reader)?;
+    let key = read_key(reader)?;
+    let flags = reader.read_u32::<BigEndian>()?;
+    let cas = reader.read_u64::<BigEndian>()?;
+    let expires_at = reader.read_u64::<BigEndian>()?;
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    let value = read_bytes(reader, len)?;
+    let grace = reader.read_u32::<BigEndian>()?;
+    let recompute_ms = reader.read_u32::<BigEndian>()?;
+    let mut tags = Vec::new();
+    for _ in 0..reader.read_u16::<BigEndian>()? {
+        let len = reader.read_u16::<BigEndian>()? as usize;
+        tags.push(read_bytes(reader, len)?);
+    }
+    let record = Record::new(value, cas, flags, 0)
+        .with_grace(grace)
+        .with_early_recompute(recompute_ms)
+        .with_tags(tags);
+    Ok((namespace, key, record, expires_at))
+}
+
+pub(crate) fn read_namespace<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = reader.read_u16::<BigEndian>()? as usize;
+    String::from_utf8(read_bytes(reader, len)?).map_err(|_| invalid_data("invalid namespace name"))
+}
+
+pub(crate) fn read_key<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    read_bytes(reader, len)
+}
+
+/// Reads exactly `len` bytes without trusting `len` for the allocation, as a
+/// corrupt length must fail on EOF rather than exhaust memory.
+pub(crate) fn read_bytes<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated snapshot",
+        ));
+    }
+    Ok(buf)
+}
+
+/// Reads the snapshot entries, handing each item to `visit`.
+fn read_entries<R: Read, F>(reader: &mut R, mut visit: F) -> io::Result<u64>
+where
+    F: FnMut(String, Vec<u8>, Record, u64),
+{
+    let magic = read_bytes(reader, MAGIC.len())?;
+    if magic != MAGIC {
+        return Err(invalid_data("not a snapshot file"));
+    }
+    let version = reader.read_u16::<BigEndian>()?;
+    if version != VERSION {
+        return Err(invalid_data("unsupported snapshot version"));
+    }
+    let mut items = 0u64;
+    loop {
+        match reader.read_u8()? {
+            TAG_ITEM => {
+                let (namespace, key, record, expires_at) = read_record(reader)?;
+                visit(namespace, key, record, expires_at);
+                items += 1;
+            }
+            TAG_END => {
+                if reader.read_u64::<BigEndian>()? != items {
+                    return Err(invalid_data("snapshot item count mismatch"));
+                }
+                return Ok(items);
+            }
+            _ => return Err(invalid_data("invalid snapshot entry")),
+        }
+    }
+}
+
+/// Checks the format and checksum of a snapshot without restoring it.
+pub fn verify<R: Read>(reader: R) -> io::Result<u64> {
+    let mut reader = ChecksumReader {
+        inner: reader,
+        hasher: crc32fast::Hasher::new(),
+    };
+    let items = read_entries(&mut reader, |_, _, _, _| {})?;
+    let expected = reader.hasher.clone().finalize();
+    if reader.inner.read_u32::<BigEndian>()? != expected {
+        return Err(invalid_data("snapshot checksum mismatch"));
+    }
+    Ok(items)
+}
+
+/// Restores the items of a verified snapshot, skipping those that expired in
+/// the meantime. Items of namespaces that no longer exist are routed by key.
+pub fn restore<R: Read>(storage: &Storage, mut reader: R) -> io::Result<LoadSummary> {
+    let now = storage.timer().secs();
+    let mut summary = LoadSummary::default();
+    read_entries(&mut reader, |namespace, key, mut record, expires_at| {
+        if expires_at != 0 && expires_at <= now {
+            summary.expired += 1;
+            return;
+        }
+        record.header.set_expires_at(now, expires_at);
+        if restore_record(storage, &namespace, key, record).is_ok() {
+            summary.loaded += 1;
+        }
+    })?;
+    Ok(summary)
+}
+
+/// Puts a record back into the namespace it was dumped from, or routes it by
+/// key if that namespace is no longer configured.
+pub(crate) fn restore_record(
+    storage: &Storage,
+    namespace: &str,
+    key: Vec<u8>,
+    record: Record,
+) -> StorageResult<()> {
+    let namespace = storage
+        .namespace_by_name(namespace)
+        .unwrap_or_else(|| storage.namespace(None, &key));
+    namespace.restore(key, record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::testing::MockTimer;
+    use std::sync::Arc;
+
+    fn create_storage(now: u64) -> (Arc<MockTimer>, Storage) {
+        let timer = Arc::new(MockTimer::new(now));
+        (timer.clone(), Storage::new(timer))
+    }
+
+    fn populated_dump() -> Vec<u8> {
+        let (_, storage) = create_storage(1000);
+        storage
+            .set(b"forever".to_vec(), Record::new(b"a".to_vec(), 7, 42, 0))
+            .unwrap();
+        storage
+            .set(b"short".to_vec(), Record::new(b"b".to_vec(), 0, 0, 10))
+            .unwrap();
+        storage
+            .set(b"long".to_vec(), Record::new(b"c".to_vec(), 0, 0, 500))
+            .unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(dump(&storage, &mut buf).unwrap(), 3);
+        buf
+    }
+
+    #[test]
+    fn restore_keeps_metadata_and_skips_expired_items() {
+        let buf = populated_dump();
+        assert_eq!(verify(&buf[..]).unwrap(), 3);
+
+        let (timer, storage) = create_storage(1100);
+        let summary = restore(&storage, &buf[..]).unwrap();
+        assert_eq!(
+            summary,
+            LoadSummary {
+                loaded: 2,
+                expired: 1
+            }
+        );
+
+        let record = storage.get(&b"forever".to_vec()).unwrap();
+        assert_eq!(record.value, b"a");
+        assert_eq!(record.header.cas, 7);
+        assert_eq!(record.header.flags, 42);
+        assert!(storage.get(&b"short".to_vec()).is_err());
+
+        assert_eq!(
+            storage.get(&b"long".to_vec()).unwrap().header.expires_at(),
+            1500
+        );
+        timer.set(1500);
+        assert!(storage.get(&b"long".to_vec()).is_err());
+    }
+
+    #[test]
+    fn restore_keeps_tags_and_freshness_settings() {
+        let (_, storage) = create_storage(1000);
+        let record = Record::new(b"v".to_vec(), 0, 0, 60)
+            .with_grace(30)
+            .with_early_recompute(500)
+            .with_tags(vec![b"user:1".to_vec(), b"team:1".to_vec()]);
+        storage.set(b"feed".to_vec(), record).unwrap();
+        let mut buf = Vec::new();
+        dump(&storage, &mut buf).unwrap();
+
+        // Generations differ between stores; restored tags take the local ones.
+        let (_, restored) = create_storage(1000);
+        restored.invalidate_tag(b"team:1");
+        restore(&restored, &buf[..]).unwrap();
+        let header = restored.get(&b"feed".to_vec()).unwrap().header;
+        assert_eq!((header.grace, header.recompute_ms), (30, 500));
+        let tags: Vec<_> = header.tags.iter().map(|tag| tag.name.clone()).collect();
+        assert_eq!(tags, vec![b"user:1".to_vec(), b"team:1".to_vec()]);
+
+        restored.invalidate_tag(b"team:1");
+        assert!(restored.get(&b"feed".to_vec()).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_corruption() {
+        let mut buf = populated_dump();
+        let middle = buf.len() / 2;
+        buf[middle] ^= 0xff;
+        assert!(verify(&buf[..]).is_err());
+
+        let buf = populated_dump();
+        assert!(verify(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn save_and_load_file() {
+        let path = std::env::temp_dir().join(format!("rustcache-{}.snapshot", std::process::id()));
+        let snapshot = Snapshot::new(&path);
+        let (_, storage) = create_storage(1000);
+        storage
+            .set(b"key".to_vec(), Record::new(b"value".to_vec(), 0, 0, 0))
+            .unwrap();
+        assert_eq!(snapshot.save(&storage).unwrap(), 1);
+
+        let (_, restored) = create_storage(1000);
+        assert_eq!(snapshot.load(&restored).unwrap().loaded, 1);
+        assert_eq!(restored.get(&b"key".to_vec()).unwrap().value, b"value");
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(snapshot.load(&restored).unwrap(), LoadSummary::default());
+    }
+}
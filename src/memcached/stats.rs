@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub(crate) fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Server wide counters, shared by every connection.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub(crate) cmd_get: AtomicU64,
+    pub(crate) cmd_set: AtomicU64,
+    pub(crate) get_hits: AtomicU64,
+    pub(crate) get_misses: AtomicU64,
+    pub(crate) delete_hits: AtomicU64,
+    pub(crate) delete_misses: AtomicU64,
+    pub(crate) incr_hits: AtomicU64,
+    pub(crate) incr_misses: AtomicU64,
+    pub(crate) decr_hits: AtomicU64,
+    pub(crate) decr_misses: AtomicU64,
+    pub(crate) cmd_touch: AtomicU64,
+    pub(crate) touch_hits: AtomicU64,
+    pub(crate) touch_misses: AtomicU64,
+    pub(crate) cmd_flush: AtomicU64,
+    pub(crate) auth_cmds: AtomicU64,
+    pub(crate) auth_errors: AtomicU64,
+    pub(crate) acl_denials: AtomicU64,
+    pub(crate) cluster_forwards: AtomicU64,
+    pub(crate) cluster_forward_errors: AtomicU64,
+    pub(crate) cluster_redirects: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    /// Counters as `(name, value)` pairs, in the order they are reported.
+    pub fn snapshot(&self) -> Vec<(String, String)> {
+        let counters = [
+            ("cmd_get", &self.cmd_get),
+            ("cmd_set", &self.cmd_set),
+            ("get_hits", &self.get_hits),
+            ("get_misses", &self.get_misses),
+            ("delete_hits", &self.delete_hits),
+            ("delete_misses", &self.delete_misses),
+            ("incr_hits", &self.incr_hits),
+            ("incr_misses", &self.incr_misses),
+            ("decr_hits", &self.decr_hits),
+            ("decr_misses", &self.decr_misses),
+            ("cmd_touch", &self.cmd_touch),
+            ("touch_hits", &self.touch_hits),
+            ("touch_misses", &self.touch_misses),
+            ("cmd_flush", &self.cmd_flush),
+            ("auth_cmds", &self.auth_cmds),
+            ("auth_errors", &self.auth_errors),
+            ("acl_denials", &self.acl_denials),
+            ("cluster_forwards", &self.cluster_forwards),
+            ("cluster_forward_errors", &self.cluster_forward_errors),
+            ("cluster_redirects", &self.cluster_redirects),
+        ];
+        counters
+            .iter()
+            .map(|(name, counter)| {
+                (
+                    name.to_string(),
+                    counter.load(Ordering::Relaxed).to_string(),
+                )
+            })
+            .collect()
+    }
+}
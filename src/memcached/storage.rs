@@ -1,8 +1,19 @@
-use dashmap::mapref::one::Ref;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use dashmap::mapref::entry::Entry;
+
+use crate::memcached::compression::{self, Compressor};
 use crate::memcached::error::StorageResult;
+use crate::memcached::extstore::{ExtLocation, ExtStore, PendingWrite};
+use crate::memcached::lease::{LeaseGet, Leases, DEFAULT_LEASE_TTL};
+use crate::memcached::namespace::{NamespaceConfig, DEFAULT_NAMESPACE};
+use crate::memcached::scan::{self, KeyMetadata, ScanPage, Segment};
+use crate::memcached::tags::{Tag, Tags};
 use crate::memcached::timer;
+use crate::memcached::watch::{EventKind, Watch, WatchFilter, Watchers, DEFAULT_WATCH_BUFFER};
 
 use super::error::StorageError;
 
@@ -12,17 +23,62 @@ pub struct Header {
     pub(crate) cas: u64,
     pub(crate) flags: u32,
     expiration: u32,
+    pub(crate) lru_seq: u64,
+    /// LRU sequence number the key got when it was stored while missing,
+    /// kept as long as the key is.
+    pub(crate) arrival: u64,
+    /// When the item was last stored or moved up the LRU by a read, in timer
+    /// seconds.
+    pub(crate) last_access: u64,
+    /// Set once the value has moved to the external store; `value` is then
+    /// empty until it is read back.
+    pub(crate) ext: Option<ExtLocation>,
+    /// Set while `value` holds the compressed form of the value.
+    pub(crate) compression: Option<compression::Method>,
+    /// Seconds the item is kept past its expiry, served as stale to lease
+    /// gets until one of them refreshes it.
+    pub(crate) grace: u32,
+    /// Milliseconds the value takes to recompute, scaled by how eagerly it
+    /// should be refreshed ahead of its expiry (XFetch's delta times beta).
+    pub(crate) recompute_ms: u32,
+    /// The tags the item can be invalidated by, stamped when it is stored.
+    pub(crate) tags: Vec<Tag>,
 }
 
 impl Header {
     pub fn new(cas: u64, flags: u32, expiration: u32) -> Header {
         Header {
             timestamp: 0,
-            cas: cas,
-            flags: flags,
-            expiration: expiration,
+            cas,
+            flags,
+            expiration,
+            lru_seq: 0,
+            arrival: 0,
+            last_access: 0,
+            ext: None,
+            compression: None,
+            grace: 0,
+            recompute_ms: 0,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Absolute expiry in timer seconds, or 0 if the item never expires.
+    pub(crate) fn expires_at(&self) -> u64 {
+        match self.expiration {
+            0 => 0,
+            expiration => self.timestamp + expiration as u64,
         }
     }
+
+    /// Re-bases the header on `now` so that it still expires at `expires_at`.
+    pub(crate) fn set_expires_at(&mut self, now: u64, expires_at: u64) {
+        self.timestamp = now;
+        self.expiration = match expires_at {
+            0 => 0,
+            expires_at => expires_at.saturating_sub(now).clamp(1, u32::MAX as u64) as u32,
+        };
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -36,6 +92,34 @@ impl Record {
         let header = Header::new(cas, flags, expiration);
         Record { header, value }
     }
+
+    /// Keeps the item for `grace` seconds past its expiry, to be served as
+    /// stale while a single client recomputes it.
+    pub fn with_grace(mut self, grace: u32) -> Record {
+        self.header.grace = grace;
+        self
+    }
+
+    /// Lets lease gets of the item ask for it to be recomputed before it
+    /// expires, the more likely the closer it is to expiring and the longer
+    /// `recompute_ms` says recomputing takes.
+    pub fn with_early_recompute(mut self, recompute_ms: u32) -> Record {
+        self.header.recompute_ms = recompute_ms;
+        self
+    }
+
+    /// Lets the item be invalidated together with every other item sharing
+    /// one of `tags`.
+    pub fn with_tags(mut self, tags: Vec<Vec<u8>>) -> Record {
+        self.header.tags = tags
+            .into_iter()
+            .map(|name| Tag {
+                name,
+                generation: 0,
+            })
+            .collect();
+        self
+    }
 }
 
 impl PartialEq for Record {
@@ -46,36 +130,405 @@ impl PartialEq for Record {
 
 #[derive(Clone)]
 pub struct IncrementParam {
-    pub(crate) delta: u64,
-    pub(crate) value: u64,
+    pub delta: u64,
+    /// Value stored when the key does not exist yet.
+    pub value: u64,
+    /// Expiration of a newly created counter; `u32::MAX` refuses to create it.
+    pub expiration: u32,
 }
 
 pub type DecrementParam = IncrementParam;
 
-pub struct Storage {
-    memory: dashmap::DashMap<Vec<u8>, Record>,
-    timer: Arc<dyn timer::Timer + Send + Sync>,
+#[derive(Debug)]
+pub struct DeltaStatus {
+    pub value: u64,
+    pub cas: u64,
+}
+
+/// Command that produced a stored record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MutationKind {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas,
+    Increment,
+    Decrement,
+    Touch,
 }
 
+/// A change applied to a namespace. Stores carry the resulting record, so
+/// replaying them in order reproduces the namespace.
 #[derive(Debug)]
-pub struct SetStatus {
-    pub cas: u64,
+pub enum Mutation<'a> {
+    Store {
+        kind: MutationKind,
+        key: &'a [u8],
+        record: &'a Record,
+    },
+    Delete {
+        key: &'a [u8],
+    },
+    Flush,
+    /// Invalidates a tag in every namespace; reported with an empty
+    /// namespace name.
+    InvalidateTag {
+        tag: &'a [u8],
+    },
 }
 
-impl Storage {
-    pub fn new(timer: Arc<dyn timer::Timer + Send + Sync>) -> Storage {
-        Storage {
+/// Gets told about every mutation. Stores and deletes are reported while the
+/// key is still locked, so changes to one key arrive in the order they were
+/// applied; observers should be quick and must not call back into storage.
+pub trait MutationObserver: Send + Sync {
+    fn on_mutation(&self, namespace: &str, mutation: &Mutation);
+
+    /// Called once the keys of the mutations reported so far are unlocked
+    /// again, before the writer is answered; the place for slow work such as
+    /// waiting for them to reach the disk.
+    fn commit(&self) {}
+}
+
+type Observers = Arc<RwLock<Vec<Arc<dyn MutationObserver>>>>;
+
+/// Bookkeeping cost charged per item on top of its key and value.
+const ITEM_OVERHEAD: usize = 48;
+
+/// Seconds an item keeps its place in the LRU after being moved up, as
+/// memcached's `ITEM_UPDATE_INTERVAL`: reads in between take no write lock.
+const BUMP_INTERVAL: u64 = 60;
+
+/// Keys copied out of the arrival order at a time when visiting every item.
+const VISIT_BATCH: usize = 1024;
+
+/// Per namespace counters.
+#[derive(Debug, Default)]
+pub struct NamespaceStats {
+    pub(crate) get_hits: AtomicU64,
+    pub(crate) get_misses: AtomicU64,
+    pub(crate) cmd_set: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+    pub(crate) reclaimed: AtomicU64,
+    pub(crate) externalized: AtomicU64,
+}
+
+/// An isolated partition of the store. Items are charged against the
+/// namespace's own quota and, once it is exceeded, the least recently used
+/// items of this namespace are evicted; other namespaces are never touched.
+/// With an external store attached, large values are first moved out to it
+/// and only their headers, kept on a separate LRU, are evicted later.
+pub struct Namespace {
+    name: String,
+    memory: dashmap::DashMap<Vec<u8>, Record>,
+    lru: Mutex<BTreeMap<u64, Vec<u8>>>,
+    ext_lru: Mutex<BTreeMap<u64, Vec<u8>>>,
+    /// Keys by arrival, which scans walk as keys never move on it.
+    arrivals: Mutex<BTreeMap<u64, Vec<u8>>>,
+    lru_clock: AtomicU64,
+    cas_clock: Arc<AtomicU64>,
+    memory_limit: usize,
+    used_memory: AtomicUsize,
+    /// Bytes of values queued to be moved to the external store, no longer
+    /// charged against the quota.
+    externalizing: AtomicUsize,
+    stats: NamespaceStats,
+    observers: Observers,
+    extstore: Option<Arc<ExtStore>>,
+    compressor: Option<Arc<Compressor>>,
+    leases: Leases,
+    tags: Arc<Tags>,
+    watchers: Arc<Watchers>,
+    timer: Arc<dyn timer::Timer + Send + Sync>,
+}
+
+impl Namespace {
+    fn new(
+        config: &NamespaceConfig,
+        timer: Arc<dyn timer::Timer + Send + Sync>,
+        cas_clock: Arc<AtomicU64>,
+        observers: Observers,
+        tags: Arc<Tags>,
+        watchers: Arc<Watchers>,
+    ) -> Namespace {
+        Namespace {
+            name: config.name.clone(),
             memory: dashmap::DashMap::new(),
+            lru: Mutex::new(BTreeMap::new()),
+            ext_lru: Mutex::new(BTreeMap::new()),
+            arrivals: Mutex::new(BTreeMap::new()),
+            lru_clock: AtomicU64::new(0),
+            cas_clock,
+            memory_limit: config.memory_limit,
+            used_memory: AtomicUsize::new(0),
+            externalizing: AtomicUsize::new(0),
+            stats: Default::default(),
+            observers,
+            extstore: None,
+            compressor: None,
+            leases: Leases::new(DEFAULT_LEASE_TTL),
+            tags,
+            watchers,
             timer,
         }
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    pub fn memory_limit(&self) -> usize {
+        self.memory_limit
+    }
+
+    /// Memory charged against the quota: all that is used but the values
+    /// queued to move out.
+    fn charged_memory(&self) -> usize {
+        self.used_memory()
+            .saturating_sub(self.externalizing.load(Ordering::Relaxed))
+    }
+
+    /// Counters as `(name, value)` pairs, prefixed with the namespace name.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        let values = [
+            ("curr_items", self.len() as u64),
+            ("bytes", self.used_memory() as u64),
+            ("limit_maxbytes", self.memory_limit as u64),
+            ("get_hits", self.stats.get_hits.load(Ordering::Relaxed)),
+            ("get_misses", self.stats.get_misses.load(Ordering::Relaxed)),
+            ("cmd_set", self.stats.cmd_set.load(Ordering::Relaxed)),
+            ("evictions", self.stats.evictions.load(Ordering::Relaxed)),
+            ("reclaimed", self.stats.reclaimed.load(Ordering::Relaxed)),
+            (
+                "externalized",
+                self.stats.externalized.load(Ordering::Relaxed),
+            ),
+            (
+                "lease_grants",
+                self.leases.stats.granted.load(Ordering::Relaxed),
+            ),
+            (
+                "lease_waits",
+                self.leases.stats.waits.load(Ordering::Relaxed),
+            ),
+            (
+                "stale_sets",
+                self.leases.stats.stale_sets.load(Ordering::Relaxed),
+            ),
+            (
+                "stale_hits",
+                self.leases.stats.stale_hits.load(Ordering::Relaxed),
+            ),
+            (
+                "early_recomputes",
+                self.leases.stats.early_recomputes.load(Ordering::Relaxed),
+            ),
+        ];
+        values
+            .iter()
+            .map(|(stat, value)| (format!("{}:{}", self.name, stat), value.to_string()))
+            .collect()
+    }
+
+    fn item_size(key: &[u8], record: &Record) -> usize {
+        let tags: usize = record
+            .header
+            .tags
+            .iter()
+            .map(|tag| tag.name.len() + 8)
+            .sum();
+        key.len() + record.value.len() + tags + ITEM_OVERHEAD
+    }
+
+    /// The LRU a record is kept on, depending on where its value lives.
+    fn lru_of(&self, record: &Record) -> &Mutex<BTreeMap<u64, Vec<u8>>> {
+        match record.header.ext {
+            Some(_) => &self.ext_lru,
+            None => &self.lru,
+        }
+    }
+
+    /// The LRU sequence number handed out last.
+    fn last_lru_seq(&self) -> u64 {
+        self.lru_clock.load(Ordering::Relaxed)
+    }
+
+    fn next_lru_seq(&self) -> u64 {
+        self.lru_clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn next_cas(&self) -> u64 {
+        self.cas_clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn notify(&self, mutation: &Mutation) {
+        for observer in self.observers.read().unwrap().iter() {
+            observer.on_mutation(&self.name, mutation);
+        }
+        match mutation {
+            Mutation::Store { key, .. } => self.publish(EventKind::Set, key),
+            Mutation::Delete { key } => self.publish(EventKind::Delete, key),
+            Mutation::Flush => self.publish(EventKind::Flush, &[]),
+            // Tagged items are found invalidated, and expire, one by one.
+            Mutation::InvalidateTag { .. } => {}
+        }
+    }
+
+    /// Lets observers finish the mutations reported so far, once no key is
+    /// locked any more.
+    fn commit(&self) {
+        for observer in self.observers.read().unwrap().iter() {
+            observer.commit();
+        }
+    }
+
+    /// Tells watchers what happened to `key`.
+    fn publish(&self, kind: EventKind, key: &[u8]) {
+        self.watchers.publish(&self.name, kind, key);
+    }
+
     pub fn get(&self, key: &Vec<u8>) -> StorageResult<Record> {
-        println!("Get: {:?} => {:?}", key, std::str::from_utf8(key));
-        self.get_by_key(key)
+        let result = self.get_by_key(key).and_then(|record| {
+            let location = match record.header.ext {
+                Some(location) => location,
+                None => return Ok(record),
+            };
+            self.read_external(key, &record)
+                .ok_or_else(|| self.drop_stale(key, location))
+        });
+        match result {
+            Ok(_) => self.stats.get_hits.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.stats.get_misses.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    /// Like `get`, but leaves a value that lives in the external store there;
+    /// pass the record to `load_external` to read it back without blocking.
+    pub(crate) fn get_raw(&self, key: &Vec<u8>) -> StorageResult<Record> {
+        let result = self.get_by_key(key);
+        match result {
+            Ok(_) => self.stats.get_hits.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.stats.get_misses.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    /// Reads the value of a record returned by `get_raw` back from the
+    /// external store on the blocking thread pool.
+    pub(crate) async fn load_external(
+        &self,
+        key: &Vec<u8>,
+        mut record: Record,
+    ) -> StorageResult<Record> {
+        let (location, extstore) = match (record.header.ext, &self.extstore) {
+            (Some(location), Some(extstore)) => (location, extstore.clone()),
+            _ => return Ok(record),
+        };
+        match extstore.read_async(location, key.clone()).await {
+            Ok(Some(value)) => {
+                record.value = value;
+                record.header.ext = None;
+                self.decompress(record)
+            }
+            Ok(None) => Err(self.drop_stale(key, location)),
+            Err(err) => {
+                error!("extstore read failed: {}", err);
+                Err(StorageError::InternalError)
+            }
+        }
+    }
+
+    /// Reads an external value back synchronously; `None` if it is lost.
+    fn read_external(&self, key: &[u8], record: &Record) -> Option<Record> {
+        let location = record.header.ext?;
+        let value = match self.extstore.as_ref()?.read(location, key) {
+            Ok(value) => value?,
+            Err(err) => {
+                error!("extstore read failed: {}", err);
+                return None;
+            }
+        };
+        let mut record = record.clone();
+        record.value = value;
+        record.header.ext = None;
+        self.decompress(record).ok()
+    }
+
+    /// Compresses a record about to be stored, if compression is enabled.
+    fn compress(&self, record: Record) -> Record {
+        match &self.compressor {
+            Some(compressor) => compressor.compress(record),
+            None => record,
+        }
+    }
+
+    /// The original value of a record held in memory compressed.
+    fn decompress(&self, record: Record) -> StorageResult<Record> {
+        if record.header.compression.is_none() {
+            return Ok(record);
+        }
+        if let Some(compressor) = &self.compressor {
+            compressor.count_decompressed();
+        }
+        compression::decompress(record).map_err(|err| {
+            error!("decompression failed: {}", err);
+            StorageError::InternalError
+        })
+    }
+
+    /// Drops the header of a value whose external page was reclaimed.
+    fn drop_stale(&self, key: &Vec<u8>, location: ExtLocation) -> StorageError {
+        if let Some((key, record)) = self
+            .memory
+            .remove_if(key, |_, record| record.header.ext == Some(location))
+        {
+            self.forget(&key, &record);
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            self.publish(EventKind::Evict, &key);
+        }
+        StorageError::NotFound
+    }
+
+    /// Whether `key` still has its value at `location`.
+    pub(crate) fn is_external_at(&self, key: &[u8], location: ExtLocation) -> bool {
+        self.memory
+            .get(key)
+            .is_some_and(|record| record.header.ext == Some(location))
+    }
+
+    /// Points `key` at the copy of its value at `new`, provided it has not
+    /// changed since it was found at `old`.
+    pub(crate) fn relocate_external(&self, key: &[u8], old: ExtLocation, new: ExtLocation) -> bool {
+        match self.memory.get_mut(key) {
+            Some(mut record) if record.header.ext == Some(old) => {
+                record.header.ext = Some(new);
+                true
+            }
+            _ => false,
+        }
     }
 
     fn get_by_key(&self, key: &Vec<u8>) -> StorageResult<Record> {
+        self.get_or_stale(key, false).map(|(record, _)| record)
+    }
+
+    /// Finds the record of `key`. With `stale`, an item that expired but is
+    /// still within its grace period is found too, flagged `true`.
+    fn get_or_stale(&self, key: &Vec<u8>, stale: bool) -> StorageResult<(Record, bool)> {
         let result = match self.memory.get(key) {
             None => Err(StorageError::NotFound),
             Some(record) => Ok(record.clone()),
@@ -83,10 +536,15 @@ impl Storage {
 
         match result {
             Ok(record) => {
-                if self.check_if_expired(key, &record) {
+                let expired = self.check_if_expired(key, &record);
+                if expired && !(stale && self.in_grace(&record, self.timer.secs())) {
                     return Err(StorageError::NotFound);
                 }
-                Ok(record)
+                self.bump(key, &record);
+                match record.header.ext {
+                    Some(_) => Ok((record, expired)),
+                    None => self.decompress(record).map(|record| (record, expired)),
+                }
             }
             Err(err) => Err(err),
         }
@@ -103,68 +561,1131 @@ impl Storage {
         // }
     }
 
+    /// Moves `key` to the most recently used end of the LRU, unless `read`,
+    /// the record just read, was moved or stored less than `BUMP_INTERVAL`
+    /// ago.
+    fn bump(&self, key: &Vec<u8>, read: &Record) {
+        if self.timer.secs() < read.header.last_access + BUMP_INTERVAL {
+            return;
+        }
+        if let Some(mut record) = self.memory.get_mut(key) {
+            let seq = self.next_lru_seq();
+            let mut lru = self.lru_of(&record).lock().unwrap();
+            lru.remove(&record.header.lru_seq);
+            lru.insert(seq, key.clone());
+            record.header.lru_seq = seq;
+            record.header.last_access = self.timer.secs();
+        }
+    }
+
+    fn is_expired(record: &Record, current_time: u64) -> bool {
+        record.header.expiration != 0
+            && record.header.timestamp + (record.header.expiration as u64) <= current_time
+    }
+
+    /// Whether one of the tags of `record` was invalidated since it was
+    /// stored.
+    fn is_invalidated(&self, record: &Record) -> bool {
+        !record.header.tags.is_empty() && self.tags.is_invalidated(&record.header.tags)
+    }
+
+    /// Whether `record` has neither expired nor been invalidated.
+    fn is_live(&self, record: &Record, current_time: u64) -> bool {
+        !Namespace::is_expired(record, current_time) && !self.is_invalidated(record)
+    }
+
+    /// Whether an expired record is still kept to be served as stale.
+    /// Invalidated records never are.
+    fn in_grace(&self, record: &Record, current_time: u64) -> bool {
+        current_time < record.header.expires_at() + record.header.grace as u64
+            && !self.is_invalidated(record)
+    }
+
+    /// Whether `record` has expired or been invalidated; it is dropped once
+    /// its grace period is over too.
     fn check_if_expired(&self, key: &Vec<u8>, record: &Record) -> bool {
         let current_time = self.timer.secs();
 
-        if record.header.expiration == 0 {
+        if self.is_live(record, current_time) {
             return false;
         }
 
-        if record.header.timestamp + (record.header.expiration as u64) > current_time {
-            return false;
+        if self.in_grace(record, current_time) {
+            return true;
         }
+        // Only the record that was found is dropped, not one stored since.
+        let removed = self
+            .memory
+            .remove_if(key, |_, current| current.header.cas == record.header.cas);
+        if let Some((key, removed)) = removed {
+            self.forget(&key, &removed);
+            match self.is_invalidated(record) {
+                true => &self.tags.stats.invalidated_items,
+                false => &self.stats.reclaimed,
+            }
+            .fetch_add(1, Ordering::Relaxed);
+            self.publish(EventKind::Expire, &key);
+        }
+        true
+    }
 
-        // self.memory.remove(key);
-
-        match self.memory.remove(key) {
-            None => true,
-            Some(_) => true,
+    /// Whether a lease get of a live record should recompute it now. Each
+    /// read draws anew, so that with many readers one is likely to refresh
+    /// a hot item shortly before it expires (XFetch).
+    fn recompute_early(record: &Record, current_time: u64) -> bool {
+        let expires_at = record.header.expires_at();
+        if expires_at == 0 || record.header.recompute_ms == 0 {
+            return false;
         }
+        let recompute = record.header.recompute_ms as f64 / 1000.0;
+        let ahead = -recompute * rand::random::<f64>().ln();
+        current_time as f64 + ahead >= expires_at as f64
     }
+
+    /// Stamps a record about to be stored with the time and the current
+    /// generations of its tags.
     fn touch_record(&self, record: &mut Record) {
-        let timer = self.timer.secs();
+        record.header.timestamp = self.timer.secs();
+        self.tags.stamp(&mut record.header.tags);
     }
+
     pub fn set(&self, key: Vec<u8>, mut record: Record) -> StorageResult<SetStatus> {
         info!("Header:{:?}", &record.header);
-        match self.check_cas(&key, &record) {
-            Ok(cas) => {
-                record.header.cas = cas;
-                self.touch_record(&mut record);
-                info!("Insert:{:?},{:?}", &key, &record.header);
-                self.memory.insert(key, record);
-                Ok(SetStatus { cas })
+        self.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+        self.touch_record(&mut record);
+        info!("Insert:{:?},{:?}", &key, &record.header);
+        let record = self.update(key, MutationKind::Set, |current| {
+            record.header.cas = self.check_cas(current, &record)?;
+            Ok(record)
+        })?;
+        Ok(SetStatus {
+            cas: record.header.cas,
+        })
+    }
+
+    /// Reads `key` like `get`, handing out the lease to fill it on a miss.
+    /// Items within their grace period are returned as stale, and the lease
+    /// to refresh stale items or those due for an early recompute goes to
+    /// a single caller.
+    pub fn lease_get(&self, key: &Vec<u8>) -> StorageResult<LeaseGet> {
+        match self.get_raw_or_stale(key) {
+            Ok((record, stale)) => {
+                let record = match record.header.ext {
+                    Some(location) => self
+                        .read_external(key, &record)
+                        .ok_or_else(|| self.drop_stale(key, location))?,
+                    None => record,
+                };
+                Ok(self.revalidate(key, record, stale))
             }
+            Err(StorageError::NotFound) => Ok(self.lease(key)),
             Err(err) => Err(err),
         }
     }
 
-    fn check_cas(&self, _key: &Vec<u8>, record: &Record) -> StorageResult<u64> {
+    /// Like `get_raw`, but also finds an item within its grace period,
+    /// returned with `true`.
+    pub(crate) fn get_raw_or_stale(&self, key: &Vec<u8>) -> StorageResult<(Record, bool)> {
+        let result = self.get_or_stale(key, true);
+        match result {
+            Ok((_, true)) => self.leases.stats.stale_hits.fetch_add(1, Ordering::Relaxed),
+            Ok(_) => self.stats.get_hits.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.stats.get_misses.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    /// Hands out the lease on a key that was just found missing, or tells
+    /// the caller to wait for the client holding it.
+    pub(crate) fn lease(&self, key: &[u8]) -> LeaseGet {
+        match self.leases.acquire(key, self.timer.secs()) {
+            Some(token) => LeaseGet::Granted(token),
+            None => {
+                self.leases.stats.waits.fetch_add(1, Ordering::Relaxed);
+                LeaseGet::Wait
+            }
+        }
+    }
+
+    /// Wraps a record found by a lease get, with the lease to refresh it if
+    /// it is stale or due for an early recompute and nobody holds it yet.
+    pub(crate) fn revalidate(&self, key: &[u8], record: Record, stale: bool) -> LeaseGet {
+        let now = self.timer.secs();
+        let token = match stale || Namespace::recompute_early(&record, now) {
+            true => self.leases.acquire(key, now),
+            false => None,
+        };
+        if token.is_some() && !stale {
+            self.leases
+                .stats
+                .early_recomputes
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        LeaseGet::Hit {
+            record,
+            stale,
+            token,
+        }
+    }
+
+    /// Stores `record` only if `token` is still the lease on `key`, that is
+    /// if the key was neither written nor deleted since it was handed out.
+    pub fn lease_set(
+        &self,
+        key: Vec<u8>,
+        mut record: Record,
+        token: u64,
+    ) -> StorageResult<SetStatus> {
+        self.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+        self.touch_record(&mut record);
+        let now = record.header.timestamp;
+        let leased = key.clone();
+        let record = self.update(key, MutationKind::Set, |_| {
+            if !self.leases.redeem(&leased, token, now) {
+                return Err(StorageError::ItemNotStored);
+            }
+            record.header.cas = self.next_cas();
+            Ok(record)
+        })?;
+        Ok(SetStatus {
+            cas: record.header.cas,
+        })
+    }
+
+    fn check_cas(&self, current: Option<&Record>, record: &Record) -> StorageResult<u64> {
         if record.header.cas > 0 {
-            if let Some(existing_record) = self.memory.get(_key) {
-                if existing_record.header.cas != record.header.cas {
-                    return Err(StorageError::KeyExists);
+            match current {
+                Some(existing_record) if existing_record.header.cas != record.header.cas => {
+                    return Err(StorageError::KeyExists)
+                }
+                Some(_) => return Ok(self.next_cas()),
+                None => {
+                    self.cas_clock
+                        .fetch_max(record.header.cas, Ordering::Relaxed);
+                    return Ok(record.header.cas);
+                }
+            }
+        }
+
+        Ok(self.next_cas())
+    }
+
+    /// Stores `record` only if the key does not exist.
+    pub fn add(&self, key: Vec<u8>, mut record: Record) -> StorageResult<SetStatus> {
+        self.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+        self.touch_record(&mut record);
+        let record = self.update(key, MutationKind::Add, |current| match current {
+            Some(_) => Err(StorageError::KeyExists),
+            None => {
+                record.header.cas = self.next_cas();
+                Ok(record)
+            }
+        })?;
+        Ok(SetStatus {
+            cas: record.header.cas,
+        })
+    }
+
+    /// Stores `record` only if the key exists, honouring its CAS if given.
+    pub fn replace(&self, key: Vec<u8>, mut record: Record) -> StorageResult<SetStatus> {
+        self.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+        self.touch_record(&mut record);
+        let record = self.update(key, MutationKind::Replace, |current| match current {
+            None => Err(StorageError::NotFound),
+            Some(current) => {
+                record.header.cas = self.check_cas(Some(current), &record)?;
+                Ok(record)
+            }
+        })?;
+        Ok(SetStatus {
+            cas: record.header.cas,
+        })
+    }
+
+    /// Stores `record` only if the key exists with exactly the CAS it carries.
+    pub fn cas(&self, key: Vec<u8>, mut record: Record) -> StorageResult<SetStatus> {
+        self.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+        self.touch_record(&mut record);
+        let record = self.update(key, MutationKind::Cas, |current| match current {
+            None => Err(StorageError::NotFound),
+            Some(current) if current.header.cas != record.header.cas => {
+                Err(StorageError::KeyExists)
+            }
+            Some(_) => {
+                record.header.cas = self.next_cas();
+                Ok(record)
+            }
+        })?;
+        Ok(SetStatus {
+            cas: record.header.cas,
+        })
+    }
+
+    pub fn append(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.concat(key, record, MutationKind::Append)
+    }
+
+    pub fn prepend(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.concat(key, record, MutationKind::Prepend)
+    }
+
+    /// Appends or prepends to an existing value, keeping its flags and expiry.
+    fn concat(&self, key: Vec<u8>, record: Record, kind: MutationKind) -> StorageResult<SetStatus> {
+        self.stats.cmd_set.fetch_add(1, Ordering::Relaxed);
+        let record = self.update(key, kind, |current| {
+            let current = current.ok_or(StorageError::ItemNotStored)?;
+            if record.header.cas > 0 && record.header.cas != current.header.cas {
+                return Err(StorageError::KeyExists);
+            }
+            let mut updated = current.clone();
+            updated.value = match kind {
+                MutationKind::Prepend => [record.value, current.value.clone()].concat(),
+                _ => [current.value.clone(), record.value].concat(),
+            };
+            updated.header.cas = self.next_cas();
+            Ok(updated)
+        })?;
+        Ok(SetStatus {
+            cas: record.header.cas,
+        })
+    }
+
+    pub fn increment(&self, key: Vec<u8>, increment: IncrementParam) -> StorageResult<DeltaStatus> {
+        self.apply_delta(key, increment, MutationKind::Increment)
+    }
+
+    pub fn decrement(&self, key: Vec<u8>, decrement: DecrementParam) -> StorageResult<DeltaStatus> {
+        self.apply_delta(key, decrement, MutationKind::Decrement)
+    }
+
+    /// Increments wrap around at 2^64, decrements stop at zero.
+    fn apply_delta(
+        &self,
+        key: Vec<u8>,
+        param: IncrementParam,
+        kind: MutationKind,
+    ) -> StorageResult<DeltaStatus> {
+        let now = self.timer.secs();
+        let mut value = 0;
+        let record = self.update(key, kind, |current| {
+            let mut updated = match current {
+                None if param.expiration == u32::MAX => return Err(StorageError::NotFound),
+                None => {
+                    value = param.value;
+                    let mut record = Record::new(Vec::new(), 0, 0, param.expiration);
+                    record.header.timestamp = now;
+                    record
+                }
+                Some(current) => {
+                    let current_value = std::str::from_utf8(&current.value)
+                        .ok()
+                        .and_then(|value| value.trim().parse::<u64>().ok())
+                        .ok_or(StorageError::ArithOnNonNumeric)?;
+                    value = match kind {
+                        MutationKind::Decrement => current_value.saturating_sub(param.delta),
+                        _ => current_value.wrapping_add(param.delta),
+                    };
+                    current.clone()
+                }
+            };
+            updated.value = value.to_string().into_bytes();
+            updated.header.cas = self.next_cas();
+            Ok(updated)
+        })?;
+        Ok(DeltaStatus {
+            value,
+            cas: record.header.cas,
+        })
+    }
+
+    /// Sets a new expiration on an existing item and returns it.
+    pub fn touch(&self, key: Vec<u8>, expiration: u32) -> StorageResult<Record> {
+        let now = self.timer.secs();
+        self.update(key, MutationKind::Touch, |current| {
+            let mut updated = current.ok_or(StorageError::NotFound)?.clone();
+            updated.header.timestamp = now;
+            updated.header.expiration = expiration;
+            Ok(updated)
+        })
+    }
+
+    /// Deletes the key, if `cas` is non-zero only when it still matches.
+    pub fn delete(&self, key: &Vec<u8>, cas: u64) -> StorageResult<()> {
+        self.leases.release(key);
+        let now = self.timer.secs();
+        let mut result = Err(StorageError::NotFound);
+        let removed = self.memory.remove_if(key, |key, record| {
+            if !self.is_live(record, now) {
+                self.publish(EventKind::Expire, key);
+                return true;
+            }
+            if cas != 0 && cas != record.header.cas {
+                result = Err(StorageError::KeyExists);
+                return false;
+            }
+            self.notify(&Mutation::Delete { key });
+            result = Ok(());
+            true
+        });
+        if let Some((key, record)) = removed {
+            self.forget(&key, &record);
+        }
+        if result.is_ok() {
+            self.commit();
+        }
+        result
+    }
+
+    /// Drops every item of the namespace.
+    pub fn flush(&self) {
+        self.notify(&Mutation::Flush);
+        self.clear();
+        self.commit();
+    }
+
+    /// Drops every item without telling observers.
+    pub(crate) fn clear(&self) {
+        for entry in self.memory.iter() {
+            self.release(entry.value());
+        }
+        self.memory.clear();
+        self.leases.clear();
+        self.lru.lock().unwrap().clear();
+        self.ext_lru.lock().unwrap().clear();
+        self.arrivals.lock().unwrap().clear();
+        self.used_memory.store(0, Ordering::Relaxed);
+    }
+
+    /// Visits every item there when the call starts; used to dump the
+    /// namespace. Keys are taken from the arrival order in batches and each
+    /// record is copied out on its own, so reading external values and the
+    /// callback run with no part of the map locked. External values are
+    /// read back and compressed ones decompressed first; those that were
+    /// lost are skipped, as are invalidated items.
+    pub(crate) fn for_each<F: FnMut(&[u8], &Record)>(&self, mut visit: F) {
+        let until = self.last_lru_seq();
+        let mut after = 0;
+        loop {
+            let batch = self.arrived(after, until, VISIT_BATCH);
+            for (seq, key) in &batch {
+                let record = match self.memory.get(key) {
+                    Some(record) if record.header.arrival == *seq => record.clone(),
+                    _ => continue,
+                };
+                if self.is_invalidated(&record) {
+                    continue;
+                }
+                let record = match record.header.ext {
+                    None => self.decompress(record).ok(),
+                    Some(_) => self.read_external(key, &record),
+                };
+                if let Some(record) = record {
+                    visit(key, &record);
                 }
             }
-            return Ok(record.header.cas);
+            match batch.last() {
+                Some((seq, _)) if batch.len() == VISIT_BATCH => after = *seq,
+                _ => return,
+            }
         }
+    }
 
-        Ok(1)
+    /// Up to `count` keys that arrived after sequence number `after` and no
+    /// later than `until`, oldest first, with their arrival numbers.
+    fn arrived(&self, after: u64, until: u64, count: usize) -> Vec<(u64, Vec<u8>)> {
+        if after >= until {
+            return Vec::new();
+        }
+        self.arrivals
+            .lock()
+            .unwrap()
+            .range(after + 1..=until)
+            .take(count)
+            .map(|(seq, key)| (*seq, key.clone()))
+            .collect()
     }
 
-    pub fn add(&self, _key: Vec<u8>, record: Record) {}
-    pub fn replace(&self, _key: Vec<u8>, record: Record) {}
+    /// Lists up to `count` live items starting with `prefix` that arrived
+    /// after sequence number `after` and no later than `until`, oldest
+    /// first, along with the sequence number to resume after or `None` once
+    /// all were visited. Keys keep their place however often they are used,
+    /// so every key there from the first page to the last is listed once.
+    /// The arrival lock is only held to copy out `count` keys, and each item
+    /// is then looked up on its own, so writers are barely held up.
+    fn scan(
+        &self,
+        after: u64,
+        until: u64,
+        prefix: &[u8],
+        count: usize,
+    ) -> (Vec<KeyMetadata>, Option<u64>) {
+        let batch = self.arrived(after, until, count);
+        let resume = match batch.len() >= count {
+            true => batch.last().map(|(seq, _)| *seq),
+            false => None,
+        };
 
-    pub fn append(&self, _key: Vec<u8>, record: Record) {}
+        let now = self.timer.secs();
+        let entries = batch
+            .into_iter()
+            .filter(|(_, key)| key.starts_with(prefix))
+            .filter_map(|(seq, key)| {
+                let record = self.memory.get(&key)?;
+                if record.header.arrival != seq || !self.is_live(&record, now) {
+                    return None;
+                }
+                Some(KeyMetadata {
+                    namespace: self.name.clone(),
+                    size: Namespace::item_size(&key, &record),
+                    flags: record.header.flags,
+                    cas: record.header.cas,
+                    expires_at: record.header.expires_at(),
+                    last_access: record.header.last_access,
+                    segment: match record.header.ext {
+                        Some(_) => Segment::External,
+                        None => Segment::Memory,
+                    },
+                    key,
+                })
+            })
+            .collect();
+        (entries, resume)
+    }
 
-    pub fn prepend(&self, _key: Vec<u8>, record: Record) {}
-    pub fn cas(&self, _key: Vec<u8>, record: Record) {}
-    pub fn increment(&self, _key: Vec<u8>, increment: IncrementParam) {}
-    pub fn decrement(&self, _key: Vec<u8>, decrement: DecrementParam) {}
-    pub fn delete(&self, _key: Vec<u8>, header: Header) {}
+    /// Stores a record as-is, keeping its CAS and timestamps, its tags
+    /// stamped with their current generations. Observers are not told, as
+    /// restored records come from a log or snapshot already.
+    pub(crate) fn restore(&self, key: Vec<u8>, mut record: Record) -> StorageResult<()> {
+        self.cas_clock
+            .fetch_max(record.header.cas, Ordering::Relaxed);
+        self.tags.stamp(&mut record.header.tags);
+        self.insert(key, record)
+    }
 
-    pub fn flush(&self) {}
+    /// Replaces the live record of `key` (`None` when missing or expired) with
+    /// the one `update` returns, telling observers while the key is locked.
+    fn update<F>(&self, key: Vec<u8>, kind: MutationKind, update: F) -> StorageResult<Record>
+    where
+        F: FnOnce(Option<&Record>) -> StorageResult<Record>,
+    {
+        let now = self.timer.secs();
+        let reads_value = matches!(
+            kind,
+            MutationKind::Append
+                | MutationKind::Prepend
+                | MutationKind::Increment
+                | MutationKind::Decrement
+                | MutationKind::Touch
+        );
+        // An external value the update builds on is read before the key is
+        // locked, and read again should it have moved in the meantime.
+        let mut loaded: Option<(ExtLocation, Option<Record>)> = None;
+        let entry = loop {
+            let entry = self.memory.entry(key.clone());
+            let unloaded = match &entry {
+                Entry::Occupied(entry) if reads_value && self.is_live(entry.get(), now) => {
+                    match (entry.get().header.ext, &loaded) {
+                        (Some(location), Some((at, _))) if *at == location => None,
+                        (Some(_), _) => Some(entry.get().clone()),
+                        (None, _) => None,
+                    }
+                }
+                _ => None,
+            };
+            match unloaded {
+                Some(record) => {
+                    drop(entry);
+                    let resolved = self.read_external(&key, &record);
+                    loaded = record.header.ext.map(|location| (location, resolved));
+                }
+                None => break entry,
+            }
+        };
+        let resolved;
+        let current = match &entry {
+            Entry::Occupied(entry) if self.is_live(entry.get(), now) => {
+                match entry.get().header.ext {
+                    None if entry.get().header.compression.is_none() => Some(entry.get()),
+                    None => {
+                        resolved = self.decompress(entry.get().clone()).ok();
+                        resolved.as_ref()
+                    }
+                    // Stores replacing the value only look at the header.
+                    Some(_) if !reads_value => Some(entry.get()),
+                    Some(_) => loaded.as_ref().and_then(|(_, record)| record.as_ref()),
+                }
+            }
+            _ => None,
+        };
+        let mut record = update(current)?;
+        record.header.lru_seq = self.next_lru_seq();
+        record.header.arrival = match &entry {
+            Entry::Occupied(entry) => entry.get().header.arrival,
+            Entry::Vacant(_) => record.header.lru_seq,
+        };
+        record.header.last_access = now;
+        let stored = self.compress(record.clone());
+        let size = Namespace::item_size(&key, &stored);
+        if self.memory_limit > 0 && size > self.memory_limit {
+            return Err(StorageError::ValueTooLarge);
+        }
+        let mutation = Mutation::Store {
+            kind,
+            key: &key,
+            record: &record,
+        };
 
-    pub fn touch(&self, _key: Vec<u8>) {}
+        let previous = match entry {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.insert(stored);
+                self.notify(&mutation);
+                self.leases.release(&key);
+                self.lru_of(&previous)
+                    .lock()
+                    .unwrap()
+                    .remove(&previous.header.lru_seq);
+                self.lru
+                    .lock()
+                    .unwrap()
+                    .insert(record.header.lru_seq, key.clone());
+                Some(previous)
+            }
+            Entry::Vacant(entry) => {
+                let _guard = entry.insert(stored);
+                self.notify(&mutation);
+                self.leases.release(&key);
+                self.lru
+                    .lock()
+                    .unwrap()
+                    .insert(record.header.lru_seq, key.clone());
+                self.arrivals
+                    .lock()
+                    .unwrap()
+                    .insert(record.header.arrival, key.clone());
+                None
+            }
+        };
+        if let Some(previous) = previous {
+            self.release(&previous);
+            self.used_memory
+                .fetch_sub(Namespace::item_size(&key, &previous), Ordering::Relaxed);
+        }
+        self.used_memory.fetch_add(size, Ordering::Relaxed);
+        self.evict();
+        self.commit();
+        Ok(record)
+    }
+
+    /// Stores `record`, charging it against the quota and evicting least
+    /// recently used items until the namespace fits again.
+    fn insert(&self, key: Vec<u8>, record: Record) -> StorageResult<()> {
+        let mut record = self.compress(record);
+        let size = Namespace::item_size(&key, &record);
+        if self.memory_limit > 0 && size > self.memory_limit {
+            return Err(StorageError::ValueTooLarge);
+        }
+        let seq = self.next_lru_seq();
+        record.header.lru_seq = seq;
+        record.header.arrival = seq;
+        record.header.last_access = self.timer.secs();
+        let old = match self.memory.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                record.header.arrival = entry.get().header.arrival;
+                Some(entry.insert(record))
+            }
+            Entry::Vacant(entry) => {
+                let _guard = entry.insert(record);
+                self.arrivals.lock().unwrap().insert(seq, key.clone());
+                None
+            }
+        };
+        if let Some(old) = old {
+            self.lru_of(&old)
+                .lock()
+                .unwrap()
+                .remove(&old.header.lru_seq);
+            self.release(&old);
+            self.used_memory
+                .fetch_sub(Namespace::item_size(&key, &old), Ordering::Relaxed);
+        }
+        self.lru.lock().unwrap().insert(seq, key);
+        self.used_memory.fetch_add(size, Ordering::Relaxed);
+        self.evict();
+        Ok(())
+    }
+
+    /// Removes `key` without telling observers.
+    pub(crate) fn remove(&self, key: &Vec<u8>) -> Option<Record> {
+        let (key, record) = self.memory.remove(key)?;
+        self.forget(&key, &record);
+        Some(record)
+    }
+
+    /// Releases the LRU slot and memory of a record already taken out of the map.
+    fn forget(&self, key: &[u8], record: &Record) {
+        self.lru_of(record)
+            .lock()
+            .unwrap()
+            .remove(&record.header.lru_seq);
+        self.arrivals.lock().unwrap().remove(&record.header.arrival);
+        self.release(record);
+        self.used_memory
+            .fetch_sub(Namespace::item_size(key, record), Ordering::Relaxed);
+    }
+
+    /// Marks the external copy of a dropped record dead.
+    fn release(&self, record: &Record) {
+        if let (Some(location), Some(extstore)) = (record.header.ext, &self.extstore) {
+            extstore.release(location);
+        }
+    }
+
+    fn evict(&self) {
+        while self.memory_limit > 0 && self.charged_memory() > self.memory_limit {
+            // The LRU lock is released before touching the map, so lookups that
+            // hold a map entry while bumping can never deadlock with us.
+            let victim = self.lru.lock().unwrap().pop_first();
+            let (seq, key) = match victim {
+                Some((seq, key)) if self.externalize(&key, seq) => continue,
+                Some(victim) => victim,
+                None => match self.ext_lru.lock().unwrap().pop_first() {
+                    Some(victim) => victim,
+                    None => break,
+                },
+            };
+            self.evict_at(&key, seq);
+        }
+    }
+
+    /// Evicts `key` unless it was used since it got LRU sequence number `seq`.
+    fn evict_at(&self, key: &Vec<u8>, seq: u64) {
+        if let Some((key, record)) = self
+            .memory
+            .remove_if(key, |_, record| record.header.lru_seq == seq)
+        {
+            self.arrivals.lock().unwrap().remove(&record.header.arrival);
+            self.release(&record);
+            self.used_memory
+                .fetch_sub(Namespace::item_size(&key, &record), Ordering::Relaxed);
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            self.publish(EventKind::Evict, &key);
+        }
+    }
+
+    /// Moves the value of the LRU victim out to the external store, keeping
+    /// its header in memory. The value is copied while the key is locked and
+    /// written once it no longer is, by the writer thread if the store was
+    /// started. Returns false if the item has to be evicted instead.
+    fn externalize(&self, key: &Vec<u8>, seq: u64) -> bool {
+        let extstore = match &self.extstore {
+            Some(extstore) => extstore,
+            None => return false,
+        };
+        let value = match self.memory.get(key) {
+            Some(record)
+                if record.header.lru_seq == seq
+                    && record.value.len() >= extstore.item_min_size() =>
+            {
+                record.value.clone()
+            }
+            _ => return false,
+        };
+        if !extstore.has_writer() {
+            let location = extstore.write(&self.name, key, &value);
+            return self.finish_externalize(key, seq, value.len(), location, false);
+        }
+        let len = value.len();
+        self.externalizing.fetch_add(len, Ordering::Relaxed);
+        let pending = PendingWrite {
+            namespace: self.name.clone(),
+            key: key.clone(),
+            seq,
+            value,
+        };
+        if !extstore.queue_write(pending) {
+            self.externalizing.fetch_sub(len, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Points the header of `key` at the copy of its `len` byte value just
+    /// written out, provided the item was not used or changed since it was
+    /// picked at LRU sequence number `seq`. A `queued` item that could not
+    /// be written is evicted after all.
+    pub(crate) fn finish_externalize(
+        &self,
+        key: &Vec<u8>,
+        seq: u64,
+        len: usize,
+        location: io::Result<Option<ExtLocation>>,
+        queued: bool,
+    ) -> bool {
+        if queued {
+            self.externalizing.fetch_sub(len, Ordering::Relaxed);
+        }
+        let location = location.unwrap_or_else(|err| {
+            error!("extstore write failed: {}", err);
+            None
+        });
+        let moved = match (self.memory.get_mut(key), location) {
+            (Some(mut record), Some(location)) if record.header.lru_seq == seq => {
+                record.value = Vec::new();
+                record.header.ext = Some(location);
+                self.ext_lru.lock().unwrap().insert(seq, key.clone());
+                true
+            }
+            _ => false,
+        };
+        match (moved, location) {
+            (true, _) => {
+                self.used_memory.fetch_sub(len, Ordering::Relaxed);
+                self.stats.externalized.fetch_add(1, Ordering::Relaxed);
+            }
+            (false, Some(location)) => {
+                if let Some(extstore) = &self.extstore {
+                    extstore.release(location);
+                }
+            }
+            (false, None) => {}
+        }
+        if queued {
+            if !moved {
+                self.evict_at(key, seq);
+            }
+            self.evict();
+        }
+        moved
+    }
+
+    /// Brings the external value of `key` back into memory, reading it on
+    /// the blocking thread pool, so that updates building on it need not
+    /// read it while holding the key.
+    pub(crate) async fn load_back(&self, key: &Vec<u8>) {
+        let location = self.memory.get(key).and_then(|record| record.header.ext);
+        let (location, extstore) = match (location, &self.extstore) {
+            (Some(location), Some(extstore)) => (location, extstore.clone()),
+            _ => return,
+        };
+        let value = match extstore.clone().read_async(location, key.clone()).await {
+            Ok(Some(value)) => value,
+            Ok(None) => {
+                self.drop_stale(key, location);
+                return;
+            }
+            Err(err) => {
+                error!("extstore read failed: {}", err);
+                return;
+            }
+        };
+        let len = value.len();
+        let loaded = match self.memory.get_mut(key) {
+            Some(mut record) if record.header.ext == Some(location) => {
+                let seq = self.next_lru_seq();
+                self.ext_lru.lock().unwrap().remove(&record.header.lru_seq);
+                self.lru.lock().unwrap().insert(seq, key.clone());
+                record.value = value;
+                record.header.ext = None;
+                record.header.lru_seq = seq;
+                true
+            }
+            _ => false,
+        };
+        if loaded {
+            extstore.release(location);
+            self.used_memory.fetch_add(len, Ordering::Relaxed);
+            self.evict();
+        }
+    }
+}
+
+/// The item store, split into namespaces that share the process but not
+/// their memory quota.
+pub struct Storage {
+    namespaces: Vec<Namespace>,
+    prefixes: Vec<(Vec<u8>, usize)>,
+    users: HashMap<String, usize>,
+    observers: Observers,
+    extstore: Option<Arc<ExtStore>>,
+    compressor: Option<Arc<Compressor>>,
+    tags: Arc<Tags>,
+    watchers: Arc<Watchers>,
+    timer: Arc<dyn timer::Timer + Send + Sync>,
+}
+
+#[derive(Debug)]
+pub struct SetStatus {
+    pub cas: u64,
+}
+
+impl Storage {
+    pub fn new(timer: Arc<dyn timer::Timer + Send + Sync>) -> Storage {
+        Storage::with_namespaces(timer, Vec::new())
+    }
+
+    /// Builds a store partitioned by `configs`. A `default` namespace, which
+    /// is unlimited unless configured otherwise, catches unrouted keys.
+    pub fn with_namespaces(
+        timer: Arc<dyn timer::Timer + Send + Sync>,
+        configs: Vec<NamespaceConfig>,
+    ) -> Storage {
+        let mut configs = configs;
+        match configs
+            .iter()
+            .position(|config| config.name == DEFAULT_NAMESPACE)
+        {
+            Some(index) => {
+                let default = configs.remove(index);
+                configs.insert(0, default);
+            }
+            None => configs.insert(0, NamespaceConfig::new(DEFAULT_NAMESPACE, 0)),
+        }
+        let mut prefixes = Vec::new();
+        let mut users = HashMap::new();
+        for (index, config) in configs.iter().enumerate() {
+            for prefix in &config.prefixes {
+                prefixes.push((prefix.clone(), index));
+            }
+            for user in &config.users {
+                users.insert(user.clone(), index);
+            }
+        }
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        let cas_clock = Arc::new(AtomicU64::new(0));
+        let observers: Observers = Default::default();
+        let tags = Arc::new(Tags::new());
+        let watchers = Arc::new(Watchers::new(DEFAULT_WATCH_BUFFER));
+        Storage {
+            namespaces: configs
+                .iter()
+                .map(|config| {
+                    Namespace::new(
+                        config,
+                        timer.clone(),
+                        cas_clock.clone(),
+                        observers.clone(),
+                        tags.clone(),
+                        watchers.clone(),
+                    )
+                })
+                .collect(),
+            prefixes,
+            users,
+            observers,
+            extstore: None,
+            compressor: None,
+            tags,
+            watchers,
+            timer,
+        }
+    }
+
+    /// Moves values that age out of memory to `extstore` instead of dropping
+    /// them.
+    pub fn with_extstore(mut self, extstore: Arc<ExtStore>) -> Storage {
+        for namespace in &mut self.namespaces {
+            namespace.extstore = Some(extstore.clone());
+        }
+        self.extstore = Some(extstore);
+        self
+    }
+
+    pub fn extstore(&self) -> Option<&Arc<ExtStore>> {
+        self.extstore.as_ref()
+    }
+
+    /// Keeps values large enough for `compressor` compressed in memory, and
+    /// charges their compressed size against the quotas.
+    pub fn with_compressor(mut self, compressor: Arc<Compressor>) -> Storage {
+        for namespace in &mut self.namespaces {
+            namespace.compressor = Some(compressor.clone());
+        }
+        self.compressor = Some(compressor);
+        self
+    }
+
+    pub fn compressor(&self) -> Option<&Arc<Compressor>> {
+        self.compressor.as_ref()
+    }
+
+    /// Lets a lease on a missing key be held for `ttl` seconds.
+    pub fn with_lease_ttl(mut self, ttl: u64) -> Storage {
+        for namespace in &mut self.namespaces {
+            namespace.leases = Leases::new(ttl);
+        }
+        self
+    }
+
+    /// Lets each watcher fall behind by up to `buffer` events before it
+    /// misses any.
+    pub fn with_watch_buffer(mut self, buffer: usize) -> Storage {
+        self.watchers = Arc::new(Watchers::new(buffer));
+        for namespace in &mut self.namespaces {
+            namespace.watchers = self.watchers.clone();
+        }
+        self
+    }
+
+    /// Picks the namespace for `key`: a configured key prefix wins, then the
+    /// authenticated user, then the default namespace.
+    pub fn namespace(&self, user: Option<&str>, key: &[u8]) -> &Namespace {
+        let index = self
+            .prefixes
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix))
+            .map(|(_, index)| *index)
+            .or_else(|| user.and_then(|user| self.users.get(user).copied()))
+            .unwrap_or(0);
+        &self.namespaces[index]
+    }
+
+    /// Registers `observer` to be told about every mutation from now on.
+    pub fn add_observer(&self, observer: Arc<dyn MutationObserver>) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    pub fn namespace_by_name(&self, name: &str) -> Option<&Namespace> {
+        self.namespaces
+            .iter()
+            .find(|namespace| namespace.name == name)
+    }
+
+    pub fn namespaces(&self) -> &[Namespace] {
+        &self.namespaces
+    }
+
+    pub fn timer(&self) -> Arc<dyn timer::Timer + Send + Sync> {
+        self.timer.clone()
+    }
+
+    /// The tag registry, shared by every namespace.
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    pub fn watchers(&self) -> &Watchers {
+        &self.watchers
+    }
+
+    /// Subscribes to the sets, deletes, evictions and expirations of the
+    /// keys `filter` lets through, in any namespace.
+    pub fn watch(&self, filter: WatchFilter) -> Watch {
+        self.watchers.subscribe(filter)
+    }
+
+    /// Lists up to `count` items starting with `prefix`, resuming where the
+    /// scan that returned `cursor` left off; a new scan starts at 0. Keys
+    /// that exist throughout a scan are listed exactly once.
+    pub fn scan(&self, cursor: u128, prefix: &[u8], count: usize) -> ScanPage {
+        let count = count.max(1);
+        let mut page = ScanPage::default();
+        let mut cursor = scan::Cursor::split(cursor);
+        while let Some(namespace) = self.namespaces.get(cursor.namespace) {
+            // Keys stored after the scan of a namespace started arrive past
+            // where it stops, so it ends however busy the namespace is.
+            if cursor.until == 0 {
+                cursor.until = namespace.last_lru_seq();
+            }
+            let wanted = count - page.entries.len();
+            let (entries, resume) = namespace.scan(cursor.after, cursor.until, prefix, wanted);
+            page.entries.extend(entries);
+            if let Some(seq) = resume {
+                cursor.after = seq;
+                page.cursor = cursor.join();
+                return page;
+            }
+            cursor = scan::Cursor {
+                namespace: cursor.namespace + 1,
+                after: 0,
+                until: 0,
+            };
+            if page.entries.len() == count && cursor.namespace < self.namespaces.len() {
+                page.cursor = cursor.join();
+                return page;
+            }
+        }
+        page
+    }
+
+    /// Logically expires every item tagged with `tag`, in any namespace.
+    pub fn invalidate_tag(&self, tag: &[u8]) -> u64 {
+        let generation = self.tags.invalidate(tag);
+        let observers = self.observers.read().unwrap();
+        for observer in observers.iter() {
+            observer.on_mutation("", &Mutation::InvalidateTag { tag });
+        }
+        for observer in observers.iter() {
+            observer.commit();
+        }
+        generation
+    }
+
+    pub fn len(&self) -> usize {
+        self.namespaces.iter().map(Namespace::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.namespaces.iter().all(Namespace::is_empty)
+    }
+
+    pub fn get(&self, key: &Vec<u8>) -> StorageResult<Record> {
+        self.namespace(None, key).get(key)
+    }
+
+    pub fn set(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.namespace(None, &key).set(key, record)
+    }
+
+    pub fn add(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.namespace(None, &key).add(key, record)
+    }
+
+    pub fn replace(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.namespace(None, &key).replace(key, record)
+    }
+
+    pub fn lease_get(&self, key: &Vec<u8>) -> StorageResult<LeaseGet> {
+        self.namespace(None, key).lease_get(key)
+    }
+
+    pub fn lease_set(&self, key: Vec<u8>, record: Record, token: u64) -> StorageResult<SetStatus> {
+        self.namespace(None, &key).lease_set(key, record, token)
+    }
+
+    pub fn append(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.namespace(None, &key).append(key, record)
+    }
+
+    pub fn prepend(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.namespace(None, &key).prepend(key, record)
+    }
+
+    pub fn cas(&self, key: Vec<u8>, record: Record) -> StorageResult<SetStatus> {
+        self.namespace(None, &key).cas(key, record)
+    }
+
+    pub fn increment(&self, key: Vec<u8>, increment: IncrementParam) -> StorageResult<DeltaStatus> {
+        self.namespace(None, &key).increment(key, increment)
+    }
+
+    pub fn decrement(&self, key: Vec<u8>, decrement: DecrementParam) -> StorageResult<DeltaStatus> {
+        self.namespace(None, &key).decrement(key, decrement)
+    }
+
+    pub fn delete(&self, key: &Vec<u8>, cas: u64) -> StorageResult<()> {
+        self.namespace(None, key).delete(key, cas)
+    }
+
+    /// Drops every item of every namespace.
+    pub fn flush(&self) {
+        for namespace in &self.namespaces {
+            namespace.flush();
+        }
+    }
+
+    pub fn touch(&self, key: Vec<u8>, expiration: u32) -> StorageResult<Record> {
+        self.namespace(None, &key).touch(key, expiration)
+    }
 }
 
 #[cfg(test)]
@@ -304,4 +1825,574 @@ mod tests {
             }
         }
     }
+
+    fn create_partitioned_storage() -> Storage {
+        create_partitioned_storage_with(Arc::new(MockSystemTimer::new()))
+    }
+
+    fn create_partitioned_storage_with(timer: Arc<MockSystemTimer>) -> Storage {
+        let mut noisy = NamespaceConfig::new("noisy", 3 * (ITEM_OVERHEAD + 8));
+        noisy.prefixes.push(b"noisy:".to_vec());
+        noisy.users.push(String::from("bob"));
+        let mut quiet = NamespaceConfig::new("quiet", 0);
+        quiet.users.push(String::from("alice"));
+        Storage::with_namespaces(timer, vec![noisy, quiet])
+    }
+
+    #[test]
+    fn namespaces_are_selected_by_prefix_then_user() {
+        let storage = create_partitioned_storage();
+        assert_eq!(storage.namespaces()[0].name(), DEFAULT_NAMESPACE);
+        assert_eq!(storage.namespace(None, b"noisy:1").name(), "noisy");
+        assert_eq!(storage.namespace(Some("alice"), b"noisy:1").name(), "noisy");
+        assert_eq!(storage.namespace(Some("alice"), b"key").name(), "quiet");
+        assert_eq!(storage.namespace(Some("bob"), b"key").name(), "noisy");
+        assert_eq!(storage.namespace(None, b"key").name(), DEFAULT_NAMESPACE);
+    }
+
+    #[test]
+    fn eviction_is_confined_to_the_namespace_over_quota() {
+        let storage = create_partitioned_storage();
+        let quiet = storage.namespace(Some("alice"), b"key");
+        quiet
+            .set(b"key".to_vec(), Record::new(b"quiet".to_vec(), 0, 0, 0))
+            .unwrap();
+
+        let noisy = storage.namespace(Some("bob"), b"noisy:");
+        for i in 0..10u8 {
+            let key = format!("noisy:{}", i).into_bytes();
+            noisy.set(key, Record::new(vec![i], 0, 0, 0)).unwrap();
+        }
+
+        assert_eq!(noisy.len(), 3);
+        assert!(noisy.used_memory() <= noisy.memory_limit());
+        assert!(noisy.get(&b"noisy:0".to_vec()).is_err());
+        assert!(noisy.get(&b"noisy:9".to_vec()).is_ok());
+        assert_eq!(noisy.stats.evictions.load(Ordering::Relaxed), 7);
+        assert!(quiet.get(&b"key".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn least_recently_used_item_is_evicted_first() {
+        let timer = Arc::new(MockSystemTimer::new());
+        let storage = create_partitioned_storage_with(timer.clone());
+        let noisy = storage.namespace(None, b"noisy:");
+        for key in ["noisy:a", "noisy:b", "noisy:c"] {
+            let record = Record::new(b"v".to_vec(), 0, 0, 0);
+            noisy.set(key.as_bytes().to_vec(), record).unwrap();
+        }
+        timer.set(BUMP_INTERVAL);
+        assert!(noisy.get(&b"noisy:a".to_vec()).is_ok());
+        let record = Record::new(b"v".to_vec(), 0, 0, 0);
+        noisy.set(b"noisy:d".to_vec(), record).unwrap();
+
+        assert!(noisy.get(&b"noisy:a".to_vec()).is_ok());
+        assert!(noisy.get(&b"noisy:b".to_vec()).is_err());
+    }
+
+    #[test]
+    fn reads_move_an_item_up_once_per_interval() {
+        let timer = Arc::new(MockSystemTimer::new());
+        let storage = create_partitioned_storage_with(timer.clone());
+        let noisy = storage.namespace(None, b"noisy:");
+        for key in ["noisy:a", "noisy:b", "noisy:c"] {
+            let record = Record::new(b"v".to_vec(), 0, 0, 0);
+            noisy.set(key.as_bytes().to_vec(), record).unwrap();
+        }
+        timer.set(BUMP_INTERVAL - 1);
+        assert!(noisy.get(&b"noisy:a".to_vec()).is_ok());
+        let record = Record::new(b"v".to_vec(), 0, 0, 0);
+        noisy.set(b"noisy:d".to_vec(), record).unwrap();
+
+        assert!(noisy.get(&b"noisy:a".to_vec()).is_err());
+        assert!(noisy.get(&b"noisy:b".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn item_larger_than_quota_is_rejected() {
+        let storage = create_partitioned_storage();
+        let record = Record::new(vec![0; 1024], 0, 0, 0);
+        let result = storage.set(b"noisy:big".to_vec(), record);
+        assert_eq!(result.unwrap_err(), StorageError::ValueTooLarge);
+    }
+
+    #[test]
+    fn compressed_values_are_charged_their_compressed_size() {
+        let timer = Arc::new(MockSystemTimer::new());
+        let quota = 3 * (ITEM_OVERHEAD + 1024);
+        let compressor = Arc::new(Compressor::new(compression::CompressionConfig::new(
+            compression::Algorithm::Lz4,
+        )));
+        let storage = Storage::with_namespaces(timer, vec![NamespaceConfig::new("default", quota)])
+            .with_compressor(compressor);
+        let value = b"0123456789abcdef".repeat(256);
+        for key in 0..10u8 {
+            let record = Record::new(value.clone(), 0, 0, 0);
+            storage.set(vec![key], record).unwrap();
+        }
+        let namespace = &storage.namespaces()[0];
+        assert!(namespace.used_memory() < quota);
+        for key in 0..10u8 {
+            assert_eq!(storage.get(&vec![key]).unwrap().value, value);
+        }
+        storage.append(vec![0], record("!")).unwrap();
+        let mut visited = 0;
+        namespace.for_each(|_, record| {
+            assert!(record.header.compression.is_none());
+            visited += 1;
+        });
+        assert_eq!(visited, 10);
+        assert_eq!(storage.get(&vec![0]).unwrap().value.len(), value.len() + 1);
+    }
+
+    #[test]
+    fn items_can_be_written_while_visited() {
+        let storage = create_server().storage;
+        for key in 0..10u8 {
+            storage.set(vec![key], record("v")).unwrap();
+        }
+        let namespace = storage.namespace(None, b"");
+        let mut visited = 0;
+        namespace.for_each(|key, _| {
+            namespace.set(key.to_vec(), record("w")).unwrap();
+            namespace.set(vec![key[0] + 10], record("new")).unwrap();
+            visited += 1;
+        });
+        assert_eq!(visited, 10);
+        assert_eq!(storage.get(&vec![0]).unwrap().value, b"w");
+    }
+
+    #[test]
+    fn writes_invalidate_leases() {
+        let storage = create_server().storage;
+        let key = b"key".to_vec();
+        let token = match storage.lease_get(&key).unwrap() {
+            LeaseGet::Granted(token) => token,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(matches!(storage.lease_get(&key).unwrap(), LeaseGet::Wait));
+        storage.set(key.clone(), record("newer")).unwrap();
+        assert_eq!(
+            storage
+                .lease_set(key.clone(), record("older"), token)
+                .unwrap_err(),
+            StorageError::ItemNotStored
+        );
+        match storage.lease_get(&key).unwrap() {
+            LeaseGet::Hit { record, .. } => assert_eq!(record.value, b"newer"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn expired_items_are_served_stale_during_their_grace_period() {
+        let server = create_server();
+        let key = b"key".to_vec();
+        let expiring = Record::new(b"old".to_vec(), 0, 0, 10).with_grace(30);
+        server.storage.set(key.clone(), expiring.clone()).unwrap();
+
+        server.timer.set(15);
+        assert!(server.storage.get(&key).is_err());
+        let token = match server.storage.lease_get(&key).unwrap() {
+            LeaseGet::Hit {
+                record,
+                stale: true,
+                token: Some(token),
+            } => {
+                assert_eq!(record.value, b"old");
+                token
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        // Only one reader gets to recompute the item.
+        assert!(matches!(
+            server.storage.lease_get(&key).unwrap(),
+            LeaseGet::Hit {
+                stale: true,
+                token: None,
+                ..
+            }
+        ));
+        server
+            .storage
+            .lease_set(key.clone(), record("new"), token)
+            .unwrap();
+        assert_eq!(server.storage.get(&key).unwrap().value, b"new");
+
+        server.storage.set(key.clone(), expiring).unwrap();
+        server.timer.set(55);
+        assert!(matches!(
+            server.storage.lease_get(&key).unwrap(),
+            LeaseGet::Granted(_)
+        ));
+        assert!(server.storage.is_empty());
+    }
+
+    #[test]
+    fn hot_items_are_recomputed_before_they_expire() {
+        let server = create_server();
+        let key = b"key".to_vec();
+        let record = Record::new(b"v".to_vec(), 0, 0, 100).with_early_recompute(5_000);
+        server.storage.set(key.clone(), record).unwrap();
+
+        let early = |storage: &Storage| match storage.lease_get(&key).unwrap() {
+            LeaseGet::Hit { stale, token, .. } => {
+                assert!(!stale);
+                token.is_some()
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        // Far from expiry, -ln(u) would have to reach 20.
+        assert!(!(0..100).any(|_| early(&server.storage)));
+        // A second before expiry, most reads are picked; only the first of
+        // them gets the lease.
+        server.timer.set(99);
+        let picked = (0..200).filter(|_| early(&server.storage)).count();
+        assert_eq!(picked, 1);
+    }
+
+    #[test]
+    fn invalidating_a_tag_expires_its_items() {
+        let storage = create_server().storage;
+        let tagged = |value: &str, tags: &[&[u8]]| {
+            record(value).with_tags(tags.iter().map(|tag| tag.to_vec()).collect())
+        };
+        storage
+            .set(b"profile".to_vec(), tagged("p", &[b"user:1"]))
+            .unwrap();
+        storage
+            .set(b"feed".to_vec(), tagged("f", &[b"user:1", b"team:1"]))
+            .unwrap();
+        storage
+            .set(b"other".to_vec(), tagged("o", &[b"user:2"]))
+            .unwrap();
+        let used = storage.namespaces()[0].used_memory();
+
+        storage.invalidate_tag(b"user:1");
+        assert_eq!(
+            storage.get(&b"profile".to_vec()),
+            Err(StorageError::NotFound)
+        );
+        assert_eq!(storage.get(&b"other".to_vec()).unwrap().value, b"o");
+        assert!(storage.namespaces()[0].used_memory() < used);
+
+        // An invalidated item counts as missing to writes too, and items
+        // stored after the invalidation carry the new generation.
+        assert!(storage
+            .add(b"feed".to_vec(), tagged("g", &[b"user:1"]))
+            .is_ok());
+        assert_eq!(storage.get(&b"feed".to_vec()).unwrap().value, b"g");
+        assert!(storage
+            .tags()
+            .stats()
+            .contains(&("tag_invalidated_items".to_string(), "1".to_string())));
+    }
+
+    #[test]
+    fn expiry_spares_a_record_stored_since() {
+        let server = create_server();
+        let key = b"key".to_vec();
+        server
+            .storage
+            .set(key.clone(), Record::new(b"old".to_vec(), 0, 0, 10))
+            .unwrap();
+        let namespace = &server.storage.namespaces()[0];
+        let found = namespace.get_raw(&key).unwrap();
+
+        // A set lands between a get reading the record and finding it expired.
+        server.timer.set(20);
+        server.storage.set(key.clone(), record("new")).unwrap();
+        assert!(namespace.check_if_expired(&key, &found));
+        assert_eq!(server.storage.get(&key).unwrap().value, b"new");
+    }
+
+    #[test]
+    fn watchers_see_sets_deletes_evictions_and_expirations() {
+        let timer = Arc::new(MockSystemTimer::new());
+        let mut noisy = NamespaceConfig::new("noisy", 2 * (ITEM_OVERHEAD + 8));
+        noisy.prefixes.push(b"noisy:".to_vec());
+        let storage = Storage::with_namespaces(timer.clone(), vec![noisy]);
+        let mut noisy = storage.watch(WatchFilter::new(b"noisy:"));
+        let mut expirations =
+            storage.watch(WatchFilter::default().with_kinds(&[EventKind::Expire]));
+
+        storage.set(b"other".to_vec(), record("o")).unwrap();
+        storage
+            .set(b"noisy:1".to_vec(), Record::new(b"1".to_vec(), 0, 0, 10))
+            .unwrap();
+        storage.set(b"noisy:2".to_vec(), record("2")).unwrap();
+        storage.set(b"noisy:3".to_vec(), record("3")).unwrap();
+        storage.delete(&b"noisy:2".to_vec(), 0).unwrap();
+        storage
+            .set(b"expiring".to_vec(), Record::new(b"e".to_vec(), 0, 0, 10))
+            .unwrap();
+        timer.set(10);
+        assert!(storage.get(&b"expiring".to_vec()).is_err());
+
+        let mut events = Vec::new();
+        while let Some(event) = noisy.try_next() {
+            assert_eq!(event.namespace, "noisy");
+            events.push((event.kind, event.key));
+        }
+        assert_eq!(
+            events,
+            vec![
+                (EventKind::Set, b"noisy:1".to_vec()),
+                (EventKind::Set, b"noisy:2".to_vec()),
+                (EventKind::Set, b"noisy:3".to_vec()),
+                (EventKind::Evict, b"noisy:1".to_vec()),
+                (EventKind::Delete, b"noisy:2".to_vec()),
+            ]
+        );
+        let event = expirations.try_next().unwrap();
+        assert_eq!(
+            (event.namespace.as_str(), event.key),
+            ("default", b"expiring".to_vec())
+        );
+        assert_eq!(expirations.try_next(), None);
+    }
+
+    #[test]
+    fn scans_visit_every_namespace_in_pages() {
+        let storage = create_partitioned_storage();
+        for key in ["a", "b", "noisy:1", "noisy:2", "c"] {
+            storage.set(key.as_bytes().to_vec(), record("v")).unwrap();
+        }
+        storage
+            .set(b"d".to_vec(), Record::new(b"v".to_vec(), 0, 3, 0))
+            .unwrap();
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let page = storage.scan(cursor, b"", 2);
+            assert!(page.entries.len() <= 2);
+            // Storing a key again keeps its place, so a busy key is neither
+            // listed twice nor keeps the scan going.
+            storage.set(b"a".to_vec(), record("w")).unwrap();
+            keys.extend(
+                page.entries
+                    .into_iter()
+                    .map(|entry| (entry.namespace, String::from_utf8(entry.key).unwrap())),
+            );
+            cursor = page.cursor;
+            if cursor == 0 {
+                break;
+            }
+        }
+        let listed = |namespace: &str, key: &str| {
+            keys.iter()
+                .filter(|entry| **entry == (namespace.to_string(), key.to_string()))
+                .count()
+        };
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(listed("default", key), 1);
+        }
+        assert_eq!(listed("noisy", "noisy:1") + listed("noisy", "noisy:2"), 2);
+
+        let page = storage.scan(0, b"d", 10);
+        assert_eq!(page.cursor, 0);
+        let entry = &page.entries[0];
+        assert_eq!((entry.flags, entry.segment), (3, Segment::Memory));
+        assert_eq!(entry.size, ITEM_OVERHEAD + 2);
+    }
+
+    #[test]
+    fn scans_list_keys_used_before_they_are_visited() {
+        let timer = Arc::new(MockSystemTimer::new());
+        let storage = create_partitioned_storage_with(timer.clone());
+        for key in ["a", "b", "c", "d"] {
+            storage.set(key.as_bytes().to_vec(), record("v")).unwrap();
+        }
+
+        let page = storage.scan(0, b"", 1);
+        let mut keys: Vec<_> = page.entries.into_iter().map(|entry| entry.key).collect();
+        timer.set(BUMP_INTERVAL);
+        assert!(storage.get(&b"c".to_vec()).is_ok());
+        storage.set(b"d".to_vec(), record("w")).unwrap();
+        storage.set(b"e".to_vec(), record("v")).unwrap();
+        let mut cursor = page.cursor;
+        while cursor != 0 {
+            let page = storage.scan(cursor, b"", 1);
+            keys.extend(page.entries.into_iter().map(|entry| entry.key));
+            cursor = page.cursor;
+        }
+        assert_eq!(keys, [b"a", b"b", b"c", b"d"]);
+    }
+
+    fn record(value: &str) -> Record {
+        Record::new(value.as_bytes().to_vec(), 0, 0, 0)
+    }
+
+    #[test]
+    fn add_and_replace_depend_on_existence() {
+        let storage = create_server().storage;
+        let key = b"key".to_vec();
+        assert_eq!(
+            storage.replace(key.clone(), record("a")).unwrap_err(),
+            StorageError::NotFound
+        );
+        assert!(storage.add(key.clone(), record("a")).is_ok());
+        assert_eq!(
+            storage.add(key.clone(), record("b")).unwrap_err(),
+            StorageError::KeyExists
+        );
+        assert!(storage.replace(key.clone(), record("c")).is_ok());
+        assert_eq!(storage.get(&key).unwrap().value, b"c");
+    }
+
+    #[test]
+    fn every_write_gets_a_new_cas() {
+        let storage = create_server().storage;
+        let key = b"key".to_vec();
+        let first = storage.set(key.clone(), record("a")).unwrap().cas;
+        let second = storage.set(key.clone(), record("b")).unwrap().cas;
+        assert_ne!(first, second);
+
+        let mut stale = record("c");
+        stale.header.cas = first;
+        assert_eq!(
+            storage.cas(key.clone(), stale).unwrap_err(),
+            StorageError::KeyExists
+        );
+        let mut fresh = record("c");
+        fresh.header.cas = second;
+        assert!(storage.cas(key.clone(), fresh).is_ok());
+        assert_eq!(
+            storage.cas(b"missing".to_vec(), record("d")).unwrap_err(),
+            StorageError::NotFound
+        );
+    }
+
+    #[test]
+    fn append_and_prepend_keep_flags() {
+        let storage = create_server().storage;
+        let key = b"key".to_vec();
+        assert_eq!(
+            storage.append(key.clone(), record("x")).unwrap_err(),
+            StorageError::ItemNotStored
+        );
+        storage
+            .set(key.clone(), Record::new(b"mid".to_vec(), 0, 9, 0))
+            .unwrap();
+        storage.append(key.clone(), record(">")).unwrap();
+        storage.prepend(key.clone(), record("<")).unwrap();
+        let found = storage.get(&key).unwrap();
+        assert_eq!(found.value, b"<mid>");
+        assert_eq!(found.header.flags, 9);
+    }
+
+    #[test]
+    fn increment_and_decrement_counters() {
+        let storage = create_server().storage;
+        let key = b"counter".to_vec();
+        let param = |delta, expiration| IncrementParam {
+            delta,
+            value: 10,
+            expiration,
+        };
+        assert_eq!(
+            storage
+                .increment(key.clone(), param(1, u32::MAX))
+                .unwrap_err(),
+            StorageError::NotFound
+        );
+        assert_eq!(
+            storage.increment(key.clone(), param(1, 0)).unwrap().value,
+            10
+        );
+        assert_eq!(
+            storage.increment(key.clone(), param(5, 0)).unwrap().value,
+            15
+        );
+        assert_eq!(
+            storage.decrement(key.clone(), param(20, 0)).unwrap().value,
+            0
+        );
+        assert_eq!(storage.get(&key).unwrap().value, b"0");
+
+        storage.set(b"text".to_vec(), record("abc")).unwrap();
+        assert_eq!(
+            storage
+                .increment(b"text".to_vec(), param(1, 0))
+                .unwrap_err(),
+            StorageError::ArithOnNonNumeric
+        );
+    }
+
+    #[test]
+    fn touch_delete_and_flush() {
+        let server = create_server();
+        let storage = &server.storage;
+        let key = b"key".to_vec();
+        storage.set(key.clone(), record("a")).unwrap();
+        storage.touch(key.clone(), 10).unwrap();
+        server.timer.set(10);
+        assert!(storage.get(&key).is_err());
+
+        let cas = storage.set(key.clone(), record("a")).unwrap().cas;
+        assert_eq!(
+            storage.delete(&key, cas + 1).unwrap_err(),
+            StorageError::KeyExists
+        );
+        assert!(storage.delete(&key, cas).is_ok());
+        assert_eq!(storage.delete(&key, 0).unwrap_err(), StorageError::NotFound);
+
+        storage.set(key.clone(), record("a")).unwrap();
+        storage.flush();
+        assert!(storage.is_empty());
+        assert_eq!(storage.namespaces()[0].used_memory(), 0);
+    }
+
+    struct RecordingObserver {
+        seen: Mutex<Vec<String>>,
+    }
+
+    impl MutationObserver for RecordingObserver {
+        fn on_mutation(&self, namespace: &str, mutation: &Mutation) {
+            let seen = match mutation {
+                Mutation::Store { kind, key, record } => format!(
+                    "{}:{:?}:{}={}",
+                    namespace,
+                    kind,
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(&record.value)
+                ),
+                Mutation::Delete { key } => {
+                    format!("{}:Delete:{}", namespace, String::from_utf8_lossy(key))
+                }
+                Mutation::Flush => format!("{}:Flush", namespace),
+                Mutation::InvalidateTag { tag } => {
+                    format!("InvalidateTag:{}", String::from_utf8_lossy(tag))
+                }
+            };
+            self.seen.lock().unwrap().push(seen);
+        }
+    }
+
+    #[test]
+    fn observers_see_applied_mutations_only() {
+        let storage = create_server().storage;
+        let observer = Arc::new(RecordingObserver {
+            seen: Mutex::new(Vec::new()),
+        });
+        storage.add_observer(observer.clone());
+
+        let key = b"k".to_vec();
+        storage.set(key.clone(), record("1")).unwrap();
+        assert!(storage.add(key.clone(), record("2")).is_err());
+        storage.append(key.clone(), record("2")).unwrap();
+        storage.delete(&key, 0).unwrap();
+        storage.flush();
+
+        assert_eq!(
+            *observer.seen.lock().unwrap(),
+            vec![
+                "default:Set:k=1",
+                "default:Append:k=12",
+                "default:Delete:k",
+                "default:Flush"
+            ]
+        );
+    }
 }
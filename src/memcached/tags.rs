@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+
+/// A tag of an item, with the generation the tag was at when the item was
+/// stored.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Tag {
+    pub(crate) name: Vec<u8>,
+    pub(crate) generation: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TagStats {
+    pub(crate) invalidations: AtomicU64,
+    pub(crate) invalidated_items: AtomicU64,
+}
+
+/// The generation of every tag ever invalidated, shared by all namespaces.
+/// Invalidating a tag only bumps its generation; items stored with an older
+/// one are found to be gone the next time they are looked up, so a tag on
+/// any number of items is invalidated in constant time.
+#[derive(Debug, Default)]
+pub struct Tags {
+    generations: DashMap<Vec<u8>, u64>,
+    pub(crate) stats: TagStats,
+}
+
+impl Tags {
+    pub fn new() -> Tags {
+        Default::default()
+    }
+
+    /// The current generation of `tag`, 0 until it is first invalidated.
+    pub(crate) fn generation(&self, tag: &[u8]) -> u64 {
+        self.generations
+            .get(tag)
+            .map_or(0, |generation| *generation)
+    }
+
+    /// Stamps `tags` with their current generations.
+    pub(crate) fn stamp(&self, tags: &mut [Tag]) {
+        for tag in tags {
+            tag.generation = self.generation(&tag.name);
+        }
+    }
+
+    /// Whether any of `tags` was invalidated since it was stamped.
+    pub(crate) fn is_invalidated(&self, tags: &[Tag]) -> bool {
+        tags.iter()
+            .any(|tag| self.generation(&tag.name) != tag.generation)
+    }
+
+    /// Logically expires every item tagged with `tag`, returning its new
+    /// generation.
+    pub fn invalidate(&self, tag: &[u8]) -> u64 {
+        self.stats.invalidations.fetch_add(1, Ordering::Relaxed);
+        let mut generation = self.generations.entry(tag.to_vec()).or_insert(0);
+        *generation += 1;
+        *generation
+    }
+
+    /// Counters as `(name, value)` pairs.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        vec![
+            ("tags".to_string(), self.generations.len().to_string()),
+            (
+                "tag_invalidations".to_string(),
+                self.stats.invalidations.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "tag_invalidated_items".to_string(),
+                self.stats
+                    .invalidated_items
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &[u8]) -> Tag {
+        Tag {
+            name: name.to_vec(),
+            generation: 0,
+        }
+    }
+
+    #[test]
+    fn invalidation_only_affects_older_stamps() {
+        let registry = Tags::new();
+        let mut tags = vec![tag(b"user:1"), tag(b"team:1")];
+        registry.stamp(&mut tags);
+        assert!(!registry.is_invalidated(&tags));
+
+        assert_eq!(registry.invalidate(b"team:1"), 1);
+        assert!(registry.is_invalidated(&tags));
+        assert!(!registry.is_invalidated(&tags[..1]));
+
+        registry.stamp(&mut tags);
+        assert_eq!(tags[1].generation, 1);
+        assert!(!registry.is_invalidated(&tags));
+        assert!(registry
+            .stats()
+            .contains(&("tag_invalidations".to_string(), "1".to_string())));
+    }
+}
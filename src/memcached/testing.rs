@@ -0,0 +1,65 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::memcached::server::TcpServer;
+use crate::memcached::storage::Storage;
+use crate::memcached::timer::Timer;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An address nothing listens on, at least until a test binds it.
+pub(crate) fn free_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Starts a server on a free port, waiting until it accepts connections.
+pub(crate) async fn start_server() -> (String, Arc<Storage>) {
+    serve(TcpServer::new()).await
+}
+
+/// Runs `server` on a free port, waiting until it accepts connections.
+pub(crate) async fn serve(server: TcpServer) -> (String, Arc<Storage>) {
+    let address = free_address();
+    let storage = serve_at(&address, server).await;
+    (address, storage)
+}
+
+/// Runs `server` on `address`, waiting until it accepts connections.
+pub(crate) async fn serve_at(address: &str, mut server: TcpServer) -> Arc<Storage> {
+    let storage = server.storage();
+    let run_address = address.to_string();
+    tokio::spawn(async move { server.run(run_address).await });
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    storage
+}
+
+/// A listener whose connections are accepted by the kernel but never
+/// answered, for as long as it is kept.
+pub(crate) fn silent_listener() -> std::net::TcpListener {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap()
+}
+
+/// A clock that stands still until a test moves it.
+pub(crate) struct MockTimer(AtomicU64);
+
+impl MockTimer {
+    pub(crate) fn new(secs: u64) -> Self {
+        MockTimer(AtomicU64::new(secs))
+    }
+
+    pub(crate) fn set(&self, secs: u64) {
+        self.0.store(secs, Ordering::Relaxed);
+    }
+}
+
+impl Timer for MockTimer {
+    fn secs(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -1,7 +1,10 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 pub trait Timer {
     fn secs(&self) -> u64;
 }
 
+#[derive(Default)]
 pub struct SystemTimer;
 
 impl SystemTimer {
@@ -12,6 +15,9 @@ impl SystemTimer {
 
 impl Timer for SystemTimer {
     fn secs(&self) -> u64 {
-        0
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0)
     }
 }
@@ -0,0 +1,214 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use num_derive::FromPrimitive;
+use tokio::sync::mpsc;
+
+/// How many events a subscriber may fall behind by before new ones are
+/// dropped.
+pub const DEFAULT_WATCH_BUFFER: usize = 1024;
+
+/// What happened to a key.
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    /// Stored by any command that writes a value, touches included.
+    Set = 1,
+    Delete = 2,
+    /// Dropped to make room for other items.
+    Evict = 3,
+    /// Found expired or invalidated by a tag and dropped.
+    Expire = 4,
+    /// Every item of the namespace was dropped; the key is empty.
+    Flush = 5,
+}
+
+impl EventKind {
+    fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub namespace: String,
+    pub key: Vec<u8>,
+}
+
+/// Which events a subscriber is sent: those on keys starting with `prefix`
+/// whose kind is in `kinds`, a bit per `EventKind`, or of any kind if 0.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WatchFilter {
+    pub prefix: Vec<u8>,
+    pub kinds: u32,
+}
+
+impl WatchFilter {
+    pub fn new(prefix: &[u8]) -> WatchFilter {
+        WatchFilter {
+            prefix: prefix.to_vec(),
+            kinds: 0,
+        }
+    }
+
+    /// Narrows the filter to events of `kinds`.
+    pub fn with_kinds(mut self, kinds: &[EventKind]) -> WatchFilter {
+        self.kinds = kinds.iter().fold(0, |mask, kind| mask | kind.mask());
+        self
+    }
+
+    fn matches(&self, kind: EventKind, key: &[u8]) -> bool {
+        (self.kinds == 0 || self.kinds & kind.mask() != 0) && key.starts_with(&self.prefix)
+    }
+}
+
+struct Subscriber {
+    filter: WatchFilter,
+    events: mpsc::Sender<Event>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// The receiving end of a subscription, which ends once it is dropped.
+pub struct Watch {
+    events: mpsc::Receiver<Event>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Watch {
+    /// The next event, waiting for one if need be.
+    pub async fn next(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// The next event if one is queued.
+    pub fn try_next(&mut self) -> Option<Event> {
+        self.events.try_recv().ok()
+    }
+
+    /// How many events were dropped so far because this subscriber fell
+    /// behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Fans the changes of every namespace out to subscribers. Events are
+/// published while the key is locked, so each subscriber has a bounded
+/// buffer: a subscriber that falls behind misses events, counted in its
+/// `dropped`, rather than slowing writers down.
+pub struct Watchers {
+    buffer: usize,
+    subscribers: RwLock<Vec<Subscriber>>,
+    active: AtomicUsize,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Watchers {
+    pub fn new(buffer: usize) -> Watchers {
+        Watchers {
+            buffer: buffer.max(1),
+            subscribers: RwLock::new(Vec::new()),
+            active: AtomicUsize::new(0),
+            delivered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn subscribe(&self, filter: WatchFilter) -> Watch {
+        let (sender, events) = mpsc::channel(self.buffer);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut subscribers = self.subscribers.write().unwrap();
+        subscribers.push(Subscriber {
+            filter,
+            events: sender,
+            dropped: dropped.clone(),
+        });
+        self.active.store(subscribers.len(), Ordering::Relaxed);
+        Watch { events, dropped }
+    }
+
+    pub(crate) fn publish(&self, namespace: &str, kind: EventKind, key: &[u8]) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut closed = false;
+        for subscriber in self.subscribers.read().unwrap().iter() {
+            if !subscriber.filter.matches(kind, key) {
+                continue;
+            }
+            let event = Event {
+                kind,
+                namespace: namespace.to_string(),
+                key: key.to_vec(),
+            };
+            match subscriber.events.try_send(event) {
+                Ok(()) => {
+                    self.delivered.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => closed = true,
+            }
+        }
+        if closed {
+            let mut subscribers = self.subscribers.write().unwrap();
+            subscribers.retain(|subscriber| !subscriber.events.is_closed());
+            self.active.store(subscribers.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Counters as `(name, value)` pairs.
+    pub fn stats(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "watchers".to_string(),
+                self.active.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "watch_events".to_string(),
+                self.delivered.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "watch_dropped".to_string(),
+                self.dropped.load(Ordering::Relaxed).to_string(),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_subscribers_drop_events() {
+        let watchers = Watchers::new(2);
+        let mut all = watchers.subscribe(WatchFilter::default());
+        let mut deletes =
+            watchers.subscribe(WatchFilter::new(b"user:").with_kinds(&[EventKind::Delete]));
+        for key in [&b"user:1"[..], b"user:2", b"session:1"] {
+            watchers.publish("default", EventKind::Set, key);
+        }
+        watchers.publish("default", EventKind::Delete, b"user:1");
+
+        assert_eq!(all.try_next().unwrap().key, b"user:1");
+        assert_eq!(all.try_next().unwrap().key, b"user:2");
+        assert_eq!(all.try_next(), None);
+        assert_eq!(all.dropped(), 2);
+        let event = deletes.try_next().unwrap();
+        assert_eq!(
+            (event.kind, event.key),
+            (EventKind::Delete, b"user:1".to_vec())
+        );
+        assert_eq!(deletes.try_next(), None);
+
+        drop(deletes);
+        watchers.publish("default", EventKind::Delete, b"user:2");
+        assert!(watchers
+            .stats()
+            .contains(&("watchers".to_string(), "1".to_string())));
+    }
+}
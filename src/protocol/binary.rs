@@ -1,6 +1,5 @@
 use num_derive::FromPrimitive;
 use serde_derive::{Deserialize, Serialize};
-use simplelog::Color::Magenta;
 
 #[derive(FromPrimitive)]
 pub enum Magic {
@@ -8,7 +7,7 @@ pub enum Magic {
     Response = 0x81,
 }
 
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
 pub enum Command {
     Get = 0x00,
     Set = 0x01,
@@ -46,9 +45,72 @@ pub enum Command {
     SaslListMechs = 0x20,
     SaslAuth = 0x21,
     SaslStep = 0x22,
+
+    // rustcache extensions
+    Snapshot = 0xc0,
+    LeaseGet = 0xc1,
+    LeaseSet = 0xc2,
+    SetTagged = 0xc3,
+    InvalidateTag = 0xc4,
+    Watch = 0xc5,
+    WatchEvent = 0xc6,
+    MetaDump = 0xc7,
+    SlowLog = 0xc8,
 }
 
-#[derive(FromPrimitive)]
+impl Command {
+    /// The command a quiet command is the silent form of, or itself.
+    pub fn loud(self) -> Command {
+        match self {
+            Command::GetQuiet => Command::Get,
+            Command::GetKeyQuiet => Command::GetKey,
+            Command::SetQuiet => Command::Set,
+            Command::AddQuiet => Command::Add,
+            Command::ReplaceQuiet => Command::Replace,
+            Command::DeleteQuiet => Command::Delete,
+            Command::IncrementQuiet => Command::Increment,
+            Command::DecrementQuiet => Command::Decrement,
+            Command::QuitQuiet => Command::Quit,
+            Command::FlushQuiet => Command::Flush,
+            Command::AppendQuiet => Command::Append,
+            Command::PrependQuiet => Command::Prepend,
+            Command::GetAndTouchQuiet => Command::GetAndTouch,
+            Command::GetAndTouchKeyQuiet => Command::GetAndTouchKey,
+            command => command,
+        }
+    }
+
+    /// The command's name in snake case, e.g. `get_quiet`.
+    pub fn name(self) -> String {
+        let mut name = String::new();
+        for c in format!("{:?}", self).chars() {
+            if c.is_ascii_uppercase() && !name.is_empty() {
+                name.push('_');
+            }
+            name.push(c.to_ascii_lowercase());
+        }
+        name
+    }
+
+    pub fn is_quiet(self) -> bool {
+        self.loud() != self
+    }
+
+    /// Whether a response with `status` is sent for this command: quiet gets
+    /// only answer hits, other quiet commands only failures.
+    pub fn answers(self, status: u16) -> bool {
+        if !self.is_quiet() {
+            return true;
+        }
+        let is_get = matches!(
+            self.loud(),
+            Command::Get | Command::GetKey | Command::GetAndTouch | Command::GetAndTouchKey
+        );
+        (status == ResponseStatus::Success as u16) == is_get
+    }
+}
+
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
 pub enum ResponseStatus {
     Success = 0x00,
     KeyNotExists = 0x01,
@@ -57,10 +119,14 @@ pub enum ResponseStatus {
     InvalidArguments = 0x04,
     NotStored = 0x05,
     NonNumericValue = 0x06,
+    NotMyVbucket = 0x07,
     AuthenticationError = 0x20,
     AuthenticationContinue = 0x21,
-    UnkownError = 0x81,
+    UnknownCommand = 0x81,
     NotEnoughMemory = 0x82,
+    NotSupported = 0x83,
+    InternalError = 0x84,
+    TemporaryFailure = 0x86,
 }
 
 #[derive(FromPrimitive)]
@@ -99,15 +165,22 @@ impl ResponseHeader {
         ResponseHeader {
             magic: Magic::Response as u8,
             opcode: cmd,
-            opaque: opaque,
+            opaque,
             ..ResponseHeader::default()
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Request {
-    header: RequestHeader,
+    pub(crate) header: RequestHeader,
+}
+
+/// A request that could not be decoded, answered with `status` alone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InvalidRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) status: u16,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -115,10 +188,19 @@ pub struct Response {
     pub(crate) header: ResponseHeader,
 }
 
+/// Sent by a cluster node for a key another node owns; the value holds the
+/// owner's address.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotMyVbucketResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) value: Vec<u8>,
+}
+
 pub type NoopRequest = Request;
 pub type NoopResponse = Response;
+pub type ErrorResponse = Response;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GetRequest {
     pub(crate) header: RequestHeader,
     pub(crate) key: Vec<u8>,
@@ -137,55 +219,276 @@ pub struct GetResponse {
 }
 
 pub type DeleteRequest = GetRequest;
+pub type DeleteQuietRequest = GetRequest;
 pub type DeleteResponse = Response;
 
 pub type GetQuietlyResponse = GetResponse;
 pub type GetKeyResponse = GetResponse;
 pub type GetKeyQuietlyResponse = GetResponse;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Stores carry `[u32 flags][u32 expiration]` extras, optionally followed
+/// by `[u32 grace][u32 recompute_ms]` as for a lease set; both are 0 if left
+/// out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SetRequest {
     pub(crate) header: RequestHeader,
     pub(crate) flags: u32,
     pub(crate) expiration: u32,
+    pub(crate) grace: u32,
+    pub(crate) recompute_ms: u32,
     pub(crate) key: Vec<u8>,
     pub(crate) value: Vec<u8>,
 }
 
 pub type AddRequest = SetRequest;
 pub type ReplaceRequest = SetRequest;
+pub type SetQuietRequest = SetRequest;
+pub type AddQuietRequest = SetRequest;
+pub type ReplaceQuietRequest = SetRequest;
 
 pub type SetResponse = Response;
 pub type AddResponse = Response;
 pub type ReplaceResponse = Response;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Append and prepend carry no extras: the item keeps its flags and expiry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppendRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+}
+
+pub type AppendQuietRequest = AppendRequest;
+pub type PrependRequest = AppendRequest;
+pub type PrependQuietRequest = AppendRequest;
+
+pub type AppendResponse = Response;
+pub type PrependResponse = Response;
+
+/// An expiration of `u32::MAX` fails the command on a missing key instead of
+/// creating it with `initial`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IncrementRequest {
-    header: RequestHeader,
-    delta: u64,
-    initial: u64,
-    expiration: u32,
+    pub(crate) header: RequestHeader,
+    pub(crate) delta: u64,
+    pub(crate) initial: u64,
+    pub(crate) expiration: u32,
+    pub(crate) key: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IncrementResponse {
-    value: u64,
+    pub(crate) header: ResponseHeader,
+    pub(crate) value: u64,
 }
 
+pub type IncrementQuietRequest = IncrementRequest;
 pub type DecrementRequest = IncrementRequest;
+pub type DecrementQuietRequest = IncrementRequest;
 pub type DecrementResponse = IncrementResponse;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TouchRequest {
-    expiration: u32,
+    pub(crate) header: RequestHeader,
+    pub(crate) expiration: u32,
+    pub(crate) key: Vec<u8>,
 }
 
 pub type TouchResponse = Response;
 
-#[derive(Serialize, Deserialize, Debug)]
+pub type GetAndTouchRequest = TouchRequest;
+pub type GetAndTouchQuietRequest = TouchRequest;
+pub type GetAndTouchResponse = GetResponse;
+pub type GetAndTouchQuietlyResponse = GetResponse;
+
+/// A non-zero expiration delays the flush by that many seconds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FlushRequest {
-    header: RequestHeader,
-    expiration: u32,
+    pub(crate) header: RequestHeader,
+    pub(crate) expiration: u32,
 }
 
+pub type FlushQuietRequest = FlushRequest;
 pub type FlushResponse = Response;
+
+pub type VersionRequest = Request;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) value: Vec<u8>,
+}
+
+pub type SaslListMechsRequest = Request;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SaslRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+}
+
+pub type SaslAuthRequest = SaslRequest;
+pub type SaslStepRequest = SaslRequest;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SaslResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) value: Vec<u8>,
+}
+
+pub type SaslListMechsResponse = SaslResponse;
+pub type SaslAuthResponse = SaslResponse;
+pub type SaslStepResponse = SaslResponse;
+
+pub type StatRequest = GetRequest;
+
+/// A stat group is answered with one packet per entry followed by an empty
+/// terminating packet.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) stats: Vec<(String, String)>,
+}
+
+pub type SnapshotRequest = Request;
+pub type SnapshotResponse = Response;
+
+pub type LeaseGetRequest = GetRequest;
+
+/// A hit carries the item with `[u32 flags][u64 token][u8 stale]` extras,
+/// the token being non-zero if the client should refresh the item. A miss
+/// has status `KeyNotExists` and the token of the lease it was granted in
+/// the extras, or status `TemporaryFailure` and no token while another
+/// client holds the lease.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaseGetResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) flags: u32,
+    pub(crate) token: u64,
+    pub(crate) stale: bool,
+    pub(crate) value: Vec<u8>,
+}
+
+/// A set that only succeeds with the current lease on the key. The grace
+/// period and early recompute time are optional extras, both 0 if left out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaseSetRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) flags: u32,
+    pub(crate) expiration: u32,
+    pub(crate) token: u64,
+    pub(crate) grace: u32,
+    pub(crate) recompute_ms: u32,
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+}
+
+pub type LeaseSetResponse = Response;
+
+/// A set whose item carries tags, sent as extras following the flags and
+/// expiration, each as `[u8 length][tag]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetTaggedRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) flags: u32,
+    pub(crate) expiration: u32,
+    pub(crate) tags: Vec<Vec<u8>>,
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+}
+
+pub type SetTaggedResponse = Response;
+
+/// The tag to invalidate is sent as the key. The response carries the new
+/// generation of the tag in the CAS field.
+pub type InvalidateTagRequest = GetRequest;
+pub type InvalidateTagResponse = Response;
+
+/// Subscribes the connection to the events on keys starting with the key,
+/// of the kinds in the optional `[u32 kinds]` extras, all if 0 or left out.
+/// Once answered, the connection only carries `WatchEvent` packets.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) kinds: u32,
+    pub(crate) key: Vec<u8>,
+}
+
+pub type WatchResponse = Response;
+
+/// An event on the key, with `[u8 kind][u64 dropped]` extras, `dropped`
+/// counting the events missed so far, and the namespace as the value.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WatchEventResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) kind: u8,
+    pub(crate) dropped: u64,
+    pub(crate) key: Vec<u8>,
+    pub(crate) namespace: Vec<u8>,
+}
+
+/// Lists the metadata of the keys starting with the key, resuming after the
+/// cursor of a previous dump, in the optional `[u128 cursor][u32 count]`
+/// extras. Both are 0 if left out, a count of 0 meaning the default.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetaDumpRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) cursor: u128,
+    pub(crate) count: u32,
+    pub(crate) key: Vec<u8>,
+}
+
+/// A dumped key, sent with `[u32 flags][u64 cas][u64 expires_at]
+/// [u64 last_access][u32 size][u8 segment]` extras and the namespace as the
+/// value.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetaDumpEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) namespace: Vec<u8>,
+    pub(crate) flags: u32,
+    pub(crate) cas: u64,
+    pub(crate) expires_at: u64,
+    pub(crate) last_access: u64,
+    pub(crate) size: u32,
+    pub(crate) segment: u8,
+}
+
+/// Answered like a stat group, one packet per entry, the terminating packet
+/// carrying the cursor to resume from as `[u128 cursor]` extras.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetaDumpResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) entries: Vec<MetaDumpEntry>,
+    pub(crate) cursor: u128,
+}
+
+/// Lists the slowest recent requests, newest first, up to the count in the
+/// optional `[u32 count]` extras, all of them if 0 or left out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlowLogRequest {
+    pub(crate) header: RequestHeader,
+    pub(crate) count: u32,
+}
+
+/// A logged request, sent with `[u64 id][u64 timestamp][u64 duration_us]
+/// [u32 size][u8 opcode]` extras, its truncated key as the key and the peer
+/// address as the value.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SlowLogEntry {
+    pub(crate) id: u64,
+    pub(crate) timestamp: u64,
+    pub(crate) duration_us: u64,
+    pub(crate) size: u32,
+    pub(crate) opcode: u8,
+    pub(crate) key: Vec<u8>,
+    pub(crate) peer: Vec<u8>,
+}
+
+/// Answered like a stat group, one packet per entry followed by a
+/// terminating packet without extras.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlowLogResponse {
+    pub(crate) header: ResponseHeader,
+    pub(crate) entries: Vec<SlowLogEntry>,
+}
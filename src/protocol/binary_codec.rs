@@ -1,6 +1,4 @@
-use actix::Message;
-use byteorder::{BigEndian, ByteOrder};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use num_traits::FromPrimitive;
 use serde_derive::{Deserialize, Serialize};
 use std::io;
@@ -9,15 +7,49 @@ use tokio_util::codec::{Decoder, Encoder};
 use crate::protocol::binary;
 
 /// Client request
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum BinaryRequest {
     Get(binary::GetRequest),
     GetQuietly(binary::GetQuietRequest),
     GetKey(binary::GetKeyRequest),
     GetKeyQuietly(binary::GetKeyQuietRequest),
     Set(binary::SetRequest),
+    SetQuietly(binary::SetQuietRequest),
     Add(binary::AddRequest),
+    AddQuietly(binary::AddQuietRequest),
     Replace(binary::ReplaceRequest),
+    ReplaceQuietly(binary::ReplaceQuietRequest),
+    Append(binary::AppendRequest),
+    AppendQuietly(binary::AppendQuietRequest),
+    Prepend(binary::PrependRequest),
+    PrependQuietly(binary::PrependQuietRequest),
+    Delete(binary::DeleteRequest),
+    DeleteQuietly(binary::DeleteQuietRequest),
+    Increment(binary::IncrementRequest),
+    IncrementQuietly(binary::IncrementQuietRequest),
+    Decrement(binary::DecrementRequest),
+    DecrementQuietly(binary::DecrementQuietRequest),
+    Touch(binary::TouchRequest),
+    GetAndTouch(binary::GetAndTouchRequest),
+    GetAndTouchQuietly(binary::GetAndTouchQuietRequest),
+    Flush(binary::FlushRequest),
+    FlushQuietly(binary::FlushQuietRequest),
+    Version(binary::VersionRequest),
+    SaslListMechs(binary::SaslListMechsRequest),
+    SaslAuth(binary::SaslAuthRequest),
+    SaslStep(binary::SaslStepRequest),
+    Stat(binary::StatRequest),
+    Snapshot(binary::SnapshotRequest),
+    LeaseGet(binary::LeaseGetRequest),
+    LeaseSet(binary::LeaseSetRequest),
+    SetTagged(binary::SetTaggedRequest),
+    InvalidateTag(binary::InvalidateTagRequest),
+    Watch(binary::WatchRequest),
+    MetaDump(binary::MetaDumpRequest),
+    SlowLog(binary::SlowLogRequest),
+    Noop(binary::NoopRequest),
+    /// Unknown commands and requests with the wrong extras or key.
+    Invalid(binary::InvalidRequest),
 }
 
 impl BinaryRequest {
@@ -28,15 +60,138 @@ impl BinaryRequest {
             BinaryRequest::GetKey(request) => &request.header,
             BinaryRequest::GetKeyQuietly(request) => &request.header,
             BinaryRequest::Set(request) => &request.header,
+            BinaryRequest::SetQuietly(request) => &request.header,
             BinaryRequest::Add(request) => &request.header,
+            BinaryRequest::AddQuietly(request) => &request.header,
             BinaryRequest::Replace(request) => &request.header,
+            BinaryRequest::ReplaceQuietly(request) => &request.header,
+            BinaryRequest::Append(request) => &request.header,
+            BinaryRequest::AppendQuietly(request) => &request.header,
+            BinaryRequest::Prepend(request) => &request.header,
+            BinaryRequest::PrependQuietly(request) => &request.header,
+            BinaryRequest::Delete(request) => &request.header,
+            BinaryRequest::DeleteQuietly(request) => &request.header,
+            BinaryRequest::Increment(request) => &request.header,
+            BinaryRequest::IncrementQuietly(request) => &request.header,
+            BinaryRequest::Decrement(request) => &request.header,
+            BinaryRequest::DecrementQuietly(request) => &request.header,
+            BinaryRequest::Touch(request) => &request.header,
+            BinaryRequest::GetAndTouch(request) => &request.header,
+            BinaryRequest::GetAndTouchQuietly(request) => &request.header,
+            BinaryRequest::Flush(request) => &request.header,
+            BinaryRequest::FlushQuietly(request) => &request.header,
+            BinaryRequest::Version(request) => &request.header,
+            BinaryRequest::SaslListMechs(request) => &request.header,
+            BinaryRequest::SaslAuth(request) => &request.header,
+            BinaryRequest::SaslStep(request) => &request.header,
+            BinaryRequest::Stat(request) => &request.header,
+            BinaryRequest::Snapshot(request) => &request.header,
+            BinaryRequest::LeaseGet(request) => &request.header,
+            BinaryRequest::LeaseSet(request) => &request.header,
+            BinaryRequest::SetTagged(request) => &request.header,
+            BinaryRequest::InvalidateTag(request) => &request.header,
+            BinaryRequest::Watch(request) => &request.header,
+            BinaryRequest::MetaDump(request) => &request.header,
+            BinaryRequest::SlowLog(request) => &request.header,
+            BinaryRequest::Noop(request) => &request.header,
+            BinaryRequest::Invalid(request) => &request.header,
+        }
+    }
+
+    pub fn get_header_mut(&mut self) -> &mut binary::RequestHeader {
+        match self {
+            BinaryRequest::Get(request) => &mut request.header,
+            BinaryRequest::GetQuietly(request) => &mut request.header,
+            BinaryRequest::GetKey(request) => &mut request.header,
+            BinaryRequest::GetKeyQuietly(request) => &mut request.header,
+            BinaryRequest::Set(request) => &mut request.header,
+            BinaryRequest::SetQuietly(request) => &mut request.header,
+            BinaryRequest::Add(request) => &mut request.header,
+            BinaryRequest::AddQuietly(request) => &mut request.header,
+            BinaryRequest::Replace(request) => &mut request.header,
+            BinaryRequest::ReplaceQuietly(request) => &mut request.header,
+            BinaryRequest::Append(request) => &mut request.header,
+            BinaryRequest::AppendQuietly(request) => &mut request.header,
+            BinaryRequest::Prepend(request) => &mut request.header,
+            BinaryRequest::PrependQuietly(request) => &mut request.header,
+            BinaryRequest::Delete(request) => &mut request.header,
+            BinaryRequest::DeleteQuietly(request) => &mut request.header,
+            BinaryRequest::Increment(request) => &mut request.header,
+            BinaryRequest::IncrementQuietly(request) => &mut request.header,
+            BinaryRequest::Decrement(request) => &mut request.header,
+            BinaryRequest::DecrementQuietly(request) => &mut request.header,
+            BinaryRequest::Touch(request) => &mut request.header,
+            BinaryRequest::GetAndTouch(request) => &mut request.header,
+            BinaryRequest::GetAndTouchQuietly(request) => &mut request.header,
+            BinaryRequest::Flush(request) => &mut request.header,
+            BinaryRequest::FlushQuietly(request) => &mut request.header,
+            BinaryRequest::Version(request) => &mut request.header,
+            BinaryRequest::SaslListMechs(request) => &mut request.header,
+            BinaryRequest::SaslAuth(request) => &mut request.header,
+            BinaryRequest::SaslStep(request) => &mut request.header,
+            BinaryRequest::Stat(request) => &mut request.header,
+            BinaryRequest::Snapshot(request) => &mut request.header,
+            BinaryRequest::LeaseGet(request) => &mut request.header,
+            BinaryRequest::LeaseSet(request) => &mut request.header,
+            BinaryRequest::SetTagged(request) => &mut request.header,
+            BinaryRequest::InvalidateTag(request) => &mut request.header,
+            BinaryRequest::Watch(request) => &mut request.header,
+            BinaryRequest::MetaDump(request) => &mut request.header,
+            BinaryRequest::SlowLog(request) => &mut request.header,
+            BinaryRequest::Noop(request) => &mut request.header,
+            BinaryRequest::Invalid(request) => &mut request.header,
+        }
+    }
+
+    /// The item key the request operates on, `None` for commands without one.
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            BinaryRequest::Get(request)
+            | BinaryRequest::GetQuietly(request)
+            | BinaryRequest::GetKey(request)
+            | BinaryRequest::GetKeyQuietly(request)
+            | BinaryRequest::Delete(request)
+            | BinaryRequest::DeleteQuietly(request)
+            | BinaryRequest::LeaseGet(request) => Some(&request.key),
+            BinaryRequest::Set(request)
+            | BinaryRequest::SetQuietly(request)
+            | BinaryRequest::Add(request)
+            | BinaryRequest::AddQuietly(request)
+            | BinaryRequest::Replace(request)
+            | BinaryRequest::ReplaceQuietly(request) => Some(&request.key),
+            BinaryRequest::Append(request)
+            | BinaryRequest::AppendQuietly(request)
+            | BinaryRequest::Prepend(request)
+            | BinaryRequest::PrependQuietly(request) => Some(&request.key),
+            BinaryRequest::Increment(request)
+            | BinaryRequest::IncrementQuietly(request)
+            | BinaryRequest::Decrement(request)
+            | BinaryRequest::DecrementQuietly(request) => Some(&request.key),
+            BinaryRequest::Touch(request)
+            | BinaryRequest::GetAndTouch(request)
+            | BinaryRequest::GetAndTouchQuietly(request) => Some(&request.key),
+            BinaryRequest::LeaseSet(request) => Some(&request.key),
+            BinaryRequest::SetTagged(request) => Some(&request.key),
+            _ => None,
         }
     }
+
+    /// SASL commands are the only ones an unauthenticated connection may issue.
+    pub fn is_sasl(&self) -> bool {
+        matches!(
+            self,
+            BinaryRequest::SaslListMechs(_)
+                | BinaryRequest::SaslAuth(_)
+                | BinaryRequest::SaslStep(_)
+        )
+    }
 }
 
 /// Server response
 #[derive(Serialize, Deserialize, Debug)]
 pub enum BinaryResponse {
+    Error(binary::ErrorResponse),
+    NotMyVbucket(binary::NotMyVbucketResponse),
     Get(binary::GetResponse),
     GetQuietly(binary::GetQuietlyResponse),
     GetKey(binary::GetKeyResponse),
@@ -44,11 +199,37 @@ pub enum BinaryResponse {
     Set(binary::SetResponse),
     Add(binary::AddResponse),
     Replace(binary::ReplaceResponse),
+    Append(binary::AppendResponse),
+    Prepend(binary::PrependResponse),
+    Delete(binary::DeleteResponse),
+    Increment(binary::IncrementResponse),
+    Decrement(binary::DecrementResponse),
+    Touch(binary::TouchResponse),
+    GetAndTouch(binary::GetAndTouchResponse),
+    GetAndTouchQuietly(binary::GetAndTouchQuietlyResponse),
+    Flush(binary::FlushResponse),
+    Version(binary::VersionResponse),
+    SaslListMechs(binary::SaslListMechsResponse),
+    SaslAuth(binary::SaslAuthResponse),
+    SaslStep(binary::SaslStepResponse),
+    Stat(binary::StatResponse),
+    Snapshot(binary::SnapshotResponse),
+    LeaseGet(binary::LeaseGetResponse),
+    LeaseSet(binary::LeaseSetResponse),
+    SetTagged(binary::SetTaggedResponse),
+    InvalidateTag(binary::InvalidateTagResponse),
+    Watch(binary::WatchResponse),
+    WatchEvent(binary::WatchEventResponse),
+    MetaDump(binary::MetaDumpResponse),
+    SlowLog(binary::SlowLogResponse),
+    Noop(binary::NoopResponse),
 }
 
 impl BinaryResponse {
     pub fn get_header(&self) -> &binary::ResponseHeader {
         match self {
+            BinaryResponse::Error(response) => &response.header,
+            BinaryResponse::NotMyVbucket(response) => &response.header,
             BinaryResponse::Get(response) => &response.header,
             BinaryResponse::GetQuietly(response) => &response.header,
             BinaryResponse::GetKey(response) => &response.header,
@@ -56,6 +237,68 @@ impl BinaryResponse {
             BinaryResponse::Set(response) => &response.header,
             BinaryResponse::Add(response) => &response.header,
             BinaryResponse::Replace(response) => &response.header,
+            BinaryResponse::Append(response) => &response.header,
+            BinaryResponse::Prepend(response) => &response.header,
+            BinaryResponse::Delete(response) => &response.header,
+            BinaryResponse::Increment(response) => &response.header,
+            BinaryResponse::Decrement(response) => &response.header,
+            BinaryResponse::Touch(response) => &response.header,
+            BinaryResponse::GetAndTouch(response) => &response.header,
+            BinaryResponse::GetAndTouchQuietly(response) => &response.header,
+            BinaryResponse::Flush(response) => &response.header,
+            BinaryResponse::Version(response) => &response.header,
+            BinaryResponse::SaslListMechs(response) => &response.header,
+            BinaryResponse::SaslAuth(response) => &response.header,
+            BinaryResponse::SaslStep(response) => &response.header,
+            BinaryResponse::Stat(response) => &response.header,
+            BinaryResponse::Snapshot(response) => &response.header,
+            BinaryResponse::LeaseGet(response) => &response.header,
+            BinaryResponse::LeaseSet(response) => &response.header,
+            BinaryResponse::SetTagged(response) => &response.header,
+            BinaryResponse::InvalidateTag(response) => &response.header,
+            BinaryResponse::Watch(response) => &response.header,
+            BinaryResponse::WatchEvent(response) => &response.header,
+            BinaryResponse::MetaDump(response) => &response.header,
+            BinaryResponse::SlowLog(response) => &response.header,
+            BinaryResponse::Noop(response) => &response.header,
+        }
+    }
+
+    pub fn get_header_mut(&mut self) -> &mut binary::ResponseHeader {
+        match self {
+            BinaryResponse::Error(response) => &mut response.header,
+            BinaryResponse::NotMyVbucket(response) => &mut response.header,
+            BinaryResponse::Get(response) => &mut response.header,
+            BinaryResponse::GetQuietly(response) => &mut response.header,
+            BinaryResponse::GetKey(response) => &mut response.header,
+            BinaryResponse::GetKeyQuietly(response) => &mut response.header,
+            BinaryResponse::Set(response) => &mut response.header,
+            BinaryResponse::Add(response) => &mut response.header,
+            BinaryResponse::Replace(response) => &mut response.header,
+            BinaryResponse::Append(response) => &mut response.header,
+            BinaryResponse::Prepend(response) => &mut response.header,
+            BinaryResponse::Delete(response) => &mut response.header,
+            BinaryResponse::Increment(response) => &mut response.header,
+            BinaryResponse::Decrement(response) => &mut response.header,
+            BinaryResponse::Touch(response) => &mut response.header,
+            BinaryResponse::GetAndTouch(response) => &mut response.header,
+            BinaryResponse::GetAndTouchQuietly(response) => &mut response.header,
+            BinaryResponse::Flush(response) => &mut response.header,
+            BinaryResponse::Version(response) => &mut response.header,
+            BinaryResponse::SaslListMechs(response) => &mut response.header,
+            BinaryResponse::SaslAuth(response) => &mut response.header,
+            BinaryResponse::SaslStep(response) => &mut response.header,
+            BinaryResponse::Stat(response) => &mut response.header,
+            BinaryResponse::Snapshot(response) => &mut response.header,
+            BinaryResponse::LeaseGet(response) => &mut response.header,
+            BinaryResponse::LeaseSet(response) => &mut response.header,
+            BinaryResponse::SetTagged(response) => &mut response.header,
+            BinaryResponse::InvalidateTag(response) => &mut response.header,
+            BinaryResponse::Watch(response) => &mut response.header,
+            BinaryResponse::WatchEvent(response) => &mut response.header,
+            BinaryResponse::MetaDump(response) => &mut response.header,
+            BinaryResponse::SlowLog(response) => &mut response.header,
+            BinaryResponse::Noop(response) => &mut response.header,
         }
     }
 }
@@ -70,10 +313,18 @@ enum RequestParserState {
 pub struct MemcachedBinaryCodec {
     header: binary::RequestHeader,
     state: RequestParserState,
+    /// Key of the request decoded last, sharing the read buffer.
+    key: Bytes,
+}
+
+impl Default for MemcachedBinaryCodec {
+    fn default() -> Self {
+        MemcachedBinaryCodec::new()
+    }
 }
 
 impl MemcachedBinaryCodec {
-    const HEADER_LEN: usize = 24;
+    pub(crate) const HEADER_LEN: usize = 24;
     pub fn new() -> MemcachedBinaryCodec {
         MemcachedBinaryCodec {
             header: binary::RequestHeader {
@@ -88,10 +339,17 @@ impl MemcachedBinaryCodec {
                 cas: 0,
             },
             state: RequestParserState::None,
+            key: Bytes::new(),
         }
     }
 
-    pub fn parse_header(&mut self, src: &mut BytesMut) {
+    /// The raw key of the request decoded last, which the connection may
+    /// log once it knows the request was slow without copying every key.
+    pub fn last_key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn parse_header(&mut self, src: &mut BytesMut) -> io::Result<()> {
         assert!(src.len() >= MemcachedBinaryCodec::HEADER_LEN);
         self.header = binary::RequestHeader {
             magic: src.get_u8(),
@@ -104,94 +362,362 @@ impl MemcachedBinaryCodec {
             opaque: src.get_u32(),
             cas: src.get_u64(),
         };
+        if self.header.magic != binary::Magic::Request as u8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid magic"));
+        }
+        if self.get_req_length()
+            < (self.header.extras_length as usize) + (self.header.key_length as usize)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid body length",
+            ));
+        }
         self.state = RequestParserState::HeaderParsed;
+        Ok(())
     }
 
     pub fn get_req_length(&self) -> usize {
-        (self.header.extras_length as usize)
-            + (self.header.key_length as usize)
-            + (self.header.body_length as usize)
+        self.header.body_length as usize
     }
 
-    pub fn parse(&mut self, src: &mut BytesMut) -> Option<BinaryRequest> {
+    /// Consumes the whole request body, so an unsupported command never
+    /// leaves stray bytes in front of the next request. Requests that cannot
+    /// be served come out as `Invalid`, to be answered with an error.
+    pub fn parse(&mut self, src: &mut BytesMut) -> BinaryRequest {
         assert!(src.len() >= self.get_req_length());
         assert_eq!(self.state, RequestParserState::RequestParsed);
 
-        let result = match FromPrimitive::from_u8(self.header.opcode) {
-            Some(binary::Command::Get) => {
-                let size = self.header.key_length as usize;
-                let buf = src.split_to(size);
-                let key = buf.to_vec();
+        let mut body = src.split_to(self.get_req_length());
+        let mut extras = body.split_to(self.header.extras_length as usize);
+        self.key = body.split_to(self.header.key_length as usize).freeze();
+        let key = self.key.to_vec();
+        let value = body.to_vec();
 
-                Some(BinaryRequest::Get(binary::GetRequest {
+        let result = match FromPrimitive::from_u8(self.header.opcode) {
+            Some(binary::Command::Get) => Some(BinaryRequest::Get(binary::GetRequest {
+                header: self.header,
+                key,
+            })),
+            Some(binary::Command::GetQuiet) => {
+                Some(BinaryRequest::GetQuietly(binary::GetQuietRequest {
+                    header: self.header,
+                    key,
+                }))
+            }
+            Some(binary::Command::GetKey) => Some(BinaryRequest::GetKey(binary::GetKeyRequest {
+                header: self.header,
+                key,
+            })),
+            Some(binary::Command::GetKeyQuiet) => {
+                Some(BinaryRequest::GetKeyQuietly(binary::GetKeyQuietRequest {
+                    header: self.header,
+                    key,
+                }))
+            }
+            Some(
+                command @ (binary::Command::Set
+                | binary::Command::SetQuiet
+                | binary::Command::Add
+                | binary::Command::AddQuiet
+                | binary::Command::Replace
+                | binary::Command::ReplaceQuiet),
+            ) => {
+                if !(extras.len() == 8 || extras.len() == 16) || key.is_empty() {
+                    None
+                } else {
+                    let flags = extras.get_u32();
+                    let expiration = extras.get_u32();
+                    let (grace, recompute_ms) = match extras.has_remaining() {
+                        true => (extras.get_u32(), extras.get_u32()),
+                        false => (0, 0),
+                    };
+                    let set_req = binary::SetRequest {
+                        header: self.header,
+                        flags,
+                        expiration,
+                        grace,
+                        recompute_ms,
+                        key,
+                        value,
+                    };
+                    Some(match command {
+                        binary::Command::SetQuiet => BinaryRequest::SetQuietly(set_req),
+                        binary::Command::Add => BinaryRequest::Add(set_req),
+                        binary::Command::AddQuiet => BinaryRequest::AddQuietly(set_req),
+                        binary::Command::Replace => BinaryRequest::Replace(set_req),
+                        binary::Command::ReplaceQuiet => BinaryRequest::ReplaceQuietly(set_req),
+                        _ => BinaryRequest::Set(set_req),
+                    })
+                }
+            }
+            Some(
+                command @ (binary::Command::Append
+                | binary::Command::AppendQuiet
+                | binary::Command::Prepend
+                | binary::Command::PrependQuiet),
+            ) => {
+                if !extras.is_empty() || key.is_empty() {
+                    None
+                } else {
+                    let append_req = binary::AppendRequest {
+                        header: self.header,
+                        key,
+                        value,
+                    };
+                    Some(match command {
+                        binary::Command::AppendQuiet => BinaryRequest::AppendQuietly(append_req),
+                        binary::Command::Prepend => BinaryRequest::Prepend(append_req),
+                        binary::Command::PrependQuiet => BinaryRequest::PrependQuietly(append_req),
+                        _ => BinaryRequest::Append(append_req),
+                    })
+                }
+            }
+            Some(command @ (binary::Command::Delete | binary::Command::DeleteQuiet)) => {
+                if !extras.is_empty() || key.is_empty() {
+                    None
+                } else {
+                    let delete_req = binary::DeleteRequest {
+                        header: self.header,
+                        key,
+                    };
+                    Some(match command {
+                        binary::Command::DeleteQuiet => BinaryRequest::DeleteQuietly(delete_req),
+                        _ => BinaryRequest::Delete(delete_req),
+                    })
+                }
+            }
+            Some(
+                command @ (binary::Command::Increment
+                | binary::Command::IncrementQuiet
+                | binary::Command::Decrement
+                | binary::Command::DecrementQuiet),
+            ) => {
+                if extras.len() != 20 || key.is_empty() {
+                    None
+                } else {
+                    let delta_req = binary::IncrementRequest {
+                        header: self.header,
+                        delta: extras.get_u64(),
+                        initial: extras.get_u64(),
+                        expiration: extras.get_u32(),
+                        key,
+                    };
+                    Some(match command {
+                        binary::Command::IncrementQuiet => {
+                            BinaryRequest::IncrementQuietly(delta_req)
+                        }
+                        binary::Command::Decrement => BinaryRequest::Decrement(delta_req),
+                        binary::Command::DecrementQuiet => {
+                            BinaryRequest::DecrementQuietly(delta_req)
+                        }
+                        _ => BinaryRequest::Increment(delta_req),
+                    })
+                }
+            }
+            Some(
+                command @ (binary::Command::Touch
+                | binary::Command::GetAndTouch
+                | binary::Command::GetAndTouchQuiet),
+            ) => {
+                if extras.len() != 4 || key.is_empty() {
+                    None
+                } else {
+                    let touch_req = binary::TouchRequest {
+                        header: self.header,
+                        expiration: extras.get_u32(),
+                        key,
+                    };
+                    Some(match command {
+                        binary::Command::GetAndTouch => BinaryRequest::GetAndTouch(touch_req),
+                        binary::Command::GetAndTouchQuiet => {
+                            BinaryRequest::GetAndTouchQuietly(touch_req)
+                        }
+                        _ => BinaryRequest::Touch(touch_req),
+                    })
+                }
+            }
+            Some(command @ (binary::Command::Flush | binary::Command::FlushQuiet)) => {
+                let expiration = match extras.len() {
+                    0 => Some(0),
+                    4 => Some(extras.get_u32()),
+                    _ => None,
+                };
+                expiration.map(|expiration| {
+                    let flush_req = binary::FlushRequest {
+                        header: self.header,
+                        expiration,
+                    };
+                    match command {
+                        binary::Command::FlushQuiet => BinaryRequest::FlushQuietly(flush_req),
+                        _ => BinaryRequest::Flush(flush_req),
+                    }
+                })
+            }
+            Some(binary::Command::Version) => {
+                Some(BinaryRequest::Version(binary::VersionRequest {
+                    header: self.header,
+                }))
+            }
+            Some(binary::Command::SaslListMechs) => {
+                Some(BinaryRequest::SaslListMechs(binary::SaslListMechsRequest {
+                    header: self.header,
+                }))
+            }
+            Some(binary::Command::SaslAuth) => {
+                Some(BinaryRequest::SaslAuth(binary::SaslAuthRequest {
+                    header: self.header,
+                    key,
+                    value,
+                }))
+            }
+            Some(binary::Command::SaslStep) => {
+                Some(BinaryRequest::SaslStep(binary::SaslStepRequest {
+                    header: self.header,
+                    key,
+                    value,
+                }))
+            }
+            Some(binary::Command::Stat) => Some(BinaryRequest::Stat(binary::StatRequest {
+                header: self.header,
+                key,
+            })),
+            Some(binary::Command::Snapshot) => {
+                Some(BinaryRequest::Snapshot(binary::SnapshotRequest {
                     header: self.header,
-                    key: key,
                 }))
             }
-            Some(binary::Command::GetQuiet) => None,
-            Some(binary::Command::GetKey) => None,
-            Some(binary::Command::Flush) => None,
-            Some(binary::Command::Append) => None,
-            Some(binary::Command::Prepend) => None,
-            Some(binary::Command::Set) => {
-                let extras_size = self.header.extras_length;
-                assert_eq!(extras_size, 8);
-                assert_ne!(self.header.key_length, 0);
-                assert!(self.header.body_length >= (self.header.key_length + 8) as u32);
-                assert!(src.len() >= (self.header.body_length) as usize);
-
-                let value_len = self.get_value_len();
-
-                let set_request = binary::SetRequest {
+            Some(binary::Command::LeaseGet) => {
+                Some(BinaryRequest::LeaseGet(binary::LeaseGetRequest {
                     header: self.header,
-                    flags: BigEndian::read_u32(&src),
-                    expiration: BigEndian::read_u32(&src),
-                    key: src.split_to(self.header.key_length as usize).to_vec(),
-                    value: src.split_to(value_len).to_vec(),
+                    key,
+                }))
+            }
+            Some(binary::Command::LeaseSet) => {
+                if (extras.len() != 16 && extras.len() != 24) || key.is_empty() {
+                    None
+                } else {
+                    let flags = extras.get_u32();
+                    let expiration = extras.get_u32();
+                    let token = extras.get_u64();
+                    let (grace, recompute_ms) = match extras.has_remaining() {
+                        true => (extras.get_u32(), extras.get_u32()),
+                        false => (0, 0),
+                    };
+                    Some(BinaryRequest::LeaseSet(binary::LeaseSetRequest {
+                        header: self.header,
+                        flags,
+                        expiration,
+                        token,
+                        grace,
+                        recompute_ms,
+                        key,
+                        value,
+                    }))
+                }
+            }
+            Some(binary::Command::SetTagged) => {
+                if extras.len() < 8 || key.is_empty() {
+                    None
+                } else {
+                    let flags = extras.get_u32();
+                    let expiration = extras.get_u32();
+                    let mut tags = Vec::new();
+                    while let Some(&len) = extras.first() {
+                        let len = len as usize;
+                        if len == 0 || len >= extras.len() {
+                            break;
+                        }
+                        extras.advance(1);
+                        tags.push(extras.split_to(len).to_vec());
+                    }
+                    match extras.is_empty() {
+                        true => Some(BinaryRequest::SetTagged(binary::SetTaggedRequest {
+                            header: self.header,
+                            flags,
+                            expiration,
+                            tags,
+                            key,
+                            value,
+                        })),
+                        false => None,
+                    }
+                }
+            }
+            Some(binary::Command::InvalidateTag) => {
+                if !extras.is_empty() || key.is_empty() {
+                    None
+                } else {
+                    Some(BinaryRequest::InvalidateTag(binary::InvalidateTagRequest {
+                        header: self.header,
+                        key,
+                    }))
+                }
+            }
+            Some(binary::Command::Watch) => {
+                let kinds = match extras.len() {
+                    0 => Some(0),
+                    4 => Some(extras.get_u32()),
+                    _ => None,
+                };
+                kinds.map(|kinds| {
+                    BinaryRequest::Watch(binary::WatchRequest {
+                        header: self.header,
+                        kinds,
+                        key,
+                    })
+                })
+            }
+            Some(binary::Command::MetaDump) => {
+                let page = match extras.len() {
+                    0 => Some((0, 0)),
+                    20 => Some((extras.get_u128(), extras.get_u32())),
+                    _ => None,
                 };
-                Some(BinaryRequest::Set(set_request))
-            }
-            Some(binary::Command::Add) => None,
-            Some(binary::Command::Replace) => None,
-            Some(binary::Command::Delete) => None,
-            Some(binary::Command::Increment) => None,
-            Some(binary::Command::Decrement) => None,
-            Some(binary::Command::Quit) => None,
-            Some(binary::Command::QuitQuiet) => None,
-            Some(binary::Command::Noop) => None,
-            Some(binary::Command::Version) => None,
-            Some(binary::Command::GetKeyQuiet) => None,
-            Some(binary::Command::Stat) => None,
-            Some(binary::Command::SetQuiet) => None,
-            Some(binary::Command::AddQuiet) => None,
-            Some(binary::Command::ReplaceQuiet) => None,
-            Some(binary::Command::DeleteQuiet) => None,
-            Some(binary::Command::IncrementQuiet) => None,
-            Some(binary::Command::DecrementQuiet) => None,
-            Some(binary::Command::FlushQuiet) => None,
-            Some(binary::Command::AppendQuiet) => None,
-            Some(binary::Command::PrependQuiet) => None,
-            Some(binary::Command::Touch) => None,
-            Some(binary::Command::GetAndTouch) => None,
-            Some(binary::Command::GetAndTouchQuiet) => None,
-            Some(binary::Command::GetAndTouchKey) => None,
-            Some(binary::Command::GetAndTouchKeyQuiet) => None,
-            Some(binary::Command::SaslListMechs) => None,
-            Some(binary::Command::SaslAuth) => None,
-            Some(binary::Command::SaslStep) => None,
-            None => {
-                println!("Cannot parse command opcode {:?}", self.header);
-                None
+                page.map(|(cursor, count)| {
+                    BinaryRequest::MetaDump(binary::MetaDumpRequest {
+                        header: self.header,
+                        cursor,
+                        count,
+                        key,
+                    })
+                })
+            }
+            Some(binary::Command::SlowLog) => {
+                let count = match extras.len() {
+                    0 => Some(0),
+                    4 => Some(extras.get_u32()),
+                    _ => None,
+                };
+                count.filter(|_| key.is_empty()).map(|count| {
+                    BinaryRequest::SlowLog(binary::SlowLogRequest {
+                        header: self.header,
+                        count,
+                    })
+                })
+            }
+            Some(binary::Command::Noop) => Some(BinaryRequest::Noop(binary::NoopRequest {
+                header: self.header,
+            })),
+            Some(_) | None => {
+                warn!("Unknown command opcode {:#04x}", self.header.opcode);
+                Some(self.invalid(binary::ResponseStatus::UnknownCommand))
             }
         };
 
         self.state = RequestParserState::None;
 
-        result
+        result.unwrap_or_else(|| {
+            warn!("Malformed request with opcode {:#04x}", self.header.opcode);
+            self.invalid(binary::ResponseStatus::InvalidArguments)
+        })
     }
 
-    fn get_value_len(&self) -> usize {
-        (self.header.body_length as usize) - ((self.header.key_length + 8) as usize)
+    fn invalid(&self, status: binary::ResponseStatus) -> BinaryRequest {
+        BinaryRequest::Invalid(binary::InvalidRequest {
+            header: self.header,
+            status: status as u16,
+        })
     }
 }
 
@@ -200,24 +726,28 @@ impl Decoder for MemcachedBinaryCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match self.state {
-            RequestParserState::None => {
-                if src.len() < MemcachedBinaryCodec::HEADER_LEN {
-                    return Ok(None);
+        // Let go of the last key, so that the read buffer may be reused.
+        self.key.clear();
+        loop {
+            match self.state {
+                RequestParserState::None => {
+                    if src.len() < MemcachedBinaryCodec::HEADER_LEN {
+                        return Ok(None);
+                    }
+                    self.parse_header(src)?;
                 }
-                self.parse_header(src);
-            }
-            RequestParserState::HeaderParsed => {
-                if src.len() < self.get_req_length() {
-                    return Ok(None);
+                RequestParserState::HeaderParsed => {
+                    if src.len() < self.get_req_length() {
+                        return Ok(None);
+                    }
+                    self.state = RequestParserState::RequestParsed;
+                    return Ok(Some(self.parse(src)));
+                }
+                RequestParserState::RequestParsed => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid data"));
                 }
-                return Ok(self.parse(src));
-            }
-            RequestParserState::RequestParsed => {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid data"));
             }
         }
-        return Ok(None);
     }
 }
 
@@ -235,7 +765,129 @@ impl MemcachedBinaryCodec {
         MemcachedBinaryCodec::RESPONSE_HEADER_LEN + (header.body_length as usize)
     }
 
-    fn write_msg(&self, msg: &BinaryResponse, dst: &mut BytesMut) {}
+    pub(crate) fn write_msg(&self, msg: &BinaryResponse, dst: &mut BytesMut) {
+        let header = *self.get_header(msg);
+        match msg {
+            BinaryResponse::Error(_)
+            | BinaryResponse::Snapshot(_)
+            | BinaryResponse::Noop(_)
+            | BinaryResponse::Delete(_)
+            | BinaryResponse::Touch(_)
+            | BinaryResponse::Flush(_) => self.write_packet(header, &[], &[], &[], dst),
+            BinaryResponse::Get(response)
+            | BinaryResponse::GetQuietly(response)
+            | BinaryResponse::GetAndTouch(response)
+            | BinaryResponse::GetAndTouchQuietly(response) => self.write_packet(
+                header,
+                &response.flags.to_be_bytes(),
+                &response.key,
+                &response.value,
+                dst,
+            ),
+            BinaryResponse::GetKey(response) | BinaryResponse::GetKeyQuietly(response) => self
+                .write_packet(
+                    header,
+                    &response.flags.to_be_bytes(),
+                    &response.key,
+                    &response.value,
+                    dst,
+                ),
+            BinaryResponse::Set(_)
+            | BinaryResponse::Add(_)
+            | BinaryResponse::Replace(_)
+            | BinaryResponse::Append(_)
+            | BinaryResponse::Prepend(_)
+            | BinaryResponse::LeaseSet(_)
+            | BinaryResponse::SetTagged(_)
+            | BinaryResponse::InvalidateTag(_)
+            | BinaryResponse::Watch(_) => self.write_packet(header, &[], &[], &[], dst),
+            BinaryResponse::WatchEvent(response) => {
+                let mut extras = [0; 9];
+                extras[0] = response.kind;
+                extras[1..].copy_from_slice(&response.dropped.to_be_bytes());
+                self.write_packet(header, &extras, &response.key, &response.namespace, dst)
+            }
+            BinaryResponse::LeaseGet(response) => {
+                if header.status == binary::ResponseStatus::Success as u16 {
+                    let mut extras = [0; 13];
+                    extras[..4].copy_from_slice(&response.flags.to_be_bytes());
+                    extras[4..12].copy_from_slice(&response.token.to_be_bytes());
+                    extras[12] = response.stale as u8;
+                    self.write_packet(header, &extras, &[], &response.value, dst)
+                } else if response.token != 0 {
+                    let token = response.token.to_be_bytes();
+                    self.write_packet(header, &token, &[], &[], dst)
+                } else {
+                    self.write_packet(header, &[], &[], &[], dst)
+                }
+            }
+            BinaryResponse::SaslListMechs(response)
+            | BinaryResponse::SaslAuth(response)
+            | BinaryResponse::SaslStep(response) => {
+                self.write_packet(header, &[], &[], &response.value, dst)
+            }
+            BinaryResponse::Increment(response) | BinaryResponse::Decrement(response) => {
+                self.write_packet(header, &[], &[], &response.value.to_be_bytes(), dst)
+            }
+            BinaryResponse::NotMyVbucket(response) => {
+                self.write_packet(header, &[], &[], &response.value, dst)
+            }
+            BinaryResponse::Version(response) => {
+                self.write_packet(header, &[], &[], &response.value, dst)
+            }
+            BinaryResponse::Stat(response) => {
+                for (key, value) in &response.stats {
+                    self.write_packet(header, &[], key.as_bytes(), value.as_bytes(), dst);
+                }
+                self.write_packet(header, &[], &[], &[], dst)
+            }
+            BinaryResponse::MetaDump(response) => {
+                for entry in &response.entries {
+                    let mut extras = Vec::with_capacity(33);
+                    extras.put_u32(entry.flags);
+                    extras.put_u64(entry.cas);
+                    extras.put_u64(entry.expires_at);
+                    extras.put_u64(entry.last_access);
+                    extras.put_u32(entry.size);
+                    extras.put_u8(entry.segment);
+                    self.write_packet(header, &extras, &entry.key, &entry.namespace, dst);
+                }
+                let cursor = response.cursor.to_be_bytes();
+                self.write_packet(header, &cursor, &[], &[], dst)
+            }
+            BinaryResponse::SlowLog(response) => {
+                for entry in &response.entries {
+                    let mut extras = Vec::with_capacity(29);
+                    extras.put_u64(entry.id);
+                    extras.put_u64(entry.timestamp);
+                    extras.put_u64(entry.duration_us);
+                    extras.put_u32(entry.size);
+                    extras.put_u8(entry.opcode);
+                    self.write_packet(header, &extras, &entry.key, &entry.peer, dst);
+                }
+                self.write_packet(header, &[], &[], &[], dst)
+            }
+        }
+    }
+
+    /// Writes a single packet, deriving the length fields from the body parts.
+    fn write_packet(
+        &self,
+        mut header: binary::ResponseHeader,
+        extras: &[u8],
+        key: &[u8],
+        value: &[u8],
+        dst: &mut BytesMut,
+    ) {
+        header.extras_length = extras.len() as u8;
+        header.key_length = key.len() as u16;
+        header.body_length = (extras.len() + key.len() + value.len()) as u32;
+        dst.reserve(self.get_len_from_header(&header));
+        self.write_header(&header, dst);
+        dst.put_slice(extras);
+        dst.put_slice(key);
+        dst.put_slice(value);
+    }
 
     fn write_header(&self, header: &binary::ResponseHeader, dst: &mut BytesMut) {
         dst.put_u8(header.magic);
@@ -245,42 +897,447 @@ impl MemcachedBinaryCodec {
         dst.put_u8(header.data_type);
         dst.put_u16(header.status);
         dst.put_u32(header.body_length);
+        dst.put_u32(header.opaque);
         dst.put_u64(header.cas);
     }
+}
+
+impl Encoder<BinaryResponse> for MemcachedBinaryCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: BinaryResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(self.get_length(&msg));
+        self.write_msg(&msg, dst);
+        Ok(())
+    }
+}
+
+/// The other end of `MemcachedBinaryCodec`: encodes requests and decodes
+/// responses, for talking to a rustcache or memcached server.
+pub struct MemcachedBinaryClientCodec {
+    header: Option<binary::ResponseHeader>,
+    stats: Vec<(String, String)>,
+    entries: Vec<binary::MetaDumpEntry>,
+    slow_requests: Vec<binary::SlowLogEntry>,
+}
+
+impl Default for MemcachedBinaryClientCodec {
+    fn default() -> Self {
+        MemcachedBinaryClientCodec::new()
+    }
+}
+
+impl MemcachedBinaryClientCodec {
+    const HEADER_LEN: usize = 24;
 
-    fn write_data(&self, msg: &BinaryResponse, dst: &mut BytesMut) {
+    pub fn new() -> MemcachedBinaryClientCodec {
+        MemcachedBinaryClientCodec {
+            header: None,
+            stats: Vec::new(),
+            entries: Vec::new(),
+            slow_requests: Vec::new(),
+        }
+    }
+
+    fn parse_header(src: &mut BytesMut) -> io::Result<binary::ResponseHeader> {
+        let header = binary::ResponseHeader {
+            magic: src.get_u8(),
+            opcode: src.get_u8(),
+            key_length: src.get_u16(),
+            extras_length: src.get_u8(),
+            data_type: src.get_u8(),
+            status: src.get_u16(),
+            body_length: src.get_u32(),
+            opaque: src.get_u32(),
+            cas: src.get_u64(),
+        };
+        if header.magic != binary::Magic::Response as u8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid magic"));
+        }
+        if (header.body_length as usize)
+            < (header.extras_length as usize) + (header.key_length as usize)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid body length",
+            ));
+        }
+        Ok(header)
+    }
+
+    /// Builds the response for one packet; `None` for the entries of a stat
+    /// group, metadump or slow log, which are collected until its
+    /// terminating packet.
+    fn parse(
+        &mut self,
+        header: binary::ResponseHeader,
+        mut extras: BytesMut,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Option<BinaryResponse> {
+        let command = FromPrimitive::from_u8(header.opcode);
+        let status = FromPrimitive::from_u16(header.status);
+        let flags = match extras.len() {
+            4 => extras.get_u32(),
+            _ => 0,
+        };
+        let response = match (command, status) {
+            (Some(binary::Command::SaslListMechs), _) => {
+                BinaryResponse::SaslListMechs(binary::SaslListMechsResponse { header, value })
+            }
+            (Some(binary::Command::SaslAuth), _) => {
+                BinaryResponse::SaslAuth(binary::SaslAuthResponse { header, value })
+            }
+            (Some(binary::Command::SaslStep), _) => {
+                BinaryResponse::SaslStep(binary::SaslStepResponse { header, value })
+            }
+            (_, Some(binary::ResponseStatus::NotMyVbucket)) => {
+                BinaryResponse::NotMyVbucket(binary::NotMyVbucketResponse { header, value })
+            }
+            (Some(binary::Command::WatchEvent), _) if extras.len() == 9 => {
+                BinaryResponse::WatchEvent(binary::WatchEventResponse {
+                    header,
+                    kind: extras.get_u8(),
+                    dropped: extras.get_u64(),
+                    key,
+                    namespace: value,
+                })
+            }
+            (Some(binary::Command::LeaseGet), _) => {
+                let (flags, token, stale) = match extras.len() {
+                    13 => (extras.get_u32(), extras.get_u64(), extras.get_u8() != 0),
+                    8 => (0, extras.get_u64(), false),
+                    _ => (0, 0, false),
+                };
+                BinaryResponse::LeaseGet(binary::LeaseGetResponse {
+                    header,
+                    flags,
+                    token,
+                    stale,
+                    value,
+                })
+            }
+            (_, Some(binary::ResponseStatus::Success)) => match command {
+                Some(binary::Command::Get) => BinaryResponse::Get(binary::GetResponse {
+                    header,
+                    flags,
+                    key,
+                    value,
+                }),
+                Some(binary::Command::GetQuiet) => {
+                    BinaryResponse::GetQuietly(binary::GetQuietlyResponse {
+                        header,
+                        flags,
+                        key,
+                        value,
+                    })
+                }
+                Some(binary::Command::GetKey) => BinaryResponse::GetKey(binary::GetKeyResponse {
+                    header,
+                    flags,
+                    key,
+                    value,
+                }),
+                Some(binary::Command::GetKeyQuiet) => {
+                    BinaryResponse::GetKeyQuietly(binary::GetKeyQuietlyResponse {
+                        header,
+                        flags,
+                        key,
+                        value,
+                    })
+                }
+                Some(binary::Command::Set) => BinaryResponse::Set(binary::SetResponse { header }),
+                Some(binary::Command::Add) => BinaryResponse::Add(binary::AddResponse { header }),
+                Some(binary::Command::Replace) => {
+                    BinaryResponse::Replace(binary::ReplaceResponse { header })
+                }
+                Some(binary::Command::Append) => {
+                    BinaryResponse::Append(binary::AppendResponse { header })
+                }
+                Some(binary::Command::Prepend) => {
+                    BinaryResponse::Prepend(binary::PrependResponse { header })
+                }
+                Some(binary::Command::Delete) => {
+                    BinaryResponse::Delete(binary::DeleteResponse { header })
+                }
+                Some(command @ (binary::Command::Increment | binary::Command::Decrement))
+                    if value.len() == 8 =>
+                {
+                    let response = binary::IncrementResponse {
+                        header,
+                        value: u64::from_be_bytes(value[..].try_into().unwrap()),
+                    };
+                    match command {
+                        binary::Command::Decrement => BinaryResponse::Decrement(response),
+                        _ => BinaryResponse::Increment(response),
+                    }
+                }
+                Some(binary::Command::Touch) => {
+                    BinaryResponse::Touch(binary::TouchResponse { header })
+                }
+                Some(binary::Command::GetAndTouch) => {
+                    BinaryResponse::GetAndTouch(binary::GetAndTouchResponse {
+                        header,
+                        flags,
+                        key,
+                        value,
+                    })
+                }
+                Some(binary::Command::GetAndTouchQuiet) => {
+                    BinaryResponse::GetAndTouchQuietly(binary::GetAndTouchQuietlyResponse {
+                        header,
+                        flags,
+                        key,
+                        value,
+                    })
+                }
+                Some(binary::Command::Flush) => {
+                    BinaryResponse::Flush(binary::FlushResponse { header })
+                }
+                Some(binary::Command::Version) => {
+                    BinaryResponse::Version(binary::VersionResponse { header, value })
+                }
+                Some(binary::Command::Stat) if key.is_empty() => {
+                    BinaryResponse::Stat(binary::StatResponse {
+                        header,
+                        stats: std::mem::take(&mut self.stats),
+                    })
+                }
+                Some(binary::Command::Stat) => {
+                    self.stats.push((
+                        String::from_utf8_lossy(&key).into_owned(),
+                        String::from_utf8_lossy(&value).into_owned(),
+                    ));
+                    return None;
+                }
+                Some(binary::Command::SlowLog) if extras.is_empty() => {
+                    BinaryResponse::SlowLog(binary::SlowLogResponse {
+                        header,
+                        entries: std::mem::take(&mut self.slow_requests),
+                    })
+                }
+                Some(binary::Command::SlowLog) if extras.len() == 29 => {
+                    self.slow_requests.push(binary::SlowLogEntry {
+                        id: extras.get_u64(),
+                        timestamp: extras.get_u64(),
+                        duration_us: extras.get_u64(),
+                        size: extras.get_u32(),
+                        opcode: extras.get_u8(),
+                        key,
+                        peer: value,
+                    });
+                    return None;
+                }
+                Some(binary::Command::Snapshot) => {
+                    BinaryResponse::Snapshot(binary::SnapshotResponse { header })
+                }
+                Some(binary::Command::LeaseSet) => {
+                    BinaryResponse::LeaseSet(binary::LeaseSetResponse { header })
+                }
+                Some(binary::Command::SetTagged) => {
+                    BinaryResponse::SetTagged(binary::SetTaggedResponse { header })
+                }
+                Some(binary::Command::InvalidateTag) => {
+                    BinaryResponse::InvalidateTag(binary::InvalidateTagResponse { header })
+                }
+                Some(binary::Command::Watch) => {
+                    BinaryResponse::Watch(binary::WatchResponse { header })
+                }
+                Some(binary::Command::MetaDump) if key.is_empty() && extras.len() == 16 => {
+                    BinaryResponse::MetaDump(binary::MetaDumpResponse {
+                        header,
+                        entries: std::mem::take(&mut self.entries),
+                        cursor: extras.get_u128(),
+                    })
+                }
+                Some(binary::Command::MetaDump) if extras.len() == 33 => {
+                    self.entries.push(binary::MetaDumpEntry {
+                        flags: extras.get_u32(),
+                        cas: extras.get_u64(),
+                        expires_at: extras.get_u64(),
+                        last_access: extras.get_u64(),
+                        size: extras.get_u32(),
+                        segment: extras.get_u8(),
+                        key,
+                        namespace: value,
+                    });
+                    return None;
+                }
+                Some(binary::Command::Noop) => {
+                    BinaryResponse::Noop(binary::NoopResponse { header })
+                }
+                _ => BinaryResponse::Error(binary::ErrorResponse { header }),
+            },
+            _ => BinaryResponse::Error(binary::ErrorResponse { header }),
+        };
+        Some(response)
+    }
+
+    pub(crate) fn write_msg(&self, msg: &BinaryRequest, dst: &mut BytesMut) {
+        let header = *msg.get_header();
         match msg {
-            BinaryResponse::Get(response) => {
-                dst.put_u32(response.flags);
-                dst.put_slice(&response.key[..]);
-                dst.put_slice(&response.value[..])
+            BinaryRequest::Get(request)
+            | BinaryRequest::GetQuietly(request)
+            | BinaryRequest::GetKey(request)
+            | BinaryRequest::GetKeyQuietly(request)
+            | BinaryRequest::Stat(request)
+            | BinaryRequest::LeaseGet(request)
+            | BinaryRequest::InvalidateTag(request) => {
+                self.write_packet(header, &[], &request.key, &[], dst)
+            }
+            BinaryRequest::Delete(request) | BinaryRequest::DeleteQuietly(request) => {
+                self.write_packet(header, &[], &request.key, &[], dst)
+            }
+            BinaryRequest::Append(request)
+            | BinaryRequest::AppendQuietly(request)
+            | BinaryRequest::Prepend(request)
+            | BinaryRequest::PrependQuietly(request) => {
+                self.write_packet(header, &[], &request.key, &request.value, dst)
+            }
+            BinaryRequest::Set(request)
+            | BinaryRequest::SetQuietly(request)
+            | BinaryRequest::Add(request)
+            | BinaryRequest::AddQuietly(request)
+            | BinaryRequest::Replace(request)
+            | BinaryRequest::ReplaceQuietly(request) => {
+                // Plain memcached servers only take the first eight bytes.
+                let mut extras = Vec::with_capacity(16);
+                extras.extend_from_slice(&request.flags.to_be_bytes());
+                extras.extend_from_slice(&request.expiration.to_be_bytes());
+                if request.grace != 0 || request.recompute_ms != 0 {
+                    extras.extend_from_slice(&request.grace.to_be_bytes());
+                    extras.extend_from_slice(&request.recompute_ms.to_be_bytes());
+                }
+                self.write_packet(header, &extras, &request.key, &request.value, dst)
+            }
+            BinaryRequest::LeaseSet(request) => {
+                let mut extras = [0; 24];
+                extras[..4].copy_from_slice(&request.flags.to_be_bytes());
+                extras[4..8].copy_from_slice(&request.expiration.to_be_bytes());
+                extras[8..16].copy_from_slice(&request.token.to_be_bytes());
+                extras[16..20].copy_from_slice(&request.grace.to_be_bytes());
+                extras[20..].copy_from_slice(&request.recompute_ms.to_be_bytes());
+                self.write_packet(header, &extras, &request.key, &request.value, dst)
+            }
+            BinaryRequest::SetTagged(request) => {
+                let mut extras = Vec::with_capacity(8);
+                extras.extend_from_slice(&request.flags.to_be_bytes());
+                extras.extend_from_slice(&request.expiration.to_be_bytes());
+                for tag in &request.tags {
+                    extras.push(tag.len() as u8);
+                    extras.extend_from_slice(tag);
+                }
+                self.write_packet(header, &extras, &request.key, &request.value, dst)
+            }
+            BinaryRequest::Increment(request)
+            | BinaryRequest::IncrementQuietly(request)
+            | BinaryRequest::Decrement(request)
+            | BinaryRequest::DecrementQuietly(request) => {
+                let mut extras = [0; 20];
+                extras[..8].copy_from_slice(&request.delta.to_be_bytes());
+                extras[8..16].copy_from_slice(&request.initial.to_be_bytes());
+                extras[16..].copy_from_slice(&request.expiration.to_be_bytes());
+                self.write_packet(header, &extras, &request.key, &[], dst)
             }
-            BinaryResponse::GetQuietly(response) => {
-                dst.put_u32(response.flags);
-                dst.put_slice(&response.key[..]);
-                dst.put_slice(&response.value[..])
+            BinaryRequest::Touch(request)
+            | BinaryRequest::GetAndTouch(request)
+            | BinaryRequest::GetAndTouchQuietly(request) => self.write_packet(
+                header,
+                &request.expiration.to_be_bytes(),
+                &request.key,
+                &[],
+                dst,
+            ),
+            BinaryRequest::Flush(request) | BinaryRequest::FlushQuietly(request) => {
+                self.write_packet(header, &request.expiration.to_be_bytes(), &[], &[], dst)
             }
-            BinaryResponse::GetKey(response) => {
-                dst.put_u32(response.flags);
-                dst.put_slice(&response.key[..]);
+            BinaryRequest::Watch(request) => {
+                self.write_packet(header, &request.kinds.to_be_bytes(), &request.key, &[], dst)
             }
-            BinaryResponse::GetKeyQuietly(response) => {
-                dst.put_u32(response.flags);
-                dst.put_slice(&response.key[..]);
+            BinaryRequest::SlowLog(request) => {
+                self.write_packet(header, &request.count.to_be_bytes(), &[], &[], dst)
             }
+            BinaryRequest::MetaDump(request) => {
+                let mut extras = [0; 20];
+                extras[..16].copy_from_slice(&request.cursor.to_be_bytes());
+                extras[16..].copy_from_slice(&request.count.to_be_bytes());
+                self.write_packet(header, &extras, &request.key, &[], dst)
+            }
+            BinaryRequest::SaslListMechs(_)
+            | BinaryRequest::Snapshot(_)
+            | BinaryRequest::Noop(_)
+            | BinaryRequest::Invalid(_)
+            | BinaryRequest::Version(_) => self.write_packet(header, &[], &[], &[], dst),
+            BinaryRequest::SaslAuth(request) | BinaryRequest::SaslStep(request) => {
+                self.write_packet(header, &[], &request.key, &request.value, dst)
+            }
+        }
+    }
+
+    /// Writes a single packet, deriving the length fields from the body parts.
+    fn write_packet(
+        &self,
+        mut header: binary::RequestHeader,
+        extras: &[u8],
+        key: &[u8],
+        value: &[u8],
+        dst: &mut BytesMut,
+    ) {
+        header.magic = binary::Magic::Request as u8;
+        header.extras_length = extras.len() as u8;
+        header.key_length = key.len() as u16;
+        header.body_length = (extras.len() + key.len() + value.len()) as u32;
+        dst.reserve(MemcachedBinaryClientCodec::HEADER_LEN + header.body_length as usize);
+        dst.put_u8(header.magic);
+        dst.put_u8(header.opcode);
+        dst.put_u16(header.key_length);
+        dst.put_u8(header.extras_length);
+        dst.put_u8(header.data_type);
+        dst.put_u16(header.reserved);
+        dst.put_u32(header.body_length);
+        dst.put_u32(header.opaque);
+        dst.put_u64(header.cas);
+        dst.put_slice(extras);
+        dst.put_slice(key);
+        dst.put_slice(value);
+    }
+}
 
-            BinaryResponse::Set(response) => {}
-            BinaryResponse::Add(response) => {}
-            BinaryResponse::Replace(response) => {}
+impl Decoder for MemcachedBinaryClientCodec {
+    type Item = BinaryResponse;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let header = match self.header {
+                Some(header) => header,
+                None => {
+                    if src.len() < MemcachedBinaryClientCodec::HEADER_LEN {
+                        return Ok(None);
+                    }
+                    let header = MemcachedBinaryClientCodec::parse_header(src)?;
+                    self.header = Some(header);
+                    header
+                }
+            };
+            if src.len() < header.body_length as usize {
+                return Ok(None);
+            }
+            self.header = None;
+            let mut body = src.split_to(header.body_length as usize);
+            let extras = body.split_to(header.extras_length as usize);
+            let key = body.split_to(header.key_length as usize).to_vec();
+            if let Some(response) = self.parse(header, extras, key, body.to_vec()) {
+                return Ok(Some(response));
+            }
         }
     }
 }
 
-impl Encoder<BinaryResponse> for MemcachedBinaryCodec {
+impl Encoder<BinaryRequest> for MemcachedBinaryClientCodec {
     type Error = io::Error;
 
-    fn encode(&mut self, msg: BinaryResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        dst.reserve(self.get_length(&msg));
+    fn encode(&mut self, msg: BinaryRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
         self.write_msg(&msg, dst);
         Ok(())
     }
@@ -288,6 +1345,153 @@ impl Encoder<BinaryResponse> for MemcachedBinaryCodec {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn request_bytes(opcode: u8, extras: &[u8], key: &[u8], value: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(binary::Magic::Request as u8);
+        buf.put_u8(opcode);
+        buf.put_u16(key.len() as u16);
+        buf.put_u8(extras.len() as u8);
+        buf.put_u8(0);
+        buf.put_u16(0);
+        buf.put_u32((extras.len() + key.len() + value.len()) as u32);
+        buf.put_u32(0xCAFE);
+        buf.put_u64(0);
+        buf.put_slice(extras);
+        buf.put_slice(key);
+        buf.put_slice(value);
+        buf
+    }
+
     #[test]
     fn test_encode_decode() {}
+
+    #[test]
+    fn decode_set_request() {
+        let mut codec = MemcachedBinaryCodec::new();
+        let mut extras = BytesMut::new();
+        extras.put_u32(0xF00D);
+        extras.put_u32(60);
+        let mut src = request_bytes(binary::Command::Set as u8, &extras, b"key", b"value");
+
+        match codec.decode(&mut src).unwrap() {
+            Some(BinaryRequest::Set(request)) => {
+                assert_eq!(request.header.opaque, 0xCAFE);
+                assert_eq!(request.flags, 0xF00D);
+                assert_eq!(request.expiration, 60);
+                assert_eq!(request.key, b"key");
+                assert_eq!(request.value, b"value");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(src.is_empty());
+        assert_eq!(codec.last_key(), b"key");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(codec.last_key().is_empty());
+    }
+
+    #[test]
+    fn decode_flags_unknown_and_malformed_requests() {
+        let mut codec = MemcachedBinaryCodec::new();
+        let mut src = request_bytes(0x3f, &[], b"ignored", b"body");
+        src.extend_from_slice(&request_bytes(
+            binary::Command::Set as u8,
+            &[0; 4],
+            b"key",
+            b"value",
+        ));
+        src.extend_from_slice(&request_bytes(
+            binary::Command::SaslAuth as u8,
+            &[],
+            b"PLAIN",
+            b"\0user\0pass",
+        ));
+
+        for status in [
+            binary::ResponseStatus::UnknownCommand,
+            binary::ResponseStatus::InvalidArguments,
+        ] {
+            match codec.decode(&mut src).unwrap() {
+                Some(BinaryRequest::Invalid(request)) => {
+                    assert_eq!(request.status, status as u16);
+                    assert_eq!(request.header.opaque, 0xCAFE);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        match codec.decode(&mut src).unwrap() {
+            Some(BinaryRequest::SaslAuth(request)) => {
+                assert_eq!(request.key, b"PLAIN");
+                assert_eq!(request.value, b"\0user\0pass");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encode_derives_lengths() {
+        let mut codec = MemcachedBinaryCodec::new();
+        let mut dst = BytesMut::new();
+        let response = BinaryResponse::Get(binary::GetResponse {
+            header: binary::ResponseHeader::new(binary::Command::Get as u8, 9),
+            flags: 1,
+            key: Vec::new(),
+            value: b"abc".to_vec(),
+        });
+        codec.encode(response, &mut dst).unwrap();
+
+        assert_eq!(dst.len(), 24 + 4 + 3);
+        assert_eq!(dst[4], 4);
+        assert_eq!(&dst[8..12], &7u32.to_be_bytes());
+        assert_eq!(&dst[12..16], &9u32.to_be_bytes());
+        assert_eq!(&dst[28..], b"abc");
+    }
+
+    #[test]
+    fn client_codec_round_trips_through_server_codec() {
+        let mut client = MemcachedBinaryClientCodec::new();
+        let mut server = MemcachedBinaryCodec::new();
+        let mut wire = BytesMut::new();
+        let request = BinaryRequest::Set(binary::SetRequest {
+            header: binary::RequestHeader {
+                opcode: binary::Command::Set as u8,
+                opaque: 7,
+                ..binary::RequestHeader::default()
+            },
+            flags: 3,
+            expiration: 60,
+            grace: 30,
+            recompute_ms: 500,
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+        });
+        client.encode(request, &mut wire).unwrap();
+        match server.decode(&mut wire).unwrap() {
+            Some(BinaryRequest::Set(request)) => {
+                assert_eq!(request.header.opaque, 7);
+                assert_eq!((request.flags, request.expiration), (3, 60));
+                assert_eq!((request.grace, request.recompute_ms), (30, 500));
+                assert_eq!(request.value, b"value");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let header = binary::ResponseHeader::new(binary::Command::Stat as u8, 8);
+        let stats = vec![("pid".to_string(), "1".to_string())];
+        server
+            .encode(
+                BinaryResponse::Stat(binary::StatResponse { header, stats }),
+                &mut wire,
+            )
+            .unwrap();
+        match client.decode(&mut wire).unwrap() {
+            Some(BinaryResponse::Stat(response)) => {
+                assert_eq!(response.header.opaque, 8);
+                assert_eq!(response.stats, vec![("pid".to_string(), "1".to_string())]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(wire.is_empty());
+    }
 }
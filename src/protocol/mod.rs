@@ -1,2 +1,3 @@
 pub mod binary;
 pub mod binary_codec;
+pub mod text_codec;
@@ -0,0 +1,437 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use std::str::FromStr;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Longest key the text protocol accepts.
+pub const MAX_KEY_LENGTH: usize = 250;
+
+/// Longest command line; a client sending more without a line end is cut off.
+const MAX_LINE_LENGTH: usize = 2048;
+
+/// Largest data block of a storage command.
+const MAX_VALUE_LENGTH: usize = 1 << 20;
+
+/// The storage commands of the text protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreCommand {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas,
+}
+
+impl FromStr for StoreCommand {
+    type Err = ();
+
+    fn from_str(command: &str) -> Result<Self, Self::Err> {
+        match command {
+            "set" => Ok(StoreCommand::Set),
+            "add" => Ok(StoreCommand::Add),
+            "replace" => Ok(StoreCommand::Replace),
+            "append" => Ok(StoreCommand::Append),
+            "prepend" => Ok(StoreCommand::Prepend),
+            "cas" => Ok(StoreCommand::Cas),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreRequest {
+    pub command: StoreCommand,
+    pub key: Vec<u8>,
+    pub flags: u32,
+    pub expiration: u32,
+    /// The CAS value a `cas` command must match, 0 for the others.
+    pub cas: u64,
+    pub value: Vec<u8>,
+    pub noreply: bool,
+}
+
+/// A command of the memcached text protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextRequest {
+    /// `get` and `gets`, the latter answering the CAS values too.
+    Get {
+        keys: Vec<Vec<u8>>,
+        cas: bool,
+    },
+    Store(StoreRequest),
+    Delete {
+        key: Vec<u8>,
+        noreply: bool,
+    },
+    /// `incr` and `decr`.
+    Delta {
+        key: Vec<u8>,
+        delta: u64,
+        increment: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: Vec<u8>,
+        expiration: u32,
+        noreply: bool,
+    },
+    Stats {
+        group: Vec<u8>,
+    },
+    Version,
+    Quit,
+    /// A line that could not be served, answered with `error` alone.
+    Invalid {
+        error: String,
+    },
+}
+
+/// An item sent back by a `get` or `gets`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextValue {
+    pub key: Vec<u8>,
+    pub flags: u32,
+    /// Only sent for `gets`.
+    pub cas: Option<u64>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextResponse {
+    /// A single line such as `STORED`, `NOT_FOUND` or `CLIENT_ERROR ...`.
+    Line(String),
+    /// `VALUE` entries followed by `END`.
+    Values(Vec<TextValue>),
+    /// `STAT` entries followed by `END`.
+    Stats(Vec<(String, String)>),
+}
+
+/// Decodes text protocol commands and encodes their responses, the text
+/// counterpart of `MemcachedBinaryCodec`. Malformed commands come back as
+/// `TextRequest::Invalid` for the caller to answer, as memcached does; only
+/// an overlong line or data block fails the connection.
+#[derive(Default)]
+pub struct MemcachedTextCodec {}
+
+impl MemcachedTextCodec {
+    pub fn new() -> MemcachedTextCodec {
+        MemcachedTextCodec {}
+    }
+
+    /// Parses the command `line`. Storage commands also take their data
+    /// block from `data`, the bytes after the line; `None` means it has
+    /// not fully arrived. Returns the request and the data bytes it used.
+    fn parse(line: &[u8], data: &[u8]) -> io::Result<Option<(TextRequest, usize)>> {
+        let fields: Vec<&[u8]> = line
+            .split(|byte| *byte == b' ')
+            .filter(|field| !field.is_empty())
+            .collect();
+        let invalid = |error: &str| {
+            Ok(Some((
+                TextRequest::Invalid {
+                    error: error.to_string(),
+                },
+                0,
+            )))
+        };
+        let bad_format = || invalid("CLIENT_ERROR bad command line format");
+        let (command, args) = match fields.split_first() {
+            Some((command, args)) => (String::from_utf8_lossy(command), args),
+            None => return invalid("ERROR"),
+        };
+        if args.first().is_some_and(|key| key.len() > MAX_KEY_LENGTH) {
+            return bad_format();
+        }
+        let noreply = |index: usize| match args.get(index) {
+            None => Some(false),
+            Some(&b"noreply") => Some(true),
+            Some(_) => None,
+        };
+        let request = match (command.as_ref(), args) {
+            ("get" | "gets", [_, ..]) => {
+                if args.iter().any(|key| key.len() > MAX_KEY_LENGTH) {
+                    return bad_format();
+                }
+                TextRequest::Get {
+                    keys: args.iter().map(|key| key.to_vec()).collect(),
+                    cas: command == "gets",
+                }
+            }
+            (command, [key, flags, expiration, length, rest @ ..])
+                if command.parse::<StoreCommand>().is_ok() =>
+            {
+                let command = command.parse().unwrap();
+                let (cas, reply_index) = match command {
+                    StoreCommand::Cas => match rest.first().and_then(|cas| number(cas)) {
+                        Some(cas) => (cas, 5),
+                        None => return bad_format(),
+                    },
+                    _ => (0, 4),
+                };
+                let fields = (
+                    number(flags),
+                    number(expiration),
+                    number::<usize>(length),
+                    noreply(reply_index),
+                );
+                let (flags, expiration, length, noreply) = match fields {
+                    (Some(flags), Some(expiration), Some(length), Some(noreply))
+                        if args.len() <= reply_index + 1 =>
+                    {
+                        (flags, expiration, length, noreply)
+                    }
+                    _ => return bad_format(),
+                };
+                if length > MAX_VALUE_LENGTH {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "data block too large",
+                    ));
+                }
+                if data.len() < length + 2 {
+                    return Ok(None);
+                }
+                if &data[length..length + 2] != b"\r\n" {
+                    // Skip the rest of the overlong block's line with it.
+                    let skipped = match data[length..].iter().position(|byte| *byte == b'\n') {
+                        Some(line_end) => length + line_end + 1,
+                        None => return Ok(None),
+                    };
+                    return Ok(Some((
+                        TextRequest::Invalid {
+                            error: "CLIENT_ERROR bad data chunk".to_string(),
+                        },
+                        skipped,
+                    )));
+                }
+                let request = TextRequest::Store(StoreRequest {
+                    command,
+                    key: key.to_vec(),
+                    flags,
+                    expiration,
+                    cas,
+                    value: data[..length].to_vec(),
+                    noreply,
+                });
+                return Ok(Some((request, length + 2)));
+            }
+            ("delete", [key, ..]) => match noreply(1) {
+                Some(noreply) if args.len() <= 2 => TextRequest::Delete {
+                    key: key.to_vec(),
+                    noreply,
+                },
+                _ => return bad_format(),
+            },
+            ("incr" | "decr", [key, delta, ..]) => match (number(delta), noreply(2)) {
+                (Some(delta), Some(noreply)) if args.len() <= 3 => TextRequest::Delta {
+                    key: key.to_vec(),
+                    delta,
+                    increment: command == "incr",
+                    noreply,
+                },
+                (None, _) => {
+                    return invalid("CLIENT_ERROR invalid numeric delta argument");
+                }
+                _ => return bad_format(),
+            },
+            ("touch", [key, expiration, ..]) => match (number(expiration), noreply(2)) {
+                (Some(expiration), Some(noreply)) if args.len() <= 3 => TextRequest::Touch {
+                    key: key.to_vec(),
+                    expiration,
+                    noreply,
+                },
+                _ => return bad_format(),
+            },
+            ("stats", []) => TextRequest::Stats { group: Vec::new() },
+            ("stats", [group]) => TextRequest::Stats {
+                group: group.to_vec(),
+            },
+            ("version", []) => TextRequest::Version,
+            ("quit", []) => TextRequest::Quit,
+            _ => return invalid("ERROR"),
+        };
+        Ok(Some((request, 0)))
+    }
+}
+
+fn number<T: FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+impl Decoder for MemcachedTextCodec {
+    type Item = TextRequest;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let line_end = match src.iter().position(|byte| *byte == b'\n') {
+            Some(line_end) => line_end,
+            None if src.len() > MAX_LINE_LENGTH => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+            }
+            None => return Ok(None),
+        };
+        let line = src[..line_end]
+            .strip_suffix(b"\r")
+            .unwrap_or(&src[..line_end]);
+        match MemcachedTextCodec::parse(line, &src[line_end + 1..])? {
+            Some((request, data_len)) => {
+                src.advance(line_end + 1 + data_len);
+                Ok(Some(request))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<TextResponse> for MemcachedTextCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: TextResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match msg {
+            TextResponse::Line(line) => {
+                dst.put_slice(line.as_bytes());
+                dst.put_slice(b"\r\n");
+            }
+            TextResponse::Values(values) => {
+                for value in values {
+                    dst.put_slice(b"VALUE ");
+                    dst.put_slice(&value.key);
+                    let header = match value.cas {
+                        Some(cas) => format!(" {} {} {}\r\n", value.flags, value.value.len(), cas),
+                        None => format!(" {} {}\r\n", value.flags, value.value.len()),
+                    };
+                    dst.put_slice(header.as_bytes());
+                    dst.put_slice(&value.value);
+                    dst.put_slice(b"\r\n");
+                }
+                dst.put_slice(b"END\r\n");
+            }
+            TextResponse::Stats(stats) => {
+                for (name, value) in stats {
+                    dst.put_slice(format!("STAT {} {}\r\n", name, value).as_bytes());
+                }
+                dst.put_slice(b"END\r\n");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(input: &[u8]) -> Vec<TextRequest> {
+        let mut codec = MemcachedTextCodec::new();
+        let mut src = BytesMut::from(input);
+        let mut requests = Vec::new();
+        while let Some(request) = codec.decode(&mut src).unwrap() {
+            requests.push(request);
+        }
+        assert!(src.is_empty());
+        requests
+    }
+
+    #[test]
+    fn decode_commands() {
+        let requests = decode_all(
+            b"gets a b\r\nset k 5 60 3 noreply\r\nv\r\n\r\ncas k 0 0 1 42\r\nw\r\n\
+              incr k 2\r\ndelete k noreply\r\nbogus\r\nincr k x\r\n",
+        );
+        assert_eq!(
+            requests[0],
+            TextRequest::Get {
+                keys: vec![b"a".to_vec(), b"b".to_vec()],
+                cas: true
+            }
+        );
+        assert_eq!(
+            requests[1],
+            TextRequest::Store(StoreRequest {
+                command: StoreCommand::Set,
+                key: b"k".to_vec(),
+                flags: 5,
+                expiration: 60,
+                cas: 0,
+                value: b"v\r\n".to_vec(),
+                noreply: true,
+            })
+        );
+        match &requests[2] {
+            TextRequest::Store(request) => {
+                assert_eq!((request.command, request.cas), (StoreCommand::Cas, 42))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            requests[3],
+            TextRequest::Delta {
+                key: b"k".to_vec(),
+                delta: 2,
+                increment: true,
+                noreply: false
+            }
+        );
+        assert_eq!(
+            requests[4],
+            TextRequest::Delete {
+                key: b"k".to_vec(),
+                noreply: true
+            }
+        );
+        assert_eq!(
+            requests[5],
+            TextRequest::Invalid {
+                error: "ERROR".to_string()
+            }
+        );
+        assert!(matches!(requests[6], TextRequest::Invalid { .. }));
+    }
+
+    #[test]
+    fn storage_commands_wait_for_their_data() {
+        let mut codec = MemcachedTextCodec::new();
+        let mut src = BytesMut::from(&b"set k 0 0 5\r\nab"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(b"cde\r\n");
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(TextRequest::Store(_))
+        ));
+
+        let mut src = BytesMut::from(&b"set k 0 0 1\r\nab\r\nversion\r\n"[..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(TextRequest::Invalid {
+                error: "CLIENT_ERROR bad data chunk".to_string()
+            })
+        );
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(TextRequest::Version));
+    }
+
+    #[test]
+    fn encode_values_and_stats() {
+        let mut codec = MemcachedTextCodec::new();
+        let mut dst = BytesMut::new();
+        let value = TextValue {
+            key: b"k".to_vec(),
+            flags: 3,
+            cas: Some(9),
+            value: b"abc".to_vec(),
+        };
+        codec
+            .encode(TextResponse::Values(vec![value]), &mut dst)
+            .unwrap();
+        codec
+            .encode(
+                TextResponse::Stats(vec![("pid".to_string(), "1".to_string())]),
+                &mut dst,
+            )
+            .unwrap();
+        assert_eq!(
+            &dst[..],
+            &b"VALUE k 3 3 9\r\nabc\r\nEND\r\nSTAT pid 1\r\nEND\r\n"[..]
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use crate::bench::OpStats;
+use crate::memcached::capture::{CaptureReader, CapturedPacket};
+use crate::protocol::binary;
+use crate::protocol::binary_codec::{
+    BinaryRequest, BinaryResponse, MemcachedBinaryClientCodec, MemcachedBinaryCodec,
+};
+use bytes::BytesMut;
+use futures_util::{SinkExt, StreamExt};
+use num_traits::FromPrimitive;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// Mismatches kept in the report to show what went wrong.
+const MAX_EXAMPLES: usize = 20;
+
+/// Opaque of the noop sent to collect the answers to trailing quiet requests.
+const FINAL_NOOP: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub server: String,
+    pub capture: PathBuf,
+    /// How much faster than recorded to replay; 0 sends every request as
+    /// soon as the one before it is answered.
+    pub speed: f64,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            server: "127.0.0.1:11211".to_string(),
+            capture: PathBuf::new(),
+            speed: 1.0,
+        }
+    }
+}
+
+/// A captured request with the response it got, if any, and how long that
+/// took.
+struct Step {
+    at: Duration,
+    request: BinaryRequest,
+    response: Option<(BinaryResponse, Duration)>,
+}
+
+/// Reads `path` into the requests of each captured connection, in order.
+fn load(path: &PathBuf) -> io::Result<(BTreeMap<u64, Vec<Step>>, Duration)> {
+    let mut connections: BTreeMap<u64, Vec<Step>> = BTreeMap::new();
+    let mut span = Duration::ZERO;
+    for entry in CaptureReader::open(path)? {
+        let entry = entry?;
+        span = span.max(entry.at);
+        let steps = connections.entry(entry.connection).or_default();
+        match entry.packet {
+            CapturedPacket::Request(request) => steps.push(Step {
+                at: entry.at,
+                request,
+                response: None,
+            }),
+            CapturedPacket::Response(response) => match steps.last_mut() {
+                Some(step) if step.response.is_none() => {
+                    step.response = Some((response, entry.at.saturating_sub(step.at)));
+                }
+                _ => warn!("Ignoring captured response without a request"),
+            },
+        }
+    }
+    Ok((connections, span))
+}
+
+/// What a response is compared on: everything but the opaque and the CAS,
+/// which differ between servers. Stats, versions and granted leases only
+/// compare the status.
+fn fingerprint(response: &BinaryResponse) -> Vec<u8> {
+    match response {
+        BinaryResponse::Stat(_) | BinaryResponse::Version(_) => {
+            response.get_header().status.to_be_bytes().to_vec()
+        }
+        BinaryResponse::LeaseGet(lease) if lease.token != 0 => {
+            response.get_header().status.to_be_bytes().to_vec()
+        }
+        _ => {
+            let mut packet = BytesMut::new();
+            MemcachedBinaryCodec::new().write_msg(response, &mut packet);
+            packet[12..24].fill(0);
+            packet.to_vec()
+        }
+    }
+}
+
+/// A request answered differently than when it was captured.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub connection: u64,
+    pub opcode: u8,
+    pub key: Vec<u8>,
+    /// Status of the captured response, `None` if there was none.
+    pub expected: Option<u16>,
+    pub actual: Option<u16>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = |status: Option<u16>| match status {
+            None => "no response".to_string(),
+            Some(status) => match binary::ResponseStatus::from_u16(status) {
+                Some(status) => format!("{:?}", status),
+                None => format!("status {:#x}", status),
+            },
+        };
+        match binary::Command::from_u8(self.opcode) {
+            Some(command) => write!(f, "connection {} {:?}", self.connection, command)?,
+            None => write!(
+                f,
+                "connection {} opcode {:#x}",
+                self.connection, self.opcode
+            )?,
+        }
+        if !self.key.is_empty() {
+            write!(f, " {:?}", String::from_utf8_lossy(&self.key))?;
+        }
+        write!(
+            f,
+            ": expected {}, got {}",
+            status(self.expected),
+            status(self.actual)
+        )?;
+        if self.expected == self.actual {
+            write!(f, " with a different body")?;
+        }
+        Ok(())
+    }
+}
+
+/// The results of a replay.
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+    pub elapsed: Duration,
+    /// Time between the first and last packet of the capture.
+    pub captured: Duration,
+    pub connections: u64,
+    pub requests: u64,
+    pub mismatches: u64,
+    /// The first mismatches found.
+    pub examples: Vec<Mismatch>,
+    /// Latencies of the answered requests when captured and when replayed.
+    pub original: OpStats,
+    pub replayed: OpStats,
+}
+
+impl ReplayReport {
+    fn new() -> ReplayReport {
+        ReplayReport {
+            elapsed: Duration::ZERO,
+            captured: Duration::ZERO,
+            connections: 0,
+            requests: 0,
+            mismatches: 0,
+            examples: Vec::new(),
+            original: OpStats::new(),
+            replayed: OpStats::new(),
+        }
+    }
+
+    fn merge(&mut self, other: ReplayReport) {
+        self.connections += other.connections;
+        self.requests += other.requests;
+        self.mismatches += other.mismatches;
+        let room = MAX_EXAMPLES.saturating_sub(self.examples.len());
+        self.examples.extend(other.examples.into_iter().take(room));
+        self.original.latency.add(&other.original.latency).unwrap();
+        self.replayed.latency.add(&other.replayed.latency).unwrap();
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "duration_secs": self.elapsed.as_secs_f64(),
+            "captured_secs": self.captured.as_secs_f64(),
+            "connections": self.connections,
+            "requests": self.requests,
+            "mismatches": self.mismatches,
+            "examples": self.examples.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+            "original": self.original.to_json(self.captured),
+            "replayed": self.replayed.to_json(self.elapsed),
+        })
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<9} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "", "answered", "mean(us)", "p50", "p90", "p99", "p99.9", "max"
+        )?;
+        for (name, stats) in [("original", &self.original), ("replayed", &self.replayed)] {
+            let percentile = |p: f64| stats.latency.value_at_quantile(p / 100.0);
+            writeln!(
+                f,
+                "{:<9} {:>10} {:>10.1} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                name,
+                stats.count(),
+                stats.latency.mean(),
+                percentile(50.0),
+                percentile(90.0),
+                percentile(99.0),
+                percentile(99.9),
+                stats.latency.max()
+            )?;
+        }
+        write!(
+            f,
+            "{} requests over {} connections in {:.2}s (captured over {:.2}s), {} mismatches",
+            self.requests,
+            self.connections,
+            self.elapsed.as_secs_f64(),
+            self.captured.as_secs_f64(),
+            self.mismatches
+        )?;
+        for mismatch in &self.examples {
+            write!(f, "\n  {}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+/// A replayed request waiting for its response.
+struct Pending {
+    opaque: u32,
+    opcode: u8,
+    key: Vec<u8>,
+    expected: Option<(BinaryResponse, Duration)>,
+    sent: Instant,
+}
+
+impl Pending {
+    fn complete(self, connection: u64, actual: Option<BinaryResponse>, report: &mut ReplayReport) {
+        report.requests += 1;
+        if let (Some((_, original)), Some(_)) = (&self.expected, &actual) {
+            report.original.record(*original);
+            report.replayed.record(self.sent.elapsed());
+        }
+        let expected = self.expected.as_ref().map(|(response, _)| response);
+        if expected.map(fingerprint) != actual.as_ref().map(fingerprint) {
+            report.mismatches += 1;
+            if report.examples.len() < MAX_EXAMPLES {
+                report.examples.push(Mismatch {
+                    connection,
+                    opcode: self.opcode,
+                    key: self.key,
+                    expected: expected.map(|response| response.get_header().status),
+                    actual: actual.map(|response| response.get_header().status),
+                });
+            }
+        }
+    }
+}
+
+/// Reads responses until the one to the request sent with `until`. The
+/// requests skipped over were quiet ones the server did not answer.
+async fn receive(
+    framed: &mut Framed<TcpStream, MemcachedBinaryClientCodec>,
+    pending: &mut VecDeque<Pending>,
+    connection: u64,
+    until: u32,
+    report: &mut ReplayReport,
+) -> io::Result<()> {
+    loop {
+        let response = match framed.next().await {
+            Some(response) => response?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed the connection",
+                ))
+            }
+        };
+        let opaque = response.get_header().opaque;
+        while pending
+            .front()
+            .is_some_and(|request| request.opaque < opaque)
+        {
+            pending
+                .pop_front()
+                .unwrap()
+                .complete(connection, None, report);
+        }
+        if pending
+            .front()
+            .is_some_and(|request| request.opaque == opaque)
+        {
+            pending
+                .pop_front()
+                .unwrap()
+                .complete(connection, Some(response), report);
+        }
+        if opaque == until {
+            return Ok(());
+        }
+    }
+}
+
+/// Replays the requests of one captured connection over a connection of its
+/// own, each no earlier than its capture time scaled by the speed.
+async fn replay_connection(
+    config: ReplayConfig,
+    connection: u64,
+    steps: Vec<Step>,
+    started: Instant,
+) -> io::Result<ReplayReport> {
+    let socket = TcpStream::connect(&config.server).await?;
+    socket.set_nodelay(true)?;
+    let mut framed = Framed::new(socket, MemcachedBinaryClientCodec::new());
+    let mut report = ReplayReport::new();
+    report.connections = 1;
+    let mut pending = VecDeque::new();
+
+    for (opaque, step) in steps.into_iter().enumerate() {
+        let opaque = opaque as u32;
+        if config.speed > 0.0 {
+            tokio::time::sleep_until((started + step.at.div_f64(config.speed)).into()).await;
+        }
+        let mut request = step.request;
+        request.get_header_mut().opaque = opaque;
+        let opcode = request.get_header().opcode;
+        pending.push_back(Pending {
+            opaque,
+            opcode,
+            key: request.key().unwrap_or_default().to_vec(),
+            expected: step.response,
+            sent: Instant::now(),
+        });
+        framed.send(request).await?;
+        let quiet = binary::Command::from_u8(opcode).is_some_and(|command| command.is_quiet());
+        if !quiet {
+            receive(&mut framed, &mut pending, connection, opaque, &mut report).await?;
+        }
+    }
+
+    if !pending.is_empty() {
+        let header = binary::RequestHeader {
+            opcode: binary::Command::Noop as u8,
+            opaque: FINAL_NOOP,
+            ..binary::RequestHeader::default()
+        };
+        framed
+            .send(BinaryRequest::Noop(binary::NoopRequest { header }))
+            .await?;
+        receive(
+            &mut framed,
+            &mut pending,
+            connection,
+            FINAL_NOOP,
+            &mut report,
+        )
+        .await?;
+    }
+    Ok(report)
+}
+
+/// Re-issues the capture described by `config` against its server, one
+/// connection per captured connection, and compares the responses with the
+/// captured ones. Requests carrying a CAS are sent with the captured one, so
+/// they only match if the target's CAS values do too.
+pub async fn run(config: &ReplayConfig) -> io::Result<ReplayReport> {
+    let (connections, captured) = load(&config.capture)?;
+    let started = Instant::now();
+    let tasks: Vec<_> = connections
+        .into_iter()
+        .map(|(connection, steps)| {
+            tokio::spawn(replay_connection(
+                config.clone(),
+                connection,
+                steps,
+                started,
+            ))
+        })
+        .collect();
+
+    let mut report = ReplayReport::new();
+    for task in tasks {
+        report.merge(
+            task.await
+                .map_err(|err| io::Error::other(err.to_string()))??,
+        );
+    }
+    report.elapsed = started.elapsed();
+    report.captured = captured;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::connection::Client;
+    use crate::memcached::capture::Capture;
+    use crate::memcached::server::TcpServer;
+    use crate::memcached::testing::{serve, start_server};
+
+    #[tokio::test]
+    async fn replay_a_capture() {
+        let path = std::env::temp_dir().join(format!("rustcache-replay-{}", std::process::id()));
+        let (captured, _) =
+            serve(TcpServer::new().with_capture(Capture::create(&path).unwrap())).await;
+        let mut client = Client::connect(&captured).await.unwrap();
+        assert!(client.get(b"a").await.unwrap().is_none());
+        client.set(b"a", b"1", 0, 0).await.unwrap();
+        client.set(b"b", b"2", 0, 0).await.unwrap();
+        let items = client.get_multi(&[b"a", b"b", b"c"]).await.unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(client.get(b"b").await.unwrap().unwrap().value, b"2");
+        drop(client);
+        // The capture is flushed once the server sees the connection close.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let config = ReplayConfig {
+            server: start_server().await.0,
+            capture: path.clone(),
+            speed: 0.0,
+        };
+        let report = run(&config).await.unwrap();
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.requests, 8);
+        assert_eq!(report.mismatches, 0, "{}", report);
+        assert_eq!(report.original.count(), 7);
+        assert_eq!(report.replayed.count(), 7);
+
+        // Against the store the capture left behind, the first get hits.
+        let config = ReplayConfig {
+            server: captured,
+            speed: 10.0,
+            ..config
+        };
+        let report = run(&config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(report.mismatches, 1);
+        // The probe waiting for the server to listen was connection 0.
+        assert_eq!(
+            report.examples[0].to_string(),
+            "connection 1 Get \"a\": expected KeyNotExists, got Success"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+extern crate failure;
+
+use crate::cache::Cache;
+use crate::client::connection::{Client, Item};
+use crate::client::error::ClientError;
+use crate::memcached::error::StorageError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// Turns values into bytes and back. Each codec has its own `FLAGS`, stored
+/// in the item's flags so that readers can tell how a value was encoded.
+/// The built-in codecs take flags 1 to 3; raw values are stored with 0.
+pub trait Codec {
+    const FLAGS: u32;
+    const NAME: &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String>;
+}
+
+pub struct Bincode;
+
+impl Codec for Bincode {
+    const FLAGS: u32 = 1;
+    const NAME: &'static str = "bincode";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+        bincode::serialize(value).map_err(|err| err.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        bincode::deserialize(bytes).map_err(|err| err.to_string())
+    }
+}
+
+pub struct Json;
+
+impl Codec for Json {
+    const FLAGS: u32 = 2;
+    const NAME: &'static str = "json";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(value).map_err(|err| err.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        serde_json::from_slice(bytes).map_err(|err| err.to_string())
+    }
+}
+
+/// MessagePack with struct fields keyed by name, so fields can be added
+/// without breaking readers.
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    const FLAGS: u32 = 3;
+    const NAME: &'static str = "msgpack";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(value).map_err(|err| err.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        rmp_serde::from_slice(bytes).map_err(|err| err.to_string())
+    }
+}
+
+/// The name of the built-in codec using `flags`.
+fn codec_name(flags: u32) -> &'static str {
+    match flags {
+        0 => "raw bytes",
+        Bincode::FLAGS => Bincode::NAME,
+        Json::FLAGS => Json::NAME,
+        MessagePack::FLAGS => MessagePack::NAME,
+        _ => "an unknown codec",
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum TypedError {
+    /// The value was stored by another codec, or as raw bytes.
+    #[fail(
+        display = "Value was stored as {} (flags {}), not {}",
+        found_name, found, expected
+    )]
+    CodecMismatch {
+        expected: &'static str,
+        found: u32,
+        found_name: &'static str,
+    },
+    #[fail(display = "Failed to encode value: {}", _0)]
+    Encode(String),
+    #[fail(display = "Failed to decode value: {}", _0)]
+    Decode(String),
+    #[fail(display = "{}", _0)]
+    Client(#[cause] ClientError),
+    #[fail(display = "{}", _0)]
+    Storage(#[cause] StorageError),
+}
+
+impl From<ClientError> for TypedError {
+    fn from(err: ClientError) -> TypedError {
+        TypedError::Client(err)
+    }
+}
+
+impl From<StorageError> for TypedError {
+    fn from(err: StorageError) -> TypedError {
+        TypedError::Storage(err)
+    }
+}
+
+pub type TypedResult<T> = Result<T, TypedError>;
+
+/// Encodes `value` with `C`, returning the bytes and the flags to store them
+/// with.
+pub fn encode<C: Codec, T: Serialize>(value: &T) -> TypedResult<(Vec<u8>, u32)> {
+    let bytes = C::encode(value).map_err(TypedError::Encode)?;
+    Ok((bytes, C::FLAGS))
+}
+
+/// Decodes a value stored with `flags`, which must be those of `C`.
+pub fn decode<C: Codec, T: DeserializeOwned>(bytes: &[u8], flags: u32) -> TypedResult<T> {
+    if flags != C::FLAGS {
+        return Err(TypedError::CodecMismatch {
+            expected: C::NAME,
+            found: flags,
+            found_name: codec_name(flags),
+        });
+    }
+    C::decode(bytes).map_err(TypedError::Decode)
+}
+
+impl Item {
+    /// Decodes the value of an item read from a server with `C`.
+    pub fn decode<C: Codec, T: DeserializeOwned>(&self) -> TypedResult<T> {
+        decode::<C, T>(&self.value, self.flags)
+    }
+}
+
+/// A `Client`, or a pooled one, reading and writing values of any
+/// serializable type with the codec `C`.
+pub struct TypedClient<'a, C: Codec> {
+    client: &'a mut Client,
+    codec: PhantomData<C>,
+}
+
+impl<'a, C: Codec> TypedClient<'a, C> {
+    pub fn new(client: &'a mut Client) -> TypedClient<'a, C> {
+        TypedClient {
+            client,
+            codec: PhantomData,
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&mut self, key: &[u8]) -> TypedResult<Option<T>> {
+        match self.client.get(key).await? {
+            Some(item) => Ok(Some(item.decode::<C, T>()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `value` under `key` and returns its new CAS.
+    pub async fn set<T: Serialize>(
+        &mut self,
+        key: &[u8],
+        value: &T,
+        expiration: u32,
+    ) -> TypedResult<u64> {
+        let (bytes, flags) = encode::<C, T>(value)?;
+        Ok(self.client.set(key, &bytes, flags, expiration).await?)
+    }
+
+    /// Stores `value` only if `key` does not exist yet.
+    pub async fn add<T: Serialize>(
+        &mut self,
+        key: &[u8],
+        value: &T,
+        expiration: u32,
+    ) -> TypedResult<u64> {
+        let (bytes, flags) = encode::<C, T>(value)?;
+        Ok(self.client.add(key, &bytes, flags, expiration).await?)
+    }
+
+    /// Stores `value` only if `key` exists.
+    pub async fn replace<T: Serialize>(
+        &mut self,
+        key: &[u8],
+        value: &T,
+        expiration: u32,
+    ) -> TypedResult<u64> {
+        let (bytes, flags) = encode::<C, T>(value)?;
+        Ok(self.client.replace(key, &bytes, flags, expiration).await?)
+    }
+
+    pub async fn delete(&mut self, key: &[u8]) -> TypedResult<bool> {
+        Ok(self.client.delete(key).await?)
+    }
+}
+
+/// An embedded `Cache` holding values of any serializable type, encoded
+/// with the codec `C`.
+pub struct TypedCache<C: Codec> {
+    cache: Cache,
+    codec: PhantomData<fn() -> C>,
+}
+
+impl<C: Codec> Clone for TypedCache<C> {
+    fn clone(&self) -> Self {
+        TypedCache::new(self.cache.clone())
+    }
+}
+
+impl<C: Codec> TypedCache<C> {
+    pub fn new(cache: Cache) -> TypedCache<C> {
+        TypedCache {
+            cache,
+            codec: PhantomData,
+        }
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn get<K: AsRef<[u8]>, T: DeserializeOwned>(&self, key: K) -> TypedResult<Option<T>> {
+        match self.cache.get_with_flags(key) {
+            Some((bytes, flags)) => Ok(Some(decode::<C, T>(&bytes, flags)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `value` under `key` with the cache's default TTL.
+    pub fn insert<K: AsRef<[u8]>, T: Serialize>(&self, key: K, value: &T) -> TypedResult<()> {
+        let (bytes, flags) = encode::<C, T>(value)?;
+        Ok(self.cache.insert_with_flags(key, &bytes, flags, None)?)
+    }
+
+    /// Stores `value` under `key`, expiring it after `ttl`.
+    pub fn insert_with_ttl<K: AsRef<[u8]>, T: Serialize>(
+        &self,
+        key: K,
+        value: &T,
+        ttl: Duration,
+    ) -> TypedResult<()> {
+        let (bytes, flags) = encode::<C, T>(value)?;
+        Ok(self
+            .cache
+            .insert_with_flags(key, &bytes, flags, Some(ttl))?)
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.cache.remove(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached::testing::start_server;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+    }
+
+    fn user() -> User {
+        User {
+            id: 7,
+            name: "alice".to_string(),
+            tags: vec!["admin".to_string()],
+        }
+    }
+
+    fn round_trip<C: Codec>() {
+        let (bytes, flags) = encode::<C, _>(&user()).unwrap();
+        assert_eq!(flags, C::FLAGS);
+        assert_eq!(decode::<C, User>(&bytes, flags).unwrap(), user());
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        round_trip::<Bincode>();
+        round_trip::<Json>();
+        round_trip::<MessagePack>();
+    }
+
+    #[test]
+    fn cache_rejects_another_codec() {
+        let json: TypedCache<Json> = TypedCache::new(Cache::new());
+        json.insert("user", &user()).unwrap();
+        assert_eq!(json.get::<_, User>("user").unwrap(), Some(user()));
+        assert_eq!(json.get::<_, User>("missing").unwrap(), None);
+
+        let bincode: TypedCache<Bincode> = TypedCache::new(json.cache().clone());
+        let err = bincode.get::<_, User>("user").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Value was stored as json (flags 2), not bincode"
+        );
+
+        json.cache().insert("raw", &b"{}".to_vec()).unwrap();
+        assert!(matches!(
+            json.get::<_, User>("raw"),
+            Err(TypedError::CodecMismatch { found: 0, .. })
+        ));
+        json.cache()
+            .insert_with_flags("bad", &b"{".to_vec(), Json::FLAGS, None)
+            .unwrap();
+        assert!(matches!(
+            json.get::<_, User>("bad"),
+            Err(TypedError::Decode(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_stores_the_codec_in_flags() {
+        let (addr, _) = start_server().await;
+        let mut client = Client::connect(&addr).await.unwrap();
+
+        let mut typed = TypedClient::<MessagePack>::new(&mut client);
+        typed.set(b"user", &user(), 0).await.unwrap();
+        assert_eq!(typed.get::<User>(b"user").await.unwrap(), Some(user()));
+        assert!(typed.add(b"user", &user(), 0).await.is_err());
+
+        let item = client.get(b"user").await.unwrap().unwrap();
+        assert_eq!(item.flags, MessagePack::FLAGS);
+        assert!(matches!(
+            item.decode::<Json, User>(),
+            Err(TypedError::CodecMismatch { found: 3, .. })
+        ));
+    }
+}